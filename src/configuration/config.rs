@@ -1,9 +1,13 @@
+use std::error::Error;
+use std::io;
 use std::mem;
 use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
 use std::sync::atomic::Ordering;
 use std::sync::{Arc, Mutex, MutexGuard};
 use std::time::{Duration, SystemTime};
 
+use crossbeam_channel::Receiver;
 use x25519_dalek::{PublicKey, StaticSecret};
 
 use super::udp::Owner;
@@ -20,12 +24,41 @@ use super::*;
 pub struct PeerState {
     pub rx_bytes: u64,
     pub tx_bytes: u64,
+    pub rx_packets: u64,
+    pub tx_packets: u64,
+    pub handshake_attempts: usize,
+    pub current_keypair_age: Option<Duration>,
     pub last_handshake_time: Option<(u64, u64)>,
     pub public_key: PublicKey,
     pub allowed_ips: Vec<(IpAddr, u32)>,
     pub endpoint: Option<SocketAddr>,
+    pub endpoint_candidates: Vec<SocketAddr>,
+    pub roaming: Roaming,
+    pub roaming_attempts: u64,
+    pub relay_acl: RelayAcl,
+    pub filter: Vec<(FilterRule, u64)>, // rules and hit counters
+    pub tx_limit: LimiterState,
+    pub rx_limit: LimiterState,
     pub persistent_keepalive_interval: u64,
     pub preshared_key: [u8; 32], // 0^32 is the "default value" (though treated like any other psk)
+    pub evicted: bool,           // runtime state evicted (idle peer)
+    pub psk_epoch: Option<u64>,  // epoch of the psk used by the latest handshake (None if static)
+    pub psk_age: Duration,       // duration since the psk was first used (or set)
+    pub capture: bool,           // are the packets exchanged with the peer captured?
+    pub handshakes: Vec<HandshakeRecord>, // recent outcomes of handshake messages (oldest first)
+    pub path_mtu: Option<usize>, // largest IP packet sent to the peer (if limited by the path MTU)
+}
+
+/// Describes an update of the rate limits of a peer:
+/// limits are in bytes or packets per second (0 for unlimited),
+/// None retains the current value.
+#[derive(Clone, Debug, Default)]
+pub struct PeerLimits {
+    pub tx_bytes: Option<u64>,
+    pub tx_packets: Option<u64>,
+    pub rx_bytes: Option<u64>,
+    pub rx_packets: Option<u64>,
+    pub excess: Option<Excess>, // applies to transmission, excess received packets are dropped
 }
 
 pub struct WireGuardConfig<T: tun::Tun, B: udp::PlatformUDP>(Arc<Mutex<Inner<T, B>>>);
@@ -33,8 +66,12 @@ pub struct WireGuardConfig<T: tun::Tun, B: udp::PlatformUDP>(Arc<Mutex<Inner<T,
 struct Inner<T: tun::Tun, B: udp::PlatformUDP> {
     wireguard: WireGuard<T, B>,
     port: u16,
+    bind_options: udp::BindOptions,
     bind: Option<B::Owner>,
     fwmark: Option<u32>,
+    rotation_grace: Duration,
+    export_private_key: bool,
+    keylog: Option<PathBuf>,
 }
 
 impl<T: tun::Tun, B: udp::PlatformUDP> WireGuardConfig<T, B> {
@@ -48,8 +85,12 @@ impl<T: tun::Tun, B: udp::PlatformUDP> WireGuardConfig<T, B> {
         WireGuardConfig(Arc::new(Mutex::new(Inner {
             wireguard: wg,
             port: 0,
+            bind_options: udp::BindOptions::default(),
             bind: None,
             fwmark: None,
+            rotation_grace: Duration::from_secs(0),
+            export_private_key: true,
+            keylog: None,
         })))
     }
 }
@@ -73,13 +114,48 @@ pub trait Configuration {
     /// - `sk`: The new private key (or None, if the private key should be cleared)
     fn set_private_key(&self, sk: Option<StaticSecret>);
 
+    /// Updates the private key of the device with a key backend,
+    /// e.g. holding the private key outside the process memory.
+    /// Replacing a key is subject to the grace window of key rotations (as by `set_private_key`).
+    ///
+    /// # Arguments
+    ///
+    /// - `key`: The new private key (or None, if the private key should be cleared)
+    fn set_static_key(&self, key: Option<Box<dyn StaticKey>>);
+
     /// Returns the private key of the device
     ///
     /// # Returns
     ///
-    /// The private if set, otherwise None.
+    /// The private if set and exportable, otherwise None.
     fn get_private_key(&self) -> Option<StaticSecret>;
 
+    /// Returns the public key of the device (also when the private key cannot be exported)
+    fn get_public_key(&self) -> Option<PublicKey>;
+
+    /// Never export the private key (e.g. over UAPI "get"),
+    /// this cannot be undone for the lifetime of the device.
+    fn disable_private_key_export(&self);
+
+    /// Returns true if the private key may be exported
+    fn get_private_key_export(&self) -> bool;
+
+    /// Enable or disable graceful rotation of the private key
+    ///
+    /// # Arguments
+    ///
+    /// - `grace`: When non-zero, replacing the private key (or key backend)
+    ///   retains the established sessions and initiations to the previous public key
+    ///   are accepted for this duration.
+    ///   When zero, replacing the private key clears all sessions.
+    fn set_key_rotation_grace(&self, grace: Duration);
+
+    /// Returns the grace window of private key rotations (zero if disabled)
+    fn get_key_rotation_grace(&self) -> Duration;
+
+    /// Returns the previous public key, if still accepted after a key rotation
+    fn get_previous_public_key(&self) -> Option<PublicKey>;
+
     /// Returns the protocol version of the device
     ///
     /// # Returns
@@ -89,6 +165,52 @@ pub trait Configuration {
 
     fn set_listen_port(&self, port: u16) -> Result<(), ConfigError>;
 
+    /// Restricts the UDP sockets to a local address,
+    /// rebinding the sockets if the device is up
+    ///
+    /// # Arguments
+    ///
+    /// - `addr`: The local address (or None, for the wildcard address of every IP version)
+    fn set_listen_address(&self, addr: Option<IpAddr>) -> Result<(), ConfigError>;
+
+    /// Restricts the UDP sockets to an interface (`SO_BINDTODEVICE` on Linux),
+    /// rebinding the sockets if the device is up
+    ///
+    /// # Arguments
+    ///
+    /// - `name`: The name of the interface (or None, for any interface)
+    fn set_bind_interface(&self, name: Option<String>) -> Result<(), ConfigError>;
+
+    /// Selects the IP versions of the UDP sockets,
+    /// rebinding the sockets if the device is up
+    ///
+    /// # Arguments
+    ///
+    /// - `family`: Both IP versions (falling back to the available version) or only one
+    fn set_bind_family(&self, family: BindFamily) -> Result<(), ConfigError>;
+
+    /// Sets the sizes of the socket buffers of the UDP sockets
+    /// (exceeding the limits of the system if privileged),
+    /// rebinding the sockets if the device is up
+    ///
+    /// # Arguments
+    ///
+    /// - `rcvbuf`: The size of the receive buffer (or None, for the system default)
+    /// - `sndbuf`: The size of the send buffer (or None, for the system default)
+    fn set_socket_buffers(
+        &self,
+        rcvbuf: Option<usize>,
+        sndbuf: Option<usize>,
+    ) -> Result<(), ConfigError>;
+
+    /// Sets the TTL (IPv4) / hop limit (IPv6) of the outer packets,
+    /// rebinding the sockets if the device is up
+    ///
+    /// # Arguments
+    ///
+    /// - `ttl`: The TTL (or None, for the system default)
+    fn set_outer_ttl(&self, ttl: Option<u8>) -> Result<(), ConfigError>;
+
     /// Set the firewall mark (or similar, depending on platform)
     ///
     /// # Arguments
@@ -101,6 +223,139 @@ pub trait Configuration {
     /// "bind" implementation.
     fn set_fwmark(&self, mark: Option<u32>) -> Result<(), ConfigError>;
 
+    /// Enable or disable relaying between peers
+    ///
+    /// # Arguments
+    ///
+    /// - `enabled`: When enabled, packets received from a peer which are destined
+    ///   for the allowed IPs of another peer are forwarded directly to that peer
+    ///   (subject to the relay ACL of the sending peer), instead of through the TUN device.
+    fn set_relay(&self, enabled: bool);
+
+    /// Returns true if relaying between peers is enabled
+    fn get_relay(&self) -> bool;
+
+    /// Enable or disable copying the DSCP bits of the inner packets to the outer packets
+    fn set_copy_dscp(&self, enabled: bool);
+
+    /// Returns true if the DSCP bits are copied to the outer packets
+    fn get_copy_dscp(&self) -> bool;
+
+    /// Enable or disable the propagation of the ECN bits between the inner
+    /// and outer packets, as by the normal mode of RFC 6040 (enabled by default)
+    fn set_ecn(&self, enabled: bool);
+
+    /// Returns true if the ECN bits are propagated
+    fn get_ecn(&self) -> bool;
+
+    /// Configure the rate limiting of handshake messages while under load
+    ///
+    /// # Arguments
+    ///
+    /// - `config`: The new configuration (must be valid), resets the rate limiter
+    fn set_ratelimiter(&self, config: RateLimiterConfig);
+
+    /// Returns the configuration of the handshake rate limiter
+    fn get_ratelimiter(&self) -> RateLimiterConfig;
+
+    /// Returns the statistics of the handshake rate limiter
+    fn get_ratelimiter_stats(&self) -> RateLimiterStats;
+
+    /// Configure when the device is considered under load (requiring cookies)
+    ///
+    /// # Arguments
+    ///
+    /// - `policy`: The new policy (must be valid)
+    fn set_load_policy(&self, policy: LoadPolicy);
+
+    /// Returns the policy for detecting that the device is under load
+    fn get_load_policy(&self) -> LoadPolicy;
+
+    /// Returns the statistics of the under load detection
+    fn get_load_stats(&self) -> LoadStats;
+
+    /// Enable or disable eviction of the runtime state of idle peers
+    ///
+    /// # Arguments
+    ///
+    /// - `timeout`: The duration without traffic after which a peer is evicted,
+    ///   None to disable eviction.
+    fn set_idle_timeout(&self, timeout: Option<Duration>);
+
+    /// Returns the idle peer eviction timeout (None if disabled)
+    fn get_idle_timeout(&self) -> Option<Duration>;
+
+    /// Set the source of peers unknown to the device
+    ///
+    /// # Arguments
+    ///
+    /// - `provider`: The provider consulted when a handshake initiation
+    ///   is received from an unknown public key, None to reject such initiations.
+    fn set_peer_provider(&self, provider: Option<Arc<dyn PeerProvider>>);
+
+    /// Set the source of epoch tagged preshared keys
+    ///
+    /// # Arguments
+    ///
+    /// - `provider`: The provider consulted for every handshake,
+    ///   None to use only the static preshared keys of the peers.
+    fn set_psk_provider(&self, provider: Option<Arc<dyn PskProvider>>);
+
+    /// Start or stop the capture of packets (in the pcapng format)
+    ///
+    /// # Arguments
+    ///
+    /// - `path`: The capture file (created exclusively with mode 0600), None to stop capturing
+    ///
+    /// # Returns
+    ///
+    /// An error if the file already exists or cannot be created
+    fn set_capture(&self, path: Option<&Path>) -> Result<(), ConfigError>;
+
+    /// Returns the path of the capture file (None if not capturing)
+    fn get_capture(&self) -> Option<PathBuf>;
+
+    /// Capture the packets of every peer (including unauthenticated handshake messages),
+    /// otherwise only the packets of peers with capture enabled.
+    fn set_capture_all(&self, all: bool);
+
+    /// Returns true if the packets of every peer are captured
+    fn get_capture_all(&self) -> bool;
+
+    /// Start or stop logging the secrets of every handshake
+    /// (allowing the decryption of captured transport messages)
+    ///
+    /// # Arguments
+    ///
+    /// - `path`: The keylog file (created exclusively with mode 0600), None to stop logging
+    ///
+    /// # Returns
+    ///
+    /// An error if the file already exists or cannot be created,
+    /// or if the private key cannot be exported
+    fn set_keylog(&self, path: Option<&Path>) -> Result<(), ConfigError>;
+
+    /// Returns the path of the keylog file (None if not logging)
+    fn get_keylog(&self) -> Option<PathBuf>;
+
+    /// Set the verbosity of the log (for the entire process)
+    ///
+    /// # Arguments
+    ///
+    /// - `level`: The most verbose level logged
+    ///
+    /// # Note
+    ///
+    /// Release builds never log below the info level.
+    fn set_log_level(&self, level: log::LevelFilter);
+
+    /// Returns the verbosity of the log
+    fn get_log_level(&self) -> log::LevelFilter;
+
+    /// Returns the recent handshake failures which could not be attributed to a peer
+    /// (e.g. invalid mac1 or unknown public key), oldest first
+    fn get_handshake_failures(&self) -> Vec<HandshakeRecord>;
+
     /// Removes all peers from the device
     fn replace_peers(&self);
 
@@ -138,15 +393,82 @@ pub trait Configuration {
     /// # Returns
     ///
     /// An error if no such peer exists
-    fn set_preshared_key(&self, peer: &PublicKey, psk: [u8; 32]);
+    fn set_preshared_key(&self, peer: &PublicKey, psk: [u8; 32]) -> Result<(), ConfigError>;
 
-    /// Update the endpoint of the
+    /// Update the candidate endpoints of the peer
     ///
     /// # Arguments
     ///
     /// - `peer': The public key of the peer
-    /// - `psk`
-    fn set_endpoint(&self, peer: &PublicKey, addr: SocketAddr);
+    /// - `addrs`: The candidate endpoints, in order of preference.
+    ///   The first candidate becomes the active endpoint,
+    ///   subsequent candidates are attempted when handshakes fail to complete.
+    ///
+    /// # Returns
+    ///
+    /// An error if no such peer exists
+    fn set_endpoints(&self, peer: &PublicKey, addrs: &[SocketAddr]) -> Result<(), ConfigError>;
+
+    /// Update the roaming policy of the peer
+    ///
+    /// # Arguments
+    ///
+    /// - `peer': The public key of the peer
+    /// - `roaming`: Whether authenticated packets may update the endpoint,
+    ///   and if packets from other sources are accepted when they may not.
+    ///
+    /// # Returns
+    ///
+    /// An error if no such peer exists
+    fn set_roaming(&self, peer: &PublicKey, roaming: Roaming) -> Result<(), ConfigError>;
+
+    /// Update the relay ACL of the peer
+    ///
+    /// # Arguments
+    ///
+    /// - `peer': The public key of the peer
+    /// - `acl`: The peers which may be reached from the peer when relaying
+    ///
+    /// # Returns
+    ///
+    /// An error if no such peer exists
+    fn set_relay_acl(&self, peer: &PublicKey, acl: RelayAcl) -> Result<(), ConfigError>;
+
+    /// Replace the filter rules applied to the inner packets exchanged with the peer
+    ///
+    /// # Arguments
+    ///
+    /// - `peer': The public key of the peer
+    /// - `rules`: The rules in order of evaluation (the first matching rule applies)
+    ///
+    /// # Returns
+    ///
+    /// An error if no such peer exists
+    fn set_filter(&self, peer: &PublicKey, rules: Vec<FilterRule>) -> Result<(), ConfigError>;
+
+    /// Enable or disable the capture of the packets exchanged with the peer
+    ///
+    /// # Arguments
+    ///
+    /// - `peer': The public key of the peer
+    /// - `enabled`: Capture the packets of the peer (while a capture is started)
+    ///
+    /// # Returns
+    ///
+    /// An error if no such peer exists
+    fn set_peer_capture(&self, peer: &PublicKey, enabled: bool) -> Result<(), ConfigError>;
+
+    /// Update the rate limits of the peer
+    ///
+    /// # Arguments
+    ///
+    /// - `peer': The public key of the peer
+    /// - `limits`: The limits to update (the token buckets are refilled)
+    ///
+    /// # Returns
+    ///
+    /// An error if no such peer exists
+    fn set_limits(&self, peer: &PublicKey, limits: &PeerLimits) -> Result<(), ConfigError>;
 
     /// Update the endpoint of the
     ///
@@ -154,7 +476,15 @@ pub trait Configuration {
     ///
     /// - `peer': The public key of the peer
     /// - `psk`
-    fn set_persistent_keepalive_interval(&self, peer: &PublicKey, secs: u64);
+    ///
+    /// # Returns
+    ///
+    /// An error if no such peer exists
+    fn set_persistent_keepalive_interval(
+        &self,
+        peer: &PublicKey,
+        secs: u64,
+    ) -> Result<(), ConfigError>;
 
     /// Remove all allowed IPs from the peer
     ///
@@ -165,7 +495,7 @@ pub trait Configuration {
     /// # Returns
     ///
     /// An error if no such peer exists
-    fn replace_allowed_ips(&self, peer: &PublicKey);
+    fn replace_allowed_ips(&self, peer: &PublicKey) -> Result<(), ConfigError>;
 
     /// Add a new allowed subnet to the peer
     ///
@@ -178,10 +508,22 @@ pub trait Configuration {
     /// # Returns
     ///
     /// An error if the peer does not exist
-    fn add_allowed_ip(&self, peer: &PublicKey, ip: IpAddr, masklen: u32);
+    fn add_allowed_ip(&self, peer: &PublicKey, ip: IpAddr, masklen: u32)
+        -> Result<(), ConfigError>;
 
     fn get_listen_port(&self) -> Option<u16>;
 
+    fn get_listen_address(&self) -> Option<IpAddr>;
+
+    fn get_bind_interface(&self) -> Option<String>;
+
+    fn get_bind_family(&self) -> BindFamily;
+
+    /// Returns the sizes of the receive and send buffers of the UDP sockets (if set)
+    fn get_socket_buffers(&self) -> (Option<usize>, Option<usize>);
+
+    fn get_outer_ttl(&self) -> Option<u8>;
+
     /// Returns the state of all peers
     ///
     /// # Returns
@@ -190,6 +532,14 @@ pub trait Configuration {
     fn get_peers(&self) -> Vec<PeerState>;
 
     fn get_fwmark(&self) -> Option<u32>;
+
+    /// Subscribe to events from the device
+    ///
+    /// # Returns
+    ///
+    /// A receiver yielding every subsequent event,
+    /// events are dropped if the receiver falls too far behind.
+    fn subscribe_events(&self) -> Receiver<Event>;
 }
 
 fn start_listener<T: tun::Tun, B: udp::PlatformUDP>(
@@ -198,10 +548,17 @@ fn start_listener<T: tun::Tun, B: udp::PlatformUDP>(
     cfg.bind = None;
 
     // create new listener
-    let (mut readers, writer, mut owner) = match B::bind(cfg.port) {
+    let (mut readers, writer, mut owner) = match B::bind(cfg.port, &cfg.bind_options) {
         Ok(r) => r,
-        Err(_) => {
-            return Err(ConfigError::FailedToBind);
+        Err(e) => {
+            let errno = (&e as &(dyn Error + 'static))
+                .downcast_ref::<io::Error>()
+                .and_then(|e| e.raw_os_error());
+            return Err(ConfigError::FailedToBind {
+                port: cfg.port,
+                errno,
+                reason: e.to_string(),
+            });
         }
     };
 
@@ -221,6 +578,26 @@ fn start_listener<T: tun::Tun, B: udp::PlatformUDP>(
     Ok(())
 }
 
+// update the bind settings and restart the listener (if bound)
+fn restart_listener<T: tun::Tun, B: udp::PlatformUDP, F: FnOnce(&mut Inner<T, B>)>(
+    mut cfg: MutexGuard<Inner<T, B>>,
+    update: F,
+) -> Result<(), ConfigError> {
+    // update settings and take old bind
+    let bound: bool = {
+        let old = mem::replace(&mut cfg.bind, None);
+        update(&mut cfg);
+        old.is_some()
+    };
+
+    // restart listener if bound
+    if bound {
+        start_listener(cfg)
+    } else {
+        Ok(())
+    }
+}
+
 impl<T: tun::Tun, B: udp::PlatformUDP> Configuration for WireGuardConfig<T, B> {
     fn up(&self, mtu: usize) -> Result<(), ConfigError> {
         log::info!("configuration, set device up");
@@ -242,11 +619,83 @@ impl<T: tun::Tun, B: udp::PlatformUDP> Configuration for WireGuardConfig<T, B> {
 
     fn set_private_key(&self, sk: Option<StaticSecret>) {
         log::info!("configuration, set private key");
-        self.lock().wireguard.set_key(sk)
+        let cfg = self.lock();
+
+        // rotate gracefully (if enabled) when replacing a key
+        match sk {
+            Some(sk)
+                if cfg.rotation_grace > Duration::from_secs(0)
+                    && cfg.wireguard.get_pk().is_some() =>
+            {
+                log::info!(
+                    "configuration, rotate private key (grace = {:?})",
+                    cfg.rotation_grace
+                );
+                cfg.wireguard.rotate_key(sk, cfg.rotation_grace)
+            }
+            sk => cfg.wireguard.set_key(sk),
+        }
+    }
+
+    fn set_static_key(&self, key: Option<Box<dyn StaticKey>>) {
+        log::info!("configuration, set static key");
+        let cfg = self.lock();
+
+        // rotate gracefully (if enabled) when replacing a key
+        match key {
+            Some(key)
+                if cfg.rotation_grace > Duration::from_secs(0)
+                    && cfg.wireguard.get_pk().is_some() =>
+            {
+                log::info!(
+                    "configuration, rotate static key (grace = {:?})",
+                    cfg.rotation_grace
+                );
+                cfg.wireguard.rotate_static_key(key, cfg.rotation_grace)
+            }
+            key => cfg.wireguard.set_static_key(key),
+        }
     }
 
     fn get_private_key(&self) -> Option<StaticSecret> {
-        self.lock().wireguard.get_sk()
+        let cfg = self.lock();
+        if cfg.export_private_key {
+            cfg.wireguard.get_sk()
+        } else {
+            None
+        }
+    }
+
+    fn get_public_key(&self) -> Option<PublicKey> {
+        self.lock().wireguard.get_pk()
+    }
+
+    fn disable_private_key_export(&self) {
+        log::info!("configuration, disable private key export");
+        let mut cfg = self.lock();
+        cfg.export_private_key = false;
+
+        // the keylog contains the private key
+        if cfg.keylog.take().is_some() {
+            cfg.wireguard.set_keylog(None);
+        }
+    }
+
+    fn get_private_key_export(&self) -> bool {
+        self.lock().export_private_key
+    }
+
+    fn set_key_rotation_grace(&self, grace: Duration) {
+        log::trace!("Config, Set key rotation grace: {:?}", grace);
+        self.lock().rotation_grace = grace;
+    }
+
+    fn get_key_rotation_grace(&self) -> Duration {
+        self.lock().rotation_grace
+    }
+
+    fn get_previous_public_key(&self) -> Option<PublicKey> {
+        self.lock().wireguard.get_previous_pk()
     }
 
     fn get_protocol_version(&self) -> usize {
@@ -261,26 +710,67 @@ impl<T: tun::Tun, B: udp::PlatformUDP> Configuration for WireGuardConfig<T, B> {
 
     fn set_listen_port(&self, port: u16) -> Result<(), ConfigError> {
         log::trace!("Config, Set listen port: {:?}", port);
+        restart_listener(self.lock(), |cfg| cfg.port = port)
+    }
 
-        // update port and take old bind
-        let mut cfg = self.lock();
-        let bound: bool = {
-            let old = mem::replace(&mut cfg.bind, None);
-            cfg.port = port;
-            old.is_some()
-        };
+    fn set_listen_address(&self, addr: Option<IpAddr>) -> Result<(), ConfigError> {
+        log::trace!("Config, Set listen address: {:?}", addr);
+        restart_listener(self.lock(), |cfg| cfg.bind_options.address = addr)
+    }
 
-        // restart listener if bound
-        if bound {
-            start_listener(cfg)
-        } else {
-            Ok(())
-        }
+    fn set_bind_interface(&self, name: Option<String>) -> Result<(), ConfigError> {
+        log::trace!("Config, Set bind interface: {:?}", name);
+        restart_listener(self.lock(), |cfg| cfg.bind_options.interface = name)
+    }
+
+    fn set_bind_family(&self, family: BindFamily) -> Result<(), ConfigError> {
+        log::trace!("Config, Set bind family: {}", family);
+        restart_listener(self.lock(), |cfg| cfg.bind_options.family = family)
+    }
+
+    fn set_socket_buffers(
+        &self,
+        rcvbuf: Option<usize>,
+        sndbuf: Option<usize>,
+    ) -> Result<(), ConfigError> {
+        log::trace!("Config, Set socket buffers: {:?} {:?}", rcvbuf, sndbuf);
+        restart_listener(self.lock(), |cfg| {
+            cfg.bind_options.rcvbuf = rcvbuf;
+            cfg.bind_options.sndbuf = sndbuf;
+        })
+    }
+
+    fn set_outer_ttl(&self, ttl: Option<u8>) -> Result<(), ConfigError> {
+        log::trace!("Config, Set outer TTL: {:?}", ttl);
+        restart_listener(self.lock(), |cfg| cfg.bind_options.ttl = ttl)
+    }
+
+    fn get_listen_address(&self) -> Option<IpAddr> {
+        self.lock().bind_options.address
+    }
+
+    fn get_bind_interface(&self) -> Option<String> {
+        self.lock().bind_options.interface.clone()
+    }
+
+    fn get_bind_family(&self) -> BindFamily {
+        self.lock().bind_options.family
+    }
+
+    fn get_socket_buffers(&self) -> (Option<usize>, Option<usize>) {
+        let cfg = self.lock();
+        (cfg.bind_options.rcvbuf, cfg.bind_options.sndbuf)
+    }
+
+    fn get_outer_ttl(&self) -> Option<u8> {
+        self.lock().bind_options.ttl
     }
 
     fn set_fwmark(&self, mark: Option<u32>) -> Result<(), ConfigError> {
         log::trace!("Config, Set fwmark: {:?}", mark);
-        match self.lock().bind.as_mut() {
+        let mut cfg = self.lock();
+        cfg.fwmark = mark;
+        match cfg.bind.as_mut() {
             Some(bind) => {
                 if bind.set_fwmark(mark).is_err() {
                     Err(ConfigError::IOError)
@@ -292,6 +782,144 @@ impl<T: tun::Tun, B: udp::PlatformUDP> Configuration for WireGuardConfig<T, B> {
         }
     }
 
+    fn set_relay(&self, enabled: bool) {
+        log::trace!("Config, Set relay: {}", enabled);
+        self.lock().wireguard.router.set_relay(enabled)
+    }
+
+    fn get_relay(&self) -> bool {
+        self.lock().wireguard.router.get_relay()
+    }
+
+    fn set_copy_dscp(&self, enabled: bool) {
+        log::trace!("Config, Set copy DSCP: {}", enabled);
+        self.lock().wireguard.router.set_dscp(enabled)
+    }
+
+    fn get_copy_dscp(&self) -> bool {
+        self.lock().wireguard.router.get_dscp()
+    }
+
+    fn set_ecn(&self, enabled: bool) {
+        log::trace!("Config, Set ECN: {}", enabled);
+        self.lock().wireguard.router.set_ecn(enabled)
+    }
+
+    fn get_ecn(&self) -> bool {
+        self.lock().wireguard.router.get_ecn()
+    }
+
+    fn set_ratelimiter(&self, config: RateLimiterConfig) {
+        log::trace!("Config, Set ratelimiter: {:?}", config);
+        self.lock()
+            .wireguard
+            .peers
+            .read()
+            .set_ratelimiter_config(config)
+    }
+
+    fn get_ratelimiter(&self) -> RateLimiterConfig {
+        self.lock().wireguard.peers.read().get_ratelimiter_config()
+    }
+
+    fn get_ratelimiter_stats(&self) -> RateLimiterStats {
+        self.lock().wireguard.peers.read().get_ratelimiter_stats()
+    }
+
+    fn set_load_policy(&self, policy: LoadPolicy) {
+        log::trace!("Config, Set load policy: {:?}", policy);
+        self.lock().wireguard.set_load_policy(policy)
+    }
+
+    fn get_load_policy(&self) -> LoadPolicy {
+        self.lock().wireguard.get_load_policy()
+    }
+
+    fn get_load_stats(&self) -> LoadStats {
+        self.lock().wireguard.get_load_stats()
+    }
+
+    fn set_idle_timeout(&self, timeout: Option<Duration>) {
+        log::trace!("Config, Set idle timeout: {:?}", timeout);
+        self.lock().wireguard.set_idle_timeout(timeout)
+    }
+
+    fn get_idle_timeout(&self) -> Option<Duration> {
+        self.lock().wireguard.get_idle_timeout()
+    }
+
+    fn set_peer_provider(&self, provider: Option<Arc<dyn PeerProvider>>) {
+        log::trace!("Config, Set peer provider: {}", provider.is_some());
+        self.lock().wireguard.set_peer_provider(provider)
+    }
+
+    fn set_psk_provider(&self, provider: Option<Arc<dyn PskProvider>>) {
+        log::trace!("Config, Set psk provider: {}", provider.is_some());
+        self.lock().wireguard.set_psk_provider(provider)
+    }
+
+    fn set_capture(&self, path: Option<&Path>) -> Result<(), ConfigError> {
+        log::info!("configuration, set capture: {:?}", path);
+        self.lock().wireguard.set_capture(path).map_err(|e| {
+            log::error!("failed to create capture file: {}", e);
+            ConfigError::IOError
+        })
+    }
+
+    fn get_capture(&self) -> Option<PathBuf> {
+        self.lock().wireguard.get_capture_path()
+    }
+
+    fn set_capture_all(&self, all: bool) {
+        log::trace!("Config, Set capture all: {}", all);
+        self.lock().wireguard.set_capture_all(all)
+    }
+
+    fn get_capture_all(&self) -> bool {
+        self.lock().wireguard.get_capture_all()
+    }
+
+    fn set_keylog(&self, path: Option<&Path>) -> Result<(), ConfigError> {
+        log::info!("configuration, set keylog: {:?}", path);
+        let mut cfg = self.lock();
+        let keylog: Option<Arc<dyn KeyLog>> = match path {
+            Some(path) if !cfg.export_private_key => {
+                return Err(ConfigError::UnsupportedValue {
+                    key: "x_keylog_file".to_owned(),
+                    value: path.display().to_string(),
+                });
+            }
+            Some(path) => match KeyLogFile::create(path) {
+                Ok(keylog) => Some(Arc::new(keylog)),
+                Err(e) => {
+                    log::error!("failed to create keylog file: {}", e);
+                    return Err(ConfigError::IOError);
+                }
+            },
+            None => None,
+        };
+        cfg.wireguard.set_keylog(keylog);
+        cfg.keylog = path.map(|path| path.to_owned());
+        Ok(())
+    }
+
+    fn get_keylog(&self) -> Option<PathBuf> {
+        self.lock().keylog.clone()
+    }
+
+    fn set_log_level(&self, level: log::LevelFilter) {
+        log::set_max_level(level);
+        log::info!("configuration, set log level: {}", level);
+    }
+
+    fn get_log_level(&self) -> log::LevelFilter {
+        log::max_level()
+    }
+
+    fn get_handshake_failures(&self) -> Vec<HandshakeRecord> {
+        self.lock().wireguard.handshake_failures.records()
+    }
+
     fn replace_peers(&self) {
         self.lock().wireguard.clear_peers();
     }
@@ -304,31 +932,126 @@ impl<T: tun::Tun, B: udp::PlatformUDP> Configuration for WireGuardConfig<T, B> {
         self.lock().wireguard.add_peer(*peer)
     }
 
-    fn set_preshared_key(&self, peer: &PublicKey, psk: [u8; 32]) {
-        self.lock().wireguard.set_psk(*peer, psk);
+    fn set_preshared_key(&self, peer: &PublicKey, psk: [u8; 32]) -> Result<(), ConfigError> {
+        if self.lock().wireguard.set_psk(*peer, psk) {
+            Ok(())
+        } else {
+            Err(ConfigError::UnknownPeer { peer: *peer })
+        }
+    }
+
+    fn set_endpoints(&self, peer: &PublicKey, addrs: &[SocketAddr]) -> Result<(), ConfigError> {
+        match self.lock().wireguard.peers.read().get(peer) {
+            Some(peer) => {
+                if let Some(addr) = peer.opaque().set_endpoint_candidates(addrs.to_vec()) {
+                    peer.set_endpoint(B::Endpoint::from_address(addr));
+                }
+                Ok(())
+            }
+            None => Err(ConfigError::UnknownPeer { peer: *peer }),
+        }
+    }
+
+    fn set_roaming(&self, peer: &PublicKey, roaming: Roaming) -> Result<(), ConfigError> {
+        match self.lock().wireguard.peers.read().get(peer) {
+            Some(peer) => {
+                peer.set_roaming(roaming);
+                Ok(())
+            }
+            None => Err(ConfigError::UnknownPeer { peer: *peer }),
+        }
     }
 
-    fn set_endpoint(&self, peer: &PublicKey, addr: SocketAddr) {
-        if let Some(peer) = self.lock().wireguard.peers.read().get(peer) {
-            peer.set_endpoint(B::Endpoint::from_address(addr));
+    fn set_relay_acl(&self, peer: &PublicKey, acl: RelayAcl) -> Result<(), ConfigError> {
+        match self.lock().wireguard.peers.read().get(peer) {
+            Some(peer) => {
+                *peer.opaque().relay_acl.lock() = acl;
+                Ok(())
+            }
+            None => Err(ConfigError::UnknownPeer { peer: *peer }),
         }
     }
 
-    fn set_persistent_keepalive_interval(&self, peer: &PublicKey, secs: u64) {
-        if let Some(peer) = self.lock().wireguard.peers.read().get(peer) {
-            peer.opaque().set_persistent_keepalive_interval(secs);
+    fn set_peer_capture(&self, peer: &PublicKey, enabled: bool) -> Result<(), ConfigError> {
+        match self.lock().wireguard.peers.read().get(peer) {
+            Some(peer) => {
+                peer.opaque().capture.store(enabled, Ordering::Relaxed);
+                Ok(())
+            }
+            None => Err(ConfigError::UnknownPeer { peer: *peer }),
         }
     }
 
-    fn replace_allowed_ips(&self, peer: &PublicKey) {
-        if let Some(peer) = self.lock().wireguard.peers.read().get(peer) {
-            peer.remove_allowed_ips();
+    fn set_filter(&self, peer: &PublicKey, rules: Vec<FilterRule>) -> Result<(), ConfigError> {
+        match self.lock().wireguard.peers.read().get(peer) {
+            Some(peer) => {
+                peer.set_filter(rules);
+                Ok(())
+            }
+            None => Err(ConfigError::UnknownPeer { peer: *peer }),
         }
     }
 
-    fn add_allowed_ip(&self, peer: &PublicKey, ip: IpAddr, masklen: u32) {
-        if let Some(peer) = self.lock().wireguard.peers.read().get(peer) {
-            peer.add_allowed_ip(ip, masklen);
+    fn set_limits(&self, peer: &PublicKey, limits: &PeerLimits) -> Result<(), ConfigError> {
+        match self.lock().wireguard.peers.read().get(peer) {
+            Some(peer) => {
+                peer.set_limits(
+                    (limits.tx_bytes, limits.tx_packets),
+                    (limits.rx_bytes, limits.rx_packets),
+                    limits.excess,
+                );
+                Ok(())
+            }
+            None => Err(ConfigError::UnknownPeer { peer: *peer }),
+        }
+    }
+
+    fn set_persistent_keepalive_interval(
+        &self,
+        peer: &PublicKey,
+        secs: u64,
+    ) -> Result<(), ConfigError> {
+        match self.lock().wireguard.peers.read().get(peer) {
+            Some(peer) => {
+                peer.opaque().set_persistent_keepalive_interval(secs);
+                Ok(())
+            }
+            None => Err(ConfigError::UnknownPeer { peer: *peer }),
+        }
+    }
+
+    fn replace_allowed_ips(&self, peer: &PublicKey) -> Result<(), ConfigError> {
+        let cfg = self.lock();
+        let peers = cfg.wireguard.peers.read();
+        match peers.get(peer) {
+            Some(handle) => {
+                handle.remove_allowed_ips();
+                cfg.wireguard
+                    .events
+                    .emit(Event::AllowedIpsChanged { public_key: *peer });
+                Ok(())
+            }
+            None => Err(ConfigError::UnknownPeer { peer: *peer }),
+        }
+    }
+
+    fn add_allowed_ip(
+        &self,
+        peer: &PublicKey,
+        ip: IpAddr,
+        masklen: u32,
+    ) -> Result<(), ConfigError> {
+        let cfg = self.lock();
+        let peers = cfg.wireguard.peers.read();
+        match peers.get(peer) {
+            Some(handle) => {
+                handle.add_allowed_ip(ip, masklen);
+                cfg.wireguard
+                    .events
+                    .emit(Event::AllowedIpsChanged { public_key: *peer });
+                Ok(())
+            }
+            None => Err(ConfigError::UnknownPeer { peer: *peer }),
         }
     }
 
@@ -365,14 +1088,35 @@ impl<T: tun::Tun, B: udp::PlatformUDP> Configuration for WireGuardConfig<T, B> {
                 Some((duration.as_secs(), duration.subsec_nanos() as u64))
             });
 
-            if let Some(psk) = cfg.wireguard.get_psk(&pk) {
+            if let (Some(psk), Some((psk_epoch, psk_age))) =
+                (cfg.wireguard.get_psk(&pk), cfg.wireguard.get_psk_age(&pk))
+            {
+                let (tx_limit, rx_limit) = p.get_limits();
+
                 // extract state into PeerState
                 state.push(PeerState {
                     preshared_key: psk,
+                    psk_epoch,
+                    psk_age,
                     endpoint: p.get_endpoint(),
+                    endpoint_candidates: p.get_endpoint_candidates(),
+                    roaming: p.get_roaming(),
+                    roaming_attempts: p.get_roaming_attempts(),
+                    relay_acl: p.relay_acl.lock().clone(),
+                    filter: p.list_filter(),
+                    tx_limit,
+                    rx_limit,
                     rx_bytes: p.rx_bytes.load(Ordering::Relaxed),
                     tx_bytes: p.tx_bytes.load(Ordering::Relaxed),
+                    rx_packets: p.rx_packets.load(Ordering::Relaxed),
+                    tx_packets: p.tx_packets.load(Ordering::Relaxed),
+                    handshake_attempts: p.get_handshake_attempts(),
+                    current_keypair_age: p.current_keypair_age(),
                     persistent_keepalive_interval: p.get_keepalive_interval(),
+                    evicted: p.evicted.load(Ordering::Relaxed),
+                    capture: p.capture.load(Ordering::Relaxed),
+                    handshakes: p.handshakes.records(),
+                    path_mtu: p.path_mtu(),
                     allowed_ips: p.list_allowed_ips(),
                     last_handshake_time,
                     public_key: pk,
@@ -381,4 +1125,8 @@ impl<T: tun::Tun, B: udp::PlatformUDP> Configuration for WireGuardConfig<T, B> {
         }
         state
     }
+
+    fn subscribe_events(&self) -> Receiver<Event> {
+        self.lock().wireguard.events.subscribe()
+    }
 }
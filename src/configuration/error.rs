@@ -1,35 +1,161 @@
 use std::error::Error;
 use std::fmt;
 
+use x25519_dalek::PublicKey;
+
+use super::Base64Key;
+
 #[cfg(unix)]
 use libc::*;
 
 #[derive(Debug)]
 pub enum ConfigError {
-    FailedToBind,
-    InvalidHexValue,
-    InvalidPortNumber,
-    InvalidFwmark,
-    InvalidKey,
-    InvalidSocketAddr,
-    InvalidKeepaliveInterval,
-    InvalidAllowedIp,
-    InvalidOperation,
+    FailedToBind {
+        port: u16,
+        errno: Option<i32>,
+        reason: String,
+    },
+    InvalidHexValue {
+        key: String,
+        value: String,
+    },
+    InvalidBase64Value {
+        key: String,
+        value: String,
+    },
+    InvalidPortNumber {
+        value: String,
+    },
+    InvalidFwmark {
+        value: String,
+    },
+    InvalidListenAddress {
+        value: String,
+    },
+    InvalidInterfaceName {
+        value: String,
+    },
+    InvalidKey {
+        key: String,
+    },
+    InvalidSocketAddr {
+        peer: PublicKey,
+        value: String,
+    },
+    InvalidKeepaliveInterval {
+        peer: PublicKey,
+        value: String,
+    },
+    InvalidAllowedIp {
+        peer: PublicKey,
+        value: String,
+    },
+    InvalidFilterRule {
+        peer: PublicKey,
+        value: String,
+    },
+    InvalidOperation {
+        operation: String,
+    },
+    InvalidLine {
+        line: String,
+    },
     LineTooLong,
     IOError,
-    UnsupportedValue,
-    UnsupportedProtocolVersion,
+    UnknownPeer {
+        peer: PublicKey,
+    },
+    UnsupportedValue {
+        key: String,
+        value: String,
+    },
+    UnsupportedProtocolVersion {
+        peer: PublicKey,
+        version: String,
+    },
+}
+
+// values of these keys are never included in messages
+fn is_secret(key: &str) -> bool {
+    key == "private_key" || key == "preshared_key"
 }
 
 impl fmt::Display for ConfigError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "ConfigError(errno = {})", self.errno())
+        let peer = |pk: &PublicKey| Base64Key::from(pk);
+        match self {
+            ConfigError::FailedToBind { port, reason, .. } => {
+                write!(f, "failed to bind to port {}: {}", port, reason)
+            }
+            ConfigError::InvalidHexValue { key, value } => {
+                if is_secret(key) {
+                    write!(f, "invalid hex value for {} (value redacted)", key)
+                } else {
+                    write!(f, "invalid hex value for {}: {:?}", key, value)
+                }
+            }
+            ConfigError::InvalidBase64Value { key, value } => {
+                if is_secret(key) {
+                    write!(
+                        f,
+                        "invalid base64 (or hex) value for {} (value redacted)",
+                        key
+                    )
+                } else {
+                    write!(f, "invalid base64 (or hex) value for {}: {:?}", key, value)
+                }
+            }
+            ConfigError::InvalidPortNumber { value } => {
+                write!(f, "invalid port number: {:?}", value)
+            }
+            ConfigError::InvalidFwmark { value } => write!(f, "invalid fwmark: {:?}", value),
+            ConfigError::InvalidListenAddress { value } => {
+                write!(f, "invalid listen address: {:?}", value)
+            }
+            ConfigError::InvalidInterfaceName { value } => {
+                write!(f, "invalid interface name: {:?}", value)
+            }
+            ConfigError::InvalidKey { key } => write!(f, "unknown or misplaced key: {:?}", key),
+            ConfigError::InvalidSocketAddr { peer: pk, value } => {
+                write!(f, "invalid endpoint for peer {}: {:?}", peer(pk), value)
+            }
+            ConfigError::InvalidKeepaliveInterval { peer: pk, value } => write!(
+                f,
+                "invalid persistent keepalive interval for peer {}: {:?}",
+                peer(pk),
+                value
+            ),
+            ConfigError::InvalidAllowedIp { peer: pk, value } => {
+                write!(f, "invalid allowed ip for peer {}: {:?}", peer(pk), value)
+            }
+            ConfigError::InvalidFilterRule { peer: pk, value } => {
+                write!(f, "invalid filter rule for peer {}: {:?}", peer(pk), value)
+            }
+            ConfigError::InvalidOperation { operation } => {
+                write!(f, "invalid operation: {:?}", operation)
+            }
+            ConfigError::InvalidLine { line } => {
+                write!(f, "malformed line (expected key=value): {:?}", line)
+            }
+            ConfigError::LineTooLong => write!(f, "line exceeds maximum length"),
+            ConfigError::IOError => write!(f, "input/output error"),
+            ConfigError::UnknownPeer { peer: pk } => write!(f, "no such peer: {}", peer(pk)),
+            ConfigError::UnsupportedValue { key, value } => {
+                write!(f, "unsupported value for {}: {:?}", key, value)
+            }
+            ConfigError::UnsupportedProtocolVersion { peer: pk, version } => write!(
+                f,
+                "unsupported protocol version for peer {}: {:?}",
+                peer(pk),
+                version
+            ),
+        }
     }
 }
 
 impl Error for ConfigError {
     fn description(&self) -> &str {
-        ""
+        self.kind()
     }
 
     fn source(&self) -> Option<&(dyn Error + 'static)> {
@@ -37,28 +163,65 @@ impl Error for ConfigError {
     }
 }
 
+impl ConfigError {
+    /// Returns the name of the error kind
+    /// (used to report structured errors, e.g. over the JSON interface)
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ConfigError::FailedToBind { .. } => "FailedToBind",
+            ConfigError::InvalidHexValue { .. } => "InvalidHexValue",
+            ConfigError::InvalidBase64Value { .. } => "InvalidBase64Value",
+            ConfigError::InvalidPortNumber { .. } => "InvalidPortNumber",
+            ConfigError::InvalidFwmark { .. } => "InvalidFwmark",
+            ConfigError::InvalidListenAddress { .. } => "InvalidListenAddress",
+            ConfigError::InvalidInterfaceName { .. } => "InvalidInterfaceName",
+            ConfigError::InvalidKey { .. } => "InvalidKey",
+            ConfigError::InvalidSocketAddr { .. } => "InvalidSocketAddr",
+            ConfigError::InvalidKeepaliveInterval { .. } => "InvalidKeepaliveInterval",
+            ConfigError::InvalidAllowedIp { .. } => "InvalidAllowedIp",
+            ConfigError::InvalidFilterRule { .. } => "InvalidFilterRule",
+            ConfigError::InvalidOperation { .. } => "InvalidOperation",
+            ConfigError::InvalidLine { .. } => "InvalidLine",
+            ConfigError::LineTooLong => "LineTooLong",
+            ConfigError::IOError => "IOError",
+            ConfigError::UnknownPeer { .. } => "UnknownPeer",
+            ConfigError::UnsupportedValue { .. } => "UnsupportedValue",
+            ConfigError::UnsupportedProtocolVersion { .. } => "UnsupportedProtocolVersion",
+        }
+    }
+}
+
 #[cfg(unix)]
 impl ConfigError {
+    /// Returns the errno reported to the UAPI client,
+    /// matching the kernel implementation where applicable.
     pub fn errno(&self) -> i32 {
-        // TODO: obtain the correct errorno values
         match self {
-            // insufficient perms
-            ConfigError::FailedToBind => EPERM,
+            // the socket error if known, otherwise assume the port is in use
+            ConfigError::FailedToBind { errno, .. } => errno.unwrap_or(EADDRINUSE),
 
             // parsing of value failed
-            ConfigError::InvalidHexValue => EINVAL,
-            ConfigError::InvalidPortNumber => EINVAL,
-            ConfigError::InvalidFwmark => EINVAL,
-            ConfigError::InvalidSocketAddr => EINVAL,
-            ConfigError::InvalidKeepaliveInterval => EINVAL,
-            ConfigError::InvalidAllowedIp => EINVAL,
-            ConfigError::InvalidOperation => EINVAL,
-            ConfigError::UnsupportedValue => EINVAL,
+            ConfigError::InvalidHexValue { .. } => EINVAL,
+            ConfigError::InvalidBase64Value { .. } => EINVAL,
+            ConfigError::InvalidPortNumber { .. } => EINVAL,
+            ConfigError::InvalidFwmark { .. } => EINVAL,
+            ConfigError::InvalidListenAddress { .. } => EINVAL,
+            ConfigError::InvalidInterfaceName { .. } => EINVAL,
+            ConfigError::InvalidKey { .. } => EINVAL,
+            ConfigError::InvalidSocketAddr { .. } => EINVAL,
+            ConfigError::InvalidKeepaliveInterval { .. } => EINVAL,
+            ConfigError::InvalidAllowedIp { .. } => EINVAL,
+            ConfigError::InvalidFilterRule { .. } => EINVAL,
+            ConfigError::InvalidOperation { .. } => EINVAL,
+            ConfigError::UnsupportedValue { .. } => EINVAL,
+
+            // peer lookup failed (e.g. "update_only" for a non-existent peer)
+            ConfigError::UnknownPeer { .. } => ENOENT,
 
             // other protocol errors
+            ConfigError::InvalidLine { .. } => EPROTO,
             ConfigError::LineTooLong => EPROTO,
-            ConfigError::InvalidKey => EPROTO,
-            ConfigError::UnsupportedProtocolVersion => EPROTO,
+            ConfigError::UnsupportedProtocolVersion { .. } => EPROTONOSUPPORT,
 
             // IO
             ConfigError::IOError => EIO,
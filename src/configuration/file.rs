@@ -0,0 +1,68 @@
+/* Loading of the configuration from a file at start-up.
+ *
+ * The file consists of the lines of a UAPI "set" operation, e.g.
+ *
+ *   # interface
+ *   private_key=<hex>
+ *   listen_port=51820
+ *   x_listen_address=192.0.2.1
+ *
+ *   # peer
+ *   public_key=<hex>
+ *   allowed_ip=10.0.0.2/32
+ *   x_filter=accept in proto=tcp port=22
+ *
+ * Lines starting with '#' are comments, empty lines are ignored
+ * and whitespace surrounding keys and values is removed.
+ */
+use log;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+use super::uapi::LineParser;
+use super::{ConfigError, Configuration};
+
+/// Applies the configuration file to the device
+///
+/// # Arguments
+///
+/// - `config`: The configuration interface of the device
+/// - `path`: The path of the configuration file
+///
+/// # Returns
+///
+/// The line number (starting from 1) and error of the first line which failed to apply
+pub fn load<C: Configuration, P: AsRef<Path>>(
+    config: &C,
+    path: P,
+) -> Result<(), (usize, ConfigError)> {
+    let file = File::open(path).map_err(|_| (0, ConfigError::IOError))?;
+    let mut parser = LineParser::new(config).accept_base64();
+    let mut number = 0;
+    for line in BufReader::new(file).lines() {
+        number += 1;
+        let line = line.map_err(|_| (number, ConfigError::IOError))?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        log::trace!("config file, line {}: {}", number, line);
+
+        let mut split = line.splitn(2, '=');
+        match (split.next(), split.next()) {
+            (Some(key), Some(value)) => parser
+                .parse_line(key.trim(), value.trim())
+                .map_err(|e| (number, e))?,
+            _ => {
+                return Err((
+                    number,
+                    ConfigError::InvalidLine {
+                        line: line.to_owned(),
+                    },
+                ))
+            }
+        }
+    }
+    parser.parse_line("", "").map_err(|e| (number, e))
+}
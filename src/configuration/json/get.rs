@@ -0,0 +1,269 @@
+use serde_json::{json, Map, Value};
+use x25519_dalek::PublicKey;
+
+use super::{Base64Key, Configuration, Excess, HandshakeRecord, RelayAcl, Roaming, SecretKey};
+
+fn public_key(pk: &PublicKey) -> Value {
+    Base64Key::from(pk).to_string().into()
+}
+
+// secrets are only revealed on request, otherwise reported as "(redacted)"
+fn secret_key(key: [u8; 32], secrets: bool) -> Value {
+    let key = SecretKey::new(key);
+    if secrets {
+        format!("{:#}", key).into()
+    } else {
+        key.to_string().into()
+    }
+}
+
+fn handshake_record(record: &HandshakeRecord) -> Value {
+    json!({
+        "time": record.unix_time(),
+        "endpoint": record.endpoint.to_string(),
+        "outcome": record.outcome.to_string(),
+    })
+}
+
+/* Serializes the state of the device,
+ * using the keys of the UAPI get operation (including all vendor extensions).
+ *
+ * Keys are encoded as base64, the private and preshared keys are redacted unless "secrets" is set.
+ */
+pub fn serialize<C: Configuration>(config: &C, secrets: bool) -> Value {
+    let mut interface = Map::new();
+
+    // serialize interface
+    if let Some(sk) = config.get_private_key() {
+        interface.insert("private_key".into(), secret_key(sk.to_bytes(), secrets));
+    }
+
+    if let Some(port) = config.get_listen_port() {
+        interface.insert("listen_port".into(), port.into());
+    }
+
+    if let Some(fwmark) = config.get_fwmark() {
+        interface.insert("fwmark".into(), fwmark.into());
+    }
+
+    let protocol_version = config.get_protocol_version();
+    interface.insert("protocol_version".into(), protocol_version.into());
+    interface.insert("x_relay".into(), config.get_relay().into());
+    interface.insert(
+        "x_bind_family".into(),
+        config.get_bind_family().to_string().into(),
+    );
+    if let Some(addr) = config.get_listen_address() {
+        interface.insert("x_listen_address".into(), addr.to_string().into());
+    }
+    if let Some(name) = config.get_bind_interface() {
+        interface.insert("x_bind_interface".into(), name.into());
+    }
+    let (rcvbuf, sndbuf) = config.get_socket_buffers();
+    if let Some(size) = rcvbuf {
+        interface.insert("x_socket_rcvbuf".into(), size.into());
+    }
+    if let Some(size) = sndbuf {
+        interface.insert("x_socket_sndbuf".into(), size.into());
+    }
+    if let Some(ttl) = config.get_outer_ttl() {
+        interface.insert("x_outer_ttl".into(), ttl.into());
+    }
+    interface.insert("x_copy_dscp".into(), config.get_copy_dscp().into());
+    interface.insert("x_ecn".into(), config.get_ecn().into());
+    if let Some(pk) = config.get_public_key() {
+        interface.insert("x_public_key".into(), public_key(&pk));
+    }
+    interface.insert(
+        "x_private_key_export".into(),
+        config.get_private_key_export().into(),
+    );
+    interface.insert(
+        "x_key_rotation_grace_secs".into(),
+        config.get_key_rotation_grace().as_secs().into(),
+    );
+    if let Some(pk) = config.get_previous_public_key() {
+        interface.insert("x_previous_public_key".into(), public_key(&pk));
+    }
+
+    let limiter = config.get_ratelimiter();
+    let stats = config.get_ratelimiter_stats();
+    interface.insert(
+        "x_ratelimit_packets_per_second".into(),
+        limiter.packets_per_second.into(),
+    );
+    interface.insert("x_ratelimit_burst".into(), limiter.packets_burstable.into());
+    interface.insert(
+        "x_ratelimit_gc_interval_ms".into(),
+        (limiter.gc_interval.as_millis() as u64).into(),
+    );
+    interface.insert("x_ratelimit_ipv4_prefix".into(), limiter.ipv4_prefix.into());
+    interface.insert("x_ratelimit_ipv6_prefix".into(), limiter.ipv6_prefix.into());
+    interface.insert("x_ratelimit_max_entries".into(), limiter.max_entries.into());
+    interface.insert("x_ratelimit_entries".into(), stats.entries.into());
+    interface.insert("x_ratelimit_allowed".into(), stats.allowed.into());
+    interface.insert("x_ratelimit_limited".into(), stats.limited.into());
+    interface.insert("x_ratelimit_table_full".into(), stats.table_full.into());
+
+    let policy = config.get_load_policy();
+    let stats = config.get_load_stats();
+    interface.insert("x_load_max_queued".into(), policy.max_queued.into());
+    interface.insert("x_load_threshold".into(), policy.threshold.into());
+    interface.insert(
+        "x_load_duration_ms".into(),
+        (policy.duration.as_millis() as u64).into(),
+    );
+    interface.insert("x_load_always_cookie".into(), policy.always_cookie.into());
+    interface.insert("x_load_under_load".into(), stats.under_load.into());
+    interface.insert("x_load_transitions".into(), stats.transitions.into());
+    interface.insert("x_load_dropped".into(), stats.dropped.into());
+    interface.insert("x_load_cookie_replies".into(), stats.cookie_replies.into());
+    interface.insert(
+        "x_evict_idle_secs".into(),
+        config
+            .get_idle_timeout()
+            .map(|timeout| timeout.as_secs())
+            .unwrap_or(0)
+            .into(),
+    );
+    if let Some(path) = config.get_capture() {
+        interface.insert("x_capture_file".into(), path.display().to_string().into());
+    }
+    interface.insert("x_capture_all".into(), config.get_capture_all().into());
+    if let Some(path) = config.get_keylog() {
+        interface.insert("x_keylog_file".into(), path.display().to_string().into());
+    }
+    interface.insert(
+        "x_log_level".into(),
+        config.get_log_level().to_string().to_lowercase().into(),
+    );
+    interface.insert(
+        "x_handshake_failure".into(),
+        config
+            .get_handshake_failures()
+            .iter()
+            .map(handshake_record)
+            .collect(),
+    );
+
+    // serialize all peers
+    let peers: Vec<Value> = config
+        .get_peers()
+        .into_iter()
+        .map(|p| {
+            let mut peer = Map::new();
+            peer.insert("public_key".into(), public_key(&p.public_key));
+            peer.insert("preshared_key".into(), secret_key(p.preshared_key, secrets));
+            peer.insert("protocol_version".into(), protocol_version.into());
+
+            if let Some(endpoint) = p.endpoint {
+                peer.insert("endpoint".into(), endpoint.to_string().into());
+            }
+
+            let (secs, nsecs) = p.last_handshake_time.unwrap_or((0, 0));
+            peer.insert("last_handshake_time_sec".into(), secs.into());
+            peer.insert("last_handshake_time_nsec".into(), nsecs.into());
+            peer.insert("tx_bytes".into(), p.tx_bytes.into());
+            peer.insert("rx_bytes".into(), p.rx_bytes.into());
+            peer.insert(
+                "persistent_keepalive_interval".into(),
+                p.persistent_keepalive_interval.into(),
+            );
+
+            peer.insert(
+                "allowed_ip".into(),
+                p.allowed_ips
+                    .iter()
+                    .map(|(ip, cidr)| json!(format!("{}/{}", ip, cidr)))
+                    .collect(),
+            );
+
+            peer.insert(
+                "x_endpoint_candidate".into(),
+                p.endpoint_candidates
+                    .iter()
+                    .map(|endpoint| json!(endpoint.to_string()))
+                    .collect(),
+            );
+            peer.insert(
+                "x_endpoint_locked".into(),
+                match p.roaming {
+                    Roaming::Enabled => "false",
+                    Roaming::Locked => "true",
+                    Roaming::Strict => "strict",
+                }
+                .into(),
+            );
+            peer.insert("x_roaming_attempts".into(), p.roaming_attempts.into());
+            if let Some(epoch) = p.psk_epoch {
+                peer.insert("x_psk_epoch".into(), epoch.into());
+            }
+            peer.insert("x_psk_age_secs".into(), p.psk_age.as_secs().into());
+            peer.insert("x_evicted".into(), p.evicted.into());
+            peer.insert("x_capture".into(), p.capture.into());
+            peer.insert(
+                "x_handshake".into(),
+                p.handshakes.iter().map(handshake_record).collect(),
+            );
+            if let Some(mtu) = p.path_mtu {
+                peer.insert("x_path_mtu".into(), mtu.into());
+            }
+            peer.insert(
+                "x_relay_allow".into(),
+                match p.relay_acl {
+                    RelayAcl::Any => json!(["*"]),
+                    RelayAcl::Peers(ref peers) if peers.is_empty() => json!(["none"]),
+                    RelayAcl::Peers(ref peers) => peers.iter().map(public_key).collect(),
+                },
+            );
+            peer.insert(
+                "x_filter".into(),
+                p.filter
+                    .iter()
+                    .map(|(rule, _)| json!(rule.to_string()))
+                    .collect(),
+            );
+            peer.insert(
+                "x_filter_hits".into(),
+                p.filter.iter().map(|(_, hits)| json!(hits)).collect(),
+            );
+            let mut limited = false;
+            for (dir, limit) in [("tx", &p.tx_limit), ("rx", &p.rx_limit)].iter() {
+                if limit.bytes_per_second == 0 && limit.packets_per_second == 0 {
+                    continue;
+                }
+                limited = true;
+                let key = |name: &str| format!("x_{}_limit_{}", dir, name);
+                peer.insert(key("bytes"), limit.bytes_per_second.into());
+                peer.insert(key("packets"), limit.packets_per_second.into());
+                peer.insert(key("available_bytes"), limit.available_bytes.into());
+                peer.insert(key("available_packets"), limit.available_packets.into());
+                peer.insert(key("dropped"), limit.dropped.into());
+                peer.insert(key("delayed"), limit.delayed.into());
+            }
+            if limited {
+                peer.insert(
+                    "x_limit_excess".into(),
+                    match p.tx_limit.excess {
+                        Excess::Drop => "drop",
+                        Excess::Delay => "delay",
+                    }
+                    .into(),
+                );
+            }
+            peer.insert("x_handshake_attempts".into(), p.handshake_attempts.into());
+            if let Some(age) = p.current_keypair_age {
+                peer.insert(
+                    "x_current_keypair_age_ms".into(),
+                    (age.as_millis() as u64).into(),
+                );
+            }
+            peer.insert("x_tx_packets".into(), p.tx_packets.into());
+            peer.insert("x_rx_packets".into(), p.rx_packets.into());
+            Value::Object(peer)
+        })
+        .collect();
+
+    interface.insert("peers".into(), peers.into());
+    Value::Object(interface)
+}
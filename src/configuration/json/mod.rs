@@ -0,0 +1,352 @@
+/* A JSON control interface, offered alongside the text based UAPI.
+ *
+ * Every request (and response) is a single JSON object terminated by a newline,
+ * a connection may carry any number of requests, e.g.
+ *
+ *   {"op": "get"} (or {"op": "get", "secrets": true} to include the private and preshared keys)
+ *   {"op": "set", "config": {"listen_port": 51820, "peers": [...]}}
+ *   {"op": "add-peer", "peer": {"public_key": "<base64>", "allowed_ip": ["10.0.0.2/32"]}}
+ *   {"op": "remove-peer", "public_key": "<base64>"}
+ *   {"op": "subscribe-events"}
+ *
+ * The configuration keys are those of the UAPI and are applied by the same parser,
+ * hence the two interfaces share semantics (and errors).
+ *
+ * After "subscribe-events" the connection is dedicated to the event stream:
+ * every event is written as a JSON object until the client disconnects.
+ */
+mod get;
+mod set;
+
+use log;
+use std::io::{Read, Write};
+
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+
+use super::{
+    Base64Key, ConfigError, Configuration, Event, Excess, HandshakeRecord, RelayAcl, Roaming,
+    SecretKey,
+};
+
+use get::serialize;
+use set::{add_peer, remove_peer, set};
+
+const MAX_REQUEST_LENGTH: usize = 1 << 20;
+
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "kebab-case", deny_unknown_fields)]
+enum Request {
+    Get {
+        #[serde(default)]
+        secrets: bool,
+    },
+    Set {
+        config: Map<String, Value>,
+    },
+    AddPeer {
+        peer: Map<String, Value>,
+    },
+    RemovePeer {
+        public_key: String,
+    },
+    SubscribeEvents,
+}
+
+/* Reads newline terminated requests from a stream,
+ * buffering any bytes read beyond the end of the current request.
+ */
+struct RequestReader {
+    buf: Vec<u8>,
+}
+
+impl RequestReader {
+    fn new() -> Self {
+        RequestReader { buf: vec![] }
+    }
+
+    /* Returns the next request (None if the stream was closed) */
+    fn next<R: Read>(&mut self, reader: &mut R) -> Result<Option<String>, ConfigError> {
+        let mut chunk = [0u8; 4096];
+        loop {
+            if let Some(n) = self.buf.iter().position(|c| *c == b'\n') {
+                let line: Vec<u8> = self.buf.drain(..=n).take(n).collect();
+                return String::from_utf8(line)
+                    .map(Some)
+                    .map_err(|e| ConfigError::InvalidLine {
+                        line: String::from_utf8_lossy(e.as_bytes()).into_owned(),
+                    });
+            }
+            if self.buf.len() > MAX_REQUEST_LENGTH {
+                return Err(ConfigError::LineTooLong);
+            }
+            match reader.read(&mut chunk) {
+                Ok(0) => return Ok(None),
+                Ok(n) => self.buf.extend_from_slice(&chunk[..n]),
+                Err(_) => return Err(ConfigError::IOError),
+            }
+        }
+    }
+}
+
+fn error(kind: &str, errno: i32, message: String) -> Value {
+    json!({
+        "ok": false,
+        "error": {
+            "kind": kind,
+            "errno": errno,
+            "message": message,
+        }
+    })
+}
+
+fn config_error(err: &ConfigError) -> Value {
+    error(err.kind(), err.errno(), err.to_string())
+}
+
+fn event(event: &Event) -> Value {
+    match event {
+        Event::DeviceUp { mtu } => json!({"event": "device-up", "mtu": mtu}),
+        Event::DeviceDown => json!({"event": "device-down"}),
+        Event::PeerAdded { public_key } => json!({
+            "event": "peer-added",
+            "public_key": Base64Key::from(public_key).to_string(),
+        }),
+        Event::PeerRemoved { public_key } => json!({
+            "event": "peer-removed",
+            "public_key": Base64Key::from(public_key).to_string(),
+        }),
+        Event::PeerEvicted { public_key } => json!({
+            "event": "peer-evicted",
+            "public_key": Base64Key::from(public_key).to_string(),
+        }),
+        Event::AllowedIpsChanged { public_key } => json!({
+            "event": "allowed-ips-changed",
+            "public_key": Base64Key::from(public_key).to_string(),
+        }),
+        Event::HandshakeCompleted { public_key } => json!({
+            "event": "handshake-completed",
+            "public_key": Base64Key::from(public_key).to_string(),
+        }),
+        Event::HandshakeFailed(public_key, record) => json!({
+            "event": "handshake-failed",
+            "public_key": Base64Key::from(public_key).to_string(),
+            "endpoint": record.endpoint.to_string(),
+            "error": record.outcome.to_string(),
+        }),
+        Event::UnderLoad { pending } => json!({"event": "under-load", "pending": pending}),
+        Event::LoadNormal => json!({"event": "load-normal"}),
+    }
+}
+
+fn respond<W: Write>(stream: &mut W, value: &Value) -> bool {
+    let mut msg = value.to_string();
+    msg.push('\n');
+    stream.write_all(msg.as_bytes()).is_ok()
+}
+
+pub fn handle<S: Read + Write, C: Configuration>(stream: &mut S, config: &C) {
+    let mut requests = RequestReader::new();
+    loop {
+        // read next request
+        let line = match requests.next(stream) {
+            Ok(Some(line)) => line,
+            Ok(None) => return,
+            Err(e) => {
+                log::debug!("JSON, failed to read request: {:?}", e);
+                respond(stream, &config_error(&e));
+                return;
+            }
+        };
+        log::trace!("JSON, request: {}", line);
+
+        // ignore empty lines
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        // decode request
+        let request: Request = match serde_json::from_str(line.as_str()) {
+            Ok(request) => request,
+            Err(e) => {
+                let err = ConfigError::InvalidOperation {
+                    operation: line.clone(),
+                };
+                if !respond(stream, &error(err.kind(), err.errno(), e.to_string())) {
+                    return;
+                }
+                continue;
+            }
+        };
+
+        // process request
+        let res = match request {
+            Request::Get { secrets } => {
+                log::debug!("JSON, Get operation");
+                Ok(serialize(config, secrets))
+            }
+            Request::Set { config: cfg } => {
+                log::debug!("JSON, Set operation");
+                set(config, &cfg).map(|_| Value::Null)
+            }
+            Request::AddPeer { peer } => {
+                log::debug!("JSON, Add peer operation");
+                add_peer(config, &peer).map(|_| Value::Null)
+            }
+            Request::RemovePeer { public_key } => {
+                log::debug!("JSON, Remove peer operation");
+                remove_peer(config, &public_key).map(|_| Value::Null)
+            }
+            Request::SubscribeEvents => {
+                log::debug!("JSON, Subscribe events operation");
+                let events = config.subscribe_events();
+                if !respond(stream, &json!({"ok": true})) {
+                    return;
+                }
+                for ev in events {
+                    if !respond(stream, &event(&ev)) {
+                        log::debug!("JSON, event subscriber disconnected");
+                        return;
+                    }
+                }
+                return;
+            }
+        };
+        log::debug!("JSON, Result of operation: {:?}", res.as_ref().map(|_| ()));
+
+        // return result
+        let ok = respond(
+            stream,
+            &match res {
+                Ok(Value::Null) => json!({"ok": true}),
+                Ok(result) => json!({"ok": true, "result": result}),
+                Err(e) => config_error(&e),
+            },
+        );
+        if !ok {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::stream::{self, config, PEER_PK, PEER_PK_BASE64};
+    use super::*;
+
+    fn requests<C: Configuration>(config: &C, requests: &[&str]) -> Vec<Value> {
+        let input = format!("{}\n", requests.join("\n"));
+        stream::request(&input, |s| handle(s, config))
+            .lines()
+            .map(|ln| serde_json::from_str(ln).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_json_peers() {
+        let cfg = config();
+
+        let res = requests(
+            &cfg,
+            &[
+                &format!(
+                    r#"{{"op": "add-peer", "peer": {{"public_key": "{}", "allowed_ip": ["10.0.0.2/32"]}}}}"#,
+                    PEER_PK_BASE64
+                ),
+                r#"{"op": "get"}"#,
+                &format!(
+                    r#"{{"op": "remove-peer", "public_key": "{}"}}"#,
+                    PEER_PK_BASE64
+                ),
+                r#"{"op": "get"}"#,
+                &format!(
+                    r#"{{"op": "add-peer", "peer": {{"public_key": "{}", "update_only": true, "allowed_ip": "10.0.0.2/32"}}}}"#,
+                    PEER_PK_BASE64
+                ),
+                r#"{"op": "add-peer", "peer": {"public_key": "zz"}}"#,
+                r#"{"op": "frobnicate"}"#,
+                r#"{"op": "set", "config": {"replace_peers": false}}"#,
+            ],
+        );
+
+        assert_eq!(res.len(), 8);
+        assert_eq!(res[0], json!({"ok": true}));
+        assert_eq!(
+            res[1]["result"]["peers"][0]["public_key"],
+            json!(PEER_PK_BASE64)
+        );
+        assert_eq!(
+            res[1]["result"]["peers"][0]["allowed_ip"],
+            json!(["10.0.0.2/32"])
+        );
+        assert_eq!(res[2], json!({"ok": true}));
+        assert_eq!(res[3]["result"]["peers"], json!([]));
+        assert_eq!(res[4]["error"]["kind"], json!("UnknownPeer"));
+        assert_eq!(res[4]["error"]["errno"], json!(libc::ENOENT));
+        assert_eq!(res[5]["error"]["kind"], json!("InvalidBase64Value"));
+        assert_eq!(res[6]["error"]["kind"], json!("InvalidOperation"));
+        assert_eq!(res[7]["error"]["kind"], json!("UnsupportedValue"));
+    }
+
+    #[test]
+    fn test_json_base64() {
+        let cfg = config();
+
+        // keys may be hex or base64 (as printed by "wg pubkey"), they are reported as base64
+        let psk = "AQIDBAUGBwgJCgsMDQ4PEBESExQVFhcYGRobHB0eHyA=";
+        let res = requests(
+            &cfg,
+            &[
+                &format!(
+                    r#"{{"op": "add-peer", "peer": {{"public_key": "{}", "preshared_key": "{}", "x_relay_allow": "{}"}}}}"#,
+                    PEER_PK, psk, PEER_PK_BASE64
+                ),
+                r#"{"op": "get"}"#,
+                r#"{"op": "get", "secrets": true}"#,
+                &format!(
+                    r#"{{"op": "add-peer", "peer": {{"public_key": "{}", "update_only": true, "endpoint": "x"}}}}"#,
+                    PEER_PK_BASE64
+                ),
+                &format!(
+                    r#"{{"op": "add-peer", "peer": {{"public_key": "{}", "preshared_key": "zz"}}}}"#,
+                    PEER_PK_BASE64
+                ),
+                &format!(
+                    r#"{{"op": "remove-peer", "public_key": "{}"}}"#,
+                    PEER_PK_BASE64
+                ),
+                r#"{"op": "get"}"#,
+            ],
+        );
+
+        assert_eq!(res.len(), 7);
+        assert_eq!(res[0], json!({"ok": true}));
+        assert_eq!(
+            res[1]["result"]["peers"][0]["public_key"],
+            json!(PEER_PK_BASE64)
+        );
+        assert_eq!(
+            res[1]["result"]["peers"][0]["x_relay_allow"],
+            json!([PEER_PK_BASE64])
+        );
+        assert_eq!(
+            res[1]["result"]["peers"][0]["preshared_key"],
+            json!("(redacted)")
+        );
+        assert_eq!(res[2]["result"]["peers"][0]["preshared_key"], json!(psk));
+        assert_eq!(
+            res[3]["error"]["message"],
+            json!(format!(
+                "invalid endpoint for peer {}: \"x\"",
+                PEER_PK_BASE64
+            ))
+        );
+        assert_eq!(res[4]["error"]["kind"], json!("InvalidBase64Value"));
+        assert_eq!(
+            res[4]["error"]["message"],
+            json!("invalid base64 (or PEER_PK) value for preshared_key (value redacted)")
+        );
+        assert_eq!(res[5], json!({"ok": true}));
+        assert_eq!(res[6]["result"]["peers"], json!([]));
+    }
+}
@@ -0,0 +1,107 @@
+use serde_json::{Map, Value};
+
+use super::super::uapi::LineParser;
+use super::{ConfigError, Configuration};
+
+/* Feeds a single JSON (key, value) pair to the UAPI parser:
+ *
+ * - Arrays result in one line per element (e.g. "allowed_ip").
+ * - Booleans are passed as "true" / "false", hence the flags of the UAPI
+ *   (e.g. "remove", "replace_peers") reject false, vendor extensions ("x_") accept both values.
+ */
+fn apply<C: Configuration>(
+    parser: &mut LineParser<C>,
+    key: &str,
+    value: &Value,
+) -> Result<(), ConfigError> {
+    match value {
+        Value::Array(values) => {
+            for value in values {
+                apply(parser, key, value)?;
+            }
+            Ok(())
+        }
+        Value::String(s) => parser.parse_line(key, s.as_str()),
+        Value::Number(n) => parser.parse_line(key, n.to_string().as_str()),
+        Value::Bool(b) => parser.parse_line(key, b.to_string().as_str()),
+        Value::Null | Value::Object(_) => Err(ConfigError::UnsupportedValue {
+            key: key.to_owned(),
+            value: value.to_string(),
+        }),
+    }
+}
+
+/* The public key must be the first line of every peer section */
+fn apply_peer<C: Configuration>(
+    parser: &mut LineParser<C>,
+    peer: &Map<String, Value>,
+) -> Result<(), ConfigError> {
+    apply(
+        parser,
+        "public_key",
+        peer.get("public_key").ok_or(ConfigError::InvalidKey {
+            key: "public_key".to_owned(),
+        })?,
+    )?;
+    for (key, value) in peer.iter().filter(|(key, _)| *key != "public_key") {
+        apply(parser, key.as_str(), value)?;
+    }
+    Ok(())
+}
+
+pub fn set<C: Configuration>(config: &C, cfg: &Map<String, Value>) -> Result<(), ConfigError> {
+    let mut parser = LineParser::new(config).accept_base64();
+
+    // interface keys precede all peers,
+    // the key rotation grace must precede the private key it applies to
+    if let Some(value) = cfg.get("x_key_rotation_grace_secs") {
+        apply(&mut parser, "x_key_rotation_grace_secs", value)?;
+    }
+    for (key, value) in cfg
+        .iter()
+        .filter(|(key, _)| *key != "peers" && *key != "x_key_rotation_grace_secs")
+    {
+        apply(&mut parser, key.as_str(), value)?;
+    }
+
+    match cfg.get("peers") {
+        Some(Value::Array(peers)) => {
+            for peer in peers {
+                match peer {
+                    Value::Object(peer) => apply_peer(&mut parser, peer)?,
+                    _ => {
+                        return Err(ConfigError::UnsupportedValue {
+                            key: "peers".to_owned(),
+                            value: peer.to_string(),
+                        })
+                    }
+                }
+            }
+        }
+        Some(value) => {
+            return Err(ConfigError::UnsupportedValue {
+                key: "peers".to_owned(),
+                value: value.to_string(),
+            })
+        }
+        None => (),
+    }
+
+    parser.parse_line("", "")
+}
+
+pub fn add_peer<C: Configuration>(
+    config: &C,
+    peer: &Map<String, Value>,
+) -> Result<(), ConfigError> {
+    let mut parser = LineParser::new(config).accept_base64();
+    apply_peer(&mut parser, peer)?;
+    parser.parse_line("", "")
+}
+
+pub fn remove_peer<C: Configuration>(config: &C, public_key: &str) -> Result<(), ConfigError> {
+    let mut parser = LineParser::new(config).accept_base64();
+    parser.parse_line("public_key", public_key)?;
+    parser.parse_line("remove", "true")?;
+    parser.parse_line("", "")
+}
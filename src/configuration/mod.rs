@@ -1,12 +1,26 @@
 mod config;
 mod error;
+pub mod file;
+pub mod json;
 pub mod uapi;
 
+#[cfg(test)]
+mod stream;
+
 use super::platform::Endpoint;
 use super::platform::{tun, udp};
 use super::wireguard::WireGuard;
 
+pub use super::wireguard::{
+    Base64Key, Event, Excess, FileKey, FilterRule, HandshakeRecord, KeyLog, KeyLogFile,
+    LimiterState, LoadPolicy, LoadStats, PeerProvider, ProvidedPeer, PskProvider,
+    RateLimiterConfig, RateLimiterStats, RelayAcl, Roaming, SecretKey, StaticKey,
+};
+
+pub use super::platform::udp::BindFamily;
+
 pub use error::ConfigError;
 
 pub use config::Configuration;
+pub use config::PeerLimits;
 pub use config::WireGuardConfig;
@@ -0,0 +1,51 @@
+/* Fixtures for testing the configuration interfaces (UAPI and JSON):
+ * a stream replaying the requests and recording the responses,
+ * and a configuration of a device with dummy IO.
+ */
+use std::io::{self, Cursor, Read, Write};
+
+use super::super::platform::dummy;
+use super::super::wireguard::WireGuard;
+use super::WireGuardConfig;
+
+/// The public key of a peer (hex and base64)
+pub const PEER_PK: &str = "a8e3e0f1e8d42c3d1a4ea1d5c6a5c5c0f6a8c0c7a4b1d8e2f3a4b5c6d7e8f9a0";
+pub const PEER_PK_BASE64: &str = "qOPg8ejULD0aTqHVxqXFwPaowMeksdji86S1xtfo+aA=";
+
+/// Returns the configuration of a new device (with dummy TUN and bind)
+pub fn config() -> WireGuardConfig<dummy::TunTest, dummy::PairBind> {
+    let (_fake, _reader, tun_writer, _) = dummy::TunTest::create(false);
+    let wg: WireGuard<dummy::TunTest, dummy::PairBind> = WireGuard::new(tun_writer);
+    WireGuardConfig::new(wg)
+}
+
+pub struct TestStream {
+    input: Cursor<Vec<u8>>,
+    output: Vec<u8>,
+}
+
+impl Read for TestStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.input.read(buf)
+    }
+}
+
+impl Write for TestStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.output.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Handles the request (read from a TestStream) and returns the response
+pub fn request<F: FnOnce(&mut TestStream)>(request: &str, handle: F) -> String {
+    let mut stream = TestStream {
+        input: Cursor::new(request.as_bytes().to_vec()),
+        output: vec![],
+    };
+    handle(&mut stream);
+    String::from_utf8(stream.output).unwrap()
+}
@@ -1,56 +1,243 @@
 use log;
 use std::io;
 
-use super::Configuration;
+use super::{Configuration, Excess, RelayAcl, Roaming};
 
-pub fn serialize<C: Configuration, W: io::Write>(writer: &mut W, config: &C) -> io::Result<()> {
-    let mut write = |key: &'static str, value: String| {
+/* Serializes the state of the device in the order of the cross-platform UAPI specification.
+ *
+ * Vendor extensions (keys prefixed with "x_") are only emitted when negotiated by the client,
+ * as to not confuse clients strictly following the specification.
+ */
+pub fn serialize<C: Configuration, W: io::Write>(
+    writer: &mut W,
+    config: &C,
+    extensions: bool,
+) -> io::Result<()> {
+    let mut write = |key: &str, value: String| {
         debug_assert!(value.is_ascii());
         debug_assert!(key.is_ascii());
         log::trace!("UAPI: return : {}={}", key, value);
-        writer.write(key.as_ref())?;
-        writer.write(b"=")?;
-        writer.write(value.as_ref())?;
-        writer.write(b"\n")
+        writer.write_all(key.as_ref())?;
+        writer.write_all(b"=")?;
+        writer.write_all(value.as_ref())?;
+        writer.write_all(b"\n")
     };
 
     // serialize interface
-    config
-        .get_private_key()
-        .map(|sk| write("private_key", hex::encode(sk.to_bytes())));
+    if let Some(sk) = config.get_private_key() {
+        write("private_key", hex::encode(sk.to_bytes()))?;
+    }
 
-    config
-        .get_listen_port()
-        .map(|port| write("listen_port", port.to_string()));
+    if let Some(port) = config.get_listen_port() {
+        write("listen_port", port.to_string())?;
+    }
 
-    config
-        .get_fwmark()
-        .map(|fwmark| write("fwmark", fwmark.to_string()));
+    if let Some(fwmark) = config.get_fwmark() {
+        write("fwmark", fwmark.to_string())?;
+    }
+
+    write(
+        "protocol_version",
+        config.get_protocol_version().to_string(),
+    )?;
 
-    // serialize all peers
     let mut peers = config.get_peers();
+
+    if extensions {
+        write("x_peer_count", peers.len().to_string())?;
+        write("x_relay", config.get_relay().to_string())?;
+        write("x_bind_family", config.get_bind_family().to_string())?;
+        if let Some(addr) = config.get_listen_address() {
+            write("x_listen_address", addr.to_string())?;
+        }
+        if let Some(name) = config.get_bind_interface() {
+            write("x_bind_interface", name)?;
+        }
+        let (rcvbuf, sndbuf) = config.get_socket_buffers();
+        if let Some(size) = rcvbuf {
+            write("x_socket_rcvbuf", size.to_string())?;
+        }
+        if let Some(size) = sndbuf {
+            write("x_socket_sndbuf", size.to_string())?;
+        }
+        if let Some(ttl) = config.get_outer_ttl() {
+            write("x_outer_ttl", ttl.to_string())?;
+        }
+        write("x_copy_dscp", config.get_copy_dscp().to_string())?;
+        write("x_ecn", config.get_ecn().to_string())?;
+        if let Some(pk) = config.get_public_key() {
+            write("x_public_key", hex::encode(pk.as_bytes()))?;
+        }
+        write(
+            "x_private_key_export",
+            config.get_private_key_export().to_string(),
+        )?;
+        write(
+            "x_key_rotation_grace_secs",
+            config.get_key_rotation_grace().as_secs().to_string(),
+        )?;
+        if let Some(pk) = config.get_previous_public_key() {
+            write("x_previous_public_key", hex::encode(pk.as_bytes()))?;
+        }
+
+        let limiter = config.get_ratelimiter();
+        let stats = config.get_ratelimiter_stats();
+        write(
+            "x_ratelimit_packets_per_second",
+            limiter.packets_per_second.to_string(),
+        )?;
+        write("x_ratelimit_burst", limiter.packets_burstable.to_string())?;
+        write(
+            "x_ratelimit_gc_interval_ms",
+            limiter.gc_interval.as_millis().to_string(),
+        )?;
+        write("x_ratelimit_ipv4_prefix", limiter.ipv4_prefix.to_string())?;
+        write("x_ratelimit_ipv6_prefix", limiter.ipv6_prefix.to_string())?;
+        write("x_ratelimit_max_entries", limiter.max_entries.to_string())?;
+        write("x_ratelimit_entries", stats.entries.to_string())?;
+        write("x_ratelimit_allowed", stats.allowed.to_string())?;
+        write("x_ratelimit_limited", stats.limited.to_string())?;
+        write("x_ratelimit_table_full", stats.table_full.to_string())?;
+
+        let policy = config.get_load_policy();
+        let stats = config.get_load_stats();
+        write("x_load_max_queued", policy.max_queued.to_string())?;
+        write("x_load_threshold", policy.threshold.to_string())?;
+        write(
+            "x_load_duration_ms",
+            policy.duration.as_millis().to_string(),
+        )?;
+        write("x_load_always_cookie", policy.always_cookie.to_string())?;
+        write("x_load_under_load", stats.under_load.to_string())?;
+        write("x_load_transitions", stats.transitions.to_string())?;
+        write("x_load_dropped", stats.dropped.to_string())?;
+        write("x_load_cookie_replies", stats.cookie_replies.to_string())?;
+        write(
+            "x_evict_idle_secs",
+            config
+                .get_idle_timeout()
+                .map(|timeout| timeout.as_secs())
+                .unwrap_or(0)
+                .to_string(),
+        )?;
+        if let Some(path) = config.get_capture() {
+            write("x_capture_file", path.display().to_string())?;
+        }
+        write("x_capture_all", config.get_capture_all().to_string())?;
+        if let Some(path) = config.get_keylog() {
+            write("x_keylog_file", path.display().to_string())?;
+        }
+        write(
+            "x_log_level",
+            config.get_log_level().to_string().to_lowercase(),
+        )?;
+        for record in config.get_handshake_failures() {
+            write("x_handshake_failure", record.to_string())?;
+        }
+    }
+
+    // serialize all peers
     while let Some(p) = peers.pop() {
         write("public_key", hex::encode(p.public_key.as_bytes()))?;
         write("preshared_key", hex::encode(p.preshared_key))?;
-        write("rx_bytes", p.rx_bytes.to_string())?;
-        write("tx_bytes", p.tx_bytes.to_string())?;
         write(
-            "persistent_keepalive_interval",
-            p.persistent_keepalive_interval.to_string(),
+            "protocol_version",
+            config.get_protocol_version().to_string(),
         )?;
 
-        if let Some((secs, nsecs)) = p.last_handshake_time {
-            write("last_handshake_time_sec", secs.to_string())?;
-            write("last_handshake_time_nsec", nsecs.to_string())?;
-        }
-
         if let Some(endpoint) = p.endpoint {
             write("endpoint", endpoint.to_string())?;
         }
 
+        let (secs, nsecs) = p.last_handshake_time.unwrap_or((0, 0));
+        write("last_handshake_time_sec", secs.to_string())?;
+        write("last_handshake_time_nsec", nsecs.to_string())?;
+        write("tx_bytes", p.tx_bytes.to_string())?;
+        write("rx_bytes", p.rx_bytes.to_string())?;
+        write(
+            "persistent_keepalive_interval",
+            p.persistent_keepalive_interval.to_string(),
+        )?;
+
         for (ip, cidr) in p.allowed_ips {
             write("allowed_ip", ip.to_string() + "/" + &cidr.to_string())?;
         }
+
+        if extensions {
+            for endpoint in p.endpoint_candidates {
+                write("x_endpoint_candidate", endpoint.to_string())?;
+            }
+            write(
+                "x_endpoint_locked",
+                match p.roaming {
+                    Roaming::Enabled => "false",
+                    Roaming::Locked => "true",
+                    Roaming::Strict => "strict",
+                }
+                .to_owned(),
+            )?;
+            write("x_roaming_attempts", p.roaming_attempts.to_string())?;
+            if let Some(epoch) = p.psk_epoch {
+                write("x_psk_epoch", epoch.to_string())?;
+            }
+            write("x_psk_age_secs", p.psk_age.as_secs().to_string())?;
+            write("x_evicted", p.evicted.to_string())?;
+            write("x_capture", p.capture.to_string())?;
+            for record in &p.handshakes {
+                write("x_handshake", record.to_string())?;
+            }
+            if let Some(mtu) = p.path_mtu {
+                write("x_path_mtu", mtu.to_string())?;
+            }
+            match p.relay_acl {
+                RelayAcl::Any => write("x_relay_allow", "*".to_owned())?,
+                RelayAcl::Peers(ref peers) if peers.is_empty() => {
+                    write("x_relay_allow", "none".to_owned())?
+                }
+                RelayAcl::Peers(ref peers) => {
+                    for pk in peers {
+                        write("x_relay_allow", hex::encode(pk.as_bytes()))?;
+                    }
+                }
+            }
+            for (rule, hits) in p.filter {
+                write("x_filter", rule.to_string())?;
+                write("x_filter_hits", hits.to_string())?;
+            }
+            let mut limited = false;
+            for (dir, limit) in [("tx", &p.tx_limit), ("rx", &p.rx_limit)].iter() {
+                if limit.bytes_per_second == 0 && limit.packets_per_second == 0 {
+                    continue;
+                }
+                limited = true;
+                let key = |name: &str| format!("x_{}_limit_{}", dir, name);
+                write(&key("bytes"), limit.bytes_per_second.to_string())?;
+                write(&key("packets"), limit.packets_per_second.to_string())?;
+                write(&key("available_bytes"), limit.available_bytes.to_string())?;
+                write(
+                    &key("available_packets"),
+                    limit.available_packets.to_string(),
+                )?;
+                write(&key("dropped"), limit.dropped.to_string())?;
+                write(&key("delayed"), limit.delayed.to_string())?;
+            }
+            if limited {
+                write(
+                    "x_limit_excess",
+                    match p.tx_limit.excess {
+                        Excess::Drop => "drop",
+                        Excess::Delay => "delay",
+                    }
+                    .to_owned(),
+                )?;
+            }
+            write("x_handshake_attempts", p.handshake_attempts.to_string())?;
+            if let Some(age) = p.current_keypair_age {
+                write("x_current_keypair_age_ms", age.as_millis().to_string())?;
+            }
+            write("x_tx_packets", p.tx_packets.to_string())?;
+            write("x_rx_packets", p.rx_packets.to_string())?;
+        }
     }
 
     Ok(())
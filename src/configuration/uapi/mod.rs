@@ -4,10 +4,13 @@ mod set;
 use log;
 use std::io::{Read, Write};
 
-use super::{ConfigError, Configuration};
+use super::{
+    ConfigError, Configuration, Excess, FilterRule, PeerLimits, RelayAcl, Roaming, SecretKey,
+};
+
+pub use set::LineParser;
 
 use get::serialize;
-use set::LineParser;
 
 const MAX_LINE_LENGTH: usize = 256;
 
@@ -39,7 +42,9 @@ pub fn handle<S: Read + Write, C: Configuration>(stream: &mut S, config: &C) {
             let mut split = ln.splitn(2, "=");
             match (split.next(), split.next()) {
                 (Some(key), Some(value)) => Ok((key, value)),
-                _ => Err(ConfigError::LineTooLong),
+                _ => Err(ConfigError::InvalidLine {
+                    line: ln.to_owned(),
+                }),
             }
         };
 
@@ -47,7 +52,24 @@ pub fn handle<S: Read + Write, C: Configuration>(stream: &mut S, config: &C) {
         match readline(stream)?.as_str() {
             "get=1" => {
                 log::debug!("UAPI, Get operation");
-                serialize(stream, config).map_err(|_| ConfigError::IOError)
+
+                // negotiate vendor extensions (until the empty line ending the request)
+                let mut extensions = false;
+                loop {
+                    let ln = readline(stream)?;
+                    if ln == "" {
+                        break;
+                    }
+                    match keypair(ln.as_str())? {
+                        ("x_extensions", "1") | ("x_extensions", "true") => extensions = true,
+                        (key, _) => {
+                            return Err(ConfigError::InvalidKey {
+                                key: key.to_owned(),
+                            })
+                        }
+                    }
+                }
+                serialize(stream, config, extensions).map_err(|_| ConfigError::IOError)
             }
             "set=1" => {
                 log::debug!("UAPI, Set operation");
@@ -62,7 +84,9 @@ pub fn handle<S: Read + Write, C: Configuration>(stream: &mut S, config: &C) {
                 }
                 parser.parse_line("", "")
             }
-            _ => Err(ConfigError::InvalidOperation),
+            op => Err(ConfigError::InvalidOperation {
+                operation: op.to_owned(),
+            }),
         }
     }
 
@@ -81,3 +105,398 @@ pub fn handle<S: Read + Write, C: Configuration>(stream: &mut S, config: &C) {
     );
     let _ = stream.write("\n\n".as_ref());
 }
+
+#[cfg(test)]
+mod tests {
+    use super::super::stream::{self, config, PEER_PK};
+    use super::super::FileKey;
+    use super::*;
+
+    use std::{env, fs, process};
+    use x25519_dalek::{PublicKey, StaticSecret};
+
+    fn request<C: Configuration>(config: &C, request: &str) -> String {
+        stream::request(request, |s| handle(s, config))
+    }
+
+    #[test]
+    fn test_uapi_get_extensions() {
+        let cfg = config();
+
+        let res = request(
+            &cfg,
+            &format!(
+                "set=1\npublic_key={}\nallowed_ip=10.0.0.2/32\nendpoint=192.0.2.1:51820\nx_endpoint_candidate=192.0.2.1:51820\nx_endpoint_candidate=[2001:db8::1]:51820\nx_endpoint_locked=strict\n\n",
+                PEER_PK
+            ),
+        );
+        assert_eq!(res, "errno=0\n\n");
+
+        // strictly the cross-platform specification
+        let res = request(&cfg, "get=1\n\n");
+        assert!(res.contains(&format!("protocol_version=1\npublic_key={}\n", PEER_PK)));
+        assert!(res.contains("\nprotocol_version=1\n"));
+        assert!(res.contains("\npersistent_keepalive_interval=0\n"));
+        assert!(!res.contains("\nx_"));
+        assert!(res.ends_with("errno=0\n\n"));
+
+        // with vendor extensions
+        let res = request(&cfg, "get=1\nx_extensions=1\n\n");
+        assert!(res.starts_with("protocol_version=1\nx_peer_count=1\n"));
+        assert!(res.contains("\nx_rx_packets=0\n"));
+        assert!(res.contains("\nx_endpoint_locked=strict\nx_roaming_attempts=0\n"));
+        assert!(res.contains(
+            "\nx_endpoint_candidate=192.0.2.1:51820\nx_endpoint_candidate=[2001:db8::1]:51820\n"
+        ));
+        assert!(res.ends_with("errno=0\n\n"));
+
+        // repeated endpoints replace the previous endpoint (and candidates)
+        let res = request(
+            &cfg,
+            &format!(
+                "set=1\npublic_key={}\nendpoint=192.0.2.2:51820\nendpoint=192.0.2.3:51820\n\n",
+                PEER_PK
+            ),
+        );
+        assert_eq!(res, "errno=0\n\n");
+        let res = request(&cfg, "get=1\nx_extensions=1\n\n");
+        assert!(res.contains("\nendpoint=192.0.2.3:51820\n"));
+        assert!(res.contains("\nx_endpoint_candidate=192.0.2.3:51820\nx_endpoint_locked="));
+        assert!(!res.contains("192.0.2.2"));
+
+        // unknown peers are reported with update_only
+        let res = request(
+            &cfg,
+            "set=1\npublic_key=0000000000000000000000000000000000000000000000000000000000000001\nupdate_only=true\nallowed_ip=10.0.0.3/32\n\n",
+        );
+        assert_eq!(res, format!("errno={}\n\n", libc::ENOENT));
+    }
+    #[test]
+    fn test_uapi_listen_address() {
+        let cfg = config();
+
+        let res = request(
+            &cfg,
+            "set=1\nx_listen_address=2001:db8::1\nx_bind_interface=eth0\n\n",
+        );
+        assert_eq!(res, "errno=0\n\n");
+        let res = request(&cfg, "get=1\nx_extensions=1\n\n");
+        assert!(res.contains("\nx_listen_address=2001:db8::1\nx_bind_interface=eth0\n"));
+
+        // invalid values are rejected, empty values restore the defaults
+        let res = request(&cfg, "set=1\nx_listen_address=192.0.2\n\n");
+        assert_eq!(res, format!("errno={}\n\n", libc::EINVAL));
+        let res = request(&cfg, "set=1\nx_bind_interface=a-very-long-name\n\n");
+        assert_eq!(res, format!("errno={}\n\n", libc::EINVAL));
+        let res = request(&cfg, "set=1\nx_listen_address=\nx_bind_interface=\n\n");
+        assert_eq!(res, "errno=0\n\n");
+        let res = request(&cfg, "get=1\nx_extensions=1\n\n");
+        assert!(!res.contains("x_listen_address"));
+        assert!(!res.contains("x_bind_interface"));
+
+        // the IP versions bound
+        assert!(res.contains("\nx_bind_family=dual\n"));
+        let res = request(&cfg, "set=1\nx_bind_family=ipv4\n\n");
+        assert_eq!(res, "errno=0\n\n");
+        let res = request(&cfg, "get=1\nx_extensions=1\n\n");
+        assert!(res.contains("\nx_bind_family=ipv4\n"));
+        let res = request(&cfg, "set=1\nx_bind_family=ipv5\n\n");
+        assert_eq!(res, format!("errno={}\n\n", libc::EINVAL));
+    }
+
+    #[test]
+    fn test_uapi_socket_options() {
+        let cfg = config();
+
+        // ECN is propagated by default, the DSCP bits are not copied
+        let res = request(&cfg, "get=1\nx_extensions=1\n\n");
+        assert!(res.contains("\nx_copy_dscp=false\nx_ecn=true\n"));
+        assert!(!res.contains("x_socket_rcvbuf"));
+        assert!(!res.contains("x_outer_ttl"));
+
+        let res = request(
+            &cfg,
+            "set=1\nx_socket_rcvbuf=4194304\nx_socket_sndbuf=1048576\nx_outer_ttl=32\nx_copy_dscp=true\nx_ecn=false\n\n",
+        );
+        assert_eq!(res, "errno=0\n\n");
+        assert_eq!(cfg.get_socket_buffers(), (Some(4194304), Some(1048576)));
+        let res = request(&cfg, "get=1\nx_extensions=1\n\n");
+        assert!(res.contains(
+            "\nx_socket_rcvbuf=4194304\nx_socket_sndbuf=1048576\nx_outer_ttl=32\nx_copy_dscp=true\nx_ecn=false\n"
+        ));
+
+        // invalid values are rejected, zero restores the defaults
+        let res = request(&cfg, "set=1\nx_outer_ttl=256\n\n");
+        assert_eq!(res, format!("errno={}\n\n", libc::EINVAL));
+        let res = request(&cfg, "set=1\nx_socket_rcvbuf=4294967296\n\n");
+        assert_eq!(res, format!("errno={}\n\n", libc::EINVAL));
+        let res = request(&cfg, "set=1\nx_ecn=yes\n\n");
+        assert_eq!(res, format!("errno={}\n\n", libc::EINVAL));
+        let res = request(&cfg, "set=1\nx_socket_rcvbuf=0\nx_outer_ttl=0\n\n");
+        assert_eq!(res, "errno=0\n\n");
+        assert_eq!(cfg.get_socket_buffers(), (None, Some(1048576)));
+        assert_eq!(cfg.get_outer_ttl(), None);
+    }
+
+    #[test]
+    fn test_uapi_relay() {
+        let cfg = config();
+
+        let pk2 = "b8e3e0f1e8d42c3d1a4ea1d5c6a5c5c0f6a8c0c7a4b1d8e2f3a4b5c6d7e8f9a0";
+        let res = request(
+            &cfg,
+            &format!(
+                "set=1\nx_relay=true\npublic_key={}\nx_relay_allow={}\npublic_key={}\nx_relay_allow=*\n\n",
+                PEER_PK, pk2, pk2
+            ),
+        );
+        assert_eq!(res, "errno=0\n\n");
+        assert!(cfg.get_relay());
+
+        let res = request(&cfg, "get=1\nx_extensions=1\n\n");
+        assert!(res.contains("\nx_relay=true\n"));
+        assert!(res.contains(&format!("\nx_relay_allow={}\n", pk2)));
+        assert!(res.contains("\nx_relay_allow=*\n"));
+
+        // the relay ACL is replaced and can be cleared
+        let res = request(
+            &cfg,
+            &format!(
+                "set=1\nx_relay=false\npublic_key={}\nx_relay_allow=none\n\n",
+                PEER_PK
+            ),
+        );
+        assert_eq!(res, "errno=0\n\n");
+        assert!(!cfg.get_relay());
+
+        let res = request(&cfg, "get=1\nx_extensions=1\n\n");
+        assert!(res.contains("\nx_relay_allow=none\n"));
+        assert!(!res.contains(&format!("\nx_relay_allow={}\n", pk2)));
+    }
+
+    #[test]
+    fn test_uapi_filter() {
+        let cfg = config();
+
+        let res = request(
+            &cfg,
+            &format!(
+                "set=1\npublic_key={}\nx_filter=accept out proto=tcp port=22\nx_filter=drop both\n\n",
+                PEER_PK
+            ),
+        );
+        assert_eq!(res, "errno=0\n\n");
+
+        let res = request(&cfg, "get=1\nx_extensions=1\n\n");
+        assert!(res.contains(
+            "\nx_filter=accept out proto=tcp port=22\nx_filter_hits=0\nx_filter=drop both\nx_filter_hits=0\n"
+        ));
+
+        // invalid rules are rejected
+        let res = request(
+            &cfg,
+            &format!("set=1\npublic_key={}\nx_filter=drop sideways\n\n", PEER_PK),
+        );
+        assert_eq!(res, format!("errno={}\n\n", libc::EINVAL));
+
+        // the rules can be cleared
+        let res = request(
+            &cfg,
+            &format!("set=1\npublic_key={}\nx_filter=none\n\n", PEER_PK),
+        );
+        assert_eq!(res, "errno=0\n\n");
+        let res = request(&cfg, "get=1\nx_extensions=1\n\n");
+        assert!(!res.contains("\nx_filter="));
+    }
+
+    #[test]
+    fn test_uapi_limits() {
+        let cfg = config();
+
+        let res = request(
+            &cfg,
+            &format!(
+                "set=1\npublic_key={}\nx_tx_limit_bytes=125000\nx_limit_excess=delay\n\n",
+                PEER_PK
+            ),
+        );
+        assert_eq!(res, "errno=0\n\n");
+
+        // limits not mentioned are retained
+        let res = request(
+            &cfg,
+            &format!("set=1\npublic_key={}\nx_rx_limit_packets=100\n\n", PEER_PK),
+        );
+        assert_eq!(res, "errno=0\n\n");
+
+        let res = request(&cfg, "get=1\nx_extensions=1\n\n");
+        assert!(res.contains(
+            "\nx_tx_limit_bytes=125000\nx_tx_limit_packets=0\nx_tx_limit_available_bytes=125000\n"
+        ));
+        assert!(res.contains("\nx_rx_limit_bytes=0\nx_rx_limit_packets=100\n"));
+        assert!(
+            res.contains("\nx_rx_limit_dropped=0\nx_rx_limit_delayed=0\nx_limit_excess=delay\n")
+        );
+
+        let res = request(
+            &cfg,
+            &format!("set=1\npublic_key={}\nx_limit_excess=queue\n\n", PEER_PK),
+        );
+        assert_eq!(res, format!("errno={}\n\n", libc::EINVAL));
+    }
+
+    #[test]
+    fn test_uapi_ratelimiter() {
+        let cfg = config();
+
+        // the defaults match the reference implementations
+        let res = request(&cfg, "get=1\nx_extensions=1\n\n");
+        assert!(res.contains("\nx_ratelimit_packets_per_second=20\nx_ratelimit_burst=5\n"));
+        assert!(res.contains("\nx_ratelimit_ipv4_prefix=32\nx_ratelimit_ipv6_prefix=128\n"));
+
+        let res = request(
+            &cfg,
+            "set=1\nx_ratelimit_ipv6_prefix=64\nx_ratelimit_max_entries=4096\n\n",
+        );
+        assert_eq!(res, "errno=0\n\n");
+
+        let res = request(&cfg, "get=1\nx_extensions=1\n\n");
+        assert!(res.contains("\nx_ratelimit_ipv6_prefix=64\nx_ratelimit_max_entries=4096\n"));
+        assert!(res.contains("\nx_ratelimit_entries=0\n"));
+
+        for line in &[
+            "x_ratelimit_ipv4_prefix=33",
+            "x_ratelimit_packets_per_second=0",
+            "x_ratelimit_burst=many",
+        ] {
+            let res = request(&cfg, &format!("set=1\n{}\n\n", line));
+            assert_eq!(res, format!("errno={}\n\n", libc::EINVAL));
+        }
+    }
+
+    #[test]
+    fn test_uapi_load() {
+        let cfg = config();
+
+        let res = request(&cfg, "get=1\nx_extensions=1\n\n");
+        assert!(res.contains("\nx_load_max_queued=4096\nx_load_threshold=512\n"));
+        assert!(res.contains("\nx_load_always_cookie=false\nx_load_under_load=false\n"));
+
+        let res = request(
+            &cfg,
+            "set=1\nx_load_threshold=64\nx_load_always_cookie=true\n\n",
+        );
+        assert_eq!(res, "errno=0\n\n");
+
+        let res = request(&cfg, "get=1\nx_extensions=1\n\n");
+        assert!(res.contains("\nx_load_threshold=64\n"));
+        assert!(res.contains("\nx_load_always_cookie=true\nx_load_under_load=true\n"));
+        assert!(res.contains("\nx_load_transitions=1\n"));
+
+        // the threshold cannot exceed the queue
+        let res = request(&cfg, "set=1\nx_load_threshold=5000\n\n");
+        assert_eq!(res, format!("errno={}\n\n", libc::EINVAL));
+    }
+
+    #[test]
+    fn test_uapi_evict_idle() {
+        let cfg = config();
+
+        let res = request(&cfg, "get=1\nx_extensions=1\n\n");
+        assert!(res.contains("\nx_evict_idle_secs=0\n"));
+
+        let res = request(&cfg, "set=1\nx_evict_idle_secs=300\n\n");
+        assert_eq!(res, "errno=0\n\n");
+        let res = request(&cfg, "get=1\nx_extensions=1\n\n");
+        assert!(res.contains("\nx_evict_idle_secs=300\n"));
+
+        let res = request(&cfg, "set=1\nx_evict_idle_secs=0\n\n");
+        assert_eq!(res, "errno=0\n\n");
+        assert_eq!(cfg.get_idle_timeout(), None);
+
+        let res = request(&cfg, "set=1\nx_evict_idle_secs=soon\n\n");
+        assert_eq!(res, format!("errno={}\n\n", libc::EINVAL));
+    }
+
+    #[test]
+    fn test_uapi_private_key_export() {
+        let cfg = config();
+
+        let sk = hex::encode([0x11u8; 32]);
+        let res = request(&cfg, &format!("set=1\nprivate_key={}\n\n", sk));
+        assert_eq!(res, "errno=0\n\n");
+        let res = request(&cfg, "get=1\nx_extensions=1\n\n");
+        assert!(res.starts_with("private_key="));
+        assert!(res.contains("\nx_private_key_export=true\n"));
+
+        // the private key is retained, but no longer reported
+        let res = request(&cfg, "set=1\nx_private_key_export=false\n\n");
+        assert_eq!(res, "errno=0\n\n");
+        let res = request(&cfg, "get=1\nx_extensions=1\n\n");
+        assert!(!res.contains("private_key="));
+        assert!(res.contains("\nx_private_key_export=false\n"));
+        assert!(res.contains(&format!(
+            "\nx_public_key={}\n",
+            hex::encode(PublicKey::from(&StaticSecret::from([0x11u8; 32])).as_bytes())
+        )));
+
+        // the export cannot be re-enabled
+        let res = request(&cfg, "set=1\nx_private_key_export=true\n\n");
+        assert_eq!(res, format!("errno={}\n\n", libc::EINVAL));
+    }
+
+    #[test]
+    fn test_uapi_key_rotation() {
+        let cfg = config();
+
+        let sk1 = [0x11u8; 32];
+        let pk1 = PublicKey::from(&StaticSecret::from(sk1));
+
+        // replacing the key without a grace window retains no previous key
+        let res = request(
+            &cfg,
+            &format!("set=1\nprivate_key={}\n\n", hex::encode(sk1)),
+        );
+        assert_eq!(res, "errno=0\n\n");
+        let res = request(
+            &cfg,
+            &format!("set=1\nprivate_key={}\n\n", hex::encode([0x22u8; 32])),
+        );
+        assert_eq!(res, "errno=0\n\n");
+        let res = request(&cfg, "get=1\nx_extensions=1\n\n");
+        assert!(res.contains("\nx_key_rotation_grace_secs=0\n"));
+        assert!(!res.contains("x_previous_public_key"));
+
+        // graceful rotation
+        let res = request(
+            &cfg,
+            &format!("set=1\nprivate_key={}\n\n", hex::encode(sk1)),
+        );
+        assert_eq!(res, "errno=0\n\n");
+        let res = request(
+            &cfg,
+            &format!(
+                "set=1\nx_key_rotation_grace_secs=60\nprivate_key={}\n\n",
+                hex::encode([0x33u8; 32])
+            ),
+        );
+        assert_eq!(res, "errno=0\n\n");
+        let res = request(&cfg, "get=1\nx_extensions=1\n\n");
+        assert!(res.contains("\nx_key_rotation_grace_secs=60\n"));
+        assert!(res.contains(&format!(
+            "\nx_previous_public_key={}\n",
+            hex::encode(pk1.as_bytes())
+        )));
+
+        // replacing the key by a key backend also rotates gracefully
+        let path = env::temp_dir().join(format!("wg-test-key-{}", process::id()));
+        fs::write(&path, hex::encode([0x44u8; 32])).unwrap();
+        cfg.set_static_key(Some(Box::new(FileKey::open(&path).unwrap())));
+        fs::remove_file(&path).unwrap();
+        let pk3 = PublicKey::from(&StaticSecret::from([0x33u8; 32]));
+        assert_eq!(
+            cfg.get_previous_public_key().map(|pk| *pk.as_bytes()),
+            Some(*pk3.as_bytes())
+        );
+    }
+}
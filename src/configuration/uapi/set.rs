@@ -1,12 +1,19 @@
 use hex::FromHex;
 use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::time::Duration;
 use subtle::ConstantTimeEq;
 use x25519_dalek::{PublicKey, StaticSecret};
 
-use super::{ConfigError, Configuration};
+use super::{
+    ConfigError, Configuration, Excess, FilterRule, PeerLimits, RelayAcl, Roaming, SecretKey,
+};
+
+// the largest socket buffer (the kernel doubles the requested size, stored as an int)
+const MAX_SOCKET_BUFFER: usize = i32::MAX as usize / 2;
 
 enum ParserState {
-    Peer(ParsedPeer),
+    Peer(Box<ParsedPeer>),
     Interface,
 }
 
@@ -17,14 +24,39 @@ struct ParsedPeer {
     remove: bool,
     preshared_key: Option<[u8; 32]>,
     replace_allowed_ips: bool,
-    persistent_keepalive_interval: Option<u64>,
+    persistent_keepalive_interval: Option<u16>,
     protocol_version: Option<usize>,
     endpoint: Option<SocketAddr>,
+    endpoint_candidates: Vec<SocketAddr>,
+    roaming: Option<Roaming>,
+    relay_acl: Option<RelayAcl>,
+    filter: Option<Vec<FilterRule>>,
+    limits: Option<PeerLimits>,
+    capture: Option<bool>,
 }
 
 pub struct LineParser<'a, C: Configuration> {
     config: &'a C,
     state: ParserState,
+    base64: bool,
+}
+
+// keys are hex, or base64 outside the UAPI (e.g. configuration files)
+fn parse_key(key: &str, value: &str, base64: bool) -> Result<[u8; 32], ConfigError> {
+    if base64 {
+        match value.parse::<SecretKey>() {
+            Ok(sk) => Ok(*sk.as_bytes()),
+            Err(_) => Err(ConfigError::InvalidBase64Value {
+                key: key.to_owned(),
+                value: value.to_owned(),
+            }),
+        }
+    } else {
+        <[u8; 32]>::from_hex(value).map_err(|_| ConfigError::InvalidHexValue {
+            key: key.to_owned(),
+            value: value.to_owned(),
+        })
+    }
 }
 
 impl<'a, C: Configuration> LineParser<'a, C> {
@@ -32,27 +64,40 @@ impl<'a, C: Configuration> LineParser<'a, C> {
         LineParser {
             config,
             state: ParserState::Interface,
+            base64: false,
         }
     }
 
-    fn new_peer(value: &str) -> Result<ParserState, ConfigError> {
-        match <[u8; 32]>::from_hex(value) {
-            Ok(pk) => Ok(ParserState::Peer(ParsedPeer {
-                public_key: PublicKey::from(pk),
-                remove: false,
-                update_only: false,
-                allowed_ips: vec![],
-                preshared_key: None,
-                replace_allowed_ips: false,
-                persistent_keepalive_interval: None,
-                protocol_version: None,
-                endpoint: None,
-            })),
-            Err(_) => Err(ConfigError::InvalidHexValue),
-        }
+    /// Additionally accept keys encoded as base64
+    /// (the UAPI specification requires hex)
+    pub fn accept_base64(mut self) -> LineParser<'a, C> {
+        self.base64 = true;
+        self
+    }
+
+    fn new_peer(&self, value: &str) -> Result<ParserState, ConfigError> {
+        let pk = parse_key("public_key", value, self.base64)?;
+        Ok(ParserState::Peer(Box::new(ParsedPeer {
+            public_key: PublicKey::from(pk),
+            remove: false,
+            update_only: false,
+            allowed_ips: vec![],
+            preshared_key: None,
+            replace_allowed_ips: false,
+            persistent_keepalive_interval: None,
+            protocol_version: None,
+            endpoint: None,
+            endpoint_candidates: vec![],
+            roaming: None,
+            relay_acl: None,
+            filter: None,
+            limits: None,
+            capture: None,
+        })))
     }
 
     pub fn parse_line(&mut self, key: &str, value: &str) -> Result<(), ConfigError> {
+        let base64 = self.base64;
         #[cfg(debug)]
         {
             if key.len() > 0 {
@@ -60,12 +105,23 @@ impl<'a, C: Configuration> LineParser<'a, C> {
             }
         }
 
+        // boolean flags only accept "true" (as per the cross-platform specification)
+        fn flag(key: &str, value: &str) -> Result<bool, ConfigError> {
+            match value {
+                "true" => Ok(true),
+                _ => Err(ConfigError::UnsupportedValue {
+                    key: key.to_owned(),
+                    value: value.to_owned(),
+                }),
+            }
+        }
+
         // flush peer updates to configuration
-        fn flush_peer<C: Configuration>(config: &C, peer: &ParsedPeer) -> Option<ConfigError> {
+        fn flush_peer<C: Configuration>(config: &C, peer: &ParsedPeer) -> Result<(), ConfigError> {
             if peer.remove {
                 log::trace!("flush peer, remove peer");
                 config.remove_peer(&peer.public_key);
-                return None;
+                return Ok(());
             }
 
             if !peer.update_only {
@@ -73,34 +129,78 @@ impl<'a, C: Configuration> LineParser<'a, C> {
                 config.add_peer(&peer.public_key);
             }
 
+            if peer.replace_allowed_ips {
+                log::trace!("flush peer, replace allowed_ips");
+                config.replace_allowed_ips(&peer.public_key)?;
+            }
+
             for (ip, cidr) in &peer.allowed_ips {
                 log::trace!("flush peer, add allowed_ips : {}/{}", ip.to_string(), cidr);
-                config.add_allowed_ip(&peer.public_key, *ip, *cidr);
+                config.add_allowed_ip(&peer.public_key, *ip, *cidr)?;
             }
 
             if let Some(psk) = peer.preshared_key {
-                log::trace!("flush peer, set preshared_key {}", hex::encode(psk));
-                config.set_preshared_key(&peer.public_key, psk);
+                log::trace!("flush peer, set preshared_key {}", SecretKey::new(psk));
+                config.set_preshared_key(&peer.public_key, psk)?;
             }
 
             if let Some(secs) = peer.persistent_keepalive_interval {
                 log::trace!("flush peer, set persistent_keepalive_interval {}", secs);
-                config.set_persistent_keepalive_interval(&peer.public_key, secs);
+                config.set_persistent_keepalive_interval(&peer.public_key, secs.into())?;
             }
 
             if let Some(version) = peer.protocol_version {
                 log::trace!("flush peer, set protocol_version {}", version);
                 if version == 0 || version > config.get_protocol_version() {
-                    return Some(ConfigError::UnsupportedProtocolVersion);
+                    return Err(ConfigError::UnsupportedProtocolVersion {
+                        peer: peer.public_key,
+                        version: version.to_string(),
+                    });
                 }
             }
 
-            if let Some(endpoint) = peer.endpoint {
-                log::trace!("flush peer, set endpoint {}", endpoint.to_string());
-                config.set_endpoint(&peer.public_key, endpoint);
+            if let Some(roaming) = peer.roaming {
+                log::trace!("flush peer, set roaming {:?}", roaming);
+                config.set_roaming(&peer.public_key, roaming)?;
+            }
+
+            if let Some(acl) = peer.relay_acl.as_ref() {
+                log::trace!("flush peer, set relay acl {:?}", acl);
+                config.set_relay_acl(&peer.public_key, acl.clone())?;
+            }
+
+            if let Some(rules) = peer.filter.as_ref() {
+                log::trace!("flush peer, set filter ({} rules)", rules.len());
+                config.set_filter(&peer.public_key, rules.clone())?;
+            }
+
+            if let Some(limits) = peer.limits.as_ref() {
+                log::trace!("flush peer, set limits {:?}", limits);
+                config.set_limits(&peer.public_key, limits)?;
+            }
+
+            if let Some(capture) = peer.capture {
+                log::trace!("flush peer, set capture {}", capture);
+                config.set_peer_capture(&peer.public_key, capture)?;
+            }
+
+            // the endpoint is the first candidate, followed by the fail-over candidates
+            let endpoints: Vec<SocketAddr> = peer
+                .endpoint
+                .iter()
+                .chain(
+                    peer.endpoint_candidates
+                        .iter()
+                        .filter(|addr| Some(**addr) != peer.endpoint),
+                )
+                .cloned()
+                .collect();
+            if !endpoints.is_empty() {
+                log::trace!("flush peer, set endpoints {:?}", endpoints);
+                config.set_endpoints(&peer.public_key, &endpoints[..])?;
             };
 
-            None
+            Ok(())
         };
 
         // parse line and update parser state
@@ -108,7 +208,7 @@ impl<'a, C: Configuration> LineParser<'a, C> {
             // configure the interface
             ParserState::Interface => match key {
                 // opt: set private key
-                "private_key" => match <[u8; 32]>::from_hex(value) {
+                "private_key" => match parse_key(key, value, base64) {
                     Ok(sk) => {
                         self.config.set_private_key(if sk.ct_eq(&[0u8; 32]).into() {
                             None
@@ -117,7 +217,33 @@ impl<'a, C: Configuration> LineParser<'a, C> {
                         });
                         Ok(())
                     }
-                    Err(_) => Err(ConfigError::InvalidHexValue),
+                    Err(e) => Err(e),
+                },
+
+                // opt: never export the private key (vendor extension), cannot be re-enabled
+                "x_private_key_export" => match value {
+                    "false" => {
+                        self.config.disable_private_key_export();
+                        Ok(())
+                    }
+                    "true" if self.config.get_private_key_export() => Ok(()),
+                    _ => Err(ConfigError::UnsupportedValue {
+                        key: key.to_owned(),
+                        value: value.to_owned(),
+                    }),
+                },
+
+                // opt: rotate the private key gracefully (vendor extension), 0 disables
+                "x_key_rotation_grace_secs" => match value.parse() {
+                    Ok(secs) => {
+                        self.config
+                            .set_key_rotation_grace(Duration::from_secs(secs));
+                        Ok(())
+                    }
+                    Err(_) => Err(ConfigError::UnsupportedValue {
+                        key: key.to_owned(),
+                        value: value.to_owned(),
+                    }),
                 },
 
                 // opt: set listen port
@@ -126,7 +252,70 @@ impl<'a, C: Configuration> LineParser<'a, C> {
                         self.config.set_listen_port(port)?;
                         Ok(())
                     }
-                    Err(_) => Err(ConfigError::InvalidPortNumber),
+                    Err(_) => Err(ConfigError::InvalidPortNumber {
+                        value: value.to_owned(),
+                    }),
+                },
+
+                // opt: bind only a local address (vendor extension), empty for any address
+                "x_listen_address" => match value {
+                    "" => self.config.set_listen_address(None),
+                    _ => match value.parse() {
+                        Ok(addr) => self.config.set_listen_address(Some(addr)),
+                        Err(_) => Err(ConfigError::InvalidListenAddress {
+                            value: value.to_owned(),
+                        }),
+                    },
+                },
+
+                // opt: bind both or only one IP version (vendor extension)
+                "x_bind_family" => match value.parse() {
+                    Ok(family) => self.config.set_bind_family(family),
+                    Err(()) => Err(ConfigError::UnsupportedValue {
+                        key: key.to_owned(),
+                        value: value.to_owned(),
+                    }),
+                },
+
+                // opt: bind only an interface (vendor extension), empty for any interface
+                "x_bind_interface" => match value {
+                    "" => self.config.set_bind_interface(None),
+                    _ if value.len() < 16
+                        && !value.contains(|c: char| c == '/' || c.is_whitespace()) =>
+                    {
+                        self.config.set_bind_interface(Some(value.to_owned()))
+                    }
+                    _ => Err(ConfigError::InvalidInterfaceName {
+                        value: value.to_owned(),
+                    }),
+                },
+
+                // opt: size the socket buffers in bytes (vendor extension), 0 for the system default
+                "x_socket_rcvbuf" | "x_socket_sndbuf" => match value.parse::<usize>() {
+                    Ok(size) if size <= MAX_SOCKET_BUFFER => {
+                        let size = if size == 0 { None } else { Some(size) };
+                        let (rcvbuf, sndbuf) = self.config.get_socket_buffers();
+                        if key == "x_socket_rcvbuf" {
+                            self.config.set_socket_buffers(size, sndbuf)
+                        } else {
+                            self.config.set_socket_buffers(rcvbuf, size)
+                        }
+                    }
+                    _ => Err(ConfigError::UnsupportedValue {
+                        key: key.to_owned(),
+                        value: value.to_owned(),
+                    }),
+                },
+
+                // opt: set the TTL / hop limit of the outer packets (vendor extension), 0 for the system default
+                "x_outer_ttl" => match value.parse::<u8>() {
+                    Ok(ttl) => self
+                        .config
+                        .set_outer_ttl(if ttl == 0 { None } else { Some(ttl) }),
+                    Err(_) => Err(ConfigError::UnsupportedValue {
+                        key: key.to_owned(),
+                        value: value.to_owned(),
+                    }),
                 },
 
                 // opt: set fwmark
@@ -136,23 +325,187 @@ impl<'a, C: Configuration> LineParser<'a, C> {
                             .set_fwmark(if fwmark == 0 { None } else { Some(fwmark) })?;
                         Ok(())
                     }
-                    Err(_) => Err(ConfigError::InvalidFwmark),
+                    Err(_) => Err(ConfigError::InvalidFwmark {
+                        value: value.to_owned(),
+                    }),
+                },
+
+                // opt: relay between peers (vendor extension)
+                "x_relay" => match value {
+                    "true" => {
+                        self.config.set_relay(true);
+                        Ok(())
+                    }
+                    "false" => {
+                        self.config.set_relay(false);
+                        Ok(())
+                    }
+                    _ => Err(ConfigError::UnsupportedValue {
+                        key: key.to_owned(),
+                        value: value.to_owned(),
+                    }),
+                },
+
+                // opt: copy the DSCP bits of the inner packets (vendor extension)
+                "x_copy_dscp" => match value.parse() {
+                    Ok(enabled) => {
+                        self.config.set_copy_dscp(enabled);
+                        Ok(())
+                    }
+                    Err(_) => Err(ConfigError::UnsupportedValue {
+                        key: key.to_owned(),
+                        value: value.to_owned(),
+                    }),
+                },
+
+                // opt: propagate the ECN bits as by RFC 6040 (vendor extension)
+                "x_ecn" => match value.parse() {
+                    Ok(enabled) => {
+                        self.config.set_ecn(enabled);
+                        Ok(())
+                    }
+                    Err(_) => Err(ConfigError::UnsupportedValue {
+                        key: key.to_owned(),
+                        value: value.to_owned(),
+                    }),
+                },
+
+                // opt: tune the handshake rate limiter (vendor extension)
+                "x_ratelimit_packets_per_second"
+                | "x_ratelimit_burst"
+                | "x_ratelimit_gc_interval_ms"
+                | "x_ratelimit_ipv4_prefix"
+                | "x_ratelimit_ipv6_prefix"
+                | "x_ratelimit_max_entries" => {
+                    let invalid = || ConfigError::UnsupportedValue {
+                        key: key.to_owned(),
+                        value: value.to_owned(),
+                    };
+                    let number: u64 = value.parse().map_err(|_| invalid())?;
+                    let mut limiter = self.config.get_ratelimiter();
+                    match key {
+                        "x_ratelimit_packets_per_second" => limiter.packets_per_second = number,
+                        "x_ratelimit_burst" => limiter.packets_burstable = number,
+                        "x_ratelimit_gc_interval_ms" => {
+                            limiter.gc_interval = Duration::from_millis(number)
+                        }
+                        "x_ratelimit_ipv4_prefix" => limiter.ipv4_prefix = number.min(255) as u8,
+                        "x_ratelimit_ipv6_prefix" => limiter.ipv6_prefix = number.min(255) as u8,
+                        _ => limiter.max_entries = number as usize,
+                    }
+                    if !limiter.is_valid() {
+                        return Err(invalid());
+                    }
+                    self.config.set_ratelimiter(limiter);
+                    Ok(())
+                }
+
+                // opt: tune the under load detection (vendor extension)
+                "x_load_max_queued"
+                | "x_load_threshold"
+                | "x_load_duration_ms"
+                | "x_load_always_cookie" => {
+                    let invalid = || ConfigError::UnsupportedValue {
+                        key: key.to_owned(),
+                        value: value.to_owned(),
+                    };
+                    let mut policy = self.config.get_load_policy();
+                    match key {
+                        "x_load_always_cookie" => {
+                            policy.always_cookie = match value {
+                                "true" => true,
+                                "false" => false,
+                                _ => return Err(invalid()),
+                            }
+                        }
+                        _ => {
+                            let number: u64 = value.parse().map_err(|_| invalid())?;
+                            match key {
+                                "x_load_max_queued" => policy.max_queued = number as usize,
+                                "x_load_threshold" => policy.threshold = number as usize,
+                                _ => policy.duration = Duration::from_millis(number),
+                            }
+                        }
+                    }
+                    if !policy.is_valid() {
+                        return Err(invalid());
+                    }
+                    self.config.set_load_policy(policy);
+                    Ok(())
+                }
+
+                // opt: evict the state of idle peers (vendor extension), 0 disables
+                "x_evict_idle_secs" => match value.parse::<u64>() {
+                    Ok(0) => {
+                        self.config.set_idle_timeout(None);
+                        Ok(())
+                    }
+                    Ok(secs) => {
+                        self.config
+                            .set_idle_timeout(Some(Duration::from_secs(secs)));
+                        Ok(())
+                    }
+                    Err(_) => Err(ConfigError::UnsupportedValue {
+                        key: key.to_owned(),
+                        value: value.to_owned(),
+                    }),
+                },
+
+                // opt: capture packets in a pcapng file (vendor extension), empty stops
+                "x_capture_file" => match value {
+                    "" => self.config.set_capture(None),
+                    path => self.config.set_capture(Some(Path::new(path))),
+                },
+
+                // opt: capture the packets of every peer (vendor extension)
+                "x_capture_all" => match value {
+                    "true" => {
+                        self.config.set_capture_all(true);
+                        Ok(())
+                    }
+                    "false" => {
+                        self.config.set_capture_all(false);
+                        Ok(())
+                    }
+                    _ => Err(ConfigError::UnsupportedValue {
+                        key: key.to_owned(),
+                        value: value.to_owned(),
+                    }),
+                },
+
+                // opt: log the secrets of handshakes (vendor extension), empty stops
+                "x_keylog_file" => match value {
+                    "" => self.config.set_keylog(None),
+                    path => self.config.set_keylog(Some(Path::new(path))),
+                },
+
+                // opt: verbosity of the log (vendor extension)
+                "x_log_level" => match value.parse() {
+                    Ok(level) => {
+                        self.config.set_log_level(level);
+                        Ok(())
+                    }
+                    Err(_) => Err(ConfigError::UnsupportedValue {
+                        key: key.to_owned(),
+                        value: value.to_owned(),
+                    }),
                 },
 
                 // opt: remove all peers
                 "replace_peers" => match value {
                     "true" => {
-                        for p in self.config.get_peers() {
-                            self.config.remove_peer(&p.public_key)
-                        }
+                        self.config.replace_peers();
                         Ok(())
                     }
-                    _ => Err(ConfigError::UnsupportedValue),
+                    _ => Err(ConfigError::UnsupportedValue {
+                        key: key.to_owned(),
+                        value: value.to_owned(),
+                    }),
                 },
 
                 // opt: transition to peer configuration
                 "public_key" => {
-                    self.state = Self::new_peer(value)?;
+                    self.state = self.new_peer(value)?;
                     Ok(())
                 }
 
@@ -160,60 +513,191 @@ impl<'a, C: Configuration> LineParser<'a, C> {
                 "" => Ok(()),
 
                 // unknown key
-                _ => Err(ConfigError::InvalidKey),
+                _ => Err(ConfigError::InvalidKey {
+                    key: key.to_owned(),
+                }),
             },
 
             // configure peers
             ParserState::Peer(ref mut peer) => match key {
                 // opt: new peer
                 "public_key" => {
-                    flush_peer(self.config, &peer);
-                    self.state = Self::new_peer(value)?;
+                    flush_peer(self.config, &peer)?;
+                    self.state = self.new_peer(value)?;
                     Ok(())
                 }
 
                 // opt: remove peer
                 "remove" => {
-                    peer.remove = true;
+                    peer.remove = flag(key, value)?;
                     Ok(())
                 }
 
                 // opt: update only
                 "update_only" => {
-                    peer.update_only = true;
+                    peer.update_only = flag(key, value)?;
                     Ok(())
                 }
 
                 // opt: set preshared key
-                "preshared_key" => match <[u8; 32]>::from_hex(value) {
+                "preshared_key" => match parse_key(key, value, base64) {
                     Ok(psk) => {
                         peer.preshared_key = Some(psk);
                         Ok(())
                     }
-                    Err(_) => Err(ConfigError::InvalidHexValue),
+                    Err(e) => Err(e),
                 },
 
-                // opt: set endpoint
+                // opt: set endpoint (replaces the endpoint set by a previous line)
                 "endpoint" => match value.parse() {
                     Ok(endpoint) => {
                         peer.endpoint = Some(endpoint);
                         Ok(())
                     }
-                    Err(_) => Err(ConfigError::InvalidSocketAddr),
+                    Err(_) => Err(ConfigError::InvalidSocketAddr {
+                        peer: peer.public_key,
+                        value: value.to_owned(),
+                    }),
+                },
+
+                // opt: add a candidate endpoint for fail-over (vendor extension),
+                // repeated to add further candidates, replaces the previous candidates
+                "x_endpoint_candidate" => match value.parse() {
+                    Ok(endpoint) => {
+                        peer.endpoint_candidates.push(endpoint);
+                        Ok(())
+                    }
+                    Err(_) => Err(ConfigError::InvalidSocketAddr {
+                        peer: peer.public_key,
+                        value: value.to_owned(),
+                    }),
+                },
+
+                // opt: lock the endpoint (vendor extension)
+                "x_endpoint_locked" => {
+                    peer.roaming = Some(match value {
+                        "false" => Roaming::Enabled,
+                        "true" => Roaming::Locked,
+                        "strict" => Roaming::Strict,
+                        _ => {
+                            return Err(ConfigError::UnsupportedValue {
+                                key: key.to_owned(),
+                                value: value.to_owned(),
+                            })
+                        }
+                    });
+                    Ok(())
+                }
+
+                // opt: peers reachable by relaying (vendor extension),
+                // repeated to allow further peers, replaces the previous relay ACL
+                "x_relay_allow" => {
+                    let acl = match (peer.relay_acl.take(), value) {
+                        (_, "*") | (Some(RelayAcl::Any), _) => RelayAcl::Any,
+                        (_, "none") => RelayAcl::Peers(vec![]),
+                        (acl, value) => match parse_key(key, value, base64) {
+                            Ok(pk) => {
+                                let mut peers = match acl {
+                                    Some(RelayAcl::Peers(peers)) => peers,
+                                    _ => vec![],
+                                };
+                                peers.push(PublicKey::from(pk));
+                                RelayAcl::Peers(peers)
+                            }
+                            Err(e) => return Err(e),
+                        },
+                    };
+                    peer.relay_acl = Some(acl);
+                    Ok(())
+                }
+
+                // opt: filter inner packets (vendor extension),
+                // repeated to append further rules, replaces the previous rules
+                "x_filter" => {
+                    let rules = peer.filter.get_or_insert_with(Vec::new);
+                    match value {
+                        "none" => {
+                            rules.clear();
+                            Ok(())
+                        }
+                        rule => match rule.parse() {
+                            Ok(rule) => {
+                                rules.push(rule);
+                                Ok(())
+                            }
+                            Err(_) => Err(ConfigError::InvalidFilterRule {
+                                peer: peer.public_key,
+                                value: value.to_owned(),
+                            }),
+                        },
+                    }
+                }
+
+                // opt: rate limits in bytes or packets per second, 0 for unlimited (vendor extension)
+                "x_tx_limit_bytes" | "x_tx_limit_packets" | "x_rx_limit_bytes"
+                | "x_rx_limit_packets" => match value.parse() {
+                    Ok(rate) => {
+                        let limits = peer.limits.get_or_insert_with(PeerLimits::default);
+                        *match key {
+                            "x_tx_limit_bytes" => &mut limits.tx_bytes,
+                            "x_tx_limit_packets" => &mut limits.tx_packets,
+                            "x_rx_limit_bytes" => &mut limits.rx_bytes,
+                            _ => &mut limits.rx_packets,
+                        } = Some(rate);
+                        Ok(())
+                    }
+                    Err(_) => Err(ConfigError::UnsupportedValue {
+                        key: key.to_owned(),
+                        value: value.to_owned(),
+                    }),
                 },
 
+                // opt: capture the packets of the peer (vendor extension)
+                "x_capture" => {
+                    peer.capture = Some(match value {
+                        "true" => true,
+                        "false" => false,
+                        _ => {
+                            return Err(ConfigError::UnsupportedValue {
+                                key: key.to_owned(),
+                                value: value.to_owned(),
+                            })
+                        }
+                    });
+                    Ok(())
+                }
+
+                // opt: drop or delay packets exceeding the rate limits (vendor extension)
+                "x_limit_excess" => {
+                    peer.limits.get_or_insert_with(PeerLimits::default).excess =
+                        Some(match value {
+                            "drop" => Excess::Drop,
+                            "delay" => Excess::Delay,
+                            _ => {
+                                return Err(ConfigError::UnsupportedValue {
+                                    key: key.to_owned(),
+                                    value: value.to_owned(),
+                                })
+                            }
+                        });
+                    Ok(())
+                }
+
                 // opt: set persistent keepalive interval
                 "persistent_keepalive_interval" => match value.parse() {
                     Ok(secs) => {
                         peer.persistent_keepalive_interval = Some(secs);
                         Ok(())
                     }
-                    Err(_) => Err(ConfigError::InvalidKeepaliveInterval),
+                    Err(_) => Err(ConfigError::InvalidKeepaliveInterval {
+                        peer: peer.public_key,
+                        value: value.to_owned(),
+                    }),
                 },
 
                 // opt replace allowed ips
                 "replace_allowed_ips" => {
-                    peer.replace_allowed_ips = true;
+                    peer.replace_allowed_ips = flag(key, value)?;
                     peer.allowed_ips.clear();
                     Ok(())
                 }
@@ -228,7 +712,10 @@ impl<'a, C: Configuration> LineParser<'a, C> {
                             peer.allowed_ips.push((addr, cidr));
                             Ok(())
                         }
-                        _ => Err(ConfigError::InvalidAllowedIp),
+                        _ => Err(ConfigError::InvalidAllowedIp {
+                            peer: peer.public_key,
+                            value: value.to_owned(),
+                        }),
                     }
                 }
 
@@ -240,19 +727,23 @@ impl<'a, C: Configuration> LineParser<'a, C> {
                             peer.protocol_version = Some(version);
                             Ok(())
                         }
-                        Err(_) => Err(ConfigError::UnsupportedProtocolVersion),
+                        Err(_) => Err(ConfigError::UnsupportedProtocolVersion {
+                            peer: peer.public_key,
+                            version: value.to_owned(),
+                        }),
                     }
                 }
 
                 // flush (used at end of transcipt)
                 "" => {
                     log::trace!("UAPI, Set, processes end of transaction");
-                    flush_peer(self.config, &peer);
-                    Ok(())
+                    flush_peer(self.config, &peer)
                 }
 
                 // unknown key
-                _ => Err(ConfigError::InvalidKey),
+                _ => Err(ConfigError::InvalidKey {
+                    key: key.to_owned(),
+                }),
             },
         }
     }
@@ -0,0 +1,295 @@
+use std::ffi::CString;
+use std::io::{self, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{Level, LevelFilter, Log, Metadata, Record, SetLoggerError};
+use serde_json::{Map, Value};
+
+/* Log messages are structured using "key=value" pairs (logfmt),
+ * for instance: "peer=<base64> endpoint=1.2.3.4:51820 event=handshake_initiation_sent".
+ *
+ * The pairs are extracted from the message when writing JSON,
+ * the remaining words of the message form the "msg" field.
+ */
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Output {
+    Stderr,
+    Syslog, // also picked up by journald
+}
+
+pub struct Logger {
+    device: String,
+    format: Format,
+    output: Output,
+}
+
+impl Logger {
+    pub fn new(device: &str, format: Format, output: Output) -> Logger {
+        if output == Output::Syslog {
+            // the identity must outlive the logger (the process)
+            let ident = CString::new("wireguard-rs").unwrap().into_raw();
+            unsafe { libc::openlog(ident, libc::LOG_PID, libc::LOG_DAEMON) };
+        }
+        Logger {
+            device: device.to_owned(),
+            format,
+            output,
+        }
+    }
+
+    /// Install the logger as the global logger
+    ///
+    /// # Arguments
+    ///
+    /// - `level`: The initial verbosity, may be changed later using `log::set_max_level`
+    pub fn init(self, level: LevelFilter) -> Result<(), SetLoggerError> {
+        log::set_boxed_logger(Box::new(self))?;
+        log::set_max_level(level);
+        Ok(())
+    }
+
+    fn format(&self, time: Option<SystemTime>, record: &Record) -> String {
+        let msg = format!("{}", record.args());
+        match self.format {
+            Format::Text => {
+                let mut line = String::new();
+                if let Some(time) = time {
+                    line.push_str(&timestamp(time));
+                    line.push(' ');
+                }
+                line.push_str(&format!(
+                    "{:<5} {} device={} {}",
+                    record.level(),
+                    record.target(),
+                    self.device,
+                    msg
+                ));
+                line
+            }
+            Format::Json => {
+                let mut obj = Map::new();
+                if let Some(time) = time {
+                    obj.insert("time".to_owned(), Value::String(timestamp(time)));
+                }
+                obj.insert("level".to_owned(), record.level().to_string().into());
+                obj.insert("target".to_owned(), record.target().into());
+                obj.insert("device".to_owned(), self.device.as_str().into());
+                let (pairs, rest) = fields(&msg);
+                for (key, value) in pairs {
+                    obj.insert(key.to_owned(), Value::String(value));
+                }
+                obj.insert("msg".to_owned(), Value::String(rest));
+                Value::Object(obj).to_string()
+            }
+        }
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        match self.output {
+            Output::Stderr => {
+                let line = self.format(Some(SystemTime::now()), record);
+                let _ = writeln!(io::stderr(), "{}", line);
+            }
+            Output::Syslog => {
+                // syslog adds the time
+                let line = self.format(None, record).replace('\0', "");
+                let priority = match record.level() {
+                    Level::Error => libc::LOG_ERR,
+                    Level::Warn => libc::LOG_WARNING,
+                    Level::Info => libc::LOG_INFO,
+                    Level::Debug | Level::Trace => libc::LOG_DEBUG,
+                };
+                let line = CString::new(line).unwrap();
+                unsafe {
+                    libc::syslog(
+                        priority,
+                        "%s\0".as_ptr() as *const libc::c_char,
+                        line.as_ptr(),
+                    )
+                };
+            }
+        }
+    }
+
+    fn flush(&self) {
+        let _ = io::stderr().flush();
+    }
+}
+
+// keys of the fields added by the logger (pairs using these keys are left in the message)
+const RESERVED: [&str; 5] = ["time", "level", "target", "device", "msg"];
+
+/// Split a log message into the "key=value" pairs and the remaining words
+///
+/// Values containing spaces are quoted (as by the Debug formatting of a string).
+fn fields(msg: &str) -> (Vec<(&str, String)>, String) {
+    let mut pairs = vec![];
+    let mut rest: Vec<&str> = vec![];
+    let mut s = msg.trim_start();
+    while !s.is_empty() {
+        // find the end of the word (respecting quotes after '=')
+        let key_len = s
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(s.len());
+        let (word, value) = if key_len > 0 && s[key_len..].starts_with('=') {
+            let value = &s[key_len + 1..];
+            if value.starts_with('"') {
+                match quoted(value) {
+                    Some((value, len)) => (&s[..key_len + 1 + len], Some(value)),
+                    None => (s, None),
+                }
+            } else {
+                let len = value.find(' ').unwrap_or(value.len());
+                (&s[..key_len + 1 + len], Some(value[..len].to_owned()))
+            }
+        } else {
+            (&s[..s.find(' ').unwrap_or(s.len())], None)
+        };
+        match value {
+            Some(value) if !RESERVED.contains(&&s[..key_len]) => pairs.push((&s[..key_len], value)),
+            _ => rest.push(word),
+        }
+        s = s[word.len()..].trim_start();
+    }
+    (pairs, rest.join(" "))
+}
+
+// parse a quoted string, returns the unescaped string and the length of the quoted string
+fn quoted(s: &str) -> Option<(String, usize)> {
+    let mut value = String::new();
+    let mut escaped = false;
+    for (i, c) in s.char_indices().skip(1) {
+        match (escaped, c) {
+            (false, '\\') => escaped = true,
+            (false, '"') => return Some((value, i + 1)),
+            (true, 'n') => {
+                value.push('\n');
+                escaped = false;
+            }
+            (true, 't') => {
+                value.push('\t');
+                escaped = false;
+            }
+            (_, c) => {
+                value.push(c);
+                escaped = false;
+            }
+        }
+    }
+    None
+}
+
+// RFC 3339 timestamp (UTC, millisecond precision)
+fn timestamp(time: SystemTime) -> String {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since.as_secs();
+    let (days, secs) = ((secs / 86400) as i64, secs % 86400);
+
+    // convert days since the epoch to a civil date
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        (secs / 60) % 60,
+        secs % 60,
+        since.subsec_millis()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    #[test]
+    fn test_fields() {
+        let (pairs, rest) =
+            fields("peer=AAAA endpoint=1.2.3.4:51820 event=handshake_initiation_sent");
+        assert_eq!(
+            pairs,
+            vec![
+                ("peer", "AAAA".to_owned()),
+                ("endpoint", "1.2.3.4:51820".to_owned()),
+                ("event", "handshake_initiation_sent".to_owned()),
+            ]
+        );
+        assert_eq!(rest, "");
+
+        let (pairs, rest) =
+            fields("wireguard(1a) : handshake worker, error=\"bad \\\"mac\\\"\" key=3");
+        assert_eq!(
+            pairs,
+            vec![("error", "bad \"mac\"".to_owned()), ("key", "3".to_owned())]
+        );
+        assert_eq!(rest, "wireguard(1a) : handshake worker,");
+
+        let (pairs, rest) = fields("a == b, =c error=\"unterminated");
+        assert!(pairs.is_empty());
+        assert_eq!(rest, "a == b, =c error=\"unterminated");
+
+        // the keys of the logger are not overwritten
+        let (pairs, rest) = fields("level=3 msg=\"x y\" peer=AAAA");
+        assert_eq!(pairs, vec![("peer", "AAAA".to_owned())]);
+        assert_eq!(rest, "level=3 msg=\"x y\"");
+    }
+
+    #[test]
+    fn test_timestamp() {
+        assert_eq!(timestamp(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        assert_eq!(
+            timestamp(UNIX_EPOCH + Duration::from_millis(1_582_934_400_123)),
+            "2020-02-29T00:00:00.123Z"
+        );
+        assert_eq!(
+            timestamp(UNIX_EPOCH + Duration::from_secs(4_102_444_799)),
+            "2099-12-31T23:59:59.000Z"
+        );
+    }
+
+    #[test]
+    fn test_json() {
+        let logger = Logger::new("wg0", Format::Json, Output::Stderr);
+        let line = logger.format(
+            None,
+            &Record::builder()
+                .args(format_args!("peer=AAAA key={} event=keypair_created", 7))
+                .level(Level::Debug)
+                .target("wireguard")
+                .build(),
+        );
+        assert_eq!(
+            line,
+            "{\"level\":\"DEBUG\",\"target\":\"wireguard\",\"device\":\"wg0\",\
+             \"peer\":\"AAAA\",\"key\":\"7\",\"event\":\"keypair_created\",\"msg\":\"\"}"
+        );
+    }
+}
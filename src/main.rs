@@ -9,7 +9,9 @@ extern crate cpuprofiler;
 use cpuprofiler::PROFILER;
 
 mod configuration;
+mod logger;
 mod platform;
+mod routes;
 mod wireguard;
 
 mod util;
@@ -59,11 +61,20 @@ fn main() {
     let mut name = None;
     let mut drop_privileges = true;
     let mut foreground = false;
+    let mut json_control = false;
+    let mut config_file = None;
+    let mut key_file = None;
+    let mut log_level = None;
+    let mut log_format = logger::Format::Text;
+    let mut log_output = logger::Output::Stderr;
+    let mut addresses: Vec<(std::net::IpAddr, u32)> = vec![];
+    let mut mtu = None;
+    let mut manage_routes = false;
     let mut args = env::args();
 
     // skip path (argv[0])
     args.next();
-    for arg in args {
+    while let Some(arg) = args.next() {
         match arg.as_str() {
             "--foreground" | "-f" => {
                 foreground = true;
@@ -71,10 +82,83 @@ fn main() {
             "--disable-drop-privileges" => {
                 drop_privileges = false;
             }
+            "--json-control" => {
+                json_control = true;
+            }
+            "--config" => match args.next() {
+                Some(path) => config_file = Some(path),
+                None => {
+                    eprintln!("No configuration file supplied");
+                    exit(-1);
+                }
+            },
+            "--private-key-file" => match args.next() {
+                Some(path) => key_file = Some(path),
+                None => {
+                    eprintln!("No private key file supplied");
+                    exit(-1);
+                }
+            },
+            "--log-level" => match args.next().map(|level| level.parse()) {
+                Some(Ok(level)) => log_level = Some(level),
+                _ => {
+                    eprintln!("No valid log level supplied (off, error, warn, info, debug, trace)");
+                    exit(-1);
+                }
+            },
+            "--log-format" => match args.next().as_deref() {
+                Some("text") => log_format = logger::Format::Text,
+                Some("json") => log_format = logger::Format::Json,
+                _ => {
+                    eprintln!("No valid log format supplied (text, json)");
+                    exit(-1);
+                }
+            },
+            "--log-syslog" => {
+                log_output = logger::Output::Syslog;
+            }
+            "--address" => {
+                let value = args.next().unwrap_or_default();
+                let mut split = value.splitn(2, '/');
+                let addr = split.next().and_then(|x| x.parse().ok());
+                let cidr = split.next().and_then(|x| x.parse().ok());
+                match (addr, cidr) {
+                    (Some(addr), Some(cidr)) => addresses.push((addr, cidr)),
+                    _ => {
+                        eprintln!("No valid address supplied (e.g. 10.0.0.1/24)");
+                        exit(-1);
+                    }
+                }
+            }
+            "--mtu" => match args.next().map(|mtu| mtu.parse()) {
+                Some(Ok(value)) => mtu = Some(value),
+                _ => {
+                    eprintln!("No valid MTU supplied");
+                    exit(-1);
+                }
+            },
+            "--manage-routes" => {
+                manage_routes = true;
+            }
             dev => name = Some(dev.to_owned()),
         }
     }
 
+    // default to RUST_LOG, which may only name a level
+    // (module filters are not supported, then the default level is used)
+    let log_level = match (log_level, env::var("RUST_LOG")) {
+        (Some(level), _) => level,
+        (None, Ok(value)) => value.parse().unwrap_or_else(|_| {
+            eprintln!(
+                "RUST_LOG must be a single log level (off, error, warn, info, debug, trace), \
+                 module filters are not supported: {:?} (using error)",
+                value
+            );
+            log::LevelFilter::Error
+        }),
+        (None, Err(_)) => log::LevelFilter::Error,
+    };
+
     // unwrap device name
     let name = match name {
         None => {
@@ -90,12 +174,49 @@ fn main() {
         exit(-2);
     });
 
+    // create JSON control socket (optional)
+    let json = if json_control {
+        Some(plt::UAPI::bind_json(name.as_str()).unwrap_or_else(|e| {
+            eprintln!("Failed to create JSON control listener: {}", e);
+            exit(-2);
+        }))
+    } else {
+        None
+    };
+
     // create TUN device
     let (mut readers, writer, status) = plt::Tun::create(name.as_str()).unwrap_or_else(|e| {
         eprintln!("Failed to create TUN device: {}", e);
         exit(-3);
     });
 
+    // routes are managed for the lifetime of the device
+    if manage_routes && drop_privileges {
+        eprintln!("Managing routes requires --disable-drop-privileges");
+        exit(-4);
+    }
+
+    // assign addresses, set the MTU and bring the link up (optional)
+    let netlink = if manage_routes || mtu.is_some() || !addresses.is_empty() {
+        let mut netlink = plt::Netlink::open(name.as_str()).unwrap_or_else(|e| {
+            eprintln!("Failed to open netlink socket: {}", e);
+            exit(-3);
+        });
+        for (addr, cidr) in addresses.iter() {
+            if let Err(e) = netlink.add_address(*addr, *cidr) {
+                eprintln!("Failed to assign address {}/{}: {}", addr, cidr, e);
+                exit(-3);
+            }
+        }
+        if let Err(e) = netlink.link_up(mtu) {
+            eprintln!("Failed to bring the link up: {}", e);
+            exit(-3);
+        }
+        Some(netlink)
+    } else {
+        None
+    };
+
     // drop privileges
     if drop_privileges {
         match util::drop_privileges() {
@@ -118,9 +239,9 @@ fn main() {
         }
     }
 
-    // start logging
-    env_logger::builder()
-        .try_init()
+    // start logging (the level may be changed at runtime using UAPI)
+    logger::Logger::new(name.as_str(), log_format, log_output)
+        .init(log_level)
         .expect("Failed to initialize event logger");
 
     log::info!("Starting {} WireGuard device.", name);
@@ -140,14 +261,55 @@ fn main() {
     // wrap in configuration interface
     let cfg = configuration::WireGuardConfig::new(wg.clone());
 
+    // use the private key file (optional), the key is never exported
+    if let Some(path) = key_file {
+        match configuration::FileKey::open(&path) {
+            Some(key) => {
+                cfg.disable_private_key_export();
+                cfg.set_static_key(Some(Box::new(key)));
+            }
+            None => {
+                log::error!("Failed to load private key file {}", path);
+                profiler_stop();
+                exit(-6);
+            }
+        }
+    }
+
+    // apply configuration file (optional)
+    if let Some(path) = config_file {
+        if let Err((line, e)) = configuration::file::load(&cfg, &path) {
+            log::error!(
+                "Failed to load configuration {} (line {}): {}",
+                path,
+                line,
+                e
+            );
+            profiler_stop();
+            exit(-6);
+        }
+    }
+
+    // start route manager (optional)
+    let routes = netlink
+        .filter(|_| manage_routes)
+        .map(|netlink| routes::RouteManager::new(cfg.clone(), netlink));
+    if let Some(routes) = routes.clone() {
+        thread::spawn(move || routes.run());
+    }
+
     // start Tun event thread
     {
         let cfg = cfg.clone();
+        let routes = routes.clone();
         let mut status = status;
         thread::spawn(move || loop {
             match status.event() {
                 Err(e) => {
                     log::info!("Tun device error {}", e);
+                    if let Some(routes) = routes.as_ref() {
+                        routes.remove_rules();
+                    }
                     profiler_stop();
                     exit(0);
                 }
@@ -163,7 +325,28 @@ fn main() {
         });
     }
 
+    // start JSON control server
+    if let Some(json) = json {
+        let cfg = cfg.clone();
+        thread::spawn(move || loop {
+            // accept and handle JSON control connections
+            match json.connect() {
+                Ok(mut stream) => {
+                    let cfg = cfg.clone();
+                    thread::spawn(move || {
+                        configuration::json::handle(&mut stream, &cfg);
+                    });
+                }
+                Err(err) => {
+                    log::info!("JSON control connection error: {}", err);
+                    break;
+                }
+            }
+        });
+    }
+
     // start UAPI server
+    let uapi_routes = routes.clone();
     thread::spawn(move || loop {
         // accept and handle UAPI config connections
         match uapi.connect() {
@@ -175,6 +358,9 @@ fn main() {
             }
             Err(err) => {
                 log::info!("UAPI connection error: {}", err);
+                if let Some(routes) = uapi_routes.as_ref() {
+                    routes.remove_rules();
+                }
                 profiler_stop();
                 exit(-1);
             }
@@ -183,5 +369,8 @@ fn main() {
 
     // block until all tun readers closed
     wg.wait();
+    if let Some(routes) = routes {
+        routes.remove_rules();
+    }
     profiler_stop();
 }
@@ -22,3 +22,21 @@ impl UnitEndpoint {
         UnitEndpoint {}
     }
 }
+
+/// An endpoint with an address (e.g. the source of a replayed packet)
+#[derive(Clone, Copy)]
+pub struct AddrEndpoint {
+    addr: SocketAddr,
+}
+
+impl Endpoint for AddrEndpoint {
+    fn from_address(addr: SocketAddr) -> AddrEndpoint {
+        AddrEndpoint { addr }
+    }
+
+    fn into_address(&self) -> SocketAddr {
+        self.addr
+    }
+
+    fn clear_src(&mut self) {}
+}
@@ -1,4 +1,5 @@
 mod endpoint;
+pub mod pcap;
 mod tun;
 mod udp;
 
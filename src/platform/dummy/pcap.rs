@@ -0,0 +1,337 @@
+// Reading and writing of packet captures:
+//
+// Used by the replay implementations of the tunnel interface and UDP bind,
+// which read the packets from a capture and record the written packets to another.
+//
+// Both the classic pcap format and pcapng are read,
+// captures are written in the classic pcap format (link-type "raw IP").
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+
+use super::super::super::wireguard::{
+    BLOCK_ENHANCED_PACKET, BLOCK_INTERFACE_DESCRIPTION, BLOCK_SECTION_HEADER, BYTE_ORDER_MAGIC,
+    OPT_END, OPT_EPB_FLAGS, OPT_IF_NAME, PROTOCOL_UDP,
+};
+
+const PCAP_MAGIC_MICROS: u32 = 0xa1b2_c3d4;
+const PCAP_MAGIC_NANOS: u32 = 0xa1b2_3c4d;
+
+const OPT_IF_TSRESOL: u16 = 9;
+
+const LINKTYPE_NULL: u32 = 0;
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_LINUX_SLL: u32 = 113;
+const LINKTYPE_IPV4: u32 = 228;
+const LINKTYPE_IPV6: u32 = 229;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86DD;
+
+/// A packet read from a capture
+pub struct Record {
+    pub time: Duration,            // timestamp (since the UNIX epoch)
+    pub interface: Option<String>, // name of the capturing interface (pcapng)
+    pub inbound: Option<bool>,     // direction of the packet, if recorded (pcapng)
+    pub linktype: u32,
+    pub data: Vec<u8>,
+}
+
+impl Record {
+    /// Returns the IP packet (stripping the link-layer header)
+    pub fn ip(&self) -> Option<&[u8]> {
+        let (offset, ethertype) = match self.linktype {
+            LINKTYPE_RAW | LINKTYPE_IPV4 | LINKTYPE_IPV6 => (0, None),
+            LINKTYPE_NULL => (4, None),
+            LINKTYPE_ETHERNET => (14, Some(12)),
+            LINKTYPE_LINUX_SLL => (16, Some(14)),
+            _ => return None,
+        };
+        if self.data.len() <= offset {
+            return None;
+        }
+        if let Some(at) = ethertype {
+            match BigEndian::read_u16(&self.data[at..]) {
+                ETHERTYPE_IPV4 | ETHERTYPE_IPV6 => (),
+                _ => return None,
+            }
+        }
+        let ip = &self.data[offset..];
+        match ip[0] >> 4 {
+            4 | 6 => Some(ip),
+            _ => None,
+        }
+    }
+
+    /// Returns the source, destination and payload of a UDP datagram
+    pub fn udp(&self) -> Option<(SocketAddr, SocketAddr, &[u8])> {
+        let ip = self.ip()?;
+        let (src, dst, protocol, header) = match ip[0] >> 4 {
+            4 if ip.len() >= 20 => {
+                let header = usize::from(ip[0] & 0xf) * 4;
+                let src: [u8; 4] = [ip[12], ip[13], ip[14], ip[15]];
+                let dst: [u8; 4] = [ip[16], ip[17], ip[18], ip[19]];
+                (IpAddr::from(src), IpAddr::from(dst), ip[9], header)
+            }
+            6 if ip.len() >= 40 => {
+                let mut src = [0u8; 16];
+                let mut dst = [0u8; 16];
+                src.copy_from_slice(&ip[8..24]);
+                dst.copy_from_slice(&ip[24..40]);
+                (IpAddr::from(src), IpAddr::from(dst), ip[6], 40)
+            }
+            _ => return None,
+        };
+        if protocol != PROTOCOL_UDP || ip.len() < header + 8 {
+            return None;
+        }
+        let udp = &ip[header..];
+        let len = usize::from(BigEndian::read_u16(&udp[4..])).min(udp.len());
+        Some((
+            SocketAddr::new(src, BigEndian::read_u16(&udp[0..])),
+            SocketAddr::new(dst, BigEndian::read_u16(&udp[2..])),
+            &udp[8..len.max(8)],
+        ))
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_owned())
+}
+
+/// Reads all packets of a capture (in the pcap or pcapng format)
+pub fn read(path: &Path) -> io::Result<Vec<Record>> {
+    let data = fs::read(path)?;
+    if data.len() < 4 {
+        return Err(invalid("truncated capture"));
+    }
+    if LittleEndian::read_u32(&data) == BLOCK_SECTION_HEADER {
+        read_pcapng(&data)
+    } else {
+        read_pcap(&data)
+    }
+}
+
+fn read_pcap(data: &[u8]) -> io::Result<Vec<Record>> {
+    if data.len() < 24 {
+        return Err(invalid("truncated pcap header"));
+    }
+
+    // the magic number determines the byte order and timestamp resolution
+    let read_u32: fn(&[u8]) -> u32 = match LittleEndian::read_u32(data) {
+        PCAP_MAGIC_MICROS | PCAP_MAGIC_NANOS => LittleEndian::read_u32,
+        _ => BigEndian::read_u32,
+    };
+    let nanos = match read_u32(data) {
+        PCAP_MAGIC_MICROS => false,
+        PCAP_MAGIC_NANOS => true,
+        _ => return Err(invalid("not a pcap file")),
+    };
+    let linktype = read_u32(&data[20..]) & 0xffff;
+
+    let mut records = vec![];
+    let mut offset = 24;
+    while offset + 16 <= data.len() {
+        let secs = u64::from(read_u32(&data[offset..]));
+        let frac = read_u32(&data[offset + 4..]);
+        let len = read_u32(&data[offset + 8..]) as usize;
+        let start = offset + 16;
+        if start + len > data.len() {
+            return Err(invalid("truncated pcap record"));
+        }
+        records.push(Record {
+            time: Duration::from_secs(secs)
+                + if nanos {
+                    Duration::from_nanos(frac.into())
+                } else {
+                    Duration::from_micros(frac.into())
+                },
+            interface: None,
+            inbound: None,
+            linktype,
+            data: data[start..start + len].to_owned(),
+        });
+        offset = start + len;
+    }
+    Ok(records)
+}
+
+fn read_pcapng(data: &[u8]) -> io::Result<Vec<Record>> {
+    struct Interface {
+        linktype: u32,
+        name: Option<String>,
+        units: u64, // timestamp units per second
+    }
+
+    let mut read_u16: fn(&[u8]) -> u16 = LittleEndian::read_u16;
+    let mut read_u32: fn(&[u8]) -> u32 = LittleEndian::read_u32;
+    let mut interfaces: Vec<Interface> = vec![];
+    let mut records = vec![];
+    let mut offset = 0;
+
+    while offset + 12 <= data.len() {
+        // the section header determines the byte order
+        if LittleEndian::read_u32(&data[offset..]) == BLOCK_SECTION_HEADER {
+            if LittleEndian::read_u32(&data[offset + 8..]) == BYTE_ORDER_MAGIC {
+                read_u16 = LittleEndian::read_u16;
+                read_u32 = LittleEndian::read_u32;
+            } else {
+                read_u16 = BigEndian::read_u16;
+                read_u32 = BigEndian::read_u32;
+            }
+            interfaces.clear();
+        }
+
+        let kind = read_u32(&data[offset..]);
+        let len = read_u32(&data[offset + 4..]) as usize;
+        if len < 12 || offset + len > data.len() {
+            return Err(invalid("truncated pcapng block"));
+        }
+        let body = &data[offset + 8..offset + len - 4];
+        offset += len;
+
+        // iterates the options following the fixed fields of a block
+        let options = |mut at: usize, f: &mut dyn FnMut(u16, &[u8])| {
+            while at + 4 <= body.len() {
+                let code = read_u16(&body[at..]);
+                let len = usize::from(read_u16(&body[at + 2..]));
+                if code == OPT_END || at + 4 + len > body.len() {
+                    break;
+                }
+                f(code, &body[at + 4..at + 4 + len]);
+                at += 4 + ((len + 3) & !3);
+            }
+        };
+
+        match kind {
+            BLOCK_INTERFACE_DESCRIPTION if body.len() >= 8 => {
+                let mut interface = Interface {
+                    linktype: u32::from(read_u16(body)),
+                    name: None,
+                    units: 1_000_000,
+                };
+                options(8, &mut |code, value| match code {
+                    OPT_IF_NAME => {
+                        interface.name = Some(String::from_utf8_lossy(value).into_owned())
+                    }
+                    OPT_IF_TSRESOL if !value.is_empty() => {
+                        let exp = u32::from(value[0] & 0x7f);
+                        interface.units = if value[0] & 0x80 == 0 {
+                            10u64.saturating_pow(exp)
+                        } else {
+                            2u64.saturating_pow(exp)
+                        };
+                    }
+                    _ => (),
+                });
+                interfaces.push(interface);
+            }
+            BLOCK_ENHANCED_PACKET if body.len() >= 20 => {
+                let interface = interfaces
+                    .get(read_u32(body) as usize)
+                    .ok_or_else(|| invalid("packet of unknown interface"))?;
+                let ts = (u64::from(read_u32(&body[4..])) << 32) | u64::from(read_u32(&body[8..]));
+                let len = read_u32(&body[12..]) as usize;
+                if 20 + len > body.len() {
+                    return Err(invalid("truncated pcapng packet"));
+                }
+                let mut inbound = None;
+                options(20 + ((len + 3) & !3), &mut |code, value| {
+                    if code == OPT_EPB_FLAGS && value.len() == 4 {
+                        inbound = match read_u32(value) & 0x3 {
+                            1 => Some(true),
+                            2 => Some(false),
+                            _ => None,
+                        };
+                    }
+                });
+                let units = interface.units.max(1);
+                records.push(Record {
+                    time: Duration::from_secs(ts / units)
+                        + Duration::from_nanos((ts % units) * 1_000_000_000 / units),
+                    interface: interface.name.clone(),
+                    inbound,
+                    linktype: interface.linktype,
+                    data: body[20..20 + len].to_owned(),
+                });
+            }
+            _ => (),
+        }
+    }
+    Ok(records)
+}
+
+/// Writes packets to a capture in the pcap format
+pub struct Recorder {
+    file: Mutex<File>,
+}
+
+impl Recorder {
+    pub fn create(path: &Path) -> io::Result<Recorder> {
+        let mut file = File::create(path)?;
+        let mut header = [0u8; 24];
+        LittleEndian::write_u32(&mut header[0..], PCAP_MAGIC_MICROS);
+        LittleEndian::write_u16(&mut header[4..], 2);
+        LittleEndian::write_u16(&mut header[6..], 4);
+        LittleEndian::write_u32(&mut header[16..], 0xffff);
+        LittleEndian::write_u32(&mut header[20..], LINKTYPE_RAW);
+        file.write_all(&header)?;
+        Ok(Recorder {
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Appends an IP packet
+    pub fn write(&self, packet: &[u8]) -> io::Result<()> {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut record = vec![0u8; 16];
+        LittleEndian::write_u32(&mut record[0..], time.as_secs() as u32);
+        LittleEndian::write_u32(&mut record[4..], time.subsec_micros());
+        LittleEndian::write_u32(&mut record[8..], packet.len() as u32);
+        LittleEndian::write_u32(&mut record[12..], packet.len() as u32);
+        record.extend_from_slice(packet);
+        self.file.lock().unwrap().write_all(&record)
+    }
+}
+
+/// Replays packets: optionally preserving the gaps between the timestamps of the capture
+pub struct Replay<T> {
+    packets: Vec<(Duration, T)>,
+    paced: bool,
+    state: Option<(Instant, Duration)>, // start of the replay and time of the first packet
+}
+
+impl<T> Replay<T> {
+    pub fn new(mut packets: Vec<(Duration, T)>, paced: bool) -> Replay<T> {
+        packets.reverse();
+        Replay {
+            packets,
+            paced,
+            state: None,
+        }
+    }
+
+    /// Returns the next packet (None once the capture is exhausted)
+    pub fn pop(&mut self) -> Option<T> {
+        let (time, packet) = self.packets.pop()?;
+        if self.paced {
+            let (start, first) = *self.state.get_or_insert((Instant::now(), time));
+            let due = start + time.checked_sub(first).unwrap_or_default();
+            let now = Instant::now();
+            if due > now {
+                thread::sleep(due - now);
+            }
+        }
+        Some(packet)
+    }
+}
@@ -64,7 +64,7 @@ impl fmt::Display for TunWriter {
 }
 
 pub struct TunStatus {
-    first: bool,
+    pub(super) first: bool,
 }
 
 impl Error for TunError {
@@ -1,6 +1,7 @@
 use super::super::tun::*;
 
 mod dummy;
+mod pcap;
 mod void;
 
 #[derive(Debug)]
@@ -9,4 +10,5 @@ pub enum TunError {
 }
 
 pub use dummy::*;
+pub use pcap::*;
 pub use void::*;
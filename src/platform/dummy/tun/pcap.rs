@@ -0,0 +1,129 @@
+// This provides a tunnel interface replaying a packet capture:
+//
+// The IP packets of the input capture are read by WireGuard (as if sent by the OS),
+// while every packet written by WireGuard is recorded in the output capture,
+// which enables replaying captured traffic through a WireGuard device
+// and comparing the resulting output.
+
+use super::super::pcap::{self, Recorder, Replay};
+use super::*;
+
+use std::cmp::min;
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+
+use hex;
+use log::debug;
+use rand::rngs::OsRng;
+use rand::Rng;
+
+pub struct PcapTun {}
+
+pub struct PcapTunReader {
+    id: u32,
+    replay: Mutex<Replay<Vec<u8>>>,
+}
+
+pub struct PcapTunWriter {
+    id: u32,
+    pcap: Recorder,
+}
+
+impl fmt::Display for PcapTunReader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PcapTunReader({})", self.id)
+    }
+}
+
+impl fmt::Display for PcapTunWriter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PcapTunWriter({})", self.id)
+    }
+}
+
+impl Reader for PcapTunReader {
+    type Error = TunError;
+
+    // fails with "Disconnected" once the capture is exhausted
+    fn read(&self, buf: &mut [u8], offset: usize) -> Result<usize, Self::Error> {
+        match self.replay.lock().unwrap().pop() {
+            Some(msg) => {
+                let n = min(buf.len() - offset, msg.len());
+                buf[offset..offset + n].copy_from_slice(&msg[..n]);
+                debug!(
+                    "pcap::TUN({}) : read ({}, {})",
+                    self.id,
+                    n,
+                    hex::encode(&buf[offset..offset + n])
+                );
+                Ok(n)
+            }
+            None => Err(TunError::Disconnected),
+        }
+    }
+}
+
+impl Writer for PcapTunWriter {
+    type Error = TunError;
+
+    fn write(&self, src: &[u8]) -> Result<(), Self::Error> {
+        debug!(
+            "pcap::TUN({}) : write ({}, {})",
+            self.id,
+            src.len(),
+            hex::encode(src)
+        );
+        self.pcap.write(src).map_err(|_| TunError::Disconnected)
+    }
+}
+
+impl Tun for PcapTun {
+    type Writer = PcapTunWriter;
+    type Reader = PcapTunReader;
+    type Error = TunError;
+}
+
+impl PcapTun {
+    /// Opens the input capture (in the pcap or pcapng format)
+    /// and creates the output capture (in the pcap format)
+    ///
+    /// # Arguments
+    ///
+    /// - `input`: The capture of IP packets to read,
+    ///   packets flagged as inbound or captured on an interface named "outer" are skipped
+    ///   (such that a capture of the device may be replayed).
+    /// - `output`: The capture to which the written IP packets are recorded
+    /// - `paced`: Preserve the gaps between the packets of the input (otherwise read at once)
+    pub fn open(
+        input: &Path,
+        output: &Path,
+        paced: bool,
+    ) -> io::Result<(PcapTunReader, PcapTunWriter, TunStatus)> {
+        let packets = pcap::read(input)?
+            .into_iter()
+            .filter(|r| r.inbound != Some(true) && r.interface.as_deref() != Some("outer"))
+            .filter_map(|r| r.ip().map(|ip| (r.time, ip.to_owned())))
+            .collect();
+
+        let id: u32 = OsRng.gen();
+        let reader = PcapTunReader {
+            id,
+            replay: Mutex::new(Replay::new(packets, paced)),
+        };
+        let writer = PcapTunWriter {
+            id,
+            pcap: Recorder::create(output)?,
+        };
+        Ok((reader, writer, TunStatus { first: true }))
+    }
+}
+
+impl PlatformTun for PcapTun {
+    type Status = TunStatus;
+
+    fn create(_name: &str) -> Result<(Vec<Self::Reader>, Self::Writer, Self::Status), Self::Error> {
+        Err(TunError::Disconnected)
+    }
+}
@@ -1,7 +1,11 @@
 use hex;
+use std::cmp::min;
 use std::error::Error;
 use std::fmt;
+use std::io;
 use std::marker;
+use std::net::SocketAddr;
+use std::path::Path;
 
 use log::debug;
 use rand::rngs::OsRng;
@@ -12,8 +16,11 @@ use std::sync::Arc;
 use std::sync::Mutex;
 
 use super::super::udp::*;
+use super::super::Endpoint;
 
-use super::UnitEndpoint;
+use super::super::super::wireguard::{encapsulate, Direction};
+use super::pcap::{self, Replay};
+use super::{AddrEndpoint, UnitEndpoint};
 
 pub struct VoidOwner {}
 
@@ -73,6 +80,30 @@ impl VoidBind {
     }
 }
 
+/// Discards every message (like VoidBind), reporting a fixed path MTU for every destination
+#[derive(Clone, Copy)]
+pub struct PathMtuBind {
+    mtu: usize,
+}
+
+impl PathMtuBind {
+    pub fn new(mtu: usize) -> PathMtuBind {
+        PathMtuBind { mtu }
+    }
+}
+
+impl Writer<UnitEndpoint> for PathMtuBind {
+    type Error = BindError;
+
+    fn write(&self, _buf: &[u8], _dst: &mut UnitEndpoint) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn path_mtu(&self, _dst: &UnitEndpoint) -> Option<usize> {
+        Some(self.mtu)
+    }
+}
+
 /* Pair Bind */
 
 #[derive(Clone)]
@@ -190,7 +221,124 @@ impl Owner for VoidOwner {
 
 impl PlatformUDP for PairBind {
     type Owner = VoidOwner;
-    fn bind(_port: u16) -> Result<(Vec<Self::Reader>, Self::Writer, Self::Owner), Self::Error> {
+    fn bind(
+        _port: u16,
+        _options: &BindOptions,
+    ) -> Result<(Vec<Self::Reader>, Self::Writer, Self::Owner), Self::Error> {
+        Err(BindError::Disconnected)
+    }
+}
+
+/* Pcap Bind
+ *
+ * Replays the UDP datagrams of a packet capture (as if received from the source of the datagram)
+ * and records every written message in another capture.
+ */
+
+pub struct PcapReader {
+    id: u32,
+    replay: Mutex<Replay<(Vec<u8>, SocketAddr)>>,
+}
+
+impl Reader<AddrEndpoint> for PcapReader {
+    type Error = BindError;
+
+    // fails with "Disconnected" once the capture is exhausted
+    fn read(&self, buf: &mut [u8]) -> Result<(usize, AddrEndpoint), Self::Error> {
+        let (msg, src) = self
+            .replay
+            .lock()
+            .unwrap()
+            .pop()
+            .ok_or(BindError::Disconnected)?;
+        let len = min(buf.len(), msg.len());
+        buf[..len].copy_from_slice(&msg[..len]);
+        debug!(
+            "pcap({}): read ({}, {}) from {}",
+            self.id,
+            len,
+            hex::encode(&buf[..len]),
+            src
+        );
+        Ok((len, AddrEndpoint::from_address(src)))
+    }
+}
+
+#[derive(Clone)]
+pub struct PcapWriter {
+    id: u32,
+    pcap: Arc<pcap::Recorder>,
+}
+
+impl Writer<AddrEndpoint> for PcapWriter {
+    type Error = BindError;
+
+    fn write(&self, buf: &[u8], dst: &mut AddrEndpoint) -> Result<(), Self::Error> {
+        let dst = dst.into_address();
+        debug!(
+            "pcap({}): write ({}, {}) to {}",
+            self.id,
+            buf.len(),
+            hex::encode(buf),
+            dst
+        );
+        let packet = encapsulate(Direction::Out, Some(dst), buf);
+        self.pcap
+            .write(&packet)
+            .map_err(|_| BindError::Disconnected)
+    }
+}
+
+pub struct PcapBind {}
+
+impl PcapBind {
+    /// Opens the input capture (in the pcap or pcapng format)
+    /// and creates the output capture (in the pcap format)
+    ///
+    /// # Arguments
+    ///
+    /// - `input`: The capture of UDP datagrams to read,
+    ///   packets flagged as outbound or captured on an interface named "inner" are skipped
+    ///   (such that a capture of the device may be replayed).
+    /// - `output`: The capture to which the written messages are recorded (as IP/UDP packets)
+    /// - `paced`: Preserve the gaps between the packets of the input (otherwise read at once)
+    pub fn open(input: &Path, output: &Path, paced: bool) -> io::Result<(PcapReader, PcapWriter)> {
+        let packets = pcap::read(input)?
+            .into_iter()
+            .filter(|r| r.inbound != Some(false) && r.interface.as_deref() != Some("inner"))
+            .filter_map(|r| {
+                r.udp()
+                    .map(|(src, _, payload)| (r.time, (payload.to_owned(), src)))
+            })
+            .collect();
+
+        let id: u32 = OsRng.gen();
+        Ok((
+            PcapReader {
+                id,
+                replay: Mutex::new(Replay::new(packets, paced)),
+            },
+            PcapWriter {
+                id,
+                pcap: Arc::new(pcap::Recorder::create(output)?),
+            },
+        ))
+    }
+}
+
+impl UDP for PcapBind {
+    type Error = BindError;
+    type Endpoint = AddrEndpoint;
+    type Reader = PcapReader;
+    type Writer = PcapWriter;
+}
+
+impl PlatformUDP for PcapBind {
+    type Owner = VoidOwner;
+    fn bind(
+        _port: u16,
+        _options: &BindOptions,
+    ) -> Result<(Vec<Self::Reader>, Self::Writer, Self::Owner), Self::Error> {
         Err(BindError::Disconnected)
     }
 }
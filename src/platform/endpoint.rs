@@ -4,4 +4,10 @@ pub trait Endpoint: Send + 'static {
     fn from_address(addr: SocketAddr) -> Self;
     fn into_address(&self) -> SocketAddr;
     fn clear_src(&mut self);
+
+    /// The TOS / traffic class of the outer packet received from the endpoint
+    /// (0 if unknown)
+    fn tos(&self) -> u8 {
+        0
+    }
 }
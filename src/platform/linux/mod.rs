@@ -1,7 +1,10 @@
+mod netlink;
 mod tun;
 mod uapi;
 mod udp;
 
+pub use netlink::LinuxNetlink as Netlink;
+pub use netlink::Rule;
 pub use tun::LinuxTun as Tun;
 pub use uapi::LinuxUAPI as UAPI;
 pub use udp::LinuxUDP as UDP;
@@ -0,0 +1,425 @@
+use libc;
+
+use std::fs;
+use std::io;
+use std::mem;
+use std::net::IpAddr;
+use std::os::unix::io::RawFd;
+
+/* Management of the addresses, link state, routes and routing policy
+ * of the interface over rtnetlink (man 7 rtnetlink).
+ *
+ * Every request is acknowledged by the kernel,
+ * requests are hence synchronous and the errno is reported as an io::Error.
+ */
+
+// attributes (linux/if_link.h, linux/if_addr.h, linux/rtnetlink.h, linux/fib_rules.h)
+const IFLA_MTU: u16 = 4;
+const IFA_ADDRESS: u16 = 1;
+const IFA_LOCAL: u16 = 2;
+const RTA_DST: u16 = 1;
+const RTA_OIF: u16 = 4;
+const RTA_TABLE: u16 = 15;
+const FRA_PRIORITY: u16 = 6;
+const FRA_FWMARK: u16 = 10;
+const FRA_SUPPRESS_PREFIXLEN: u16 = 14;
+const FRA_TABLE: u16 = 15;
+
+const RT_TABLE_MAIN: u32 = 254;
+const RT_SCOPE_UNIVERSE: u8 = 0;
+const RT_SCOPE_LINK: u8 = 253;
+const RTPROT_BOOT: u8 = 3;
+const RTN_UNICAST: u8 = 1;
+const FR_ACT_TO_TBL: u8 = 1;
+const FIB_RULE_INVERT: u32 = 2;
+
+const SRC_VALID_MARK: &str = "/proc/sys/net/ipv4/conf/all/src_valid_mark";
+
+#[repr(C)]
+struct IfInfomsg {
+    ifi_family: libc::c_uchar,
+    __ifi_pad: libc::c_uchar,
+    ifi_type: libc::c_ushort,
+    ifi_index: libc::c_int,
+    ifi_flags: libc::c_uint,
+    ifi_change: libc::c_uint,
+}
+
+#[repr(C)]
+struct IfAddrmsg {
+    ifa_family: u8,
+    ifa_prefixlen: u8,
+    ifa_flags: u8,
+    ifa_scope: u8,
+    ifa_index: u32,
+}
+
+#[repr(C)]
+struct Rtmsg {
+    rtm_family: u8,
+    rtm_dst_len: u8,
+    rtm_src_len: u8,
+    rtm_tos: u8,
+    rtm_table: u8,
+    rtm_protocol: u8,
+    rtm_scope: u8,
+    rtm_type: u8,
+    rtm_flags: u32,
+}
+
+#[repr(C)]
+struct FibRuleHdr {
+    family: u8,
+    dst_len: u8,
+    src_len: u8,
+    tos: u8,
+    table: u8,
+    res1: u8,
+    res2: u8,
+    action: u8,
+    flags: u32,
+}
+
+/// A routing policy rule of wg-quick's full-tunnel setup
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Rule {
+    /// Packets without the fwmark are looked up in the table
+    NotFwmark { fwmark: u32, table: u32 },
+    /// The main table is consulted for all but the default routes
+    SuppressDefault,
+}
+
+fn family(ip: &IpAddr) -> u8 {
+    match ip {
+        IpAddr::V4(_) => libc::AF_INET as u8,
+        IpAddr::V6(_) => libc::AF_INET6 as u8,
+    }
+}
+
+fn octets(ip: &IpAddr) -> Vec<u8> {
+    match ip {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    }
+}
+
+// the octets of the subnet, the kernel refuses destinations with host bits set
+fn network(ip: &IpAddr, prefix: u32) -> Vec<u8> {
+    let mut octets = octets(ip);
+    for (i, b) in octets.iter_mut().enumerate() {
+        let bits = prefix.saturating_sub(8 * i as u32).min(8);
+        *b &= !(0xffu16 >> bits) as u8;
+    }
+    octets
+}
+
+fn bytes<H>(hdr: &H) -> &[u8] {
+    unsafe { std::slice::from_raw_parts(hdr as *const H as *const u8, mem::size_of::<H>()) }
+}
+
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+// a netlink request: header, fixed size body and attributes
+struct Request(Vec<u8>);
+
+impl Request {
+    fn new<H>(kind: u16, flags: u16, hdr: &H) -> Request {
+        let mut msg = vec![0u8; mem::size_of::<libc::nlmsghdr>()];
+        msg[4..6].copy_from_slice(&kind.to_ne_bytes());
+        msg[6..8].copy_from_slice(&(flags | libc::NLM_F_REQUEST as u16).to_ne_bytes());
+        msg.extend_from_slice(bytes(hdr));
+        msg.resize(align(msg.len()), 0);
+        Request(msg)
+    }
+
+    fn attr(mut self, kind: u16, value: &[u8]) -> Request {
+        let len = 4 + value.len();
+        self.0.extend_from_slice(&(len as u16).to_ne_bytes());
+        self.0.extend_from_slice(&kind.to_ne_bytes());
+        self.0.extend_from_slice(value);
+        self.0.resize(align(self.0.len()), 0);
+        self
+    }
+
+    fn attr_u32(self, kind: u16, value: u32) -> Request {
+        self.attr(kind, &value.to_ne_bytes())
+    }
+
+    // set the length and sequence number
+    fn finish(mut self, seq: u32) -> Vec<u8> {
+        let len = self.0.len() as u32;
+        self.0[0..4].copy_from_slice(&len.to_ne_bytes());
+        self.0[8..12].copy_from_slice(&seq.to_ne_bytes());
+        self.0
+    }
+}
+
+pub struct LinuxNetlink {
+    fd: RawFd,
+    seq: u32,
+    index: u32,
+}
+
+impl Drop for LinuxNetlink {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+impl LinuxNetlink {
+    /// Opens a netlink socket for managing the interface
+    ///
+    /// # Arguments
+    ///
+    /// - `name`: The name of the interface
+    pub fn open(name: &str) -> io::Result<LinuxNetlink> {
+        let name = std::ffi::CString::new(name)
+            .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+        let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+        if index == 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let fd = unsafe { libc::socket(libc::AF_NETLINK, libc::SOCK_RAW, libc::NETLINK_ROUTE) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(LinuxNetlink { fd, seq: 0, index })
+    }
+
+    // send the request and wait for the acknowledgement
+    fn request(&mut self, req: Request) -> io::Result<()> {
+        const HDR_SIZE: usize = mem::size_of::<libc::nlmsghdr>();
+        const ERROR: u16 = libc::NLMSG_ERROR as u16;
+
+        self.seq = self.seq.wrapping_add(1);
+        let msg = req.finish(self.seq);
+        let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as u16;
+        let sent = unsafe {
+            libc::sendto(
+                self.fd,
+                msg.as_ptr() as _,
+                msg.len(),
+                0,
+                &addr as *const libc::sockaddr_nl as _,
+                mem::size_of::<libc::sockaddr_nl>() as u32,
+            )
+        };
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut buf = [0u8; 1 << 12];
+        loop {
+            let size = unsafe { libc::recv(self.fd, buf.as_mut_ptr() as _, buf.len(), 0) };
+            if size < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut remain = &buf[..size as usize];
+            while remain.len() >= HDR_SIZE {
+                let len = u32::from_ne_bytes([remain[0], remain[1], remain[2], remain[3]]) as usize;
+                let kind = u16::from_ne_bytes([remain[4], remain[5]]);
+                let seq = u32::from_ne_bytes([remain[8], remain[9], remain[10], remain[11]]);
+                if len < HDR_SIZE || len > remain.len() {
+                    return Err(io::Error::from(io::ErrorKind::InvalidData));
+                }
+
+                // the acknowledgement is an error message with errno 0
+                if kind == ERROR && seq == self.seq && len >= HDR_SIZE + 4 {
+                    let b = &remain[HDR_SIZE..HDR_SIZE + 4];
+                    return match i32::from_ne_bytes([b[0], b[1], b[2], b[3]]) {
+                        0 => Ok(()),
+                        err => Err(io::Error::from_raw_os_error(-err)),
+                    };
+                }
+                remain = &remain[align(len).min(remain.len())..];
+            }
+        }
+    }
+
+    /// Sets the MTU of the interface and brings the link up
+    ///
+    /// # Arguments
+    ///
+    /// - `mtu`: The new MTU (or None, if the MTU should not be changed)
+    pub fn link_up(&mut self, mtu: Option<usize>) -> io::Result<()> {
+        log::debug!("netlink, set link up (mtu = {:?})", mtu);
+        let info = IfInfomsg {
+            ifi_family: libc::AF_UNSPEC as u8,
+            __ifi_pad: 0,
+            ifi_type: 0,
+            ifi_index: self.index as i32,
+            ifi_flags: libc::IFF_UP as u32,
+            ifi_change: libc::IFF_UP as u32,
+        };
+        let mut req = Request::new(libc::RTM_NEWLINK, libc::NLM_F_ACK as u16, &info);
+        if let Some(mtu) = mtu {
+            req = req.attr_u32(IFLA_MTU, mtu as u32);
+        }
+        self.request(req)
+    }
+
+    /// Assigns an address to the interface (if not already assigned)
+    pub fn add_address(&mut self, ip: IpAddr, prefix: u32) -> io::Result<()> {
+        log::debug!("netlink, add address {}/{}", ip, prefix);
+        let msg = IfAddrmsg {
+            ifa_family: family(&ip),
+            ifa_prefixlen: prefix as u8,
+            ifa_flags: 0,
+            ifa_scope: RT_SCOPE_UNIVERSE,
+            ifa_index: self.index,
+        };
+        let flags = libc::NLM_F_ACK | libc::NLM_F_CREATE | libc::NLM_F_EXCL;
+        let req = Request::new(libc::RTM_NEWADDR, flags as u16, &msg)
+            .attr(IFA_LOCAL, &octets(&ip))
+            .attr(IFA_ADDRESS, &octets(&ip));
+        existing(self.request(req))
+    }
+
+    fn route(&self, kind: u16, flags: u16, ip: IpAddr, prefix: u32, table: u32) -> Request {
+        let msg = Rtmsg {
+            rtm_family: family(&ip),
+            rtm_dst_len: prefix as u8,
+            rtm_src_len: 0,
+            rtm_tos: 0,
+            rtm_table: if table < 256 { table as u8 } else { 0 },
+            rtm_protocol: RTPROT_BOOT,
+            rtm_scope: RT_SCOPE_LINK,
+            rtm_type: RTN_UNICAST,
+            rtm_flags: 0,
+        };
+        Request::new(kind, flags | libc::NLM_F_ACK as u16, &msg)
+            .attr(RTA_DST, &network(&ip, prefix))
+            .attr_u32(RTA_OIF, self.index)
+            .attr_u32(RTA_TABLE, table)
+    }
+
+    /// Routes the subnet to the interface (the main table if `table` is None)
+    pub fn add_route(&mut self, ip: IpAddr, prefix: u32, table: Option<u32>) -> io::Result<()> {
+        log::debug!("netlink, add route {}/{} (table = {:?})", ip, prefix, table);
+        let flags = (libc::NLM_F_CREATE | libc::NLM_F_EXCL) as u16;
+        let table = table.unwrap_or(RT_TABLE_MAIN);
+        let req = self.route(libc::RTM_NEWROUTE, flags, ip, prefix, table);
+        existing(self.request(req))
+    }
+
+    /// Removes the route of the subnet to the interface (if any)
+    pub fn del_route(&mut self, ip: IpAddr, prefix: u32, table: Option<u32>) -> io::Result<()> {
+        log::debug!(
+            "netlink, remove route {}/{} (table = {:?})",
+            ip,
+            prefix,
+            table
+        );
+        let table = table.unwrap_or(RT_TABLE_MAIN);
+        let req = self.route(libc::RTM_DELROUTE, 0, ip, prefix, table);
+        missing(self.request(req))
+    }
+
+    fn rule(kind: u16, flags: u16, ipv6: bool, rule: Rule) -> Request {
+        let mut hdr = FibRuleHdr {
+            family: if ipv6 { libc::AF_INET6 } else { libc::AF_INET } as u8,
+            dst_len: 0,
+            src_len: 0,
+            tos: 0,
+            table: 0,
+            res1: 0,
+            res2: 0,
+            action: FR_ACT_TO_TBL,
+            flags: 0,
+        };
+        match rule {
+            Rule::NotFwmark { fwmark, table } => {
+                hdr.flags = FIB_RULE_INVERT;
+                Request::new(kind, flags | libc::NLM_F_ACK as u16, &hdr)
+                    .attr_u32(FRA_FWMARK, fwmark)
+                    .attr_u32(FRA_TABLE, table)
+            }
+            Rule::SuppressDefault => Request::new(kind, flags | libc::NLM_F_ACK as u16, &hdr)
+                .attr_u32(FRA_TABLE, RT_TABLE_MAIN)
+                .attr_u32(FRA_SUPPRESS_PREFIXLEN, 0),
+        }
+    }
+
+    /// Adds the routing policy rule (if not already present) with the given priority
+    pub fn add_rule(&mut self, ipv6: bool, rule: Rule, priority: u32) -> io::Result<()> {
+        log::debug!("netlink, add rule {:?} (ipv6 = {})", rule, ipv6);
+        let flags = (libc::NLM_F_CREATE | libc::NLM_F_EXCL) as u16;
+        let req = Self::rule(libc::RTM_NEWRULE, flags, ipv6, rule).attr_u32(FRA_PRIORITY, priority);
+        existing(self.request(req))
+    }
+
+    /// Removes the routing policy rule (if present)
+    pub fn del_rule(&mut self, ipv6: bool, rule: Rule) -> io::Result<()> {
+        log::debug!("netlink, remove rule {:?} (ipv6 = {})", rule, ipv6);
+        let req = Self::rule(libc::RTM_DELRULE, 0, ipv6, rule);
+        missing(self.request(req))
+    }
+
+    /// Lets the reverse path filter consider the fwmark (as wg-quick),
+    /// otherwise replies to the tunnel endpoint are dropped in a full-tunnel setup.
+    pub fn enable_src_valid_mark(&self) -> io::Result<()> {
+        fs::write(SRC_VALID_MARK, b"1")
+    }
+}
+
+// adding an existing address, route or rule is not an error
+fn existing(res: io::Result<()>) -> io::Result<()> {
+    match res {
+        Err(e) if e.raw_os_error() == Some(libc::EEXIST) => Ok(()),
+        res => res,
+    }
+}
+
+// neither is removing a missing one
+fn missing(res: io::Result<()>) -> io::Result<()> {
+    match res {
+        Err(e) if matches!(e.raw_os_error(), Some(libc::ENOENT) | Some(libc::ESRCH)) => Ok(()),
+        res => res,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_encoding() {
+        let msg = Request::new(libc::RTM_NEWRULE, 0, &[1u8, 2, 3])
+            .attr_u32(FRA_FWMARK, 51820)
+            .attr(RTA_DST, &[10, 0])
+            .finish(7);
+
+        // header, body and attributes are aligned to 4 bytes
+        assert_eq!(msg.len(), 16 + 4 + 8 + 8);
+        assert_eq!(u32::from_ne_bytes([msg[0], msg[1], msg[2], msg[3]]), 36);
+        assert_eq!(u16::from_ne_bytes([msg[4], msg[5]]), libc::RTM_NEWRULE);
+        assert_eq!(
+            u16::from_ne_bytes([msg[6], msg[7]]),
+            libc::NLM_F_REQUEST as u16
+        );
+        assert_eq!(u32::from_ne_bytes([msg[8], msg[9], msg[10], msg[11]]), 7);
+        assert_eq!(&msg[16..20], &[1, 2, 3, 0]);
+        assert_eq!(u16::from_ne_bytes([msg[20], msg[21]]), 8);
+        assert_eq!(u16::from_ne_bytes([msg[22], msg[23]]), FRA_FWMARK);
+        assert_eq!(&msg[24..28], &51820u32.to_ne_bytes());
+        assert_eq!(u16::from_ne_bytes([msg[28], msg[29]]), 6);
+        assert_eq!(&msg[32..36], &[10, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_network() {
+        let ip: IpAddr = "10.1.2.3".parse().unwrap();
+        assert_eq!(network(&ip, 32), vec![10, 1, 2, 3]);
+        assert_eq!(network(&ip, 20), vec![10, 1, 0, 0]);
+        assert_eq!(network(&ip, 15), vec![10, 0, 0, 0]);
+        assert_eq!(network(&ip, 0), vec![0, 0, 0, 0]);
+
+        let ip: IpAddr = "fd00::1:2".parse().unwrap();
+        assert_eq!(network(&ip, 128), octets(&ip));
+        assert_eq!(network(&ip, 64), octets(&"fd00::".parse().unwrap()));
+    }
+}
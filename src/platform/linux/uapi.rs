@@ -13,11 +13,20 @@ impl PlatformUAPI for LinuxUAPI {
     type Bind = UnixListener;
 
     fn bind(name: &str) -> Result<UnixListener, io::Error> {
-        let socket_path = format!("{}{}.sock", SOCK_DIR, name);
-        let _ = fs::create_dir_all(SOCK_DIR);
-        let _ = fs::remove_file(&socket_path);
-        UnixListener::bind(socket_path)
+        bind_path(format!("{}{}.sock", SOCK_DIR, name))
     }
+
+    // note: not using the ".sock" suffix,
+    // since wg(8) considers every such socket to be a text UAPI.
+    fn bind_json(name: &str) -> Result<UnixListener, io::Error> {
+        bind_path(format!("{}{}.json", SOCK_DIR, name))
+    }
+}
+
+fn bind_path(socket_path: String) -> Result<UnixListener, io::Error> {
+    let _ = fs::create_dir_all(SOCK_DIR);
+    let _ = fs::remove_file(&socket_path);
+    UnixListener::bind(socket_path)
 }
 
 impl BindUAPI for UnixListener {
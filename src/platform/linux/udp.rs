@@ -3,13 +3,32 @@ use super::super::Endpoint;
 
 use log;
 
+use std::collections::HashMap;
 use std::convert::TryInto;
 use std::io;
 use std::mem;
-use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
 use std::os::unix::io::RawFd;
 use std::ptr;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// path MTU discovery (not exported by all versions of the libc crate)
+const IPV6_MTU_DISCOVER: libc::c_int = 23;
+const IPV6_RECVERR: libc::c_int = 25;
+const IPV6_PMTUDISC_WANT: libc::c_int = 1;
+
+// socket buffers beyond net.core.rmem_max / wmem_max (requires CAP_NET_ADMIN)
+const SO_SNDBUFFORCE: libc::c_int = 32;
+const SO_RCVBUFFORCE: libc::c_int = 33;
+
+// the TOS / traffic class of received and sent packets
+const IP_RECVTOS: libc::c_int = 13;
+const IPV6_RECVTCLASS: libc::c_int = 66;
+const IPV6_TCLASS: libc::c_int = 67;
+
+// the kernel forgets learned path MTUs after 10 minutes (net.ipv4.route.mtu_expires)
+const PATH_MTU_EXPIRY: Duration = Duration::from_secs(600);
 
 pub struct FD(RawFd);
 
@@ -24,26 +43,118 @@ impl Drop for FD {
     }
 }
 
-#[repr(C, align(1))]
-struct ControlHeaderV4 {
-    hdr: libc::cmsghdr,
-    info: libc::in_pktinfo,
+/* The control messages (ancillary data) of a packet:
+ * the packet info (source address and interface) and the TOS / traffic class.
+ */
+struct Control {
+    buf: [u64; 8], // aligned for cmsghdr
+    len: usize,
+}
+
+impl Control {
+    fn new() -> Control {
+        Control {
+            buf: [0; 8],
+            len: 0,
+        }
+    }
+
+    // the control messages of an outgoing IPv4 packet (the TOS is omitted if 0)
+    fn v4(info: Option<libc::in_pktinfo>, tos: u8) -> Control {
+        let mut control = Control::new();
+        if let Some(info) = info {
+            control.push(libc::IPPROTO_IP, libc::IP_PKTINFO, info);
+        }
+        if tos != 0 {
+            control.push(libc::IPPROTO_IP, libc::IP_TOS, libc::c_int::from(tos));
+        }
+        control
+    }
+
+    // the control messages of an outgoing IPv6 packet (the traffic class is omitted if 0)
+    fn v6(info: Option<libc::in6_pktinfo>, tos: u8) -> Control {
+        let mut control = Control::new();
+        if let Some(info) = info {
+            control.push(libc::IPPROTO_IPV6, libc::IPV6_PKTINFO, info);
+        }
+        if tos != 0 {
+            control.push(libc::IPPROTO_IPV6, IPV6_TCLASS, libc::c_int::from(tos));
+        }
+        control
+    }
+
+    fn push<V: Copy>(&mut self, level: libc::c_int, kind: libc::c_int, value: V) {
+        let size = mem::size_of::<V>() as u32;
+        let space = unsafe { libc::CMSG_SPACE(size) } as usize;
+        debug_assert!(self.len + space <= mem::size_of_val(&self.buf));
+        unsafe {
+            let hdr = (self.buf.as_mut_ptr() as *mut u8).add(self.len) as *mut libc::cmsghdr;
+            (*hdr).cmsg_len = libc::CMSG_LEN(size) as _;
+            (*hdr).cmsg_level = level;
+            (*hdr).cmsg_type = kind;
+            ptr::write_unaligned(libc::CMSG_DATA(hdr) as *mut V, value);
+        }
+        self.len += space;
+    }
+
+    fn ptr(&mut self) -> *mut libc::c_void {
+        self.buf.as_mut_ptr() as *mut libc::c_void
+    }
+}
+
+#[repr(C)]
+struct SockExtendedErr {
+    ee_errno: u32,
+    ee_origin: u8,
+    ee_type: u8,
+    ee_code: u8,
+    ee_pad: u8,
+    ee_info: u32, // the MTU for EMSGSIZE
+    ee_data: u32,
 }
 
-#[repr(C, align(1))]
-struct ControlHeaderV6 {
-    hdr: libc::cmsghdr,
-    info: libc::in6_pktinfo,
+/* Path MTUs towards destinations learned from the error queues of the sockets:
+ * ICMP "fragmentation needed" / "packet too big" messages.
+ * Path MTU discovery is only "wanted" (IP_PMTUDISC_WANT):
+ * outer packets exceeding the path MTU are fragmented by the kernel rather than rejected,
+ * the router only refuses inner packets which may not be fragmented.
+ *
+ * Shared between the readers and the writer of a bind.
+ */
+#[derive(Default)]
+pub struct PathMtu {
+    mtus: Mutex<HashMap<IpAddr, (usize, Instant)>>,
+}
+
+impl PathMtu {
+    fn update(&self, dst: IpAddr, mtu: usize) {
+        log::debug!("linux udp, path mtu towards {} is {}", dst, mtu);
+        self.mtus.lock().unwrap().insert(dst, (mtu, Instant::now()));
+    }
+
+    fn get(&self, dst: &IpAddr) -> Option<usize> {
+        let mut mtus = self.mtus.lock().unwrap();
+        match mtus.get(dst) {
+            Some((mtu, learned)) if learned.elapsed() < PATH_MTU_EXPIRY => Some(*mtu),
+            Some(_) => {
+                mtus.remove(dst);
+                None
+            }
+            None => None,
+        }
+    }
 }
 
 pub struct EndpointV4 {
     dst: libc::sockaddr_in, // destination IP
     info: libc::in_pktinfo, // src & ifindex
+    tos: u8,                // TOS of the received packet
 }
 
 pub struct EndpointV6 {
     dst: libc::sockaddr_in6, // destination IP
     info: libc::in6_pktinfo, // src & zone id
+    tos: u8,                 // traffic class of the received packet
 }
 
 pub struct LinuxUDP();
@@ -55,14 +166,15 @@ pub struct LinuxOwner {
 }
 
 pub enum LinuxUDPReader {
-    V4(Arc<FD>),
-    V6(Arc<FD>),
+    V4(Arc<FD>, Arc<PathMtu>),
+    V6(Arc<FD>, Arc<PathMtu>),
 }
 
 #[derive(Clone)]
 pub struct LinuxUDPWriter {
-    sock4: Arc<FD>,
-    sock6: Arc<FD>,
+    sock4: Option<Arc<FD>>, // None if IPv4 is not bound
+    sock6: Option<Arc<FD>>, // None if IPv6 is not bound
+    pmtu: Arc<PathMtu>,
 }
 
 pub enum LinuxEndpoint {
@@ -107,6 +219,26 @@ fn setsockopt<V: Sized>(
 }
 
 #[inline(always)]
+// restrict the socket to an interface (SO_BINDTODEVICE), retaining the errno (e.g. ENODEV)
+fn bind_to_device(fd: RawFd, name: &str) -> Result<(), io::Error> {
+    let res = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_BINDTODEVICE,
+            name.as_ptr() as *const libc::c_void,
+            name.len().try_into().unwrap(),
+        )
+    };
+    if res == 0 {
+        Ok(())
+    } else {
+        let err = io::Error::from_raw_os_error(errno());
+        log::debug!("failed to bind to device {} (errno = {})", name, err);
+        Err(err)
+    }
+}
+
 fn setsockopt_int(
     fd: RawFd,
     level: libc::c_int,
@@ -116,14 +248,46 @@ fn setsockopt_int(
     setsockopt(fd, level, name, &value)
 }
 
-#[allow(non_snake_case)]
-const fn CMSG_ALIGN(len: usize) -> usize {
-    ((len) + mem::size_of::<u32>() - 1) & !(mem::size_of::<u32>() - 1)
+// set the size of a socket buffer,
+// exceeding the system limit if privileged (falls back to the limited size otherwise)
+fn set_buffer(
+    fd: RawFd,
+    force: libc::c_int,
+    name: libc::c_int,
+    size: usize,
+) -> Result<(), io::Error> {
+    let size = size.min(libc::c_int::MAX as usize) as libc::c_int;
+    if setsockopt_int(fd, libc::SOL_SOCKET, force, size).is_ok() {
+        return Ok(());
+    }
+    log::debug!(
+        "failed to force socket buffer size (errno = {}), limited by the system",
+        errno()
+    );
+    setsockopt_int(fd, libc::SOL_SOCKET, name, size)
 }
 
-#[allow(non_snake_case)]
-const fn CMSG_LEN(len: usize) -> usize {
-    CMSG_ALIGN(len + mem::size_of::<libc::cmsghdr>())
+/* Applies the socket buffer sizes and the TTL / hop limit of the bind options,
+ * and enables the reception of the TOS / traffic class (for ECN).
+ */
+fn set_options(fd: RawFd, ipv6: bool, options: &BindOptions) -> Result<(), io::Error> {
+    if let Some(size) = options.rcvbuf {
+        set_buffer(fd, SO_RCVBUFFORCE, libc::SO_RCVBUF, size)?;
+    }
+    if let Some(size) = options.sndbuf {
+        set_buffer(fd, SO_SNDBUFFORCE, libc::SO_SNDBUF, size)?;
+    }
+    if ipv6 {
+        if let Some(ttl) = options.ttl {
+            setsockopt_int(fd, libc::IPPROTO_IPV6, libc::IPV6_UNICAST_HOPS, ttl.into())?;
+        }
+        setsockopt_int(fd, libc::IPPROTO_IPV6, IPV6_RECVTCLASS, 1)
+    } else {
+        if let Some(ttl) = options.ttl {
+            setsockopt_int(fd, libc::IPPROTO_IP, libc::IP_TTL, ttl.into())?;
+        }
+        setsockopt_int(fd, libc::IPPROTO_IP, IP_RECVTOS, 1)
+    }
 }
 
 #[inline(always)]
@@ -131,7 +295,89 @@ fn safe_cast<T, D>(v: &mut T) -> *mut D {
     (v as *mut T) as *mut D
 }
 
+/* Reads the error queue of the socket (without blocking),
+ * recording the path MTUs reported by EMSGSIZE errors.
+ *
+ * Returns the number of errors read.
+ */
+fn read_errors(fd: RawFd, pmtu: &PathMtu) -> usize {
+    let mut count = 0;
+    loop {
+        let mut buf = [0u8; 1]; // the (truncated) offending packet
+        let mut iovs: [libc::iovec; 1] = [libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut core::ffi::c_void,
+            iov_len: buf.len(),
+        }];
+        let mut dst: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let mut control = [0u64; 64];
+        let mut hdr = libc::msghdr {
+            msg_name: safe_cast(&mut dst),
+            msg_namelen: mem::size_of_val(&dst) as u32,
+            msg_iov: iovs.as_mut_ptr(),
+            msg_iovlen: iovs.len(),
+            msg_control: safe_cast(&mut control),
+            msg_controllen: mem::size_of_val(&control),
+            msg_flags: 0,
+        };
+
+        let len = unsafe {
+            libc::recvmsg(
+                fd,
+                &mut hdr as *mut libc::msghdr,
+                libc::MSG_ERRQUEUE | libc::MSG_DONTWAIT,
+            )
+        };
+        if len < 0 {
+            return count;
+        }
+        count += 1;
+
+        // the destination of the offending packet
+        let addr = match dst.ss_family as libc::c_int {
+            libc::AF_INET => {
+                let dst: &libc::sockaddr_in = unsafe { &*(&dst as *const _ as *const _) };
+                IpAddr::V4(Ipv4Addr::from(u32::from_be(dst.sin_addr.s_addr)))
+            }
+            libc::AF_INET6 => {
+                let dst: &libc::sockaddr_in6 = unsafe { &*(&dst as *const _ as *const _) };
+                IpAddr::V6(Ipv6Addr::from(dst.sin6_addr.s6_addr))
+            }
+            _ => continue,
+        };
+
+        let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&hdr) };
+        while !cmsg.is_null() {
+            let (level, kind) = unsafe { ((*cmsg).cmsg_level, (*cmsg).cmsg_type) };
+            if (level, kind) == (libc::IPPROTO_IP, libc::IP_RECVERR)
+                || (level, kind) == (libc::IPPROTO_IPV6, IPV6_RECVERR)
+            {
+                let err: SockExtendedErr =
+                    unsafe { ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const _) };
+                log::trace!(
+                    "linux udp, error (errno = {}, origin = {}, type = {}, code = {}, info = {})",
+                    err.ee_errno,
+                    err.ee_origin,
+                    err.ee_type,
+                    err.ee_code,
+                    err.ee_info
+                );
+                if err.ee_errno == libc::EMSGSIZE as u32 && err.ee_info > 0 {
+                    pmtu.update(addr, err.ee_info as usize);
+                }
+            }
+            cmsg = unsafe { libc::CMSG_NXTHDR(&hdr, cmsg) };
+        }
+    }
+}
+
 impl Endpoint for LinuxEndpoint {
+    fn tos(&self) -> u8 {
+        match self {
+            LinuxEndpoint::V4(EndpointV4 { tos, .. }) => *tos,
+            LinuxEndpoint::V6(EndpointV6 { tos, .. }) => *tos,
+        }
+    }
+
     fn clear_src(&mut self) {
         match self {
             LinuxEndpoint::V4(EndpointV4 { ref mut info, .. }) => {
@@ -161,6 +407,7 @@ impl Endpoint for LinuxEndpoint {
                     ipi_spec_dst: libc::in_addr { s_addr: 0 }, // src IP (dst of incoming packet)
                     ipi_addr: libc::in_addr { s_addr: 0 },
                 },
+                tos: 0,
             }),
             SocketAddr::V6(addr) => LinuxEndpoint::V6(EndpointV6 {
                 dst: libc::sockaddr_in6 {
@@ -176,6 +423,7 @@ impl Endpoint for LinuxEndpoint {
                     ipi6_addr: libc::in6_addr { s6_addr: [0; 16] }, // src IP
                     ipi6_ifindex: 0,                                // zone id
                 },
+                tos: 0,
             }),
         }
     }
@@ -199,7 +447,11 @@ impl Endpoint for LinuxEndpoint {
 }
 
 impl LinuxUDPReader {
-    fn read6(fd: RawFd, buf: &mut [u8]) -> Result<(usize, LinuxEndpoint), io::Error> {
+    fn read6(
+        fd: RawFd,
+        pmtu: &PathMtu,
+        buf: &mut [u8],
+    ) -> Result<(usize, LinuxEndpoint), io::Error> {
         log::trace!(
             "receive IPv6 packet (block), (fd {}, max-len {})",
             fd,
@@ -213,23 +465,23 @@ impl LinuxUDPReader {
             iov_len: buf.len(),
         }];
         let mut src: libc::sockaddr_in6 = unsafe { mem::MaybeUninit::uninit().assume_init() };
-        let mut control: ControlHeaderV6 = unsafe { mem::MaybeUninit::uninit().assume_init() };
+        let mut control = Control::new();
         let mut hdr = libc::msghdr {
             msg_name: safe_cast(&mut src),
             msg_namelen: mem::size_of_val(&src) as u32,
             msg_iov: iovs.as_mut_ptr(),
             msg_iovlen: iovs.len(),
-            msg_control: safe_cast(&mut control),
-            msg_controllen: mem::size_of_val(&control),
+            msg_control: control.ptr(),
+            msg_controllen: mem::size_of_val(&control.buf),
             msg_flags: 0,
         };
 
-        debug_assert!(
-            hdr.msg_controllen
-                >= mem::size_of::<libc::cmsghdr>() + mem::size_of::<libc::in6_pktinfo>(),
-        );
+        let mut len = unsafe { libc::recvmsg(fd, &mut hdr as *mut libc::msghdr, 0) };
 
-        let len = unsafe { libc::recvmsg(fd, &mut hdr as *mut libc::msghdr, 0) };
+        // errors reported by ICMP are queued on the socket, retry after reading them
+        while len < 0 && read_errors(fd, pmtu) > 0 {
+            len = unsafe { libc::recvmsg(fd, &mut hdr as *mut libc::msghdr, 0) };
+        }
 
         if len <= 0 {
             // TODO: FIX!
@@ -244,16 +496,40 @@ impl LinuxUDPReader {
             ));
         }
 
+        // the packet info and the TOS / traffic class
+        let mut info: libc::in6_pktinfo = unsafe { mem::zeroed() };
+        let mut tos = 0;
+        let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&hdr) };
+        while !cmsg.is_null() {
+            unsafe {
+                match ((*cmsg).cmsg_level, (*cmsg).cmsg_type) {
+                    (libc::IPPROTO_IPV6, libc::IPV6_PKTINFO) => {
+                        info = ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const _)
+                    }
+                    (libc::IPPROTO_IPV6, IPV6_TCLASS) => {
+                        tos = ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::c_int) as u8
+                    }
+                    _ => (),
+                }
+                cmsg = libc::CMSG_NXTHDR(&hdr, cmsg);
+            }
+        }
+
         Ok((
             len.try_into().unwrap(),
             LinuxEndpoint::V6(EndpointV6 {
-                info: control.info, // save pktinfo (sticky source)
-                dst: src,           // our future destination is the source address
+                info,     // save pktinfo (sticky source)
+                dst: src, // our future destination is the source address
+                tos,
             }),
         ))
     }
 
-    fn read4(fd: RawFd, buf: &mut [u8]) -> Result<(usize, LinuxEndpoint), io::Error> {
+    fn read4(
+        fd: RawFd,
+        pmtu: &PathMtu,
+        buf: &mut [u8],
+    ) -> Result<(usize, LinuxEndpoint), io::Error> {
         log::trace!(
             "receive IPv4 packet (block), (fd {}, max-len {})",
             fd,
@@ -267,23 +543,23 @@ impl LinuxUDPReader {
             iov_len: buf.len(),
         }];
         let mut src: libc::sockaddr_in = unsafe { mem::MaybeUninit::uninit().assume_init() };
-        let mut control: ControlHeaderV4 = unsafe { mem::MaybeUninit::uninit().assume_init() };
+        let mut control = Control::new();
         let mut hdr = libc::msghdr {
             msg_name: safe_cast(&mut src),
             msg_namelen: mem::size_of_val(&src) as u32,
             msg_iov: iovs.as_mut_ptr(),
             msg_iovlen: iovs.len(),
-            msg_control: safe_cast(&mut control),
-            msg_controllen: mem::size_of_val(&control),
+            msg_control: control.ptr(),
+            msg_controllen: mem::size_of_val(&control.buf),
             msg_flags: 0,
         };
 
-        debug_assert!(
-            hdr.msg_controllen
-                >= mem::size_of::<libc::cmsghdr>() + mem::size_of::<libc::in_pktinfo>(),
-        );
+        let mut len = unsafe { libc::recvmsg(fd, &mut hdr as *mut libc::msghdr, 0) };
 
-        let len = unsafe { libc::recvmsg(fd, &mut hdr as *mut libc::msghdr, 0) };
+        // errors reported by ICMP are queued on the socket, retry after reading them
+        while len < 0 && read_errors(fd, pmtu) > 0 {
+            len = unsafe { libc::recvmsg(fd, &mut hdr as *mut libc::msghdr, 0) };
+        }
 
         if len <= 0 {
             return Err(io::Error::new(
@@ -297,11 +573,29 @@ impl LinuxUDPReader {
             ));
         }
 
+        // the packet info and the TOS / traffic class
+        let mut info: libc::in_pktinfo = unsafe { mem::zeroed() };
+        let mut tos = 0;
+        let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&hdr) };
+        while !cmsg.is_null() {
+            unsafe {
+                match ((*cmsg).cmsg_level, (*cmsg).cmsg_type) {
+                    (libc::IPPROTO_IP, libc::IP_PKTINFO) => {
+                        info = ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const _)
+                    }
+                    (libc::IPPROTO_IP, libc::IP_TOS) => tos = *libc::CMSG_DATA(cmsg),
+                    _ => (),
+                }
+                cmsg = libc::CMSG_NXTHDR(&hdr, cmsg);
+            }
+        }
+
         Ok((
             len.try_into().unwrap(),
             LinuxEndpoint::V4(EndpointV4 {
-                info: control.info, // save pktinfo (sticky source)
-                dst: src,           // our future destination is the source address
+                info,     // save pktinfo (sticky source)
+                dst: src, // our future destination is the source address
+                tos,
             }),
         ))
     }
@@ -312,14 +606,20 @@ impl Reader<LinuxEndpoint> for LinuxUDPReader {
 
     fn read(&self, buf: &mut [u8]) -> Result<(usize, LinuxEndpoint), Self::Error> {
         match self {
-            Self::V4(fd) => Self::read4(fd.0, buf),
-            Self::V6(fd) => Self::read6(fd.0, buf),
+            Self::V4(fd, pmtu) => Self::read4(fd.0, pmtu, buf),
+            Self::V6(fd, pmtu) => Self::read6(fd.0, pmtu, buf),
         }
     }
 }
 
 impl LinuxUDPWriter {
-    fn write6(fd: RawFd, buf: &[u8], dst: &mut EndpointV6) -> Result<(), io::Error> {
+    fn write6(
+        fd: RawFd,
+        pmtu: &PathMtu,
+        buf: &[u8],
+        dst: &mut EndpointV6,
+        tos: u8,
+    ) -> Result<(), io::Error> {
         log::debug!("sending IPv6 packet ({} fd, {} bytes)", fd, buf.len());
 
         let mut iovs: [libc::iovec; 1] = [libc::iovec {
@@ -327,20 +627,7 @@ impl LinuxUDPWriter {
             iov_len: buf.len(),
         }];
 
-        let mut control = ControlHeaderV6 {
-            hdr: libc::cmsghdr {
-                cmsg_len: CMSG_LEN(mem::size_of::<libc::in6_pktinfo>()),
-                cmsg_level: libc::IPPROTO_IPV6,
-                cmsg_type: libc::IPV6_PKTINFO,
-            },
-            info: dst.info,
-        };
-
-        debug_assert_eq!(
-            control.hdr.cmsg_len % mem::size_of::<u32>(),
-            0,
-            "cmsg_len must be aligned to a long"
-        );
+        let mut control = Control::v6(Some(dst.info), tos);
 
         debug_assert_eq!(
             dst.dst.sin6_family,
@@ -353,18 +640,25 @@ impl LinuxUDPWriter {
             msg_namelen: mem::size_of_val(&dst.dst) as u32,
             msg_iov: iovs.as_mut_ptr(),
             msg_iovlen: iovs.len(),
-            msg_control: safe_cast(&mut control),
-            msg_controllen: mem::size_of_val(&control),
+            msg_control: control.ptr(),
+            msg_controllen: control.len,
             msg_flags: 0,
         };
 
-        let ret = unsafe { libc::sendmsg(fd, &hdr, 0) };
+        let mut ret = unsafe { libc::sendmsg(fd, &hdr, 0) };
+
+        // errors reported by ICMP (for any destination) are queued on the socket,
+        // retry after reading them
+        if ret < 0 && read_errors(fd, pmtu) > 0 {
+            ret = unsafe { libc::sendmsg(fd, &hdr, 0) };
+        }
 
         if ret < 0 {
             if errno() == libc::EINVAL {
                 log::trace!("clear source and retry");
-                hdr.msg_control = ptr::null_mut();
-                hdr.msg_controllen = 0;
+                control = Control::v6(None, tos);
+                hdr.msg_control = control.ptr();
+                hdr.msg_controllen = control.len;
                 dst.info = unsafe { mem::zeroed() };
                 if unsafe { libc::sendmsg(fd, &hdr, 0) } < 0 {
                     return Err(io::Error::new(
@@ -384,7 +678,13 @@ impl LinuxUDPWriter {
         Ok(())
     }
 
-    fn write4(fd: RawFd, buf: &[u8], dst: &mut EndpointV4) -> Result<(), io::Error> {
+    fn write4(
+        fd: RawFd,
+        pmtu: &PathMtu,
+        buf: &[u8],
+        dst: &mut EndpointV4,
+        tos: u8,
+    ) -> Result<(), io::Error> {
         log::debug!("sending IPv4 packet ({} fd, {} bytes)", fd, buf.len());
 
         let mut iovs: [libc::iovec; 1] = [libc::iovec {
@@ -392,20 +692,7 @@ impl LinuxUDPWriter {
             iov_len: buf.len(),
         }];
 
-        let mut control = ControlHeaderV4 {
-            hdr: libc::cmsghdr {
-                cmsg_len: CMSG_LEN(mem::size_of::<libc::in_pktinfo>()),
-                cmsg_level: libc::IPPROTO_IP,
-                cmsg_type: libc::IP_PKTINFO,
-            },
-            info: dst.info,
-        };
-
-        debug_assert_eq!(
-            control.hdr.cmsg_len % mem::size_of::<u32>(),
-            0,
-            "cmsg_len must be aligned to a long"
-        );
+        let mut control = Control::v4(Some(dst.info), tos);
 
         debug_assert_eq!(
             dst.dst.sin_family,
@@ -418,18 +705,25 @@ impl LinuxUDPWriter {
             msg_namelen: mem::size_of_val(&dst.dst) as u32,
             msg_iov: iovs.as_mut_ptr(),
             msg_iovlen: iovs.len(),
-            msg_control: safe_cast(&mut control),
-            msg_controllen: mem::size_of_val(&control),
+            msg_control: control.ptr(),
+            msg_controllen: control.len,
             msg_flags: 0,
         };
 
-        let ret = unsafe { libc::sendmsg(fd, &hdr, 0) };
+        let mut ret = unsafe { libc::sendmsg(fd, &hdr, 0) };
+
+        // errors reported by ICMP (for any destination) are queued on the socket,
+        // retry after reading them
+        if ret < 0 && read_errors(fd, pmtu) > 0 {
+            ret = unsafe { libc::sendmsg(fd, &hdr, 0) };
+        }
 
         if ret < 0 {
             if errno() == libc::EINVAL {
                 log::trace!("clear source and retry");
-                hdr.msg_control = ptr::null_mut();
-                hdr.msg_controllen = 0;
+                control = Control::v4(None, tos);
+                hdr.msg_control = control.ptr();
+                hdr.msg_controllen = control.len;
                 dst.info = unsafe { mem::zeroed() };
                 if unsafe { libc::sendmsg(fd, &hdr, 0) } < 0 {
                     return Err(io::Error::new(
@@ -450,15 +744,40 @@ impl LinuxUDPWriter {
     }
 }
 
+// the error of writes to an endpoint of an IP version without a bound socket
+fn unbound(version: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::AddrNotAvailable,
+        format!(
+            "no {} socket bound (disabled by the bind family or unavailable)",
+            version
+        ),
+    )
+}
+
 impl Writer<LinuxEndpoint> for LinuxUDPWriter {
     type Error = io::Error;
 
     fn write(&self, buf: &[u8], dst: &mut LinuxEndpoint) -> Result<(), Self::Error> {
+        self.write_tos(buf, dst, 0)
+    }
+
+    fn write_tos(&self, buf: &[u8], dst: &mut LinuxEndpoint, tos: u8) -> Result<(), Self::Error> {
         match dst {
-            LinuxEndpoint::V4(ref mut end) => Self::write4(self.sock4.0, buf, end),
-            LinuxEndpoint::V6(ref mut end) => Self::write6(self.sock6.0, buf, end),
+            LinuxEndpoint::V4(ref mut end) => match self.sock4.as_ref() {
+                Some(fd) => Self::write4(fd.0, &self.pmtu, buf, end, tos),
+                None => Err(unbound("IPv4")),
+            },
+            LinuxEndpoint::V6(ref mut end) => match self.sock6.as_ref() {
+                Some(fd) => Self::write6(fd.0, &self.pmtu, buf, end, tos),
+                None => Err(unbound("IPv6")),
+            },
         }
     }
+
+    fn path_mtu(&self, dst: &LinuxEndpoint) -> Option<usize> {
+        self.pmtu.get(&dst.into_address().ip())
+    }
 }
 
 impl Owner for LinuxOwner {
@@ -504,38 +823,71 @@ impl UDP for LinuxUDP {
 }
 
 impl LinuxUDP {
-    /* Bind on all IPv6 interfaces
+    /* Bind on the IPv6 address (all IPv6 addresses if unspecified)
      *
      * Arguments:
      *
      * - 'port', port to bind to (0 = any)
+     * - 'address', local address to bind to (None = any)
+     * - 'options', interface, buffer sizes and TTL of the socket
      *
      * Returns:
      *
      * Returns a tuple of the resulting port and socket.
      */
-    fn bind6(port: u16) -> Result<(u16, RawFd), io::Error> {
+    fn bind6(
+        port: u16,
+        address: Option<Ipv6Addr>,
+        options: &BindOptions,
+    ) -> Result<(u16, RawFd), io::Error> {
         log::trace!("attempting to bind on IPv6 (port {})", port);
 
         // create socket fd
         let fd: RawFd = unsafe { libc::socket(libc::AF_INET6, libc::SOCK_DGRAM, 0) };
         if fd < 0 {
-            log::debug!("failed to create IPv6 socket (errno = {})", errno());
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                "failed to create socket",
-            ));
+            // retain errno (e.g. EAFNOSUPPORT) to fall back to the other IP version
+            let err = io::Error::from_raw_os_error(errno());
+            log::debug!("failed to create IPv6 socket (errno = {})", err);
+            return Err(err);
         }
 
+        // configure and bind the socket, closing it on failure
+        match Self::setup6(fd, port, address, options) {
+            Ok(new_port) => Ok((new_port, fd)),
+            Err(err) => {
+                unsafe { libc::close(fd) };
+                Err(err)
+            }
+        }
+    }
+
+    /* Configures the IPv6 socket and binds it, returns the resulting port */
+    fn setup6(
+        fd: RawFd,
+        port: u16,
+        address: Option<Ipv6Addr>,
+        options: &BindOptions,
+    ) -> Result<u16, io::Error> {
+        if let Some(name) = options.interface.as_deref() {
+            bind_to_device(fd, name)?;
+        }
         setsockopt_int(fd, libc::SOL_SOCKET, libc::SO_REUSEADDR, 1)?;
         setsockopt_int(fd, libc::IPPROTO_IPV6, libc::IPV6_RECVPKTINFO, 1)?;
         setsockopt_int(fd, libc::IPPROTO_IPV6, libc::IPV6_V6ONLY, 1)?;
-
-        const INADDR_ANY: libc::in6_addr = libc::in6_addr { s6_addr: [0; 16] };
+        setsockopt_int(
+            fd,
+            libc::IPPROTO_IPV6,
+            IPV6_MTU_DISCOVER,
+            IPV6_PMTUDISC_WANT,
+        )?;
+        setsockopt_int(fd, libc::IPPROTO_IPV6, IPV6_RECVERR, 1)?;
+        set_options(fd, true, options)?;
 
         // bind
         let mut sockaddr = libc::sockaddr_in6 {
-            sin6_addr: INADDR_ANY,
+            sin6_addr: libc::in6_addr {
+                s6_addr: address.unwrap_or(Ipv6Addr::UNSPECIFIED).octets(),
+            },
             sin6_family: libc::AF_INET6 as libc::sa_family_t,
             sin6_port: port.to_be(), // convert to network (big-endian) byte-order
             sin6_scope_id: 0,
@@ -550,11 +902,10 @@ impl LinuxUDP {
             )
         };
         if err != 0 {
-            log::debug!("failed to bind IPv6 socket (errno = {})", errno());
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                "failed to create socket",
-            ));
+            // retain errno (e.g. EADDRINUSE) for the configuration interface
+            let err = io::Error::from_raw_os_error(errno());
+            log::debug!("failed to bind IPv6 socket (errno = {})", err);
+            return Err(err);
         }
 
         // get the assigned port
@@ -580,40 +931,73 @@ impl LinuxUDP {
         debug_assert_eq!(sockaddr.sin6_family, libc::AF_INET6 as libc::sa_family_t);
         debug_assert_eq!(new_port, if port != 0 { port } else { new_port });
         log::trace!("bound IPv6 socket (port {}, fd {})", new_port, fd);
-        return Ok((new_port, fd));
+        Ok(new_port)
     }
 
-    /* Bind on all IPv4 interfaces.
+    /* Bind on the IPv4 address (all IPv4 addresses if unspecified).
      *
      * Arguments:
      *
      * - 'port', port to bind to (0 = any)
+     * - 'address', local address to bind to (None = any)
+     * - 'options', interface, buffer sizes and TTL of the socket
      *
      * Returns:
      *
      * Returns a tuple of the resulting port and socket.
      */
-    fn bind4(port: u16) -> Result<(u16, RawFd), io::Error> {
+    fn bind4(
+        port: u16,
+        address: Option<Ipv4Addr>,
+        options: &BindOptions,
+    ) -> Result<(u16, RawFd), io::Error> {
         log::trace!("attempting to bind on IPv4 (port {})", port);
 
         // create socket fd
         let fd: RawFd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0) };
         if fd < 0 {
-            log::debug!("failed to create IPv4 socket (errno = {})", errno());
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                "failed to create socket",
-            ));
+            // retain errno (e.g. EAFNOSUPPORT) to fall back to the other IP version
+            let err = io::Error::from_raw_os_error(errno());
+            log::debug!("failed to create IPv4 socket (errno = {})", err);
+            return Err(err);
         }
 
+        // configure and bind the socket, closing it on failure
+        match Self::setup4(fd, port, address, options) {
+            Ok(new_port) => Ok((new_port, fd)),
+            Err(err) => {
+                unsafe { libc::close(fd) };
+                Err(err)
+            }
+        }
+    }
+
+    /* Configures the IPv4 socket and binds it, returns the resulting port */
+    fn setup4(
+        fd: RawFd,
+        port: u16,
+        address: Option<Ipv4Addr>,
+        options: &BindOptions,
+    ) -> Result<u16, io::Error> {
+        if let Some(name) = options.interface.as_deref() {
+            bind_to_device(fd, name)?;
+        }
         setsockopt_int(fd, libc::SOL_SOCKET, libc::SO_REUSEADDR, 1)?;
         setsockopt_int(fd, libc::IPPROTO_IP, libc::IP_PKTINFO, 1)?;
-
-        const INADDR_ANY: libc::in_addr = libc::in_addr { s_addr: 0 };
+        setsockopt_int(
+            fd,
+            libc::IPPROTO_IP,
+            libc::IP_MTU_DISCOVER,
+            libc::IP_PMTUDISC_WANT,
+        )?;
+        setsockopt_int(fd, libc::IPPROTO_IP, libc::IP_RECVERR, 1)?;
+        set_options(fd, false, options)?;
 
         // bind
         let mut sockaddr = libc::sockaddr_in {
-            sin_addr: INADDR_ANY,
+            sin_addr: libc::in_addr {
+                s_addr: u32::from_ne_bytes(address.unwrap_or(Ipv4Addr::UNSPECIFIED).octets()),
+            },
             sin_family: libc::AF_INET as libc::sa_family_t,
             sin_port: port.to_be(),
             sin_zero: [0; 8],
@@ -627,11 +1011,10 @@ impl LinuxUDP {
             )
         };
         if err != 0 {
-            log::debug!("failed to bind IPv4 socket (errno = {})", errno());
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                "failed to create socket",
-            ));
+            // retain errno (e.g. EADDRINUSE) for the configuration interface
+            let err = io::Error::from_raw_os_error(errno());
+            log::debug!("failed to bind IPv4 socket (errno = {})", err);
+            return Err(err);
         }
 
         // get the assigned port
@@ -657,33 +1040,62 @@ impl LinuxUDP {
         debug_assert_eq!(sockaddr.sin_family, libc::AF_INET as libc::sa_family_t);
         debug_assert_eq!(new_port, if port != 0 { port } else { new_port });
         log::trace!("bound IPv4 socket (port {}, fd {})", new_port, fd);
-        return Ok((new_port, fd));
+        Ok(new_port)
     }
 }
 
 impl PlatformUDP for LinuxUDP {
     type Owner = LinuxOwner;
 
-    fn bind(mut port: u16) -> Result<(Vec<Self::Reader>, Self::Writer, Self::Owner), Self::Error> {
-        log::debug!("bind to port {}", port);
-
-        // attempt to bind on ipv6
-        let bind6 = Self::bind6(port);
+    fn bind(
+        mut port: u16,
+        options: &BindOptions,
+    ) -> Result<(Vec<Self::Reader>, Self::Writer, Self::Owner), Self::Error> {
+        log::debug!("bind to port {} ({:?})", port, options);
+        let unsupported = || Err(io::Error::from_raw_os_error(libc::EAFNOSUPPORT));
+        let ipv4 = options.family != BindFamily::Ipv6;
+        let ipv6 = options.family != BindFamily::Ipv4;
+
+        // attempt to bind on ipv6 (unless disabled or bound to an IPv4 address)
+        let bind6 = match options.address {
+            None if ipv6 => Self::bind6(port, None, options),
+            Some(IpAddr::V6(addr)) if ipv6 => Self::bind6(port, Some(addr), options),
+            _ => unsupported(),
+        };
         if let Ok((new_port, _)) = bind6 {
             port = new_port;
         }
 
-        // attempt to bind on ipv4 on the same port
-        let bind4 = Self::bind4(port);
+        // attempt to bind on ipv4 on the same port (unless disabled or bound to an IPv6 address)
+        let bind4 = match options.address {
+            None if ipv4 => Self::bind4(port, None, options),
+            Some(IpAddr::V4(addr)) if ipv4 => Self::bind4(port, Some(addr), options),
+            _ => unsupported(),
+        };
         if let Ok((new_port, _)) = bind4 {
             port = new_port;
         }
 
         // check if failed to bind on both
-        if bind4.is_err() && bind6.is_err() {
-            log::trace!("failed to bind for either IP version");
-            return Err(bind6.unwrap_err());
-        }
+        // (report the IPv4 error if IPv6 is not attempted or not supported by the host)
+        let (bind6, bind4) = match (bind6, bind4) {
+            (Err(err6), Err(err4)) => {
+                log::trace!("failed to bind for either IP version");
+                return match err6.raw_os_error() {
+                    Some(libc::EAFNOSUPPORT) => Err(err4),
+                    _ => Err(err6),
+                };
+            }
+            (Err(err), Ok(bind4)) if ipv6 && options.address.is_none() => {
+                log::info!("IPv6 unavailable, bound IPv4 only: {}", err);
+                (Err(err), Ok(bind4))
+            }
+            (Ok(bind6), Err(err)) if ipv4 && options.address.is_none() => {
+                log::info!("IPv4 unavailable, bound IPv6 only: {}", err);
+                (Ok(bind6), Err(err))
+            }
+            binds => binds,
+        };
 
         let sock6 = bind6.ok().map(|(_, fd)| Arc::new(FD(fd)));
         let sock4 = bind4.ok().map(|(_, fd)| Arc::new(FD(fd)));
@@ -696,20 +1108,18 @@ impl PlatformUDP for LinuxUDP {
         };
 
         // create readers
+        let pmtu = Arc::new(PathMtu::default());
         let mut readers: Vec<Self::Reader> = Vec::with_capacity(2);
         sock6
             .clone()
-            .map(|sock| readers.push(LinuxUDPReader::V6(sock)));
+            .map(|sock| readers.push(LinuxUDPReader::V6(sock, pmtu.clone())));
         sock4
             .clone()
-            .map(|sock| readers.push(LinuxUDPReader::V4(sock)));
+            .map(|sock| readers.push(LinuxUDPReader::V4(sock, pmtu.clone())));
         debug_assert!(readers.len() > 0);
 
         // create writer
-        let writer = LinuxUDPWriter {
-            sock4: sock4.unwrap_or(Arc::new(FD(-1))),
-            sock6: sock6.unwrap_or(Arc::new(FD(-1))),
-        };
+        let writer = LinuxUDPWriter { sock4, sock6, pmtu };
 
         Ok((readers, writer, owner))
     }
@@ -13,4 +13,7 @@ pub trait PlatformUAPI {
     type Bind: BindUAPI;
 
     fn bind(name: &str) -> Result<Self::Bind, Self::Error>;
+
+    /// Bind the (optional) JSON control interface of the device
+    fn bind_json(name: &str) -> Result<Self::Bind, Self::Error>;
 }
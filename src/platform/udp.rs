@@ -1,5 +1,8 @@
 use super::Endpoint;
 use std::error::Error;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
 
 pub trait Reader<E: Endpoint>: Send + Sync {
     type Error: Error;
@@ -11,10 +14,22 @@ pub trait Writer<E: Endpoint>: Send + Sync + 'static {
     type Error: Error;
 
     fn write(&self, buf: &[u8], dst: &mut E) -> Result<(), Self::Error>;
+
+    /// Writes the buffer with the given TOS / traffic class (DSCP and ECN bits)
+    /// in the outer IP header, on platforms which support setting it per packet
+    fn write_tos(&self, buf: &[u8], dst: &mut E, _tos: u8) -> Result<(), Self::Error> {
+        self.write(buf, dst)
+    }
+
+    /// Returns the path MTU towards the destination (the largest outer IP packet),
+    /// if learned by path MTU discovery
+    fn path_mtu(&self, _dst: &E) -> Option<usize> {
+        None
+    }
 }
 
 pub trait UDP: Send + Sync + 'static {
-    type Error: Error;
+    type Error: Error + 'static;
     type Endpoint: Endpoint;
 
     /* Until Rust gets type equality constraints these have to be generic */
@@ -33,6 +48,50 @@ pub trait Owner: Send {
     fn set_fwmark(&mut self, value: Option<u32>) -> Result<(), Self::Error>;
 }
 
+/// The IP versions bound by `PlatformUDP::bind`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BindFamily {
+    #[default]
+    Dual, // both IP versions, or whichever is available
+    Ipv4, // only IPv4
+    Ipv6, // only IPv6
+}
+
+impl fmt::Display for BindFamily {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BindFamily::Dual => write!(f, "dual"),
+            BindFamily::Ipv4 => write!(f, "ipv4"),
+            BindFamily::Ipv6 => write!(f, "ipv6"),
+        }
+    }
+}
+
+impl FromStr for BindFamily {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dual" => Ok(BindFamily::Dual),
+            "ipv4" => Ok(BindFamily::Ipv4),
+            "ipv6" => Ok(BindFamily::Ipv6),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Restricts the sockets created by `PlatformUDP::bind`,
+/// by default the wildcard address of every IP version is bound.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BindOptions {
+    pub family: BindFamily,
+    pub address: Option<IpAddr>, // bind only this local address (and hence IP version)
+    pub interface: Option<String>, // send and receive only through this interface
+    pub rcvbuf: Option<usize>,   // size of the receive buffer (None = system default)
+    pub sndbuf: Option<usize>,   // size of the send buffer (None = system default)
+    pub ttl: Option<u8>,         // TTL / hop limit of the outer packets (None = system default)
+}
+
 /// On some platforms the application can itself bind to a socket.
 /// This enables configuration using the UAPI interface.
 pub trait PlatformUDP: UDP {
@@ -41,5 +100,8 @@ pub trait PlatformUDP: UDP {
     /// Bind to a new port, returning the reader/writer and
     /// an associated instance of the owner type, which closes the UDP socket upon "drop"
     /// and enables configuration of the fwmark value.
-    fn bind(port: u16) -> Result<(Vec<Self::Reader>, Self::Writer, Self::Owner), Self::Error>;
+    fn bind(
+        port: u16,
+        options: &BindOptions,
+    ) -> Result<(Vec<Self::Reader>, Self::Writer, Self::Owner), Self::Error>;
 }
@@ -0,0 +1,231 @@
+use std::collections::HashSet;
+use std::iter;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use super::configuration::{Configuration, Event};
+use super::platform::plt::{Netlink, Rule};
+
+/* Keeps the routes of the interface in sync with the allowed IPs of the peers.
+ *
+ * Every allowed IP is routed to the interface through the main table,
+ * except default routes (prefix length 0) which use wg-quick's policy routing:
+ * the default route is installed in a separate table (equal to the fwmark),
+ * which is consulted by every packet without the fwmark (i.e. not sent by the device itself),
+ * while the main table is consulted for everything but its default route.
+ */
+
+// the table (and fwmark) used when the device has no fwmark, as by wg-quick
+const DEFAULT_TABLE: u32 = 51820;
+
+// priorities of the rules (below the "from all lookup main" rule at 32766)
+const PRIORITY_SUPPRESS: u32 = 32764;
+const PRIORITY_FWMARK: u32 = 32765;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct Route {
+    ip: IpAddr,
+    prefix: u32,
+    table: Option<u32>, // None for the main table
+}
+
+pub struct RouteManager<C: Configuration>(Arc<Mutex<Inner<C>>>);
+
+struct Inner<C: Configuration> {
+    cfg: C,
+    netlink: Netlink,
+    routes: HashSet<Route>,
+    rules: HashSet<(bool, Rule)>, // (ipv6, rule)
+}
+
+impl<C: Configuration> Clone for RouteManager<C> {
+    fn clone(&self) -> Self {
+        RouteManager(self.0.clone())
+    }
+}
+
+impl<C: Configuration> RouteManager<C> {
+    pub fn new(cfg: C, netlink: Netlink) -> RouteManager<C> {
+        RouteManager(Arc::new(Mutex::new(Inner {
+            cfg,
+            netlink,
+            routes: HashSet::new(),
+            rules: HashSet::new(),
+        })))
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner<C>> {
+        self.0.lock().unwrap()
+    }
+
+    /// Syncs the routes whenever the peers or their allowed IPs change
+    /// (does not return)
+    pub fn run(&self) {
+        let events = self.lock().cfg.subscribe_events();
+        self.lock().sync(false);
+        while let Ok(event) = events.recv() {
+            // events are dropped while the queue is full (the queue only drains here):
+            // then the routes are no longer known to be installed and are all synced again
+            let overflow = events
+                .capacity()
+                .map_or(false, |capacity| events.len() + 1 >= capacity);
+            if overflow {
+                log::warn!("routes, events dropped, syncing all routes");
+            }
+
+            // coalesce the changes of a single configuration operation
+            let mut changed = overflow;
+            for event in iter::once(event).chain(events.try_iter()) {
+                match event {
+                    Event::DeviceDown => {
+                        // the routes of the interface are flushed by the kernel
+                        self.lock().routes.clear();
+                    }
+                    Event::DeviceUp { .. }
+                    | Event::PeerAdded { .. }
+                    | Event::PeerRemoved { .. }
+                    | Event::AllowedIpsChanged { .. } => changed = true,
+                    _ => (),
+                }
+            }
+            if changed {
+                self.lock().sync(overflow);
+            }
+        }
+    }
+
+    /// Removes the routing policy rules,
+    /// which (unlike the routes) outlive the interface
+    pub fn remove_rules(&self) {
+        let mut inner = self.lock();
+        for (ipv6, rule) in inner.rules.drain().collect::<Vec<_>>() {
+            if let Err(e) = inner.netlink.del_rule(ipv6, rule) {
+                log::warn!("routes, failed to remove rule {:?}: {}", rule, e);
+            }
+        }
+    }
+}
+
+impl<C: Configuration> Inner<C> {
+    // the table of the default routes, sets the fwmark of the device if unset
+    fn table(&mut self) -> u32 {
+        match self.cfg.get_fwmark() {
+            Some(mark) if mark != 0 => mark,
+            _ => {
+                log::info!(
+                    "routes, set fwmark {} for the default routes",
+                    DEFAULT_TABLE
+                );
+                if let Err(e) = self.cfg.set_fwmark(Some(DEFAULT_TABLE)) {
+                    log::warn!("routes, failed to set fwmark: {}", e);
+                }
+                DEFAULT_TABLE
+            }
+        }
+    }
+
+    // add the missing routes (and rules) and remove those no longer needed,
+    // if full the routes and rules believed to be installed are added again
+    // (adding an existing route or rule is not an error)
+    fn sync(&mut self, full: bool) {
+        // collect the allowed IPs of all peers
+        let mut wanted: HashSet<Route> = HashSet::new();
+        for peer in self.cfg.get_peers() {
+            for (ip, prefix) in peer.allowed_ips {
+                wanted.insert(Route {
+                    ip,
+                    prefix,
+                    table: None,
+                });
+            }
+        }
+
+        // move default routes to the table of the fwmark
+        let mut rules: HashSet<(bool, Rule)> = HashSet::new();
+        if wanted.iter().any(|r| r.prefix == 0) {
+            let table = self.table();
+            wanted = wanted
+                .into_iter()
+                .map(|r| match r.prefix {
+                    0 => {
+                        let ipv6 = r.ip.is_ipv6();
+                        rules.insert((
+                            ipv6,
+                            Rule::NotFwmark {
+                                fwmark: table,
+                                table,
+                            },
+                        ));
+                        rules.insert((ipv6, Rule::SuppressDefault));
+                        Route {
+                            table: Some(table),
+                            ..r
+                        }
+                    }
+                    _ => r,
+                })
+                .collect();
+        }
+
+        // remove stale routes and rules
+        for route in self.routes.difference(&wanted).cloned().collect::<Vec<_>>() {
+            match self.netlink.del_route(route.ip, route.prefix, route.table) {
+                Ok(()) => {
+                    self.routes.remove(&route);
+                }
+                Err(e) => log::warn!(
+                    "routes, failed to remove route {}/{}: {}",
+                    route.ip,
+                    route.prefix,
+                    e
+                ),
+            }
+        }
+        for (ipv6, rule) in self.rules.difference(&rules).cloned().collect::<Vec<_>>() {
+            match self.netlink.del_rule(ipv6, rule) {
+                Ok(()) => {
+                    self.rules.remove(&(ipv6, rule));
+                }
+                Err(e) => log::warn!("routes, failed to remove rule {:?}: {}", rule, e),
+            }
+        }
+
+        // add the routes before the rules directing traffic to them
+        if full {
+            self.routes.clear();
+            self.rules.clear();
+        }
+        for route in wanted.difference(&self.routes).cloned().collect::<Vec<_>>() {
+            match self.netlink.add_route(route.ip, route.prefix, route.table) {
+                Ok(()) => {
+                    self.routes.insert(route);
+                }
+                Err(e) => log::warn!(
+                    "routes, failed to add route {}/{}: {}",
+                    route.ip,
+                    route.prefix,
+                    e
+                ),
+            }
+        }
+        for (ipv6, rule) in rules.difference(&self.rules).cloned().collect::<Vec<_>>() {
+            let priority = match rule {
+                Rule::NotFwmark { .. } => {
+                    if !ipv6 {
+                        if let Err(e) = self.netlink.enable_src_valid_mark() {
+                            log::warn!("routes, failed to enable src_valid_mark: {}", e);
+                        }
+                    }
+                    PRIORITY_FWMARK
+                }
+                Rule::SuppressDefault => PRIORITY_SUPPRESS,
+            };
+            match self.netlink.add_rule(ipv6, rule, priority) {
+                Ok(()) => {
+                    self.rules.insert((ipv6, rule));
+                }
+                Err(e) => log::warn!("routes, failed to add rule {:?}: {}", rule, e),
+            }
+        }
+    }
+}
@@ -0,0 +1,462 @@
+use super::keys::{self, Base64Key};
+use super::router::{Direction, Layer};
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use byteorder::{ByteOrder, LittleEndian};
+use crossbeam_channel::{bounded, Receiver, Sender, TryRecvError};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/* Capture of the packets exchanged with peers in the pcapng format:
+ *
+ * The file has two interfaces, both of link-type "raw IP":
+ *
+ * - "inner": the plaintext IP packets read from / written to the TUN device.
+ * - "outer": the WireGuard messages sent / received on the bind,
+ *            encapsulated in synthesized IP/UDP headers (addressed to the endpoint of the peer)
+ *            such that they are recognized by the WireGuard dissector of Wireshark.
+ *
+ * Every packet is annotated (by a comment) with the public key of the peer
+ * and the receiver index of transport messages.
+ *
+ * The blocks are appended by a dedicated writer thread (buffering the file),
+ * blocks are dropped when the writer falls behind.
+ */
+
+pub(crate) const BLOCK_SECTION_HEADER: u32 = 0x0A0D_0D0A;
+pub(crate) const BLOCK_INTERFACE_DESCRIPTION: u32 = 0x0000_0001;
+pub(crate) const BLOCK_ENHANCED_PACKET: u32 = 0x0000_0006;
+
+pub(crate) const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+const LINKTYPE_RAW: u16 = 101;
+
+pub(crate) const OPT_END: u16 = 0;
+const OPT_COMMENT: u16 = 1;
+pub(crate) const OPT_IF_NAME: u16 = 2;
+pub(crate) const OPT_EPB_FLAGS: u16 = 2;
+
+const INTERFACE_INNER: u32 = 0;
+const INTERFACE_OUTER: u32 = 1;
+
+pub(crate) const PROTOCOL_UDP: u8 = 17;
+const DEFAULT_TTL: u8 = 64;
+
+const MAX_QUEUED_BLOCKS: usize = 1024;
+
+pub struct Capture {
+    path: PathBuf,
+    blocks: Option<Sender<Vec<u8>>>, // None when dropped
+    writer: Option<thread::JoinHandle<()>>,
+}
+
+impl Drop for Capture {
+    fn drop(&mut self) {
+        // close the queue and wait for the writer to flush the file
+        self.blocks.take();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+impl Capture {
+    /// Creates the capture file (readable only by the owner),
+    /// existing files are never overwritten.
+    ///
+    /// # Arguments
+    ///
+    /// - `path`: The path of the pcapng file
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Capture> {
+        let path = path.as_ref().to_owned();
+        let mut file = create_private(&path)?;
+
+        // section header (of unspecified length)
+        let mut body = vec![0u8; 16];
+        LittleEndian::write_u32(&mut body[0..], BYTE_ORDER_MAGIC);
+        LittleEndian::write_u16(&mut body[4..], 1);
+        LittleEndian::write_u16(&mut body[6..], 0);
+        LittleEndian::write_i64(&mut body[8..], -1);
+        file.write_all(&block(BLOCK_SECTION_HEADER, body))?;
+
+        // interfaces (with microsecond timestamps)
+        for name in &["inner", "outer"] {
+            let mut body = vec![0u8; 8];
+            LittleEndian::write_u16(&mut body[0..], LINKTYPE_RAW);
+            option(&mut body, OPT_IF_NAME, name.as_bytes());
+            option(&mut body, OPT_END, &[]);
+            file.write_all(&block(BLOCK_INTERFACE_DESCRIPTION, body))?;
+        }
+
+        let (tx, rx) = bounded(MAX_QUEUED_BLOCKS);
+        Ok(Capture {
+            path,
+            blocks: Some(tx),
+            writer: Some(thread::spawn(move || writer(file, rx))),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Appends a packet to the capture
+    ///
+    /// # Arguments
+    ///
+    /// - `layer`: Plaintext IP packet or WireGuard message (and the endpoint of the peer)
+    /// - `direction`: Received from or sent to the peer
+    /// - `peer`: The public key of the peer (None if not yet authenticated)
+    /// - `id`: The receiver index of a transport message
+    /// - `packet`: The packet
+    pub fn write(
+        &self,
+        layer: Layer,
+        direction: Direction,
+        peer: Option<&PublicKey>,
+        id: Option<u32>,
+        packet: &[u8],
+    ) {
+        let (interface, data) = match layer {
+            Layer::Inner => (INTERFACE_INNER, packet.to_owned()),
+            Layer::Outer(endpoint) => (INTERFACE_OUTER, encapsulate(direction, endpoint, packet)),
+        };
+
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let micros = time.as_secs() * 1_000_000 + u64::from(time.subsec_micros());
+
+        let mut body = vec![0u8; 20];
+        LittleEndian::write_u32(&mut body[0..], interface);
+        LittleEndian::write_u32(&mut body[4..], (micros >> 32) as u32);
+        LittleEndian::write_u32(&mut body[8..], micros as u32);
+        LittleEndian::write_u32(&mut body[12..], data.len() as u32);
+        LittleEndian::write_u32(&mut body[16..], data.len() as u32);
+        body.extend_from_slice(&data);
+        pad(&mut body);
+
+        // annotation
+        let mut comment = String::new();
+        if let Some(pk) = peer {
+            comment.push_str(&format!("peer={}", Base64Key::from(pk)));
+        }
+        if let Some(id) = id {
+            comment.push_str(&format!(" key={}", id));
+        }
+        if !comment.is_empty() {
+            option(&mut body, OPT_COMMENT, comment.trim_start().as_bytes());
+        }
+
+        // inbound (1) or outbound (2)
+        let mut flags = [0u8; 4];
+        LittleEndian::write_u32(
+            &mut flags,
+            match direction {
+                Direction::In => 1,
+                _ => 2,
+            },
+        );
+        option(&mut body, OPT_EPB_FLAGS, &flags);
+        option(&mut body, OPT_END, &[]);
+
+        if let Some(blocks) = self.blocks.as_ref() {
+            if blocks.try_send(block(BLOCK_ENHANCED_PACKET, body)).is_err() {
+                log::debug!("event=capture_packet_dropped");
+            }
+        }
+    }
+}
+
+// appends the queued blocks to the capture file (flushing when the queue is empty)
+fn writer(file: File, blocks: Receiver<Vec<u8>>) {
+    let mut file = BufWriter::new(file);
+    loop {
+        let block = match blocks.try_recv() {
+            Ok(block) => block,
+            Err(TryRecvError::Empty) => {
+                let _ = file
+                    .flush()
+                    .map_err(|e| log::debug!("error={} event=capture_write_failed", e));
+                match blocks.recv() {
+                    Ok(block) => block,
+                    Err(_) => break,
+                }
+            }
+            Err(TryRecvError::Disconnected) => break,
+        };
+        let _ = file
+            .write_all(&block)
+            .map_err(|e| log::debug!("error={} event=capture_write_failed", e));
+    }
+    let _ = file
+        .flush()
+        .map_err(|e| log::debug!("error={} event=capture_write_failed", e));
+}
+
+// creates a file readable only by the owner,
+// failing if the path exists (without following symbolic links)
+fn create_private(path: &Path) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    options.open(path)
+}
+
+/// Receives the secrets of every handshake,
+/// e.g. to decrypt captured transport messages.
+pub trait KeyLog: Send + Sync + 'static {
+    /// Logs the secrets of a handshake
+    ///
+    /// # Arguments
+    ///
+    /// - `local`: The static private key of the device
+    /// - `remote`: The static public key of the peer
+    /// - `ephemeral`: The ephemeral private key of the device
+    /// - `psk`: The preshared key
+    fn log(
+        &self,
+        local: &StaticSecret,
+        remote: &PublicKey,
+        ephemeral: &StaticSecret,
+        psk: &[u8; 32],
+    );
+}
+
+/// Writes a keylog in the format read by the WireGuard dissector of Wireshark
+pub struct KeyLogFile {
+    file: Mutex<File>,
+}
+
+impl KeyLogFile {
+    /// Creates the keylog file (readable only by the owner),
+    /// existing files are never overwritten.
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<KeyLogFile> {
+        Ok(KeyLogFile {
+            file: Mutex::new(create_private(path.as_ref())?),
+        })
+    }
+}
+
+impl KeyLog for KeyLogFile {
+    fn log(
+        &self,
+        local: &StaticSecret,
+        remote: &PublicKey,
+        ephemeral: &StaticSecret,
+        psk: &[u8; 32],
+    ) {
+        let entry = format!(
+            "LOCAL_STATIC_PRIVATE_KEY = {}\n\
+             REMOTE_STATIC_PUBLIC_KEY = {}\n\
+             LOCAL_EPHEMERAL_PRIVATE_KEY = {}\n\
+             PRESHARED_KEY = {}\n",
+            keys::encode(&local.to_bytes()),
+            keys::encode(remote.as_bytes()),
+            keys::encode(&ephemeral.to_bytes()),
+            keys::encode(psk)
+        );
+        let _ = self
+            .file
+            .lock()
+            .unwrap()
+            .write_all(entry.as_bytes())
+            .map_err(|e| log::debug!("error={} event=keylog_write_failed", e));
+    }
+}
+
+// frames the body of a block (padded to 32-bits) by the type and length fields
+fn block(kind: u32, body: Vec<u8>) -> Vec<u8> {
+    let len = (body.len() + 12) as u32;
+    let mut block = vec![0u8; 8];
+    LittleEndian::write_u32(&mut block[0..], kind);
+    LittleEndian::write_u32(&mut block[4..], len);
+    block.extend(body);
+    block.extend_from_slice(&len.to_le_bytes());
+    block
+}
+
+// appends an option (padded to 32-bits)
+fn option(body: &mut Vec<u8>, code: u16, value: &[u8]) {
+    let mut header = [0u8; 4];
+    LittleEndian::write_u16(&mut header[0..], code);
+    LittleEndian::write_u16(&mut header[2..], value.len() as u16);
+    body.extend_from_slice(&header);
+    body.extend_from_slice(value);
+    pad(body);
+}
+
+fn pad(body: &mut Vec<u8>) {
+    body.resize((body.len() + 3) & !3, 0);
+}
+
+/* Prefixes a WireGuard message with IP/UDP headers,
+ * the local address is unknown (unspecified address and port).
+ */
+pub(crate) fn encapsulate(
+    direction: Direction,
+    endpoint: Option<SocketAddr>,
+    msg: &[u8],
+) -> Vec<u8> {
+    let remote = endpoint.unwrap_or_else(|| SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0));
+    let local = match remote.ip() {
+        IpAddr::V4(_) => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0),
+        IpAddr::V6(_) => SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0),
+    };
+    let (src, dst) = match direction {
+        Direction::In => (remote, local),
+        _ => (local, remote),
+    };
+
+    // UDP header (without checksum)
+    let mut udp = vec![0u8; 8];
+    udp[0..2].copy_from_slice(&src.port().to_be_bytes());
+    udp[2..4].copy_from_slice(&dst.port().to_be_bytes());
+    udp[4..6].copy_from_slice(&((msg.len() + 8) as u16).to_be_bytes());
+    udp.extend_from_slice(msg);
+
+    let mut packet = match (src.ip(), dst.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            let mut header = vec![0u8; 20];
+            header[0] = 0x45;
+            header[2..4].copy_from_slice(&((udp.len() + 20) as u16).to_be_bytes());
+            header[6] = 0x40; // don't fragment
+            header[8] = DEFAULT_TTL;
+            header[9] = PROTOCOL_UDP;
+            header[12..16].copy_from_slice(&src.octets());
+            header[16..20].copy_from_slice(&dst.octets());
+            let checksum = !header.chunks(2).fold(0u32, |sum, word| {
+                let sum = sum + u32::from(u16::from_be_bytes([word[0], word[1]]));
+                (sum & 0xffff) + (sum >> 16)
+            }) as u16;
+            header[10..12].copy_from_slice(&checksum.to_be_bytes());
+            header
+        }
+        (src, dst) => {
+            let (src, dst) = (ipv6(src), ipv6(dst));
+            let mut header = vec![0u8; 40];
+            header[0] = 0x60;
+            header[4..6].copy_from_slice(&(udp.len() as u16).to_be_bytes());
+            header[6] = PROTOCOL_UDP;
+            header[7] = DEFAULT_TTL;
+            header[8..24].copy_from_slice(&src.octets());
+            header[24..40].copy_from_slice(&dst.octets());
+            header
+        }
+    };
+    packet.extend(udp);
+    packet
+}
+
+pub(crate) fn ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    #[test]
+    fn test_capture() {
+        let path = env::temp_dir().join(format!("wg-test-capture-{}", process::id()));
+        let _ = fs::remove_file(&path);
+        let capture = Capture::create(&path).unwrap();
+        let pk = PublicKey::from([1u8; 32]);
+        let endpoint = "10.0.0.1:51820".parse().ok();
+        capture.write(
+            Layer::Inner,
+            Direction::Out,
+            Some(&pk),
+            Some(7),
+            &[0x45; 21],
+        );
+        capture.write(
+            Layer::Outer(endpoint),
+            Direction::In,
+            None,
+            None,
+            &[1u8; 148],
+        );
+
+        // walk the blocks (flushed when the capture is dropped)
+        drop(capture);
+        let data = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let mut blocks = vec![];
+        let mut offset = 0;
+        while offset < data.len() {
+            let len = LittleEndian::read_u32(&data[offset + 4..]) as usize;
+            assert_eq!(len % 4, 0);
+            assert_eq!(
+                LittleEndian::read_u32(&data[offset + len - 4..]) as usize,
+                len
+            );
+            blocks.push(&data[offset..offset + len]);
+            offset += len;
+        }
+        let kinds: Vec<u32> = blocks.iter().map(|b| LittleEndian::read_u32(b)).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                BLOCK_SECTION_HEADER,
+                BLOCK_INTERFACE_DESCRIPTION,
+                BLOCK_INTERFACE_DESCRIPTION,
+                BLOCK_ENHANCED_PACKET,
+                BLOCK_ENHANCED_PACKET
+            ]
+        );
+
+        // inner packet (annotated with the peer and key)
+        let inner = blocks[3];
+        assert_eq!(LittleEndian::read_u32(&inner[8..]), INTERFACE_INNER);
+        assert_eq!(LittleEndian::read_u32(&inner[20..]), 21);
+        let comment = format!("peer={} key=7", keys::encode(&[1u8; 32]));
+        assert!(inner
+            .windows(comment.len())
+            .any(|w| w == comment.as_bytes()));
+
+        // outer message (encapsulated in IPv4/UDP from the endpoint)
+        let outer = blocks[4];
+        assert_eq!(LittleEndian::read_u32(&outer[8..]), INTERFACE_OUTER);
+        assert_eq!(LittleEndian::read_u32(&outer[20..]), 20 + 8 + 148);
+        assert_eq!(&outer[28 + 12..28 + 16], &[10, 0, 0, 1]);
+        assert_eq!(&outer[28 + 20..28 + 22], &51820u16.to_be_bytes());
+    }
+
+    #[test]
+    fn test_create_private() {
+        let path = env::temp_dir().join(format!("wg-test-private-{}", process::id()));
+        let _ = fs::remove_file(&path);
+
+        // the file is only readable by the owner
+        KeyLogFile::create(&path).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        // existing files are not overwritten
+        fs::write(&path, b"data").unwrap();
+        assert!(KeyLogFile::create(&path).is_err());
+        assert!(Capture::create(&path).is_err());
+        assert_eq!(fs::read(&path).unwrap(), b"data");
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -15,19 +15,33 @@ pub const MAX_TIMER_HANDSHAKES: usize =
 
 // Semantics:
 // Maximum number of buffered handshake requests
-// (either from outside message or handshake requests triggered locally)
+// (either from outside message or handshake requests triggered locally),
+// incoming handshake messages exceeding the limit are dropped.
+// Default, the limit can be configured at runtime.
 pub const MAX_QUEUED_INCOMING_HANDSHAKES: usize = 4096;
 
 // Semantics:
 // When the number of queued handshake requests exceeds this number
 // the device is considered under load and DoS mitigation is triggered.
+// Default, the threshold can be configured at runtime.
 pub const THRESHOLD_UNDER_LOAD: usize = MAX_QUEUED_INCOMING_HANDSHAKES / 8;
 
 // Semantics:
 // When a device is detected to go under load,
 // it will remain under load for at least the following duration.
+// Default, the duration can be configured at runtime.
 pub const DURATION_UNDER_LOAD: Duration = Duration::from_secs(1);
 
+// Semantics:
+// Number of consecutive unanswered handshake initiations,
+// after which the next candidate endpoint of the peer (if any) is attempted.
+pub const ENDPOINT_FAILOVER_ATTEMPTS: usize = 3;
+
+// Semantics:
+// Interval between scans for idle peers (when idle peer eviction is enabled),
+// hence peers are evicted at most this long after exceeding the idle timeout.
+pub const IDLE_EVICTION_INTERVAL: Duration = Duration::from_secs(10);
+
 // Semantics:
 // The payload of transport messages are padded to this multiple
 pub const MESSAGE_PADDING_MULTIPLE: usize = 16;
@@ -55,3 +69,19 @@ pub const TIMERS_CAPACITY: usize = 16;
  * Note, this duration need not fit inside the timer wheel.
  */
 pub const TIME_HORIZON: Duration = Duration::from_secs(TIMER_MAX_DURATION.as_secs() * 2);
+
+// Semantics:
+// Maximum number of undelivered events buffered for a single subscriber,
+// beyond this events are dropped for that subscriber.
+pub const MAX_QUEUED_EVENTS: usize = 1024;
+
+// Semantics:
+// Number of recent handshake outcomes retained for every peer
+// (and of failures not attributed to any peer, for the device).
+pub const MAX_HANDSHAKE_HISTORY: usize = 16;
+
+// DoS mitigation:
+// Sustained rate and burst of peer provider lookups for every source address
+// (the public keys looked up are not authenticated).
+pub const PROVIDER_LOOKUPS_PER_SECOND: u64 = 1;
+pub const PROVIDER_LOOKUPS_BURSTABLE: u64 = 4;
@@ -0,0 +1,164 @@
+use super::constants::MAX_HANDSHAKE_HISTORY;
+use super::handshake::HandshakeError;
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use spin::Mutex;
+
+/// The outcome of processing a handshake message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeOutcome {
+    Accepted,            // authenticated initiation or response
+    CookieReceived,      // our message lacked a valid cookie (the peer is under load)
+    UnknownPeer,         // the static public key is not that of a peer
+    UnknownReceiver,     // the receiver id is not that of a pending handshake
+    InvalidMac1,         // e.g. the peer is configured with another public key for the device
+    DecryptionFailure,   // e.g. mismatching preshared keys
+    Replay,              // timestamp not newer than that of the previous initiation
+    InitiationFlood,     // initiations too frequent
+    RateLimited,         // dropped by the rate limiter of the device
+    InvalidState,        // the message does not apply to the state of the handshake
+    InvalidMessage,      // malformed message
+    InvalidSharedSecret, // low order point
+    KeyUnavailable,      // the private key of the device is unavailable
+    RejectedByPolicy,    // authenticated, but the source is rejected by the roaming policy
+}
+
+impl HandshakeOutcome {
+    pub fn is_failure(&self) -> bool {
+        !matches!(
+            self,
+            HandshakeOutcome::Accepted | HandshakeOutcome::CookieReceived
+        )
+    }
+}
+
+impl From<&HandshakeError> for HandshakeOutcome {
+    fn from(e: &HandshakeError) -> HandshakeOutcome {
+        match e {
+            HandshakeError::DecryptionFailure => HandshakeOutcome::DecryptionFailure,
+            HandshakeError::UnknownPublicKey(_) => HandshakeOutcome::UnknownPeer,
+            HandshakeError::UnknownReceiverId => HandshakeOutcome::UnknownReceiver,
+            HandshakeError::InvalidMessageFormat => HandshakeOutcome::InvalidMessage,
+            HandshakeError::InvalidSharedSecret => HandshakeOutcome::InvalidSharedSecret,
+            HandshakeError::KeyUnavailable => HandshakeOutcome::KeyUnavailable,
+            HandshakeError::OldTimestamp => HandshakeOutcome::Replay,
+            HandshakeError::InvalidState => HandshakeOutcome::InvalidState,
+            HandshakeError::InvalidMac1 => HandshakeOutcome::InvalidMac1,
+            HandshakeError::RateLimited => HandshakeOutcome::RateLimited,
+            HandshakeError::InitiationFlood => HandshakeOutcome::InitiationFlood,
+            HandshakeError::SourceRejected => HandshakeOutcome::RejectedByPolicy,
+        }
+    }
+}
+
+impl fmt::Display for HandshakeOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandshakeOutcome::Accepted => "accepted",
+            HandshakeOutcome::CookieReceived => "cookie-received",
+            HandshakeOutcome::UnknownPeer => "unknown-peer",
+            HandshakeOutcome::UnknownReceiver => "unknown-receiver",
+            HandshakeOutcome::InvalidMac1 => "invalid-mac1",
+            HandshakeOutcome::DecryptionFailure => "decryption-failure",
+            HandshakeOutcome::Replay => "replay",
+            HandshakeOutcome::InitiationFlood => "initiation-flood",
+            HandshakeOutcome::RateLimited => "rate-limited",
+            HandshakeOutcome::InvalidState => "invalid-state",
+            HandshakeOutcome::InvalidMessage => "invalid-message",
+            HandshakeOutcome::InvalidSharedSecret => "invalid-shared-secret",
+            HandshakeOutcome::KeyUnavailable => "key-unavailable",
+            HandshakeOutcome::RejectedByPolicy => "rejected-by-policy",
+        }
+        .fmt(f)
+    }
+}
+
+/// A handshake message received from the endpoint and the outcome of processing it
+#[derive(Debug, Clone, Copy)]
+pub struct HandshakeRecord {
+    pub time: SystemTime,
+    pub endpoint: SocketAddr,
+    pub outcome: HandshakeOutcome,
+}
+
+impl HandshakeRecord {
+    /// Seconds since the epoch
+    pub fn unix_time(&self) -> u64 {
+        self.time
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0)
+    }
+}
+
+// "<seconds since the epoch> <endpoint> <outcome>"
+impl fmt::Display for HandshakeRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} {}", self.unix_time(), self.endpoint, self.outcome)
+    }
+}
+
+/* A bounded history of the most recent handshake outcomes,
+ * the oldest record is dropped when full.
+ */
+pub struct HandshakeHistory {
+    records: Mutex<VecDeque<HandshakeRecord>>,
+}
+
+impl HandshakeHistory {
+    pub fn new() -> HandshakeHistory {
+        HandshakeHistory {
+            records: Mutex::new(VecDeque::with_capacity(MAX_HANDSHAKE_HISTORY)),
+        }
+    }
+
+    pub fn push(&self, endpoint: SocketAddr, outcome: HandshakeOutcome) -> HandshakeRecord {
+        let mut records = self.records.lock();
+        if records.len() == MAX_HANDSHAKE_HISTORY {
+            records.pop_front();
+        }
+        let record = HandshakeRecord {
+            time: SystemTime::now(),
+            endpoint,
+            outcome,
+        };
+        records.push_back(record);
+        record
+    }
+
+    /// Returns the records (oldest first)
+    pub fn records(&self) -> Vec<HandshakeRecord> {
+        self.records.lock().iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_history() {
+        let history = HandshakeHistory::new();
+        let endpoint = "10.0.0.1:51820".parse().unwrap();
+        history.push(endpoint, HandshakeOutcome::InvalidMac1);
+        for _ in 0..MAX_HANDSHAKE_HISTORY {
+            history.push(endpoint, HandshakeOutcome::Replay);
+        }
+        history.push(endpoint, HandshakeOutcome::Accepted);
+
+        let records = history.records();
+        assert_eq!(records.len(), MAX_HANDSHAKE_HISTORY);
+        assert_eq!(records[0].outcome, HandshakeOutcome::Replay);
+        assert_eq!(
+            records[MAX_HANDSHAKE_HISTORY - 1].outcome,
+            HandshakeOutcome::Accepted
+        );
+        assert!(records.iter().all(|r| r.endpoint == endpoint));
+        assert!(records[0].outcome.is_failure());
+        assert!(!records[MAX_HANDSHAKE_HISTORY - 1].outcome.is_failure());
+    }
+}
@@ -0,0 +1,69 @@
+use super::constants::MAX_QUEUED_EVENTS;
+use super::diagnostics::HandshakeRecord;
+
+use crossbeam_channel::{bounded, Receiver, Sender, TrySendError};
+use spin::Mutex;
+
+use x25519_dalek::PublicKey;
+
+/// Describes a change in the state of the device,
+/// delivered to every subscriber of the event stream.
+#[derive(Debug, Clone)]
+pub enum Event {
+    DeviceUp { mtu: usize },
+    DeviceDown,
+    PeerAdded { public_key: PublicKey },
+    PeerRemoved { public_key: PublicKey },
+    PeerEvicted { public_key: PublicKey }, // the runtime state of an idle peer was evicted
+    AllowedIpsChanged { public_key: PublicKey },
+    HandshakeCompleted { public_key: PublicKey },
+    // a handshake message attributed to the peer failed (unattributed failures are not emitted)
+    HandshakeFailed(PublicKey, HandshakeRecord),
+    UnderLoad { pending: usize }, // handshake messages pending when going under load
+    LoadNormal,
+}
+
+/* A simple broadcast of device events.
+ *
+ * Every subscriber is handed a bounded channel:
+ * if the subscriber falls behind, events are dropped for that subscriber only,
+ * the device never blocks on a slow consumer.
+ */
+pub struct Events {
+    subscribers: Mutex<Vec<Sender<Event>>>,
+}
+
+impl Events {
+    pub fn new() -> Events {
+        Events {
+            subscribers: Mutex::new(vec![]),
+        }
+    }
+
+    pub fn subscribe(&self) -> Receiver<Event> {
+        let (tx, rx) = bounded(MAX_QUEUED_EVENTS);
+        self.subscribers.lock().push(tx);
+        rx
+    }
+
+    pub fn emit(&self, event: Event) {
+        let mut subscribers = self.subscribers.lock();
+
+        // fast path: no subscribers
+        if subscribers.is_empty() {
+            return;
+        }
+
+        log::trace!("payload={:?} event=event_emitted", event);
+
+        // deliver to every subscriber, remove disconnected subscribers
+        subscribers.retain(|tx| match tx.try_send(event.clone()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                log::debug!("event=event_dropped");
+                true
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        });
+    }
+}
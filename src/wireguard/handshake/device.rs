@@ -1,8 +1,10 @@
 use spin::RwLock;
+use std::cell::Cell;
 use std::collections::hash_map;
 use std::collections::HashMap;
 use std::net::SocketAddr;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use zerocopy::AsBytes;
 
 use byteorder::{ByteOrder, LittleEndian};
@@ -10,25 +12,26 @@ use byteorder::{ByteOrder, LittleEndian};
 use rand::prelude::{CryptoRng, RngCore};
 use rand::Rng;
 
-use clear_on_drop::clear::Clear;
-
 use x25519_dalek::PublicKey;
 use x25519_dalek::StaticSecret;
 
+use super::super::capture::KeyLog;
+use super::super::provider::PskProvider;
+use super::key::{SoftwareKey, StaticKey};
 use super::macs;
-use super::messages::{CookieReply, Initiation, Response};
+use super::messages::{CookieReply, Initiation, MacsFooter, Response};
 use super::messages::{TYPE_COOKIE_REPLY, TYPE_INITIATION, TYPE_RESPONSE};
 use super::noise;
 use super::peer::Peer;
-use super::ratelimiter::RateLimiter;
+use super::ratelimiter::{RateLimiter, RateLimiterConfig, RateLimiterStats};
 use super::types::*;
 
 const MAX_PEER_PER_DEVICE: usize = 1 << 20;
 
 pub struct KeyState {
-    pub(super) sk: StaticSecret, // static secret key
-    pub(super) pk: PublicKey,    // static public key
-    macs: macs::Validator,       // validator for the mac fields
+    pub(super) sk: Box<dyn StaticKey>, // static secret key
+    pub(super) pk: PublicKey,          // static public key
+    macs: macs::Validator,             // validator for the mac fields
 }
 
 /// The device is generic over an "opaque" type
@@ -36,9 +39,12 @@ pub struct KeyState {
 /// (the instance is a Peer object in the parent module)
 pub struct Device<O> {
     keyst: Option<KeyState>,
+    previous: Option<(KeyState, Instant)>, // key state accepted for initiations until the deadline
     id_map: RwLock<HashMap<u32, [u8; 32]>>,
     pk_map: HashMap<[u8; 32], Peer<O>>,
     limiter: Mutex<RateLimiter>,
+    psk_provider: Option<Arc<dyn PskProvider>>,
+    keylog: Option<Arc<dyn KeyLog>>,
 }
 
 pub struct Iter<'a, O> {
@@ -96,9 +102,12 @@ impl<O> Device<O> {
     pub fn new() -> Device<O> {
         Device {
             keyst: None,
+            previous: None,
             id_map: RwLock::new(HashMap::new()),
             pk_map: HashMap::new(),
             limiter: Mutex::new(RateLimiter::new()),
+            psk_provider: None,
+            keylog: None,
         }
     }
 
@@ -109,14 +118,11 @@ impl<O> Device<O> {
             if let Some(key) = self.keyst.as_ref() {
                 if key.pk.as_bytes() == pk {
                     same = Some(PublicKey::from(*pk));
-                    peer.ss.clear()
-                } else {
-                    let pk = PublicKey::from(*pk);
-                    peer.ss = *key.sk.diffie_hellman(&pk).as_bytes();
                 }
-            } else {
-                peer.ss.clear();
             }
+
+            // the shared secrets are recomputed on demand
+            peer.clear_ss();
             peer.reset_state().map(|id| ids.push(id));
         }
 
@@ -129,14 +135,26 @@ impl<O> Device<O> {
     ///
     /// * `sk` - x25519 scalar representing the local private key
     pub fn set_sk(&mut self, sk: Option<StaticSecret>) -> Option<PublicKey> {
+        self.set_key(sk.map(|sk| Box::new(SoftwareKey::new(sk)) as Box<dyn StaticKey>))
+    }
+
+    /// Update the static key of the device
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The local private key (e.g. held outside the process memory)
+    pub fn set_key(&mut self, key: Option<Box<dyn StaticKey>>) -> Option<PublicKey> {
+        // abort any key rotation
+        self.previous = None;
+
         // update secret and public key
-        self.keyst = sk.map(|sk| {
-            let pk = PublicKey::from(&sk);
+        self.keyst = key.map(|sk| {
+            let pk = sk.public_key();
             let macs = macs::Validator::new(pk);
             KeyState { pk, sk, macs }
         });
 
-        // recalculate / erase the shared secrets for every peer
+        // erase the shared secrets for every peer
         let (ids, same) = self.update_ss();
 
         // release ids from aborted handshakes
@@ -152,13 +170,77 @@ impl<O> Device<O> {
         })
     }
 
-    /// Return the secret key of the device
+    /// Rotate the secret key of the device:
+    /// new handshakes use the new key, while initiations to the previous public key
+    /// are still accepted until the end of the grace window.
+    ///
+    /// # Arguments
+    ///
+    /// * `sk` - x25519 scalar representing the new local private key
+    /// * `grace` - Duration for which the previous key is accepted
+    pub fn rotate_sk(&mut self, sk: StaticSecret, grace: Duration) -> Option<PublicKey> {
+        self.rotate_key(Box::new(SoftwareKey::new(sk)), grace)
+    }
+
+    /// Rotate the static key of the device (see "rotate_sk")
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The new local private key
+    /// * `grace` - Duration for which the previous key is accepted
+    pub fn rotate_key(&mut self, key: Box<dyn StaticKey>, grace: Duration) -> Option<PublicKey> {
+        let previous = self.keyst.take();
+        let same = self.set_key(Some(key));
+        self.previous = previous
+            .filter(|key| {
+                self.keyst.as_ref().map(|new| new.pk.as_bytes()) != Some(key.pk.as_bytes())
+            })
+            .map(|key| (key, Instant::now() + grace));
+        same
+    }
+
+    /// Return the public key still accepted during a key rotation
+    pub fn get_previous_pk(&self) -> Option<PublicKey> {
+        self.previous_keyst().map(|key| key.pk)
+    }
+
+    fn previous_keyst(&self) -> Option<&KeyState> {
+        match self.previous.as_ref() {
+            Some((key, deadline)) if Instant::now() < *deadline => Some(key),
+            _ => None,
+        }
+    }
+
+    // Internal function
+    //
+    // Return the key state an initiation is addressed to (by the mac1 field)
+    fn initiation_keyst<'a>(
+        &'a self,
+        keyst: &'a KeyState,
+        inner: &[u8],
+        macs: &MacsFooter,
+    ) -> Result<&'a KeyState, HandshakeError> {
+        match keyst.macs.check_mac1(inner, macs) {
+            Ok(()) => Ok(keyst),
+            Err(e) => match self.previous_keyst() {
+                Some(previous) => previous.macs.check_mac1(inner, macs).map(|_| previous),
+                None => Err(e),
+            },
+        }
+    }
+
+    /// Return (a copy of) the secret key of the device
     ///
     /// # Returns
     ///
-    /// A secret key (x25519 scalar)
-    pub fn get_sk(&self) -> Option<&StaticSecret> {
-        self.keyst.as_ref().map(|key| &key.sk)
+    /// A secret key (x25519 scalar), None if not set or the key cannot be exported
+    pub fn get_sk(&self) -> Option<StaticSecret> {
+        self.keyst.as_ref().and_then(|key| key.sk.export())
+    }
+
+    /// Return the public key of the device
+    pub fn get_pk(&self) -> Option<PublicKey> {
+        self.keyst.as_ref().map(|key| key.pk)
     }
 
     /// Add a new public key to the state machine
@@ -181,18 +263,8 @@ impl<O> Device<O> {
             }
         }
 
-        // pre-compute shared secret and add to pk_map
-        self.pk_map.insert(
-            *pk.as_bytes(),
-            Peer::new(
-                pk,
-                self.keyst
-                    .as_ref()
-                    .map(|key| *key.sk.diffie_hellman(&pk).as_bytes())
-                    .unwrap_or([0u8; 32]),
-                opaque,
-            ),
-        );
+        // add to pk_map (the shared secret is computed on demand)
+        self.pk_map.insert(*pk.as_bytes(), Peer::new(pk, opaque));
 
         Ok(())
     }
@@ -235,6 +307,7 @@ impl<O> Device<O> {
         match self.pk_map.get_mut(pk.as_bytes()) {
             Some(mut peer) => {
                 peer.psk = psk;
+                *peer.psk_epoch.lock() = (None, Instant::now());
                 Ok(())
             }
             _ => Err(ConfigError::new("No such public key")),
@@ -259,6 +332,114 @@ impl<O> Device<O> {
         }
     }
 
+    /// Set the source of epoch tagged psks,
+    /// consulted for every handshake before the static psk of the peer
+    ///
+    /// # Arguments
+    ///
+    /// * `provider` - The psk provider, None to use only the static psks
+    pub fn set_psk_provider(&mut self, provider: Option<Arc<dyn PskProvider>>) {
+        self.psk_provider = provider;
+    }
+
+    /// Return the epoch of the psk used by the latest handshake (None for the static psk)
+    /// and the duration since the psk was first used (or set)
+    ///
+    /// # Arguments
+    ///
+    /// * `pk` - The public key of the peer
+    pub fn get_psk_age(&self, pk: &PublicKey) -> Result<(Option<u64>, Duration), ConfigError> {
+        match self.pk_map.get(pk.as_bytes()) {
+            Some(peer) => {
+                let (epoch, since) = *peer.psk_epoch.lock();
+                Ok((epoch, since.elapsed()))
+            }
+            _ => Err(ConfigError::new("No such public key")),
+        }
+    }
+
+    // Internal function
+    //
+    // Return the psks accepted in a response from the peer (tagged by epoch),
+    // the first of which is used when responding.
+    pub(super) fn psks(&self, peer: &Peer<O>) -> Vec<(Option<u64>, Psk)> {
+        let provider = match self.psk_provider.as_ref() {
+            Some(provider) => provider,
+            None => return vec![(None, peer.psk)],
+        };
+        match provider.current(&peer.pk) {
+            Some((epoch, psk)) => {
+                let mut psks = vec![(Some(epoch), psk)];
+                for adjacent in [epoch.checked_add(1), epoch.checked_sub(1)].iter() {
+                    if let Some(adjacent) = *adjacent {
+                        if let Some(psk) = provider.lookup(&peer.pk, adjacent) {
+                            psks.push((Some(adjacent), psk));
+                        }
+                    }
+                }
+                psks
+            }
+            None => vec![(None, peer.psk)],
+        }
+    }
+
+    /// Set the receiver of the secrets of every handshake
+    /// (only logged while the private key can be exported)
+    ///
+    /// # Arguments
+    ///
+    /// * `keylog` - The keylog, None to stop logging
+    pub fn set_keylog(&mut self, keylog: Option<Arc<dyn KeyLog>>) {
+        self.keylog = keylog;
+    }
+
+    // Internal function
+    //
+    // Log the secrets of a handshake with the peer:
+    // the ephemeral private key and the psk of the current epoch.
+    fn log_keys(&self, keyst: &KeyState, peer: &Peer<O>, eph_sk: &StaticSecret) {
+        if let Some(keylog) = self.keylog.as_ref() {
+            if let Some(sk) = keyst.sk.export() {
+                keylog.log(&sk, &peer.pk, eph_sk, &self.psks(peer)[0].1);
+            }
+        }
+    }
+
+    /// Update the configuration of the handshake rate limiter
+    /// (clearing the state of all rate limited sources)
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The new (valid) configuration
+    pub fn set_ratelimiter_config(&self, config: RateLimiterConfig) {
+        self.limiter.lock().unwrap().configure(config)
+    }
+
+    pub fn get_ratelimiter_config(&self) -> RateLimiterConfig {
+        self.limiter.lock().unwrap().config()
+    }
+
+    pub fn get_ratelimiter_stats(&self) -> RateLimiterStats {
+        self.limiter.lock().unwrap().stats()
+    }
+
+    /// Evict the handshake state of an idle peer,
+    /// the state is rebuilt on demand by the next handshake.
+    ///
+    /// The timestamp of the last initiation is retained (to prevent replay).
+    ///
+    /// # Arguments
+    ///
+    /// * `pk` - The public key of the peer to evict
+    pub fn evict(&self, pk: &PublicKey) {
+        if let Some(peer) = self.pk_map.get(pk.as_bytes()) {
+            peer.clear_ss();
+            if let Some(id) = peer.reset_state() {
+                self.release(id);
+            }
+        }
+    }
+
     /// Release an id back to the pool
     ///
     /// # Arguments
@@ -281,14 +462,15 @@ impl<O> Device<O> {
         pk: &PublicKey,
     ) -> Result<Vec<u8>, HandshakeError> {
         match (self.keyst.as_ref(), self.pk_map.get(pk.as_bytes())) {
-            (_, None) => Err(HandshakeError::UnknownPublicKey),
-            (None, _) => Err(HandshakeError::UnknownPublicKey),
+            (_, None) => Err(HandshakeError::UnknownPublicKey(*pk)),
+            (None, _) => Err(HandshakeError::UnknownPublicKey(*pk)),
             (Some(keyst), Some(peer)) => {
                 let local = self.allocate(rng, pk);
                 let mut msg = Initiation::default();
 
                 // create noise part of initation
-                noise::create_initiation(rng, keyst, peer, pk, local, &mut msg.noise)?;
+                let keylog = |eph_sk: &StaticSecret| self.log_keys(keyst, peer, eph_sk);
+                noise::create_initiation(rng, keyst, peer, pk, local, &keylog, &mut msg.noise)?;
 
                 // add macs to initation
                 peer.macs
@@ -310,6 +492,49 @@ impl<O> Device<O> {
         rng: &mut R,             // rng instance to sample randomness from
         msg: &[u8],              // message buffer
         src: Option<SocketAddr>, // optional source endpoint, set when "under load"
+    ) -> Result<Output<'a, O>, HandshakeError> {
+        self.process_attributed(rng, msg, src, &|_| true)
+            .map(|(output, _)| output)
+            .map_err(|(e, _)| e)
+    }
+
+    /// Process a handshake message,
+    /// additionally returning the peer the message was attributed to (also on failure).
+    ///
+    /// A message is attributed to a peer once the peer is identified:
+    /// by the decrypted static public key (initiation) or by the receiver id (response and cookie reply),
+    /// hence e.g. a message with an invalid mac1 is not attributed to any peer.
+    ///
+    /// # Arguments
+    ///
+    /// * `msg` - Byte slice containing the message (untrusted input)
+    /// * `src` - Optional source endpoint, set when "under load"
+    /// * `admit` - Checks an authenticated initiation or response against the policy of the peer,
+    ///   before the handshake state of the peer is updated
+    ///   (the message fails with `SourceRejected` if not admitted)
+    pub fn process_attributed<'a, R: RngCore + CryptoRng>(
+        &'a self,
+        rng: &mut R,
+        msg: &[u8],
+        src: Option<SocketAddr>,
+        admit: &dyn Fn(&O) -> bool,
+    ) -> AttributedResult<'a, O> {
+        let identified = Cell::new(None);
+        let result = self.process_message(rng, msg, src, admit, &identified);
+        let peer = identified.get().map(|peer: &Peer<O>| &peer.opaque);
+        match result {
+            Ok(output) => Ok((output, peer)),
+            Err(e) => Err((e, peer)),
+        }
+    }
+
+    fn process_message<'a, R: RngCore + CryptoRng>(
+        &'a self,
+        rng: &mut R,
+        msg: &[u8],
+        src: Option<SocketAddr>,
+        admit: &dyn Fn(&O) -> bool,
+        identified: &Cell<Option<&'a Peer<O>>>,
     ) -> Result<Output<'a, O>, HandshakeError> {
         // ensure type read in-range
         if msg.len() < 4 {
@@ -331,8 +556,8 @@ impl<O> Device<O> {
                 // parse message
                 let msg = Initiation::parse(msg)?;
 
-                // check mac1 field
-                keyst.macs.check_mac1(msg.noise.as_bytes(), &msg.macs)?;
+                // check mac1 field (selecting the previous key during a rotation)
+                let keyst = self.initiation_keyst(keyst, msg.noise.as_bytes(), &msg.macs)?;
 
                 // address validation & DoS mitigation
                 if let Some(src) = src {
@@ -356,7 +581,8 @@ impl<O> Device<O> {
                 }
 
                 // consume the initiation
-                let (peer, pk, st) = noise::consume_initiation(self, keyst, &msg.noise)?;
+                let (peer, pk, st) =
+                    noise::consume_initiation(self, keyst, &msg.noise, admit, identified)?;
 
                 // allocate new index for response
                 let local = self.allocate(rng, &pk);
@@ -364,12 +590,16 @@ impl<O> Device<O> {
                 // prepare memory for response, TODO: take slice for zero allocation
                 let mut resp = Response::default();
 
-                // create response (release id on error)
-                let keys = noise::create_response(rng, peer, &pk, local, st, &mut resp.noise)
-                    .map_err(|e| {
-                        self.release(local);
-                        e
-                    })?;
+                // create response with the psk of the current epoch (release id on error)
+                let (epoch, psk) = self.psks(peer)[0];
+                let keylog = |eph_sk: &StaticSecret| self.log_keys(keyst, peer, eph_sk);
+                let keys =
+                    noise::create_response(rng, &psk, &pk, local, st, &keylog, &mut resp.noise)
+                        .map_err(|e| {
+                            self.release(local);
+                            e
+                        })?;
+                peer.psk_used(epoch);
 
                 // add macs to response
                 peer.macs
@@ -414,13 +644,14 @@ impl<O> Device<O> {
                 }
 
                 // consume inner playload
-                noise::consume_response(self, keyst, &msg.noise)
+                noise::consume_response(self, keyst, &msg.noise, admit, identified)
             }
             TYPE_COOKIE_REPLY => {
                 let msg = CookieReply::parse(msg)?;
 
                 // lookup peer
                 let (peer, _) = self.lookup_id(msg.f_receiver.get())?;
+                identified.set(Some(peer));
 
                 // validate cookie reply
                 peer.macs.lock().process(&msg)?;
@@ -439,7 +670,7 @@ impl<O> Device<O> {
     pub(super) fn lookup_pk(&self, pk: &PublicKey) -> Result<&Peer<O>, HandshakeError> {
         self.pk_map
             .get(pk.as_bytes())
-            .ok_or(HandshakeError::UnknownPublicKey)
+            .ok_or(HandshakeError::UnknownPublicKey(*pk))
     }
 
     // Internal function
@@ -505,7 +736,7 @@ mod tests {
             // every shared secret is unique
             let mut ss: HashSet<[u8; 32]> = HashSet::new();
             for peer in dev.pk_map.values() {
-                ss.insert(peer.ss);
+                ss.insert(peer.ss(dev.keyst.as_ref().unwrap()).unwrap());
             }
             assert_eq!(ss.len(), dev.len());
         }
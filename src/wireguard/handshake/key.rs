@@ -0,0 +1,160 @@
+use std::fs;
+use std::path::Path;
+
+use clear_on_drop::clear::Clear;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use super::super::keys::SecretKey;
+
+/// The static private key of the device,
+/// abstracting the Diffie-Hellman operations of the handshake from the storage of the secret,
+/// e.g. allowing the secret to be held outside the process memory.
+pub trait StaticKey: Send + Sync + 'static {
+    /// Returns the public key corresponding to the private key
+    fn public_key(&self) -> PublicKey;
+
+    /// Computes the Diffie-Hellman shared secret with a public key
+    ///
+    /// # Arguments
+    ///
+    /// - `pk`: The public key of the other party (static or ephemeral)
+    ///
+    /// # Returns
+    ///
+    /// The shared secret, or None if the private key is unavailable
+    fn diffie_hellman(&self, pk: &PublicKey) -> Option<[u8; 32]>;
+
+    /// Returns a copy of the private key (e.g. to report over UAPI),
+    /// None if the private key cannot be exported.
+    fn export(&self) -> Option<StaticSecret>;
+}
+
+/// A private key held in process memory (the default)
+pub struct SoftwareKey {
+    sk: StaticSecret,
+    pk: PublicKey,
+}
+
+impl SoftwareKey {
+    pub fn new(sk: StaticSecret) -> SoftwareKey {
+        let pk = PublicKey::from(&sk);
+        SoftwareKey { sk, pk }
+    }
+}
+
+impl StaticKey for SoftwareKey {
+    fn public_key(&self) -> PublicKey {
+        self.pk
+    }
+
+    fn diffie_hellman(&self, pk: &PublicKey) -> Option<[u8; 32]> {
+        Some(*self.sk.diffie_hellman(pk).as_bytes())
+    }
+
+    fn export(&self) -> Option<StaticSecret> {
+        Some(StaticSecret::from(self.sk.to_bytes()))
+    }
+}
+
+/// A private key read from a file (hex encoded like the UAPI "private_key", or base64 like "wg genkey"),
+/// which is never exported.
+///
+/// The file is read once when opened: a replaced key file is only used once opened again
+/// (and set or rotated to as the static key of the device).
+pub struct FileKey {
+    sk: StaticSecret,
+    pk: PublicKey,
+}
+
+impl FileKey {
+    /// Opens the key file, deriving the public key
+    ///
+    /// # Arguments
+    ///
+    /// - `path`: The path of the key file
+    ///
+    /// # Returns
+    ///
+    /// The key, or None if the file does not contain a valid private key
+    pub fn open<P: AsRef<Path>>(path: P) -> Option<FileKey> {
+        let sk = Self::read(path.as_ref())?;
+        Some(FileKey {
+            pk: PublicKey::from(&sk),
+            sk,
+        })
+    }
+
+    fn read(path: &Path) -> Option<StaticSecret> {
+        let mut contents = fs::read(path).ok()?;
+        let key = std::str::from_utf8(&contents)
+            .ok()
+            .and_then(|key| key.trim().parse::<SecretKey>().ok());
+
+        // erase the copy of the secret (the parsed key is erased on drop)
+        contents[..].clear();
+        key.as_ref().map(StaticSecret::from)
+    }
+}
+
+impl StaticKey for FileKey {
+    fn public_key(&self) -> PublicKey {
+        self.pk
+    }
+
+    fn diffie_hellman(&self, pk: &PublicKey) -> Option<[u8; 32]> {
+        Some(*self.sk.diffie_hellman(pk).as_bytes())
+    }
+
+    fn export(&self) -> Option<StaticSecret> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rand::rngs::OsRng;
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn test_file_key() {
+        let sk = StaticSecret::new(&mut OsRng);
+        let other = PublicKey::from(&StaticSecret::new(&mut OsRng));
+
+        let path = env::temp_dir().join(format!("wg-test-key-{}", process::id()));
+        fs::write(&path, format!("{}\n", hex::encode(sk.to_bytes()))).unwrap();
+
+        let key = FileKey::open(&path).unwrap();
+        assert_eq!(key.public_key().as_bytes(), PublicKey::from(&sk).as_bytes());
+        assert_eq!(
+            key.diffie_hellman(&other),
+            SoftwareKey::new(StaticSecret::from(sk.to_bytes())).diffie_hellman(&other)
+        );
+        assert!(key.export().is_none());
+
+        // the file is read once: replacing or removing it takes effect when opened again
+        let sk2 = StaticSecret::new(&mut OsRng);
+        fs::write(&path, format!("{:#}\n", SecretKey::from(&sk2))).unwrap();
+        assert_eq!(key.public_key().as_bytes(), PublicKey::from(&sk).as_bytes());
+
+        // the key may also be base64 encoded (like the output of "wg genkey")
+        let key2 = FileKey::open(&path).unwrap();
+        assert_eq!(
+            key2.public_key().as_bytes(),
+            PublicKey::from(&sk2).as_bytes()
+        );
+        assert_eq!(
+            key2.diffie_hellman(&other),
+            SoftwareKey::new(sk2).diffie_hellman(&other)
+        );
+
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            key.diffie_hellman(&other),
+            SoftwareKey::new(sk).diffie_hellman(&other)
+        );
+        assert!(FileKey::open(&path).is_none());
+    }
+}
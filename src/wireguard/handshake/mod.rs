@@ -7,6 +7,7 @@
  */
 
 mod device;
+mod key;
 mod macs;
 mod messages;
 mod noise;
@@ -21,4 +22,7 @@ mod tests;
 // publicly exposed interface
 
 pub use device::Device;
+pub use key::{FileKey, StaticKey};
 pub use messages::{MAX_HANDSHAKE_MSG_SIZE, TYPE_COOKIE_REPLY, TYPE_INITIATION, TYPE_RESPONSE};
+pub use ratelimiter::{RateLimiter, RateLimiterConfig, RateLimiterStats};
+pub use types::HandshakeError;
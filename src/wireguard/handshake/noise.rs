@@ -1,3 +1,4 @@
+use std::cell::Cell;
 use std::time::Instant;
 
 // DH
@@ -24,6 +25,7 @@ use clear_on_drop::clear_stack_on_return;
 use subtle::ConstantTimeEq;
 
 use super::device::{Device, KeyState};
+use super::key::StaticKey;
 use super::messages::{NoiseInitiation, NoiseResponse};
 use super::messages::{TYPE_INITIATION, TYPE_RESPONSE};
 use super::peer::{Peer, State};
@@ -229,18 +231,34 @@ fn shared_secret(sk: &StaticSecret, pk: &PublicKey) -> Result<SharedSecret, Hand
     }
 }
 
+// Computes an X25519 shared secret with the static key of the device
+// (with the same zero-check as "shared_secret").
+#[inline(always)]
+fn static_shared_secret(key: &dyn StaticKey, pk: &PublicKey) -> Result<[u8; 32], HandshakeError> {
+    let ss = key
+        .diffie_hellman(pk)
+        .ok_or(HandshakeError::KeyUnavailable)?;
+    if ss.ct_eq(&[0u8; 32]).into() {
+        Err(HandshakeError::InvalidSharedSecret)
+    } else {
+        Ok(ss)
+    }
+}
+
 pub(super) fn create_initiation<R: RngCore + CryptoRng, O>(
     rng: &mut R,
     keyst: &KeyState,
     peer: &Peer<O>,
     pk: &PublicKey,
     local: u32,
+    keylog: &dyn Fn(&StaticSecret),
     msg: &mut NoiseInitiation,
 ) -> Result<(), HandshakeError> {
-    log::debug!("create initiation");
+    log::debug!("event=create_initiation");
 
     // check for zero shared-secret (see "shared_secret" note).
-    if peer.ss.ct_eq(&[0u8; 32]).into() {
+    let ss = peer.ss(keyst).ok_or(HandshakeError::KeyUnavailable)?;
+    if ss.ct_eq(&[0u8; 32]).into() {
         return Err(HandshakeError::InvalidSharedSecret);
     }
 
@@ -258,6 +276,7 @@ pub(super) fn create_initiation<R: RngCore + CryptoRng, O>(
 
         let eph_sk = StaticSecret::new(rng);
         let eph_pk = PublicKey::from(&eph_sk);
+        keylog(&eph_sk);
 
         // C := Kdf(C, E_pub)
 
@@ -290,7 +309,7 @@ pub(super) fn create_initiation<R: RngCore + CryptoRng, O>(
 
         // (C, k) := Kdf2(C, DH(S_priv, S_pub))
 
-        let (ck, key) = KDF2!(&ck, &peer.ss);
+        let (ck, key) = KDF2!(&ck, &ss);
 
         // msg.timestamp := Aead(k, 0, Timestamp(), H)
 
@@ -322,8 +341,10 @@ pub(super) fn consume_initiation<'a, O>(
     device: &'a Device<O>,
     keyst: &KeyState,
     msg: &NoiseInitiation,
+    admit: &dyn Fn(&O) -> bool,
+    identified: &Cell<Option<&'a Peer<O>>>,
 ) -> Result<(&'a Peer<O>, PublicKey, TemporaryState), HandshakeError> {
-    log::debug!("consume initiation");
+    log::debug!("event=consume_initiation");
 
     clear_stack_on_return(CLEAR_PAGES, || {
         // initialize new state
@@ -343,7 +364,7 @@ pub(super) fn consume_initiation<'a, O>(
         // (C, k) := Kdf2(C, DH(E_priv, S_pub))
 
         let eph_r_pk = PublicKey::from(msg.f_ephemeral);
-        let (ck, key) = KDF2!(&ck, shared_secret(&keyst.sk, &eph_r_pk)?.as_bytes());
+        let (ck, key) = KDF2!(&ck, &static_shared_secret(&*keyst.sk, &eph_r_pk)?);
 
         // msg.static := Aead(k, 0, S_pub, H)
 
@@ -357,10 +378,12 @@ pub(super) fn consume_initiation<'a, O>(
         )?;
 
         let peer = device.lookup_pk(&PublicKey::from(pk))?;
+        identified.set(Some(peer));
 
         // check for zero shared-secret (see "shared_secret" note).
 
-        if peer.ss.ct_eq(&[0u8; 32]).into() {
+        let ss = peer.ss(keyst).ok_or(HandshakeError::KeyUnavailable)?;
+        if ss.ct_eq(&[0u8; 32]).into() {
             return Err(HandshakeError::InvalidSharedSecret);
         }
 
@@ -374,7 +397,7 @@ pub(super) fn consume_initiation<'a, O>(
 
         // (C, k) := Kdf2(C, DH(S_priv, S_pub))
 
-        let (ck, key) = KDF2!(&ck, &peer.ss);
+        let (ck, key) = KDF2!(&ck, &ss);
 
         // msg.timestamp := Aead(k, 0, Timestamp(), H)
 
@@ -387,9 +410,9 @@ pub(super) fn consume_initiation<'a, O>(
             &msg.f_timestamp  // ct || tag
         )?;
 
-        // check and update timestamp
+        // check and update timestamp (if admitted by the policy of the peer)
 
-        peer.check_replay_flood(device, &ts)?;
+        peer.check_replay_flood(device, &ts, admit)?;
 
         // H := Hash(H || msg.timestamp)
 
@@ -405,15 +428,16 @@ pub(super) fn consume_initiation<'a, O>(
     })
 }
 
-pub(super) fn create_response<R: RngCore + CryptoRng, O>(
+pub(super) fn create_response<R: RngCore + CryptoRng>(
     rng: &mut R,
-    peer: &Peer<O>,
+    psk: &Psk,
     pk: &PublicKey,
-    local: u32,              // sending identifier
-    state: TemporaryState,   // state from "consume_initiation"
-    msg: &mut NoiseResponse, // resulting response
+    local: u32,                     // sending identifier
+    state: TemporaryState,          // state from "consume_initiation"
+    keylog: &dyn Fn(&StaticSecret), // receiver of the ephemeral secret
+    msg: &mut NoiseResponse,        // resulting response
 ) -> Result<KeyPair, HandshakeError> {
-    log::debug!("create response");
+    log::debug!("event=create_response");
     clear_stack_on_return(CLEAR_PAGES, || {
         // unpack state
 
@@ -427,6 +451,7 @@ pub(super) fn create_response<R: RngCore + CryptoRng, O>(
 
         let eph_sk = StaticSecret::new(rng);
         let eph_pk = PublicKey::from(&eph_sk);
+        keylog(&eph_sk);
 
         // C := Kdf1(C, E_pub)
 
@@ -450,7 +475,7 @@ pub(super) fn create_response<R: RngCore + CryptoRng, O>(
 
         // (C, tau, k) := Kdf3(C, Q)
 
-        let (ck, tau, key) = KDF3!(&ck, &peer.psk);
+        let (ck, tau, key) = KDF3!(&ck, psk);
 
         // H := Hash(H || tau)
 
@@ -497,11 +522,14 @@ pub(super) fn consume_response<'a, O>(
     device: &'a Device<O>,
     keyst: &KeyState,
     msg: &NoiseResponse,
+    admit: &dyn Fn(&O) -> bool,
+    identified: &Cell<Option<&'a Peer<O>>>,
 ) -> Result<Output<'a, O>, HandshakeError> {
-    log::debug!("consume response");
+    log::debug!("event=consume_response");
     clear_stack_on_return(CLEAR_PAGES, || {
         // retrieve peer and copy initiation state
         let (peer, _) = device.lookup_id(msg.f_receiver.get())?;
+        identified.set(Some(peer));
 
         let (hs, ck, local, eph_sk) = match *peer.state.lock() {
             State::InitiationSent {
@@ -528,24 +556,30 @@ pub(super) fn consume_response<'a, O>(
 
         // C := Kdf1(C, DH(E_priv, S_pub))
 
-        let ck = KDF1!(&ck, shared_secret(&keyst.sk, &eph_r_pk)?.as_bytes());
+        let ck = KDF1!(&ck, &static_shared_secret(&*keyst.sk, &eph_r_pk)?);
 
         // (C, tau, k) := Kdf3(C, Q)
-
-        let (ck, tau, key) = KDF3!(&ck, &peer.psk);
-
         // H := Hash(H || tau)
-
-        let hs = HASH!(&hs, tau);
-
         // msg.empty := Aead(k, 0, [], H)
-
-        OPEN!(
-            &key,
-            &hs,          // ad
-            &mut [],      // pt
-            &msg.f_empty  // \epsilon || tag
-        )?;
+        //
+        // Q is the psk of the current epoch of the responder (tried in turn)
+
+        let (epoch, ck) = device
+            .psks(peer)
+            .iter()
+            .find_map(|(epoch, psk)| {
+                let (ck, tau, key) = KDF3!(&ck, psk);
+                let hs = HASH!(&hs, tau);
+                OPEN!(
+                    &key,
+                    &hs,          // ad
+                    &mut [],      // pt
+                    &msg.f_empty  // \epsilon || tag
+                )
+                .ok()
+                .map(|_| (*epoch, ck))
+            })
+            .ok_or(HandshakeError::DecryptionFailure)?;
 
         // derive key-pair
 
@@ -563,9 +597,15 @@ pub(super) fn consume_response<'a, O>(
         };
 
         if update {
+            // check the policy of the peer before consuming the initiation state
+            if !admit(&peer.opaque) {
+                return Err(HandshakeError::SourceRejected);
+            }
+
             // null the initiation state
             // (to avoid replay of this response message)
             *state = State::Reset;
+            peer.psk_used(epoch);
             let remote = msg.f_sender.get();
 
             // return confirmed key-pair
@@ -11,7 +11,7 @@ use x25519_dalek::StaticSecret;
 
 use clear_on_drop::clear::Clear;
 
-use super::device::Device;
+use super::device::{Device, KeyState};
 use super::macs;
 use super::timestamp;
 use super::types::*;
@@ -34,8 +34,16 @@ pub(super) struct Peer<O> {
     pub macs: Mutex<macs::Generator>,
 
     // constant state
-    pub ss: [u8; 32], // precomputed DH(static, static)
-    pub psk: Psk,     // psk of peer
+    pub pk: PublicKey, // static public key of peer
+    pub psk: Psk,      // psk of peer
+
+    // epoch of the psk used by the latest handshake (None if the static psk)
+    // and the instant the psk was first used (or set)
+    pub psk_epoch: Mutex<(Option<u64>, Instant)>,
+
+    // DH(static, static) and the device public key it was computed for,
+    // computed on demand and cleared when the peer is evicted
+    ss: Mutex<Option<([u8; 32], [u8; 32])>>,
 }
 
 pub enum State {
@@ -62,15 +70,61 @@ impl Drop for State {
 }
 
 impl<O> Peer<O> {
-    pub fn new(pk: PublicKey, ss: [u8; 32], opaque: O) -> Self {
+    pub fn new(pk: PublicKey, opaque: O) -> Self {
         Self {
             opaque,
             macs: Mutex::new(macs::Generator::new(pk)),
             state: Mutex::new(State::Reset),
             timestamp: Mutex::new(None),
             last_initiation_consumption: Mutex::new(None),
-            ss,
+            pk,
             psk: [0u8; 32],
+            psk_epoch: Mutex::new((None, Instant::now())),
+            ss: Mutex::new(None),
+        }
+    }
+
+    /// Returns the static-static shared secret,
+    /// computing (and caching) it if not already computed for the key state
+    ///
+    /// # Arguments
+    ///
+    /// * keyst - The static key state of the device
+    ///   (the current key, or the previous key during a key rotation)
+    ///
+    /// # Returns
+    ///
+    /// The shared secret, None if the private key is unavailable
+    pub fn ss(&self, keyst: &KeyState) -> Option<[u8; 32]> {
+        let mut cache = self.ss.lock();
+        match cache.as_ref() {
+            Some((pk, ss)) if pk == keyst.pk.as_bytes() => Some(*ss),
+            _ => {
+                let ss = keyst.sk.diffie_hellman(&self.pk)?;
+                if let Some((_, old)) = cache.as_mut() {
+                    old.clear();
+                }
+                *cache = Some((*keyst.pk.as_bytes(), ss));
+                Some(ss)
+            }
+        }
+    }
+
+    /// Clear the cached shared secret
+    /// (e.g. when the static key of the device changes)
+    pub fn clear_ss(&self) {
+        let mut ss = self.ss.lock();
+        if let Some((_, ss)) = ss.as_mut() {
+            ss.clear();
+        }
+        *ss = None;
+    }
+
+    /// Record the epoch of the psk used by a completed handshake
+    pub fn psk_used(&self, epoch: Option<u64>) {
+        let mut psk_epoch = self.psk_epoch.lock();
+        if psk_epoch.0 != epoch {
+            *psk_epoch = (epoch, Instant::now());
         }
     }
 
@@ -87,10 +141,12 @@ impl<O> Peer<O> {
     ///
     /// * st_new - The updated state of the peer
     /// * ts_new - The associated timestamp
+    /// * admit - The policy of the peer, checked before the state is updated
     pub fn check_replay_flood(
         &self,
         device: &Device<O>,
         timestamp_new: &timestamp::TAI64N,
+        admit: &dyn Fn(&O) -> bool,
     ) -> Result<(), HandshakeError> {
         let mut state = self.state.lock();
         let mut timestamp = self.timestamp.lock();
@@ -116,6 +172,11 @@ impl<O> Peer<O> {
             _ => (),
         }
 
+        // check policy
+        if !admit(&self.opaque) {
+            return Err(HandshakeError::SourceRejected);
+        }
+
         // reset state
         match *state {
             State::InitiationSent { local, .. } => device.release(local),
@@ -1,6 +1,6 @@
 use std::collections::HashMap;
-use std::net::IpAddr;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
 use std::time::{Duration, Instant};
@@ -9,11 +9,81 @@ use spin;
 
 const PACKETS_PER_SECOND: u64 = 20;
 const PACKETS_BURSTABLE: u64 = 5;
-const PACKET_COST: u64 = 1_000_000_000 / PACKETS_PER_SECOND;
-const MAX_TOKENS: u64 = PACKET_COST * PACKETS_BURSTABLE;
 
 const GC_INTERVAL: Duration = Duration::from_secs(1);
 
+/// Configuration of the handshake rate limiter,
+/// the default matches the limits of the other WireGuard implementations.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateLimiterConfig {
+    pub packets_per_second: u64, // sustained rate for every source
+    pub packets_burstable: u64,  // number of packets a source may burst
+    pub gc_interval: Duration,   // entries idle for longer than this are removed
+    pub ipv4_prefix: u8,         // IPv4 sources are grouped by prefix of this length
+    pub ipv6_prefix: u8,         // IPv6 sources are grouped by prefix of this length
+    pub max_entries: usize,      // maximum number of tracked sources (0 for unlimited)
+}
+
+impl Default for RateLimiterConfig {
+    fn default() -> Self {
+        RateLimiterConfig {
+            packets_per_second: PACKETS_PER_SECOND,
+            packets_burstable: PACKETS_BURSTABLE,
+            gc_interval: GC_INTERVAL,
+            ipv4_prefix: 32,
+            ipv6_prefix: 128,
+            max_entries: 0,
+        }
+    }
+}
+
+impl RateLimiterConfig {
+    /// Returns true if the configuration can be used by the rate limiter
+    pub fn is_valid(&self) -> bool {
+        self.packets_per_second > 0
+            && self.packets_per_second <= 1_000_000_000
+            && self.packets_burstable > 0
+            && self.gc_interval > Duration::from_secs(0)
+            && self.ipv4_prefix <= 32
+            && self.ipv6_prefix <= 128
+    }
+
+    fn packet_cost(&self) -> u64 {
+        1_000_000_000 / self.packets_per_second
+    }
+
+    fn max_tokens(&self) -> u64 {
+        self.packet_cost().saturating_mul(self.packets_burstable)
+    }
+
+    // the key of the table entry for the source address
+    fn key(&self, addr: &IpAddr) -> IpAddr {
+        match addr {
+            IpAddr::V4(ip) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.ipv4_prefix))
+                    .unwrap_or(0);
+                IpAddr::V4(Ipv4Addr::from(u32::from(*ip) & mask))
+            }
+            IpAddr::V6(ip) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.ipv6_prefix))
+                    .unwrap_or(0);
+                IpAddr::V6(Ipv6Addr::from(u128::from(*ip) & mask))
+            }
+        }
+    }
+}
+
+/// Statistics of the handshake rate limiter
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RateLimiterStats {
+    pub entries: usize,  // number of tracked sources
+    pub allowed: u64,    // number of allowed packets
+    pub limited: u64,    // number of rejected packets (including "table_full")
+    pub table_full: u64, // number of packets rejected since the table was full
+}
+
 struct Entry {
     pub last_time: Instant,
     pub tokens: u64,
@@ -24,7 +94,11 @@ pub struct RateLimiter(Arc<RateLimiterInner>);
 struct RateLimiterInner {
     gc_running: AtomicBool,
     gc_dropped: (Mutex<bool>, Condvar),
+    config: spin::RwLock<RateLimiterConfig>,
     table: spin::RwLock<HashMap<IpAddr, spin::Mutex<Entry>>>,
+    allowed: AtomicU64,
+    limited: AtomicU64,
+    table_full: AtomicU64,
 }
 
 impl Drop for RateLimiter {
@@ -42,26 +116,64 @@ impl RateLimiter {
         RateLimiter(Arc::new(RateLimiterInner {
             gc_dropped: (Mutex::new(false), Condvar::new()),
             gc_running: AtomicBool::from(false),
+            config: spin::RwLock::new(RateLimiterConfig::default()),
             table: spin::RwLock::new(HashMap::new()),
+            allowed: AtomicU64::new(0),
+            limited: AtomicU64::new(0),
+            table_full: AtomicU64::new(0),
         }))
     }
 
+    /// Replaces the configuration, clearing all entries.
+    pub fn configure(&self, config: RateLimiterConfig) {
+        debug_assert!(config.is_valid());
+        *self.0.config.write() = config;
+        self.0.table.write().clear();
+    }
+
+    pub fn config(&self) -> RateLimiterConfig {
+        *self.0.config.read()
+    }
+
+    pub fn stats(&self) -> RateLimiterStats {
+        RateLimiterStats {
+            entries: self.0.table.read().len(),
+            allowed: self.0.allowed.load(Ordering::Relaxed),
+            limited: self.0.limited.load(Ordering::Relaxed),
+            table_full: self.0.table_full.load(Ordering::Relaxed),
+        }
+    }
+
     pub fn allow(&self, addr: &IpAddr) -> bool {
+        let allowed = self.check(addr);
+        if allowed {
+            self.0.allowed.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.0.limited.fetch_add(1, Ordering::Relaxed);
+        }
+        allowed
+    }
+
+    fn check(&self, addr: &IpAddr) -> bool {
+        let config = self.config();
+        let key = config.key(addr);
+
         // check if allowed
         let allowed = {
             // check for existing entry (only requires read lock)
-            if let Some(entry) = self.0.table.read().get(addr) {
+            if let Some(entry) = self.0.table.read().get(&key) {
                 // update existing entry
                 let mut entry = entry.lock();
 
                 // add tokens earned since last time
-                entry.tokens = MAX_TOKENS
-                    .min(entry.tokens + u64::from(entry.last_time.elapsed().subsec_nanos()));
+                let max_tokens = config.max_tokens();
+                let earned = entry.last_time.elapsed().as_nanos().min(max_tokens as u128) as u64;
+                entry.tokens = max_tokens.min(entry.tokens + earned);
                 entry.last_time = Instant::now();
 
                 // subtract cost of packet
-                if entry.tokens > PACKET_COST {
-                    entry.tokens -= PACKET_COST;
+                if entry.tokens > config.packet_cost() {
+                    entry.tokens -= config.packet_cost();
                     return true;
                 } else {
                     return false;
@@ -69,11 +181,16 @@ impl RateLimiter {
             }
 
             // add new entry (write lock)
-            self.0.table.write().insert(
-                *addr,
+            let mut table = self.0.table.write();
+            if config.max_entries > 0 && table.len() >= config.max_entries {
+                self.0.table_full.fetch_add(1, Ordering::Relaxed);
+                return false;
+            }
+            table.insert(
+                key,
                 spin::Mutex::new(Entry {
                     last_time: Instant::now(),
-                    tokens: MAX_TOKENS - PACKET_COST,
+                    tokens: config.max_tokens() - config.packet_cost(),
                 }),
             );
             true
@@ -86,11 +203,13 @@ impl RateLimiter {
                 let &(ref lock, ref cvar) = &limiter.gc_dropped;
                 let mut dropped = lock.lock().unwrap();
                 while !*dropped {
+                    let gc_interval = limiter.config.read().gc_interval;
+
                     // garbage collect
                     {
                         let mut tw = limiter.table.write();
                         tw.retain(|_, ref mut entry| {
-                            entry.lock().last_time.elapsed() <= GC_INTERVAL
+                            entry.lock().last_time.elapsed() <= gc_interval
                         });
                         if tw.len() == 0 {
                             limiter.gc_running.store(false, Ordering::Relaxed);
@@ -98,8 +217,8 @@ impl RateLimiter {
                         }
                     }
 
-                    // wait until stopped or new GC (~1 every sec)
-                    let res = cvar.wait_timeout(dropped, GC_INTERVAL).unwrap();
+                    // wait until stopped or new GC (~1 every interval)
+                    let res = cvar.wait_timeout(dropped, gc_interval).unwrap();
                     dropped = res.0;
                 }
             });
@@ -114,6 +233,8 @@ mod tests {
     use super::*;
     use std;
 
+    const PACKET_COST: u64 = 1_000_000_000 / PACKETS_PER_SECOND;
+
     struct Result {
         allowed: bool,
         text: &'static str,
@@ -197,4 +318,35 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_ratelimiter_prefix() {
+        let ratelimiter = RateLimiter::new();
+        ratelimiter.configure(RateLimiterConfig {
+            ipv6_prefix: 64,
+            max_entries: 2,
+            ..Default::default()
+        });
+
+        // sources within the same /64 share a bucket
+        for i in 0..PACKETS_BURSTABLE {
+            let ip = format!("2001:db8::{:x}", i + 1).parse().unwrap();
+            assert!(ratelimiter.allow(&ip));
+        }
+        assert!(!ratelimiter.allow(&"2001:db8::ffff".parse().unwrap()));
+
+        // the table is capped
+        assert!(ratelimiter.allow(&"2001:db8:0:1::1".parse().unwrap()));
+        assert!(!ratelimiter.allow(&"2001:db8:0:2::1".parse().unwrap()));
+
+        assert_eq!(
+            ratelimiter.stats(),
+            RateLimiterStats {
+                entries: 2,
+                allowed: PACKETS_BURSTABLE + 1,
+                limited: 2,
+                table_full: 1,
+            }
+        );
+    }
 }
@@ -12,8 +12,12 @@ use rand::rngs::OsRng;
 use x25519_dalek::PublicKey;
 use x25519_dalek::StaticSecret;
 
+use super::super::provider::PskProvider;
 use super::messages::{Initiation, Response};
 
+use spin::Mutex;
+use std::sync::Arc;
+
 fn setup_devices<R: RngCore + CryptoRng, O: Default>(
     rng: &mut R,
 ) -> (PublicKey, Device<O>, PublicKey, Device<O>) {
@@ -197,3 +201,141 @@ fn handshake_no_load() {
     dev1.remove(&pk2).unwrap();
     dev2.remove(&pk1).unwrap();
 }
+
+/* Test that messages rejected by the policy of the peer
+ * do not update the handshake state (the message is processed again once admitted).
+ */
+#[test]
+fn handshake_policy() {
+    let (_pk1, dev1, pk2, dev2): (_, Device<usize>, _, _) = setup_devices(&mut OsRng);
+
+    // the rejected initiation does not consume the timestamp
+    let msg1 = dev1.begin(&mut OsRng, &pk2).unwrap();
+    match dev2.process_attributed(&mut OsRng, &msg1, None, &|_| false) {
+        Err((HandshakeError::SourceRejected, Some(_))) => (),
+        _ => panic!("initiation not rejected by the policy"),
+    }
+    let (_, msg2, _) = dev2
+        .process(&mut OsRng, &msg1, None)
+        .expect("failed to process initiation");
+
+    // the rejected response does not consume the initiation state
+    let msg2 = msg2.unwrap();
+    match dev1.process_attributed(&mut OsRng, &msg2, None, &|_| false) {
+        Err((HandshakeError::SourceRejected, Some(_))) => (),
+        _ => panic!("response not rejected by the policy"),
+    }
+    dev1.process(&mut OsRng, &msg2, None)
+        .expect("failed to process response");
+}
+
+#[test]
+fn handshake_key_rotation() {
+    let (pk1, mut dev1, pk2, mut dev2): (_, Device<usize>, _, _) = setup_devices(&mut OsRng);
+    let psk = dev1.get_psk(&pk2).unwrap();
+
+    // the responder rotates its key
+    let sk3 = StaticSecret::new(&mut OsRng);
+    let pk3 = PublicKey::from(&sk3);
+    dev2.rotate_sk(sk3, Duration::from_secs(60));
+    assert_eq!(dev2.get_previous_pk().unwrap().as_bytes(), pk2.as_bytes());
+
+    // initiations to the previous public key are accepted during the grace window
+    let msg1 = dev1.begin(&mut OsRng, &pk2).unwrap();
+    let (_, msg2, ks_r) = dev2
+        .process(&mut OsRng, &msg1, None)
+        .expect("failed to process initiation to the previous key");
+    let (_, _, ks_i) = dev1
+        .process(&mut OsRng, &msg2.unwrap(), None)
+        .expect("failed to process response");
+    assert_eq!(ks_i.unwrap().send, ks_r.unwrap().recv);
+
+    // initiations to the new public key are accepted
+    wait();
+    dev1.remove(&pk2).unwrap();
+    dev1.add(pk3, 0).unwrap();
+    dev1.set_psk(pk3, psk).unwrap();
+    let msg1 = dev1.begin(&mut OsRng, &pk3).unwrap();
+    let (_, msg2, _) = dev2
+        .process(&mut OsRng, &msg1, None)
+        .expect("failed to process initiation to the new key");
+    dev1.process(&mut OsRng, &msg2.unwrap(), None)
+        .expect("failed to process response");
+
+    // after the grace window the previous key is rejected (without being erased explicitly)
+    wait();
+    let sk4 = StaticSecret::new(&mut OsRng);
+    dev2.rotate_sk(sk4, Duration::from_secs(0));
+    assert!(dev2.get_previous_pk().is_none());
+    let msg1 = dev1.begin(&mut OsRng, &pk3).unwrap();
+    match dev2.process(&mut OsRng, &msg1, None) {
+        Err(HandshakeError::InvalidMac1) => (),
+        _ => panic!("initiation to an expired key accepted"),
+    }
+
+    dev2.remove(&pk1).unwrap();
+}
+
+/* A psk provider deriving the psk of every epoch from the epoch,
+ * the current epoch is set by the test.
+ */
+struct EpochPsks {
+    current: Mutex<Option<u64>>,
+}
+
+impl EpochPsks {
+    fn new(current: Option<u64>) -> Arc<EpochPsks> {
+        Arc::new(EpochPsks {
+            current: Mutex::new(current),
+        })
+    }
+}
+
+impl PskProvider for EpochPsks {
+    fn current(&self, pk: &PublicKey) -> Option<(u64, [u8; 32])> {
+        let epoch = (*self.current.lock())?;
+        self.lookup(pk, epoch).map(|psk| (epoch, psk))
+    }
+
+    fn lookup(&self, _pk: &PublicKey, epoch: u64) -> Option<[u8; 32]> {
+        Some([epoch as u8; 32])
+    }
+}
+
+#[test]
+fn handshake_psk_provider() {
+    let (pk1, mut dev1, pk2, mut dev2): (_, Device<usize>, _, _) = setup_devices(&mut OsRng);
+
+    let handshake = |dev1: &Device<usize>, dev2: &Device<usize>| {
+        wait();
+        let msg1 = dev1.begin(&mut OsRng, &pk2).unwrap();
+        let (_, msg2, ks_r) = dev2.process(&mut OsRng, &msg1, None)?;
+        let (_, _, ks_i) = dev1.process(&mut OsRng, &msg2.unwrap(), None)?;
+        assert_eq!(ks_i.unwrap().send, ks_r.unwrap().recv);
+        Ok::<(), HandshakeError>(())
+    };
+
+    // both sides in the same epoch
+    let psks1 = EpochPsks::new(Some(5));
+    let psks2 = EpochPsks::new(Some(5));
+    dev1.set_psk_provider(Some(psks1.clone()));
+    dev2.set_psk_provider(Some(psks2.clone()));
+    handshake(&dev1, &dev2).unwrap();
+    assert_eq!(dev1.get_psk_age(&pk2).unwrap().0, Some(5));
+    assert_eq!(dev2.get_psk_age(&pk1).unwrap().0, Some(5));
+
+    // the responder switches epoch first (the initiator accepts the adjacent epoch)
+    *psks2.current.lock() = Some(6);
+    handshake(&dev1, &dev2).unwrap();
+    assert_eq!(dev1.get_psk_age(&pk2).unwrap().0, Some(6));
+
+    // more than one epoch apart
+    *psks2.current.lock() = Some(8);
+    assert!(handshake(&dev1, &dev2).is_err());
+
+    // without a current epoch the static psk is used
+    *psks1.current.lock() = None;
+    *psks2.current.lock() = None;
+    handshake(&dev1, &dev2).unwrap();
+    assert_eq!(dev1.get_psk_age(&pk2).unwrap().0, None);
+}
@@ -3,6 +3,8 @@ use super::super::types::KeyPair;
 use std::error::Error;
 use std::fmt;
 
+use x25519_dalek::PublicKey;
+
 /* Internal types for the noise IKpsk2 implementation */
 
 // config error
@@ -37,23 +39,26 @@ impl Error for ConfigError {
 #[derive(Debug)]
 pub enum HandshakeError {
     DecryptionFailure,
-    UnknownPublicKey,
+    UnknownPublicKey(PublicKey),
     UnknownReceiverId,
     InvalidMessageFormat,
     InvalidSharedSecret,
+    KeyUnavailable,
     OldTimestamp,
     InvalidState,
     InvalidMac1,
     RateLimited,
     InitiationFlood,
+    SourceRejected,
 }
 
 impl fmt::Display for HandshakeError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             HandshakeError::InvalidSharedSecret => write!(f, "Zero shared secret"),
+            HandshakeError::KeyUnavailable => write!(f, "Static private key unavailable"),
             HandshakeError::DecryptionFailure => write!(f, "Failed to AEAD:OPEN"),
-            HandshakeError::UnknownPublicKey => write!(f, "Unknown public key"),
+            HandshakeError::UnknownPublicKey(_) => write!(f, "Unknown public key"),
             HandshakeError::UnknownReceiverId => {
                 write!(f, "Receiver id not allocated to any handshake")
             }
@@ -65,6 +70,9 @@ impl fmt::Display for HandshakeError {
             HandshakeError::InitiationFlood => {
                 write!(f, "Message was dropped because of initiation flood")
             }
+            HandshakeError::SourceRejected => {
+                write!(f, "Message source was rejected by the policy of the peer")
+            }
         }
     }
 }
@@ -85,6 +93,11 @@ pub type Output<'a, O> = (
     Option<KeyPair>, // resulting key-pair of successful handshake
 );
 
+// output (or error) along with the identifier of the peer the message was attributed to
+
+pub type AttributedResult<'a, O> =
+    Result<(Output<'a, O>, Option<&'a O>), (HandshakeError, Option<&'a O>)>;
+
 // preshared key
 
 pub type Psk = [u8; 32];
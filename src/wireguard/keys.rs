@@ -0,0 +1,239 @@
+/* Textual representation of keys:
+ *
+ * The UAPI encodes keys as hex (as required by the cross-platform specification),
+ * while users (e.g. the output of "wg genkey") and logs use standard base64.
+ */
+use std::fmt;
+use std::str::FromStr;
+
+use clear_on_drop::clear::Clear;
+use hex::FromHex;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct InvalidKey;
+
+impl fmt::Display for InvalidKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid key (expected 32 bytes as base64 or hex)")
+    }
+}
+
+/// A public key (or any other non-secret 32 byte value),
+/// printed as base64 and parsed from base64 or hex.
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub struct Base64Key([u8; 32]);
+
+impl Base64Key {
+    pub fn new(key: [u8; 32]) -> Base64Key {
+        Base64Key(key)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl From<&PublicKey> for Base64Key {
+    fn from(pk: &PublicKey) -> Base64Key {
+        Base64Key(*pk.as_bytes())
+    }
+}
+
+impl From<Base64Key> for PublicKey {
+    fn from(key: Base64Key) -> PublicKey {
+        PublicKey::from(key.0)
+    }
+}
+
+impl FromStr for Base64Key {
+    type Err = InvalidKey;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse(s).map(Base64Key)
+    }
+}
+
+impl fmt::Display for Base64Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&encode(&self.0))
+    }
+}
+
+impl fmt::Debug for Base64Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Base64Key({})", self)
+    }
+}
+
+/// A private (or preshared) key, parsed from base64 or hex.
+///
+/// The key is redacted when formatted,
+/// except by the alternate Display flag (i.e. "{:#}") which prints the key as base64.
+/// The key is zeroed on drop.
+#[derive(Clone)]
+pub struct SecretKey([u8; 32]);
+
+impl SecretKey {
+    pub fn new(key: [u8; 32]) -> SecretKey {
+        SecretKey(key)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl Drop for SecretKey {
+    fn drop(&mut self) {
+        self.0.clear()
+    }
+}
+
+impl From<&StaticSecret> for SecretKey {
+    fn from(sk: &StaticSecret) -> SecretKey {
+        SecretKey(sk.to_bytes())
+    }
+}
+
+impl From<&SecretKey> for StaticSecret {
+    fn from(key: &SecretKey) -> StaticSecret {
+        StaticSecret::from(key.0)
+    }
+}
+
+impl FromStr for SecretKey {
+    type Err = InvalidKey;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse(s).map(SecretKey)
+    }
+}
+
+impl fmt::Display for SecretKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if f.alternate() {
+            f.write_str(&encode(&self.0))
+        } else {
+            f.write_str("(redacted)")
+        }
+    }
+}
+
+impl fmt::Debug for SecretKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // never revealed (e.g. by "{:#?}" of an enclosing struct)
+        write!(f, "SecretKey(redacted)")
+    }
+}
+
+/// Parse a 32 byte key encoded as base64 (44 characters) or hex (64 characters)
+pub fn parse(s: &str) -> Result<[u8; 32], InvalidKey> {
+    if s.len() == 64 {
+        return <[u8; 32]>::from_hex(s).map_err(|_| InvalidKey);
+    }
+    let mut bytes = decode(s).ok_or(InvalidKey)?;
+    if bytes.len() != 32 {
+        bytes[..].clear();
+        return Err(InvalidKey);
+    }
+    let mut key = [0u8; 32];
+    key.copy_from_slice(&bytes);
+    bytes[..].clear();
+    Ok(key)
+}
+
+/// Encode as standard base64 (with padding)
+pub fn encode(bytes: &[u8]) -> String {
+    let mut out = String::new();
+    for chunk in bytes.chunks(3) {
+        let mut buf = [0u8; 3];
+        buf[..chunk.len()].copy_from_slice(chunk);
+        let n = (u32::from(buf[0]) << 16) | (u32::from(buf[1]) << 8) | u32::from(buf[2]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[((n >> (18 - 6 * i)) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Decode standard base64 (padding is required)
+pub fn decode(s: &str) -> Option<Vec<u8>> {
+    let chunks = s.as_bytes().chunks_exact(4);
+    if !chunks.remainder().is_empty() {
+        return None;
+    }
+    let count = chunks.len();
+    let mut out = Vec::with_capacity(count * 3);
+    for (i, chunk) in chunks.enumerate() {
+        let last = i == count - 1;
+        let pad = chunk.iter().rev().take_while(|c| **c == b'=').count();
+        if pad > 2 || (pad > 0 && !last) {
+            return None;
+        }
+        let mut n = 0u32;
+        for c in &chunk[..4 - pad] {
+            let v = ALPHABET.iter().position(|a| a == c)? as u32;
+            n = (n << 6) | v;
+        }
+        n <<= 6 * pad as u32;
+        let bytes = [(n >> 16) as u8, (n >> 8) as u8, n as u8];
+        out.extend_from_slice(&bytes[..3 - pad]);
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_base64() {
+        let vectors: &[(&[u8], &str)] = &[
+            (b"", ""),
+            (b"f", "Zg=="),
+            (b"fo", "Zm8="),
+            (b"foo", "Zm9v"),
+            (b"foob", "Zm9vYg=="),
+            (b"foobar", "Zm9vYmFy"),
+        ];
+        for (bytes, text) in vectors {
+            assert_eq!(encode(bytes), *text);
+            assert_eq!(decode(text).unwrap(), *bytes);
+        }
+        assert_eq!(decode("Zg="), None);
+        assert_eq!(decode("Zg=a"), None);
+        assert_eq!(decode("Zg==Zg=="), None);
+        assert_eq!(decode("Z!=="), None);
+    }
+
+    #[test]
+    fn test_keys() {
+        let bytes = [0xa5u8; 32];
+        let b64 = encode(&bytes);
+        let hex = hex::encode(bytes);
+
+        let pk: Base64Key = b64.parse().unwrap();
+        assert_eq!(pk.as_bytes(), &bytes);
+        assert_eq!(hex.parse::<Base64Key>().unwrap(), pk);
+        assert_eq!(pk.to_string(), b64);
+        assert_eq!(format!("{:?}", pk), format!("Base64Key({})", b64));
+
+        let sk: SecretKey = b64.parse().unwrap();
+        assert_eq!(sk.as_bytes(), &bytes);
+        assert_eq!(sk.to_string(), "(redacted)");
+        assert_eq!(format!("{:?}", sk), "SecretKey(redacted)");
+        assert_eq!(format!("{:#?}", sk), "SecretKey(redacted)");
+        assert_eq!(format!("{:#}", sk), b64);
+
+        assert!(b64[..40].parse::<Base64Key>().is_err());
+        assert!(encode(&[0u8; 31]).parse::<Base64Key>().is_err());
+        assert!(hex[..62].parse::<SecretKey>().is_err());
+    }
+}
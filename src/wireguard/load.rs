@@ -0,0 +1,190 @@
+use super::constants::*;
+use super::events::{Event, Events};
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use spin::{Mutex, RwLock};
+
+/// Policy for detecting that the device is under load,
+/// while under load handshake initiations must carry a valid cookie
+/// and are rate limited per source.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LoadPolicy {
+    pub max_queued: usize, // maximum number of queued handshake messages (excess is dropped)
+    pub threshold: usize,  // queued handshake messages above which the device is under load
+    pub duration: Duration, // minimum duration of the under load state
+    pub always_cookie: bool, // consider the device permanently under load
+}
+
+impl Default for LoadPolicy {
+    fn default() -> Self {
+        LoadPolicy {
+            max_queued: MAX_QUEUED_INCOMING_HANDSHAKES,
+            threshold: THRESHOLD_UNDER_LOAD,
+            duration: DURATION_UNDER_LOAD,
+            always_cookie: false,
+        }
+    }
+}
+
+impl LoadPolicy {
+    /// Returns true if the policy can be applied to the device
+    pub fn is_valid(&self) -> bool {
+        self.max_queued > 0 && self.threshold <= self.max_queued
+    }
+}
+
+/// Statistics of the under load detection
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LoadStats {
+    pub under_load: bool,    // currently under load
+    pub transitions: u64,    // number of times the device went under load
+    pub dropped: u64,        // number of handshake messages dropped since the queue was full
+    pub cookie_replies: u64, // number of cookie replies sent while under load
+}
+
+pub struct Load {
+    policy: RwLock<LoadPolicy>,
+    last_under_load: Mutex<Instant>,
+    under_load: AtomicBool,
+    transitions: AtomicU64,
+    dropped: AtomicU64,
+    cookie_replies: AtomicU64,
+}
+
+impl Load {
+    pub fn new() -> Load {
+        Load {
+            policy: RwLock::new(LoadPolicy::default()),
+            last_under_load: Mutex::new(Instant::now() - TIME_HORIZON),
+            under_load: AtomicBool::new(false),
+            transitions: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            cookie_replies: AtomicU64::new(0),
+        }
+    }
+
+    pub fn set_policy(&self, policy: LoadPolicy) {
+        debug_assert!(policy.is_valid());
+        *self.policy.write() = policy;
+    }
+
+    pub fn get_policy(&self) -> LoadPolicy {
+        *self.policy.read()
+    }
+
+    /// Checks if another handshake message may be queued
+    ///
+    /// # Arguments
+    ///
+    /// - `pending`: The number of handshake messages currently queued
+    pub fn admit(&self, pending: usize) -> bool {
+        if pending < self.policy.read().max_queued {
+            true
+        } else {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            false
+        }
+    }
+
+    /// Determines if the device is under load,
+    /// emitting an event whenever the device goes under load or recovers.
+    ///
+    /// Note that the recovery is only detected when the next handshake message is processed
+    /// (or the statistics are read).
+    ///
+    /// # Arguments
+    ///
+    /// - `pending`: The number of handshake messages currently queued
+    /// - `events`: The event stream of the device
+    pub fn check(&self, pending: usize, events: &Events) -> bool {
+        let policy = self.get_policy();
+
+        // immediate go under load if too many handshakes pending
+        let under_load = if policy.always_cookie || pending > policy.threshold {
+            *self.last_under_load.lock() = Instant::now();
+            true
+        } else {
+            // remain under load for the configured duration
+            policy.duration >= self.last_under_load.lock().elapsed()
+        };
+
+        self.transition(under_load, pending, events);
+        under_load
+    }
+
+    /// Accounts for a cookie reply sent while under load
+    pub fn cookie_reply(&self) {
+        self.cookie_replies.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self, events: &Events) -> LoadStats {
+        let policy = self.get_policy();
+        let under_load =
+            policy.always_cookie || policy.duration >= self.last_under_load.lock().elapsed();
+        self.transition(under_load, 0, events);
+        LoadStats {
+            under_load,
+            transitions: self.transitions.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            cookie_replies: self.cookie_replies.load(Ordering::Relaxed),
+        }
+    }
+
+    fn transition(&self, under_load: bool, pending: usize, events: &Events) {
+        if self.under_load.swap(under_load, Ordering::Relaxed) == under_load {
+            return;
+        }
+        if under_load {
+            log::info!("pending={} event=under_load", pending);
+            self.transitions.fetch_add(1, Ordering::Relaxed);
+            events.emit(Event::UnderLoad { pending });
+        } else {
+            log::info!("event=load_normal");
+            events.emit(Event::LoadNormal);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_transitions() {
+        let load = Load::new();
+        let events = Events::new();
+        let rx = events.subscribe();
+
+        load.set_policy(LoadPolicy {
+            max_queued: 8,
+            threshold: 4,
+            duration: Duration::from_millis(20),
+            always_cookie: false,
+        });
+
+        assert!(!load.check(4, &events));
+        assert!(load.check(5, &events));
+        assert!(load.check(0, &events), "remains under load");
+        assert!(load.admit(7));
+        assert!(!load.admit(8));
+
+        std::thread::sleep(Duration::from_millis(40));
+        assert!(!load.check(0, &events));
+
+        match (rx.try_recv(), rx.try_recv()) {
+            (Ok(Event::UnderLoad { pending: 5 }), Ok(Event::LoadNormal)) => (),
+            events => panic!("unexpected events: {:?}", events),
+        }
+
+        load.set_policy(LoadPolicy {
+            always_cookie: true,
+            ..Default::default()
+        });
+        let stats = load.stats(&events);
+        assert!(stats.under_load);
+        assert_eq!(stats.transitions, 2);
+        assert_eq!(stats.dropped, 1);
+    }
+}
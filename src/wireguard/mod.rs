@@ -7,9 +7,15 @@
  * and the crypto-key router code together,
  * e.g. every WireGuard peer consists of a handshake and router peer.
  */
+mod capture;
 mod constants;
+mod diagnostics;
+mod events;
 mod handshake;
+mod keys;
+mod load;
 mod peer;
+mod provider;
 mod queue;
 mod router;
 mod timers;
@@ -23,6 +29,51 @@ mod tests;
 // represents a WireGuard interface
 pub use wireguard::WireGuard;
 
+// packet capture and keylog
+pub use capture::{KeyLog, KeyLogFile};
+
+// the pcapng format and encapsulation of messages (shared with the replay of captures)
+#[cfg(test)]
+pub(crate) use capture::{
+    encapsulate, BLOCK_ENHANCED_PACKET, BLOCK_INTERFACE_DESCRIPTION, BLOCK_SECTION_HEADER,
+    BYTE_ORDER_MAGIC, OPT_END, OPT_EPB_FLAGS, OPT_IF_NAME, PROTOCOL_UDP,
+};
+#[cfg(test)]
+pub(crate) use router::Direction;
+
+// textual representation of keys
+pub use keys::{Base64Key, SecretKey};
+
+// recent outcomes of handshakes
+pub use diagnostics::HandshakeRecord;
+
+// events emitted by a WireGuard interface
+pub use events::Event;
+
+// roaming policy of a peer
+pub use router::Roaming;
+
+// peers reachable from a peer when relaying
+pub use peer::RelayAcl;
+
+// rules filtering the inner packets exchanged with a peer
+pub use router::FilterRule;
+
+// rate limits of a peer
+pub use router::{Excess, LimiterState};
+
+// configuration and statistics of the handshake rate limiter
+pub use handshake::{RateLimiterConfig, RateLimiterStats};
+
+// private key backends
+pub use handshake::{FileKey, StaticKey};
+
+// policy and statistics of the under load detection
+pub use load::{LoadPolicy, LoadStats};
+
+// on-demand provisioning of peers
+pub use provider::{PeerProvider, ProvidedPeer, PskProvider};
+
 #[cfg(test)]
 use super::platform::dummy;
 
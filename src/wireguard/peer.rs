@@ -3,11 +3,14 @@ use super::timers::Timers;
 use super::tun::Tun;
 use super::udp::UDP;
 
-use super::constants::REKEY_TIMEOUT;
+use super::constants::{REKEY_TIMEOUT, TIME_HORIZON};
+use super::diagnostics::HandshakeHistory;
+use super::keys::Base64Key;
 use super::wireguard::WireGuard;
 use super::workers::HandshakeJob;
 
 use std::fmt;
+use std::net::SocketAddr;
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::time::{Instant, SystemTime};
 
@@ -15,10 +18,23 @@ use spin::{Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
 
 use x25519_dalek::PublicKey;
 
-pub struct PeerInner<T: Tun, B: UDP> {
-    // internal id (for logging)
-    pub id: u64,
+/// Governs which peers a peer may reach when the device relays between peers
+#[derive(Clone, Debug)]
+pub enum RelayAcl {
+    Any,                   // every other peer of the device
+    Peers(Vec<PublicKey>), // only the listed peers (none by default)
+}
+
+impl RelayAcl {
+    pub fn allows(&self, dst: &PublicKey) -> bool {
+        match self {
+            RelayAcl::Any => true,
+            RelayAcl::Peers(peers) => peers.iter().any(|pk| pk.as_bytes() == dst.as_bytes()),
+        }
+    }
+}
 
+pub struct PeerInner<T: Tun, B: UDP> {
     // wireguard device state
     pub wg: WireGuard<T, B>,
 
@@ -31,6 +47,25 @@ pub struct PeerInner<T: Tun, B: UDP> {
     pub pk: PublicKey, // public key (TODO: there has to be a way to remove this)
     pub rx_bytes: AtomicU64, // received bytes
     pub tx_bytes: AtomicU64, // transmitted bytes
+    pub rx_packets: AtomicU64, // received transport messages
+    pub tx_packets: AtomicU64, // transmitted transport messages
+
+    // candidate endpoints (ordered by preference) and index of the active candidate
+    pub endpoints: Mutex<(Vec<SocketAddr>, usize)>,
+
+    // peers which may be reached by relaying through the device
+    pub relay_acl: Mutex<RelayAcl>,
+
+    // idle peer eviction: is the runtime state evicted?
+    // and the traffic counter at the instant it last changed
+    pub evicted: AtomicBool,
+    pub activity: Mutex<(u64, Instant)>,
+
+    // are the packets exchanged with the peer captured?
+    pub capture: AtomicBool,
+
+    // recent outcomes of handshake messages attributed to the peer
+    pub handshakes: HandshakeHistory,
 
     // timer model
     pub timers: RwLock<Timers>,
@@ -43,13 +78,13 @@ impl<T: Tun, B: UDP> PeerInner<T, B> {
      * The function is ratelimited.
      */
     pub fn packet_send_handshake_initiation(&self) {
-        log::trace!("{} : packet_send_handshake_initiation", self);
+        log::trace!("{} event=handshake_initiation_requested", self);
 
         // the function is rate limited
         {
             let mut lhs = self.last_handshake_sent.lock();
             if lhs.elapsed() < REKEY_TIMEOUT {
-                log::trace!("{} : packet_send_handshake_initiation, rate-limited!", self);
+                log::trace!("{} event=handshake_initiation_rate_limited", self);
                 return;
             }
             *lhs = Instant::now();
@@ -59,16 +94,97 @@ impl<T: Tun, B: UDP> PeerInner<T, B> {
         if !self.handshake_queued.swap(true, Ordering::SeqCst) {
             self.wg.pending.fetch_add(1, Ordering::SeqCst);
             self.wg.queue.send(HandshakeJob::New(self.pk));
-            log::trace!(
-                "{} : packet_send_handshake_initiation, handshake queued",
-                self
-            );
+            log::trace!("{} event=handshake_initiation_queued", self);
         } else {
-            log::trace!(
-                "{} : packet_send_handshake_initiation, handshake already queued",
-                self
-            );
+            log::trace!("{} event=handshake_initiation_already_queued", self);
+        }
+    }
+
+    /* Rebuild the runtime state of an evicted peer
+     * (called on the next handshake or outbound packet)
+     *
+     * The remaining state (keys, shared secret and queues) is rebuilt on demand.
+     */
+    pub fn revive(&self) {
+        if self.evicted.swap(false, Ordering::SeqCst) {
+            log::debug!("{} event=peer_revived", self);
+            *self.activity.lock() = (self.traffic(), Instant::now());
+
+            // the handshake state was evicted: allow an initiation immediately
+            *self.last_handshake_sent.lock() = Instant::now() - TIME_HORIZON;
+
+            // timers are only running while the device is up
+            // (if the device is going up / down, "up" starts the timers)
+            let enabled = self.wg.enabled.try_read().map(|enabled| *enabled);
+            if enabled == Some(true) {
+                self.start_timers();
+            }
+        }
+    }
+
+    /* Begin the eviction of the runtime state of the peer
+     *
+     * The peer is marked evicted before the state is evicted,
+     * such that a concurrent revive (e.g. by a handshake) is observed by `end_eviction`.
+     *
+     * Returns false if the peer is already evicted.
+     */
+    pub fn begin_eviction(&self) -> bool {
+        self.evicted
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+    }
+
+    /* Complete the eviction of the runtime state of the peer
+     *
+     * If the peer was revived during the eviction (which may have stopped its timers),
+     * the state is rebuilt again.
+     *
+     * Returns false if the peer was revived.
+     */
+    pub fn end_eviction(&self) -> bool {
+        if self.evicted.load(Ordering::SeqCst) {
+            return true;
+        }
+        self.evicted.store(true, Ordering::SeqCst);
+        self.revive();
+        false
+    }
+
+    /* The total number of bytes exchanged with the peer
+     * (including handshake messages)
+     */
+    pub fn traffic(&self) -> u64 {
+        self.rx_bytes.load(Ordering::Relaxed) + self.tx_bytes.load(Ordering::Relaxed)
+    }
+
+    /* Replace the candidate endpoints of the peer
+     *
+     * Returns the first candidate, which becomes the active candidate.
+     */
+    pub fn set_endpoint_candidates(&self, candidates: Vec<SocketAddr>) -> Option<SocketAddr> {
+        let mut endpoints = self.endpoints.lock();
+        *endpoints = (candidates, 0);
+        endpoints.0.first().cloned()
+    }
+
+    pub fn get_endpoint_candidates(&self) -> Vec<SocketAddr> {
+        self.endpoints.lock().0.clone()
+    }
+
+    /* Rotate to the next candidate endpoint
+     *
+     * Returns the new active candidate,
+     * or None if there are no alternative candidates to fail over to.
+     */
+    pub fn next_endpoint_candidate(&self) -> Option<SocketAddr> {
+        let mut endpoints = self.endpoints.lock();
+        let (candidates, active) = &mut *endpoints;
+        if candidates.len() < 2 {
+            return None;
         }
+        *active = (*active + 1) % candidates.len();
+        Some(candidates[*active])
     }
 
     #[inline(always)]
@@ -84,6 +200,6 @@ impl<T: Tun, B: UDP> PeerInner<T, B> {
 
 impl<T: Tun, B: UDP> fmt::Display for PeerInner<T, B> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "peer(id = {})", self.id)
+        write!(f, "peer={}", Base64Key::from(&self.pk))
     }
 }
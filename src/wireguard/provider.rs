@@ -0,0 +1,106 @@
+use std::net::IpAddr;
+
+#[cfg(test)]
+use spin::Mutex;
+#[cfg(test)]
+use std::collections::HashMap;
+use x25519_dalek::PublicKey;
+
+/// The configuration of a peer supplied by a provider
+#[derive(Clone, Debug, Default)]
+pub struct ProvidedPeer {
+    pub psk: Option<[u8; 32]>,
+    pub allowed_ips: Vec<(IpAddr, u32)>,
+    pub persistent_keepalive_interval: Option<u64>,
+}
+
+/// A source of peers which are not (yet) known to the device,
+/// e.g. an external database of clients.
+///
+/// The provider is consulted when a handshake initiation is received from an unknown public key:
+/// if the provider returns a configuration the peer is provisionally added to the device
+/// and the initiation is processed again (the peer is removed unless the initiation is accepted).
+/// Lookups are rate limited for every source address.
+pub trait PeerProvider: Send + Sync + 'static {
+    /// Looks up the configuration of a peer
+    ///
+    /// Note that the public key has not been authenticated when the provider is consulted
+    /// (the initiation has only been checked by the mac1 field and the DoS mitigation),
+    /// hence the provider should be prepared to look up arbitrary keys.
+    ///
+    /// # Arguments
+    ///
+    /// - `pk`: The static public key of the initiator
+    ///
+    /// # Returns
+    ///
+    /// The configuration of the peer, or None to refuse the peer
+    fn provide(&self, pk: &PublicKey) -> Option<ProvidedPeer>;
+}
+
+/// A source of epoch tagged preshared keys,
+/// e.g. established by an out-of-band (post-quantum) key exchange.
+///
+/// The provider is consulted for every handshake. Epochs are agreed upon out-of-band
+/// (e.g. derived from the time): the responder uses the psk of its current epoch,
+/// while the initiator accepts the psk of its current epoch or an adjacent epoch,
+/// hence both sides switch epochs in lockstep (tolerating a skew of one epoch).
+///
+/// Note that the psk only enters the handshake with the response message (Noise_IKpsk2).
+pub trait PskProvider: Send + Sync + 'static {
+    /// Returns the current epoch and the psk of that epoch
+    ///
+    /// # Arguments
+    ///
+    /// - `pk`: The static public key of the peer
+    ///
+    /// # Returns
+    ///
+    /// The epoch and psk, or None to use the static psk of the peer
+    fn current(&self, pk: &PublicKey) -> Option<(u64, [u8; 32])>;
+
+    /// Looks up the psk of a specific epoch
+    ///
+    /// # Arguments
+    ///
+    /// - `pk`: The static public key of the peer
+    /// - `epoch`: The epoch of the psk
+    ///
+    /// # Returns
+    ///
+    /// The psk, or None if unknown for the epoch (e.g. not yet established or expired)
+    fn lookup(&self, pk: &PublicKey, epoch: u64) -> Option<[u8; 32]>;
+}
+
+/// A provider serving peers from memory
+#[cfg(test)]
+pub struct MemoryPeerProvider {
+    peers: Mutex<HashMap<[u8; 32], ProvidedPeer>>,
+}
+
+#[cfg(test)]
+impl Default for MemoryPeerProvider {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+impl MemoryPeerProvider {
+    pub fn new() -> MemoryPeerProvider {
+        MemoryPeerProvider {
+            peers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn insert(&self, pk: PublicKey, peer: ProvidedPeer) {
+        self.peers.lock().insert(*pk.as_bytes(), peer);
+    }
+}
+
+#[cfg(test)]
+impl PeerProvider for MemoryPeerProvider {
+    fn provide(&self, pk: &PublicKey) -> Option<ProvidedPeer> {
+        self.peers.lock().get(pk.as_bytes()).cloned()
+    }
+}
@@ -1,3 +1,5 @@
+use std::time::Duration;
+
 // WireGuard semantics constants
 
 pub const MAX_QUEUED_PACKETS: usize = 1024;
@@ -7,3 +9,15 @@ pub const MAX_QUEUED_PACKETS: usize = 1024;
 pub const PARALLEL_QUEUE_SIZE: usize = 4 * MAX_QUEUED_PACKETS;
 
 pub const INORDER_QUEUE_SIZE: usize = MAX_QUEUED_PACKETS;
+
+// rate limiting constants
+
+// Longest delay imposed on a packet exceeding the rate limit of a peer
+// (the packet is held back by the shaper of the device, the workers never stall)
+pub const MAX_SHAPING_DELAY: Duration = Duration::from_millis(50);
+
+// path MTU constants (the headers of the outer packet)
+
+pub const SIZE_IP4_HEADER: usize = 20;
+pub const SIZE_IP6_HEADER: usize = 40;
+pub const SIZE_UDP_HEADER: usize = 8;
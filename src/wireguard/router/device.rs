@@ -1,6 +1,6 @@
 use std::collections::HashMap;
 use std::ops::Deref;
-use std::sync::atomic::AtomicBool;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::thread;
 use std::time::Instant;
@@ -12,6 +12,9 @@ use zerocopy::LayoutVerified;
 use super::anti_replay::AntiReplay;
 
 use super::constants::PARALLEL_QUEUE_SIZE;
+use super::filter::Direction;
+use super::icmp;
+use super::ip::{inner_length, may_fragment};
 use super::messages::{TransportHeader, TYPE_TRANSPORT};
 use super::peer::{new_peer, Peer, PeerHandle};
 use super::types::{Callbacks, RouterError};
@@ -19,6 +22,7 @@ use super::SIZE_MESSAGE_PREFIX;
 
 use super::receive::ReceiveJob;
 use super::route::RoutingTable;
+use super::shaper::Shaper;
 use super::worker::{worker, JobUnion};
 
 use super::super::{tun, udp, Endpoint, KeyPair};
@@ -31,12 +35,24 @@ pub struct DeviceInner<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer
     // outbound writer (Bind)
     pub outbound: RwLock<(bool, Option<B>)>,
 
+    // forward packets between peers (instead of writing them to the TUN)
+    pub relay: AtomicBool,
+
+    // copy the DSCP bits of the inner packet to the outer packet
+    pub dscp: AtomicBool,
+
+    // propagate the ECN bits between the inner and outer packets (RFC 6040)
+    pub ecn: AtomicBool,
+
     // routing
     pub recv: RwLock<HashMap<u32, Arc<DecryptionState<E, C, T, B>>>>, // receiver id -> decryption state
     pub table: RoutingTable<Peer<E, C, T, B>>,
 
     // work queue
     pub work: ParallelQueue<JobUnion<E, C, T, B>>,
+
+    // messages held back by the rate limits of the peers
+    pub shaper: Shaper<E, C, T, B>,
 }
 
 pub struct EncryptionState {
@@ -91,17 +107,18 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> Drop
     for DeviceHandle<E, C, T, B>
 {
     fn drop(&mut self) {
-        log::debug!("router: dropping device");
+        log::debug!("event=router_stopping");
 
-        // close worker queue
+        // close worker queue and shaper
         self.state.work.close();
+        self.state.shaper.close();
 
         // join all worker threads
         while let Some(handle) = self.handles.pop() {
             handle.thread().unpark();
             handle.join().unwrap();
         }
-        log::debug!("router: joined with all workers from pool");
+        log::debug!("event=router_stopped");
     }
 }
 
@@ -113,8 +130,12 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> DeviceHandle<
                 work,
                 inbound: tun,
                 outbound: RwLock::new((true, None)),
+                relay: AtomicBool::new(false),
+                dscp: AtomicBool::new(false),
+                ecn: AtomicBool::new(true),
                 recv: RwLock::new(HashMap::new()),
                 table: RoutingTable::new(),
+                shaper: Shaper::new(),
             }),
         };
 
@@ -130,6 +151,12 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> DeviceHandle<
             "workers does not match consumers"
         );
 
+        // start shaper thread
+        {
+            let device = device.clone();
+            threads.push(thread::spawn(move || device.shaper.run()));
+        }
+
         // return exported device handle
         DeviceHandle {
             state: device,
@@ -160,10 +187,46 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> DeviceHandle<
         self.state.outbound.write().0 = true;
     }
 
+    /// Enables or disables relaying between peers.
+    /// When enabled, inbound packets destined for the allowed IPs of another peer
+    /// are encrypted and sent directly to that peer (subject to `Callbacks::relay`),
+    /// rather than being written to the TUN device.
+    pub fn set_relay(&self, enabled: bool) {
+        self.state.relay.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Returns true if relaying between peers is enabled
+    pub fn get_relay(&self) -> bool {
+        self.state.relay.load(Ordering::Relaxed)
+    }
+
+    /// Enables or disables copying the DSCP bits of the inner packets to the outer packets.
+    pub fn set_dscp(&self, enabled: bool) {
+        self.state.dscp.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Returns true if the DSCP bits are copied to the outer packets
+    pub fn get_dscp(&self) -> bool {
+        self.state.dscp.load(Ordering::Relaxed)
+    }
+
+    /// Enables or disables the propagation of the ECN bits
+    /// between the inner and outer packets (as by the normal mode of RFC 6040).
+    /// When enabled, inner packets which are not ECN-capable are dropped
+    /// if the outer packet was marked "congestion experienced".
+    pub fn set_ecn(&self, enabled: bool) {
+        self.state.ecn.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Returns true if the ECN bits are propagated
+    pub fn get_ecn(&self) -> bool {
+        self.state.ecn.load(Ordering::Relaxed)
+    }
+
     /// A new secret key has been set for the device.
     /// According to WireGuard semantics, this should cause all "sending" keys to be discarded.
     pub fn clear_sending_keys(&self) {
-        log::debug!("Clear sending keys");
+        log::debug!("event=sending_keys_cleared");
         // TODO: Implement. Consider: The device does not have an explicit list of peers
     }
 
@@ -185,7 +248,7 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> DeviceHandle<
     pub fn send(&self, msg: Vec<u8>) -> Result<(), RouterError> {
         debug_assert!(msg.len() > SIZE_MESSAGE_PREFIX);
         log::trace!(
-            "send, packet = {}",
+            "packet={} event=route",
             hex::encode(&msg[SIZE_MESSAGE_PREFIX..])
         );
 
@@ -199,6 +262,29 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> DeviceHandle<
             .get_route(packet)
             .ok_or(RouterError::NoCryptoKeyRoute)?;
 
+        // apply the filter of the peer
+        if !peer.filter.read().check(Direction::Out, packet) {
+            return Err(RouterError::Filtered);
+        }
+
+        // the transport message must fit the path MTU towards the peer,
+        // unless the packet may be fragmented (then the outer packet is fragmented)
+        let mut msg = msg;
+        if let Some(mtu) = peer.path_mtu() {
+            let packet = &msg[SIZE_MESSAGE_PREFIX..];
+            let length = inner_length(packet).unwrap_or(packet.len());
+            if length > mtu && !may_fragment(packet, length) {
+                log::debug!("mtu={} event=packet_too_big", mtu);
+                if let Some(icmp) = icmp::packet_too_big(packet, mtu) {
+                    let _ = self.state.inbound.write(&icmp[..]);
+                }
+                return Err(RouterError::PacketTooBig(mtu));
+            }
+
+            // truncate the padding
+            msg.truncate(SIZE_MESSAGE_PREFIX + mtu.max(length));
+        }
+
         // schedule for encryption and transmission to peer
         peer.send(msg, true);
         Ok(())
@@ -215,8 +301,6 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> DeviceHandle<
     ///
     ///
     pub fn recv(&self, src: E, msg: Vec<u8>) -> Result<(), RouterError> {
-        log::trace!("receive, src: {}", src.into_address());
-
         // parse / cast
         let (header, _) = match LayoutVerified::new_from_prefix(&msg[..]) {
             Some(v) => v,
@@ -233,7 +317,8 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> DeviceHandle<
         );
 
         log::trace!(
-            "handle transport message: (receiver = {}, counter = {})",
+            "endpoint={} key={} counter={} event=transport_received",
+            src.into_address(),
             header.f_receiver,
             header.f_counter
         );
@@ -244,8 +329,15 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> DeviceHandle<
             .get(&header.f_receiver.get())
             .ok_or(RouterError::UnknownReceiverId)?;
 
+        // retain the transport message for capture (once authenticated)
+        let capture = if C::capturing(&dec.peer.opaque) {
+            Some(msg.clone())
+        } else {
+            None
+        };
+
         // create inbound job
-        let job = ReceiveJob::new(msg, dec.clone(), src);
+        let job = ReceiveJob::new(msg, capture, dec.clone(), src);
 
         // 1. add to sequential queue (drop if full)
         // 2. then add to parallel work queue (wait if full)
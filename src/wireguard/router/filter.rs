@@ -0,0 +1,556 @@
+use super::ip::*;
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+// TODO: no_std alternatives
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+use zerocopy::LayoutVerified;
+
+const PROTOCOL_ICMP: u8 = 1;
+const PROTOCOL_TCP: u8 = 6;
+const PROTOCOL_UDP: u8 = 17;
+const PROTOCOL_ICMP6: u8 = 58;
+const PROTOCOL_SCTP: u8 = 132;
+const PROTOCOL_UDPLITE: u8 = 136;
+
+// IPv6 extension headers (preceding the upper-layer header)
+const NEXT_HEADER_HOP_BY_HOP: u8 = 0;
+const NEXT_HEADER_ROUTING: u8 = 43;
+const NEXT_HEADER_FRAGMENT: u8 = 44;
+const NEXT_HEADER_AUTH: u8 = 51;
+const NEXT_HEADER_DESTINATION: u8 = 60;
+const NEXT_HEADER_MOBILITY: u8 = 135;
+const NEXT_HEADER_HIP: u8 = 139;
+const NEXT_HEADER_SHIM6: u8 = 140;
+
+/* Functions for filtering the inner (IP) packets exchanged with a peer */
+
+/// Direction of a packet relative to the peer
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    In,   // received from the peer (written to the TUN device or relayed)
+    Out,  // sent to the peer (read from the TUN device or relayed)
+    Both, // only used by rules: matches both directions
+}
+
+/// Action taken when a packet matches a rule
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    Accept,
+    Drop,
+}
+
+/// A rule matching inner packets exchanged with a peer,
+/// every criteria which is None matches any packet.
+///
+/// The textual representation is:
+///
+/// `<accept|drop> <in|out|both> [proto=<tcp|udp|icmp|icmpv6|number>] [dst=<ip>/<cidr>] [port=<port>[-<port>]]`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FilterRule {
+    pub action: Action,
+    pub direction: Direction,
+    pub protocol: Option<u8>,
+    pub destination: Option<(IpAddr, u32)>,
+    pub ports: Option<(u16, u16)>, // inclusive range of destination ports
+}
+
+// the destination port of a packet
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Port {
+    Number(u16),
+    Absent,  // the protocol has no ports
+    Unknown, // the transport header is missing (non-first fragment) or truncated
+}
+
+// the fields of a packet matched by the rules
+struct Fields {
+    protocol: Option<u8>, // None if the IPv6 header chain cannot be traversed
+    destination: IpAddr,
+    port: Port,
+}
+
+fn has_ports(protocol: u8) -> bool {
+    matches!(
+        protocol,
+        PROTOCOL_TCP | PROTOCOL_UDP | PROTOCOL_SCTP | PROTOCOL_UDPLITE
+    )
+}
+
+fn is_extension6(next_header: u8) -> bool {
+    matches!(
+        next_header,
+        NEXT_HEADER_HOP_BY_HOP
+            | NEXT_HEADER_ROUTING
+            | NEXT_HEADER_FRAGMENT
+            | NEXT_HEADER_AUTH
+            | NEXT_HEADER_DESTINATION
+            | NEXT_HEADER_MOBILITY
+            | NEXT_HEADER_HIP
+            | NEXT_HEADER_SHIM6
+    )
+}
+
+// the destination port is located at the same offset for all protocols with ports
+fn port(protocol: u8, transport: Option<&[u8]>) -> Port {
+    if !has_ports(protocol) {
+        return Port::Absent;
+    }
+    match transport.and_then(|transport| transport.get(2..4)) {
+        Some(port) => Port::Number(u16::from_be_bytes([port[0], port[1]])),
+        None => Port::Unknown,
+    }
+}
+
+// walks the IPv6 extension headers,
+// returning the upper-layer protocol (None if the chain is truncated)
+// and the upper-layer header (None for non-first fragments)
+fn upper_layer6(mut next_header: u8, mut rest: &[u8]) -> (Option<u8>, Option<&[u8]>) {
+    loop {
+        let length = match next_header {
+            NEXT_HEADER_FRAGMENT => {
+                let header = match rest.get(..8) {
+                    Some(header) => header,
+                    None => return (None, None),
+                };
+
+                // only the first fragment carries the upper-layer header
+                if u16::from_be_bytes([header[2], header[3]]) >> 3 != 0 {
+                    let protocol = Some(header[0]).filter(|p| !is_extension6(*p));
+                    return (protocol, None);
+                }
+                8
+            }
+            NEXT_HEADER_AUTH => match rest.get(1) {
+                Some(len) => (*len as usize + 2) * 4,
+                None => return (None, None),
+            },
+            next_header if is_extension6(next_header) => match rest.get(1) {
+                Some(len) => (*len as usize + 1) * 8,
+                None => return (None, None),
+            },
+            protocol => return (Some(protocol), Some(rest)),
+        };
+        match rest.get(..length) {
+            Some(header) => {
+                next_header = header[0];
+                rest = &rest[length..];
+            }
+            None => return (None, None),
+        }
+    }
+}
+
+fn fields(packet: &[u8]) -> Option<Fields> {
+    match packet.first()? >> 4 {
+        VERSION_IP4 => {
+            let (header, _): (LayoutVerified<&[u8], IPv4Header>, _) =
+                LayoutVerified::new_from_prefix(packet)?;
+
+            // only the first fragment carries the transport header
+            let length = (packet[0] & 0xf) as usize * 4;
+            let first = header.f_fragment.get() & 0x1fff == 0;
+            Some(Fields {
+                protocol: Some(header.f_protocol),
+                destination: IpAddr::V4(Ipv4Addr::from(header.f_destination)),
+                port: port(header.f_protocol, packet.get(length..).filter(|_| first)),
+            })
+        }
+        VERSION_IP6 => {
+            let (header, rest): (LayoutVerified<&[u8], IPv6Header>, _) =
+                LayoutVerified::new_from_prefix(packet)?;
+
+            let (protocol, transport) = upper_layer6(header.f_next_header, rest);
+            Some(Fields {
+                protocol,
+                destination: IpAddr::V6(Ipv6Addr::from(header.f_destination)),
+                port: match protocol {
+                    Some(protocol) => port(protocol, transport),
+                    None => Port::Unknown,
+                },
+            })
+        }
+        _ => None,
+    }
+}
+
+fn prefix_contains(prefix: &IpAddr, cidr: u32, ip: &IpAddr) -> bool {
+    match (prefix, ip) {
+        (IpAddr::V4(prefix), IpAddr::V4(ip)) => {
+            let mask = u32::MAX.checked_shl(32 - cidr.min(32)).unwrap_or(0);
+            u32::from(*prefix) & mask == u32::from(*ip) & mask
+        }
+        (IpAddr::V6(prefix), IpAddr::V6(ip)) => {
+            let mask = u128::MAX.checked_shl(128 - cidr.min(128)).unwrap_or(0);
+            u128::from(*prefix) & mask == u128::from(*ip) & mask
+        }
+        _ => false,
+    }
+}
+
+impl FilterRule {
+    // criteria which cannot be evaluated (e.g. the port of a fragment)
+    // match drop rules and never match accept rules
+    fn matches(&self, direction: Direction, fields: &Fields) -> bool {
+        let unknown = self.action == Action::Drop;
+        (self.direction == Direction::Both || self.direction == direction)
+            && self
+                .destination
+                .iter()
+                .all(|(prefix, cidr)| prefix_contains(prefix, *cidr, &fields.destination))
+            && match (self.protocol, fields.protocol) {
+                (None, _) => true,
+                (Some(protocol), Some(other)) => protocol == other,
+                (Some(_), None) => unknown,
+            }
+            && match (self.ports, fields.port) {
+                (None, _) => true,
+                (Some((lo, hi)), Port::Number(port)) => lo <= port && port <= hi,
+                (Some(_), Port::Absent) => false,
+                (Some(_), Port::Unknown) => unknown,
+            }
+    }
+}
+
+impl fmt::Display for FilterRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {}",
+            match self.action {
+                Action::Accept => "accept",
+                Action::Drop => "drop",
+            },
+            match self.direction {
+                Direction::In => "in",
+                Direction::Out => "out",
+                Direction::Both => "both",
+            }
+        )?;
+        match self.protocol {
+            None => (),
+            Some(PROTOCOL_TCP) => write!(f, " proto=tcp")?,
+            Some(PROTOCOL_UDP) => write!(f, " proto=udp")?,
+            Some(PROTOCOL_ICMP) => write!(f, " proto=icmp")?,
+            Some(PROTOCOL_ICMP6) => write!(f, " proto=icmpv6")?,
+            Some(protocol) => write!(f, " proto={}", protocol)?,
+        }
+        if let Some((ip, cidr)) = self.destination {
+            write!(f, " dst={}/{}", ip, cidr)?;
+        }
+        match self.ports {
+            None => (),
+            Some((lo, hi)) if lo == hi => write!(f, " port={}", lo)?,
+            Some((lo, hi)) => write!(f, " port={}-{}", lo, hi)?,
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for FilterRule {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace();
+
+        let action = match words.next() {
+            Some("accept") => Action::Accept,
+            Some("drop") => Action::Drop,
+            _ => return Err(()),
+        };
+
+        let direction = match words.next() {
+            Some("in") => Direction::In,
+            Some("out") => Direction::Out,
+            Some("both") => Direction::Both,
+            _ => return Err(()),
+        };
+
+        let mut rule = FilterRule {
+            action,
+            direction,
+            protocol: None,
+            destination: None,
+            ports: None,
+        };
+
+        for word in words {
+            let mut split = word.splitn(2, '=');
+            match (split.next(), split.next()) {
+                (Some("proto"), Some(proto)) => {
+                    rule.protocol = Some(match proto {
+                        "tcp" => PROTOCOL_TCP,
+                        "udp" => PROTOCOL_UDP,
+                        "icmp" => PROTOCOL_ICMP,
+                        "icmpv6" => PROTOCOL_ICMP6,
+                        n => n.parse().map_err(|_| ())?,
+                    })
+                }
+                (Some("dst"), Some(dst)) => {
+                    let mut split = dst.splitn(2, '/');
+                    let ip: IpAddr = split.next().ok_or(())?.parse().map_err(|_| ())?;
+                    let cidr: u32 = match split.next() {
+                        Some(cidr) => cidr.parse().map_err(|_| ())?,
+                        None if ip.is_ipv4() => 32,
+                        None => 128,
+                    };
+                    if cidr > if ip.is_ipv4() { 32 } else { 128 } {
+                        return Err(());
+                    }
+                    rule.destination = Some((ip, cidr));
+                }
+                (Some("port"), Some(ports)) => {
+                    let mut split = ports.splitn(2, '-');
+                    let lo: u16 = split.next().ok_or(())?.parse().map_err(|_| ())?;
+                    let hi: u16 = match split.next() {
+                        Some(hi) => hi.parse().map_err(|_| ())?,
+                        None => lo,
+                    };
+                    if lo > hi {
+                        return Err(());
+                    }
+                    rule.ports = Some((lo, hi));
+                }
+                _ => return Err(()),
+            }
+        }
+
+        // ports are only meaningful for protocols with ports
+        match (rule.ports, rule.protocol) {
+            (Some(_), Some(protocol)) if !has_ports(protocol) => Err(()),
+            _ => Ok(rule),
+        }
+    }
+}
+
+/// An ordered list of rules, the first matching rule decides the fate of the packet.
+/// Packets matching no rule are accepted, unparsable packets are dropped (unless the list is empty).
+pub struct Filter {
+    rules: Vec<(FilterRule, AtomicU64)>, // rule and number of matching packets
+}
+
+impl Filter {
+    pub fn new(rules: Vec<FilterRule>) -> Filter {
+        Filter {
+            rules: rules
+                .into_iter()
+                .map(|rule| (rule, AtomicU64::new(0)))
+                .collect(),
+        }
+    }
+
+    /// Evaluates the rules against an IP packet
+    ///
+    /// # Arguments
+    ///
+    /// - `direction`: The direction of the packet relative to the peer
+    /// - `packet`: The IP packet (excluding padding)
+    ///
+    /// # Returns
+    ///
+    /// A bool indicating if the packet should be let through
+    #[inline(always)]
+    pub fn check(&self, direction: Direction, packet: &[u8]) -> bool {
+        debug_assert_ne!(direction, Direction::Both);
+
+        // fast path: no rules
+        if self.rules.is_empty() {
+            return true;
+        }
+
+        let fields = match fields(packet) {
+            Some(fields) => fields,
+            None => return false,
+        };
+
+        for (rule, hits) in self.rules.iter() {
+            if rule.matches(direction, &fields) {
+                hits.fetch_add(1, Ordering::Relaxed);
+                return rule.action == Action::Accept;
+            }
+        }
+        true
+    }
+
+    /// Returns the rules and their hit counters
+    pub fn list(&self) -> Vec<(FilterRule, u64)> {
+        self.rules
+            .iter()
+            .map(|(rule, hits)| (rule.clone(), hits.load(Ordering::Relaxed)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use super::super::super::tests::make_packet;
+
+    #[test]
+    fn test_filter_rule_syntax() {
+        for s in [
+            "accept in",
+            "drop both proto=tcp dst=10.0.0.0/24 port=22",
+            "drop out proto=udp port=53-54",
+            "accept in proto=icmpv6 dst=2001:db8::/32",
+            "drop both proto=47",
+        ]
+        .iter()
+        {
+            let rule: FilterRule = s.parse().unwrap();
+            assert_eq!(&rule.to_string(), s);
+        }
+
+        for s in [
+            "",
+            "accept",
+            "reject in",
+            "accept sideways",
+            "accept in proto=icmp port=22",
+            "accept in dst=10.0.0.0/33",
+            "accept in port=22-21",
+            "accept in color=red",
+        ]
+        .iter()
+        {
+            assert!(s.parse::<FilterRule>().is_err(), "{:?} should not parse", s);
+        }
+    }
+
+    #[test]
+    fn test_filter_check() {
+        let filter = Filter::new(vec![
+            "accept out dst=10.0.0.1".parse().unwrap(),
+            "drop both dst=10.0.0.0/24".parse().unwrap(),
+        ]);
+
+        let src = "192.168.0.1".parse().unwrap();
+        let allowed = make_packet(64, src, "10.0.0.1".parse().unwrap(), 0);
+        let denied = make_packet(64, src, "10.0.0.2".parse().unwrap(), 0);
+        let other = make_packet(64, src, "10.0.1.1".parse().unwrap(), 0);
+
+        assert!(filter.check(Direction::Out, &allowed));
+        assert!(!filter.check(Direction::In, &allowed));
+        assert!(!filter.check(Direction::Out, &denied));
+        assert!(filter.check(Direction::In, &other));
+
+        let hits: Vec<u64> = filter.list().into_iter().map(|(_, n)| n).collect();
+        assert_eq!(hits, vec![1, 2]);
+    }
+
+    // a transport header with the destination port
+    fn transport(port: u16) -> Vec<u8> {
+        let mut header = vec![0u8; 20];
+        header[2..4].copy_from_slice(&port.to_be_bytes());
+        header
+    }
+
+    // an IPv4 packet with the fragment offset (in units of 8 bytes)
+    fn packet4(protocol: u8, offset: u16, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0u8; 20];
+        packet[0] = 0x45;
+        packet[2..4].copy_from_slice(&(20 + payload.len() as u16).to_be_bytes());
+        packet[6..8].copy_from_slice(&offset.to_be_bytes());
+        packet[9] = protocol;
+        packet[16..20].copy_from_slice(&[10, 0, 0, 1]);
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    // an IPv6 packet followed by the extension and upper-layer headers
+    fn packet6(next_header: u8, headers: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0u8; 40];
+        packet[0] = 0x60;
+        packet[4..6].copy_from_slice(&(headers.len() as u16).to_be_bytes());
+        packet[6] = next_header;
+        packet[24..40].copy_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        packet.extend_from_slice(headers);
+        packet
+    }
+
+    #[test]
+    fn test_filter_extension_headers() {
+        let filter = Filter::new(vec!["drop in proto=tcp port=22".parse().unwrap()]);
+
+        let hop_by_hop = [PROTOCOL_TCP, 0, 1, 4, 0, 0, 0, 0];
+        let destination = [PROTOCOL_TCP, 0, 1, 4, 0, 0, 0, 0];
+        let first_fragment = [PROTOCOL_TCP, 0, 0, 1, 0, 0, 0, 42];
+        let other_fragment = [PROTOCOL_TCP, 0, 0, 8 * 2, 0, 0, 0, 42];
+        let truncated = [PROTOCOL_TCP, 5, 1, 4, 0, 0, 0, 0];
+
+        // the extension headers are traversed
+        let dropped = [
+            packet6(PROTOCOL_TCP, &transport(22)),
+            packet6(
+                NEXT_HEADER_HOP_BY_HOP,
+                &[&hop_by_hop[..], &transport(22)[..]].concat(),
+            ),
+            packet6(
+                NEXT_HEADER_FRAGMENT,
+                &[&first_fragment[..], &transport(22)[..]].concat(),
+            ),
+            packet6(
+                NEXT_HEADER_FRAGMENT,
+                &[&other_fragment[..], &[0u8; 64][..]].concat(),
+            ),
+            packet6(
+                NEXT_HEADER_HOP_BY_HOP,
+                &[&truncated[..], &transport(22)[..]].concat(),
+            ),
+        ];
+        for packet in dropped.iter() {
+            assert!(!filter.check(Direction::In, packet));
+        }
+
+        let accepted = [
+            packet6(PROTOCOL_TCP, &transport(80)),
+            packet6(
+                NEXT_HEADER_DESTINATION,
+                &[&destination[..], &transport(80)[..]].concat(),
+            ),
+            packet6(
+                NEXT_HEADER_FRAGMENT,
+                &[&first_fragment[..], &transport(80)[..]].concat(),
+            ),
+        ];
+        for packet in accepted.iter() {
+            assert!(filter.check(Direction::In, packet));
+        }
+    }
+
+    #[test]
+    fn test_filter_fragments() {
+        // fragments without ports never match accept rules
+        let filter = Filter::new(vec![
+            "accept in proto=udp port=53".parse().unwrap(),
+            "drop in proto=udp".parse().unwrap(),
+        ]);
+        assert!(filter.check(Direction::In, &packet4(PROTOCOL_UDP, 0, &transport(53))));
+        assert!(!filter.check(Direction::In, &packet4(PROTOCOL_UDP, 185, &[0; 64])));
+        assert!(!filter.check(Direction::In, &packet4(PROTOCOL_UDP, 0, &[0; 3])));
+        assert!(filter.check(Direction::In, &packet4(PROTOCOL_ICMP, 185, &[0; 64])));
+
+        // fragments without ports match drop rules
+        let filter = Filter::new(vec!["drop in proto=tcp port=22".parse().unwrap()]);
+        assert!(filter.check(Direction::In, &packet4(PROTOCOL_TCP, 0, &transport(80))));
+        assert!(!filter.check(Direction::In, &packet4(PROTOCOL_TCP, 0, &transport(22))));
+        assert!(!filter.check(Direction::In, &packet4(PROTOCOL_TCP, 185, &[0; 64])));
+        assert!(filter.check(Direction::In, &packet4(PROTOCOL_UDP, 185, &[0; 64])));
+    }
+
+    #[test]
+    fn test_filter_unparsable() {
+        let packets: [&[u8]; 3] = [&[], &[0x45, 0, 0, 20], &[0x00; 40]];
+
+        // unparsable packets are only accepted when no rules are set
+        let filter = Filter::new(vec!["accept both".parse().unwrap()]);
+        for packet in packets.iter() {
+            assert!(!filter.check(Direction::In, packet));
+            assert!(Filter::new(vec![]).check(Direction::In, packet));
+        }
+    }
+}
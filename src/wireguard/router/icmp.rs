@@ -0,0 +1,235 @@
+use super::ip::{MIN_MTU_IP6, VERSION_IP4, VERSION_IP6};
+
+/* ICMP errors generated by the router and written to the TUN device,
+ * sent on behalf of the destination of the offending packet.
+ */
+
+const PROTO_ICMP: u8 = 1;
+const PROTO_ICMP6: u8 = 58;
+
+const ICMP_DEST_UNREACH: u8 = 3;
+const ICMP_FRAG_NEEDED: u8 = 4;
+const ICMP6_PACKET_TOO_BIG: u8 = 2;
+
+const SIZE_IP4_HEADER: usize = 20;
+const SIZE_IP6_HEADER: usize = 40;
+const SIZE_ICMP_HEADER: usize = 8;
+
+// the smallest MTU of IPv4 links
+const MIN_MTU_IP4: usize = 68;
+
+// the size of the generated ICMP error is limited as by RFC 1812 / RFC 4443
+const MAX_ICMP_IP4: usize = 576;
+const MAX_ICMP_IP6: usize = MIN_MTU_IP6;
+
+// internet checksum (RFC 1071) of the concatenated slices
+pub fn checksum(parts: &[&[u8]]) -> u16 {
+    let mut sum: u32 = 0;
+    let mut odd: Option<u8> = None;
+    for part in parts {
+        for &b in part.iter() {
+            match odd.take() {
+                Some(hi) => sum += u32::from(u16::from_be_bytes([hi, b])),
+                None => odd = Some(b),
+            }
+        }
+    }
+    if let Some(hi) = odd {
+        sum += u32::from(u16::from_be_bytes([hi, 0]));
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Creates an ICMP "fragmentation needed" (IPv4) or "packet too big" (IPv6) error
+/// for a packet exceeding the MTU.
+///
+/// # Arguments
+///
+/// - `packet`: The offending IP packet
+/// - `mtu`: The largest IP packet which can be sent
+///
+/// # Returns
+///
+/// The ICMP error (an IP packet), or None if no error should be sent in response to the packet
+/// (e.g. the packet is malformed or is itself an ICMP error)
+pub fn packet_too_big(packet: &[u8], mtu: usize) -> Option<Vec<u8>> {
+    match packet.first()? >> 4 {
+        VERSION_IP4 => packet_too_big4(packet, mtu),
+        VERSION_IP6 => packet_too_big6(packet, mtu),
+        _ => None,
+    }
+}
+
+fn packet_too_big4(packet: &[u8], mtu: usize) -> Option<Vec<u8>> {
+    if packet.len() < SIZE_IP4_HEADER {
+        return None;
+    }
+
+    // never respond to fragments (other than the first) or ICMP errors
+    let ihl = usize::from(packet[0] & 0xf) * 4;
+    let offset = u16::from_be_bytes([packet[6], packet[7]]) & 0x1fff;
+    if offset != 0 {
+        return None;
+    }
+    if packet[9] == PROTO_ICMP {
+        match packet.get(ihl) {
+            Some(&kind) if kind == 0 || kind == 8 || kind >= 13 => (), // echo, timestamp, ...
+            _ => return None,
+        }
+    }
+
+    // quote as much of the packet as fits
+    let quote = &packet[..packet
+        .len()
+        .min(MAX_ICMP_IP4 - SIZE_IP4_HEADER - SIZE_ICMP_HEADER)];
+    let total = SIZE_IP4_HEADER + SIZE_ICMP_HEADER + quote.len();
+    let mtu = mtu.clamp(MIN_MTU_IP4, 0xffff) as u16;
+
+    let mut icmp = vec![0u8; SIZE_ICMP_HEADER];
+    icmp[0] = ICMP_DEST_UNREACH;
+    icmp[1] = ICMP_FRAG_NEEDED;
+    icmp[6..8].copy_from_slice(&mtu.to_be_bytes());
+    let sum = checksum(&[&icmp[..], quote]);
+    icmp[2..4].copy_from_slice(&sum.to_be_bytes());
+
+    let mut msg = Vec::with_capacity(total);
+    msg.extend_from_slice(&[0x45, 0]);
+    msg.extend_from_slice(&(total as u16).to_be_bytes());
+    msg.extend_from_slice(&[0, 0, 0, 0, 64, PROTO_ICMP, 0, 0]);
+    msg.extend_from_slice(&packet[16..20]); // source is the destination of the packet
+    msg.extend_from_slice(&packet[12..16]);
+    let sum = checksum(&[&msg[..]]);
+    msg[10..12].copy_from_slice(&sum.to_be_bytes());
+    msg.extend_from_slice(&icmp);
+    msg.extend_from_slice(quote);
+    Some(msg)
+}
+
+fn packet_too_big6(packet: &[u8], mtu: usize) -> Option<Vec<u8>> {
+    if packet.len() < SIZE_IP6_HEADER {
+        return None;
+    }
+
+    // never respond to ICMPv6 errors (types below 128) or multicast / unspecified sources
+    if packet[6] == PROTO_ICMP6
+        && !matches!(packet.get(SIZE_IP6_HEADER), Some(&kind) if kind >= 128)
+    {
+        return None;
+    }
+    let src = &packet[8..24];
+    if src[0] == 0xff || src.iter().all(|b| *b == 0) {
+        return None;
+    }
+
+    // quote as much of the packet as fits
+    let quote = &packet[..packet
+        .len()
+        .min(MAX_ICMP_IP6 - SIZE_IP6_HEADER - SIZE_ICMP_HEADER)];
+    let length = SIZE_ICMP_HEADER + quote.len();
+
+    // packets of at most the minimum MTU are never dropped (the outer packet is fragmented)
+    let mtu = mtu.max(MIN_MTU_IP6) as u32;
+
+    let mut msg = Vec::with_capacity(SIZE_IP6_HEADER + length);
+    msg.extend_from_slice(&[0x60, 0, 0, 0]);
+    msg.extend_from_slice(&(length as u16).to_be_bytes());
+    msg.extend_from_slice(&[PROTO_ICMP6, 64]);
+    msg.extend_from_slice(&packet[24..40]); // source is the destination of the packet
+    msg.extend_from_slice(src);
+
+    let mut icmp = vec![0u8; SIZE_ICMP_HEADER];
+    icmp[0] = ICMP6_PACKET_TOO_BIG;
+    icmp[4..8].copy_from_slice(&mtu.to_be_bytes());
+    let pseudo = [
+        &(length as u32).to_be_bytes()[..],
+        &[0, 0, 0, PROTO_ICMP6][..],
+    ]
+    .concat();
+    let sum = checksum(&[&msg[8..40], &pseudo[..], &icmp[..], quote]);
+    icmp[2..4].copy_from_slice(&sum.to_be_bytes());
+
+    msg.extend_from_slice(&icmp);
+    msg.extend_from_slice(quote);
+    Some(msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pnet::packet::ipv4::MutableIpv4Packet;
+    use pnet::packet::ipv6::MutableIpv6Packet;
+
+    fn udp4(size: usize) -> Vec<u8> {
+        let mut msg = vec![0u8; size];
+        let mut packet = MutableIpv4Packet::new(&mut msg[..]).unwrap();
+        packet.set_version(4);
+        packet.set_header_length(5);
+        packet.set_total_length(size as u16);
+        packet.set_next_level_protocol(pnet::packet::ip::IpNextHeaderProtocols::Udp);
+        packet.set_source("10.0.0.1".parse().unwrap());
+        packet.set_destination("10.0.0.2".parse().unwrap());
+        msg
+    }
+
+    fn udp6(size: usize) -> Vec<u8> {
+        let mut msg = vec![0u8; size];
+        let mut packet = MutableIpv6Packet::new(&mut msg[..]).unwrap();
+        packet.set_version(6);
+        packet.set_payload_length((size - SIZE_IP6_HEADER) as u16);
+        packet.set_next_header(pnet::packet::ip::IpNextHeaderProtocols::Udp);
+        packet.set_source("fd00::1".parse().unwrap());
+        packet.set_destination("fd00::2".parse().unwrap());
+        msg
+    }
+
+    #[test]
+    fn test_packet_too_big4() {
+        let packet = udp4(1400);
+        let msg = packet_too_big(&packet, 1380).unwrap();
+        assert_eq!(msg.len(), MAX_ICMP_IP4);
+        assert_eq!(checksum(&[&msg[..SIZE_IP4_HEADER]]), 0);
+        assert_eq!(checksum(&[&msg[SIZE_IP4_HEADER..]]), 0);
+        assert_eq!(&msg[12..16], &[10, 0, 0, 2]);
+        assert_eq!(&msg[16..20], &[10, 0, 0, 1]);
+        assert_eq!(&msg[20..22], &[ICMP_DEST_UNREACH, ICMP_FRAG_NEEDED]);
+        assert_eq!(u16::from_be_bytes([msg[26], msg[27]]), 1380);
+        assert_eq!(&msg[28..], &packet[..MAX_ICMP_IP4 - 28]);
+
+        // never respond to an ICMP error
+        let msg = packet_too_big(&msg, 500);
+        assert!(msg.is_none());
+    }
+
+    #[test]
+    fn test_packet_too_big6() {
+        let packet = udp6(1400);
+        assert_eq!(
+            packet_too_big(&packet, 1200).unwrap()[44..48],
+            1280u32.to_be_bytes()
+        );
+
+        let packet = udp6(1500);
+        let msg = packet_too_big(&packet, 1420).unwrap();
+        assert_eq!(msg.len(), MAX_ICMP_IP6);
+        assert_eq!(&msg[8..24], &packet[24..40]);
+        assert_eq!(&msg[24..40], &packet[8..24]);
+        assert_eq!(msg[40], ICMP6_PACKET_TOO_BIG);
+        assert_eq!(
+            u32::from_be_bytes([msg[44], msg[45], msg[46], msg[47]]),
+            1420
+        );
+        let pseudo = [
+            &((msg.len() - SIZE_IP6_HEADER) as u32).to_be_bytes()[..],
+            &[0, 0, 0, PROTO_ICMP6][..],
+        ]
+        .concat();
+        assert_eq!(checksum(&[&msg[8..40], &pseudo, &msg[40..]]), 0);
+
+        // never respond to an ICMPv6 error
+        assert!(packet_too_big(&msg, 1280).is_none());
+    }
+}
@@ -8,12 +8,21 @@ use zerocopy::{AsBytes, FromBytes};
 pub const VERSION_IP4: u8 = 4;
 pub const VERSION_IP6: u8 = 6;
 
+// the smallest MTU of IPv6 links
+pub const MIN_MTU_IP6: usize = 1280;
+
+const FLAG_DONT_FRAGMENT: u16 = 0x4000;
+
 #[repr(packed)]
 #[derive(Copy, Clone, FromBytes, AsBytes)]
 pub struct IPv4Header {
     _f_space1: [u8; 2],
     pub f_total_len: U16<BigEndian>,
-    _f_space2: [u8; 8],
+    _f_space2: [u8; 2],
+    pub f_fragment: U16<BigEndian>,
+    _f_space3: [u8; 1],
+    pub f_protocol: u8,
+    _f_space4: [u8; 2],
     pub f_source: [u8; 4],
     pub f_destination: [u8; 4],
 }
@@ -23,7 +32,8 @@ pub struct IPv4Header {
 pub struct IPv6Header {
     _f_space1: [u8; 4],
     pub f_len: U16<BigEndian>,
-    _f_space2: [u8; 2],
+    pub f_next_header: u8,
+    _f_space2: [u8; 1],
     pub f_source: [u8; 16],
     pub f_destination: [u8; 16],
 }
@@ -47,3 +57,18 @@ pub fn inner_length(packet: &[u8]) -> Option<usize> {
         _ => None,
     }
 }
+
+/// Returns true if the transport message carrying a packet exceeding the path MTU
+/// may be fragmented (rather than dropped): IPv4 packets without the "don't fragment" bit (RFC 1191)
+/// and IPv6 packets no larger than the minimum MTU of IPv6 links (RFC 8200).
+#[inline(always)]
+pub fn may_fragment(packet: &[u8], length: usize) -> bool {
+    match packet.get(0).map(|v| v >> 4) {
+        Some(VERSION_IP4) => match LayoutVerified::<&[u8], IPv4Header>::new_from_prefix(packet) {
+            Some((header, _)) => header.f_fragment.get() & FLAG_DONT_FRAGMENT == 0,
+            None => false,
+        },
+        Some(VERSION_IP6) => length <= MIN_MTU_IP6,
+        _ => false,
+    }
+}
@@ -0,0 +1,267 @@
+use super::constants::MAX_SHAPING_DELAY;
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+// TODO: consider no_std alternatives
+use std::time::{Duration, Instant};
+
+use spin::Mutex;
+
+const NANOS_PER_SEC: i128 = 1_000_000_000;
+
+/// Action taken for packets exceeding the rate limit of a peer
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Excess {
+    Drop,  // excess packets are dropped (default)
+    Delay, // excess packets are delayed (at most MAX_SHAPING_DELAY), then dropped
+}
+
+/// Decision of a limiter for a single packet
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Admission {
+    Pass,           // the packet can be sent immediately
+    Defer(Instant), // the packet must be held back until the instant
+    Drop,           // the packet must be dropped
+}
+
+/// A snapshot of the state of a limiter
+#[derive(Clone, Debug)]
+pub struct LimiterState {
+    pub bytes_per_second: u64,   // 0 if unlimited
+    pub packets_per_second: u64, // 0 if unlimited
+    pub excess: Excess,
+    pub available_bytes: i64, // currently available tokens (negative when in debt)
+    pub available_packets: i64,
+    pub dropped: u64, // number of packets dropped
+    pub delayed: u64, // number of packets delayed
+}
+
+/* A token bucket holding at most one second worth of tokens.
+ *
+ * Tokens are measured in units of 1/NANOS_PER_SEC (to allow integer arithmetic).
+ * A packet is admitted when the bucket holds its cost (capped at the capacity of the bucket),
+ * the full cost is then consumed (possibly putting the bucket in debt),
+ * hence a single packet larger than the capacity is not starved.
+ */
+struct Bucket {
+    rate: u64,    // tokens per second (0 = unlimited)
+    tokens: i128, // available tokens * NANOS_PER_SEC
+}
+
+impl Bucket {
+    fn new(rate: u64) -> Bucket {
+        Bucket {
+            rate,
+            tokens: rate as i128 * NANOS_PER_SEC,
+        }
+    }
+
+    fn refill(&mut self, elapsed: Duration) {
+        let max = self.rate as i128 * NANOS_PER_SEC;
+        let add = elapsed.as_nanos() as i128 * self.rate as i128;
+        self.tokens = max.min(self.tokens.saturating_add(add));
+    }
+
+    // time until the bucket holds the cost of the packet
+    fn wait(&self, amount: usize) -> Duration {
+        let max = self.rate as i128 * NANOS_PER_SEC;
+        let need = max.min(amount as i128 * NANOS_PER_SEC);
+        if self.rate == 0 || self.tokens >= need {
+            Duration::from_secs(0)
+        } else {
+            let nanos = (need - self.tokens + self.rate as i128 - 1) / self.rate as i128;
+            Duration::from_nanos(nanos as u64)
+        }
+    }
+
+    fn consume(&mut self, amount: usize) {
+        if self.rate > 0 {
+            self.tokens -= amount as i128 * NANOS_PER_SEC;
+        }
+    }
+
+    fn available(&self) -> i64 {
+        (self.tokens / NANOS_PER_SEC) as i64
+    }
+}
+
+struct LimiterInner {
+    bytes: Bucket,
+    packets: Bucket,
+    excess: Excess,
+    last: Instant,
+    deferred: usize, // packets currently held back
+}
+
+pub struct Limiter {
+    inner: Mutex<LimiterInner>,
+    dropped: AtomicU64,
+    delayed: AtomicU64,
+}
+
+impl Limiter {
+    pub fn new() -> Limiter {
+        Limiter {
+            inner: Mutex::new(LimiterInner {
+                bytes: Bucket::new(0),
+                packets: Bucket::new(0),
+                excess: Excess::Drop,
+                last: Instant::now(),
+                deferred: 0,
+            }),
+            dropped: AtomicU64::new(0),
+            delayed: AtomicU64::new(0),
+        }
+    }
+
+    /// Updates the limits, refilling the buckets
+    ///
+    /// # Arguments
+    ///
+    /// - `bytes_per_second`: The new byte rate (0 for unlimited), None to retain the current
+    /// - `packets_per_second`: The new packet rate (0 for unlimited), None to retain the current
+    /// - `excess`: The new action for excess packets, None to retain the current
+    pub fn configure(
+        &self,
+        bytes_per_second: Option<u64>,
+        packets_per_second: Option<u64>,
+        excess: Option<Excess>,
+    ) {
+        let mut inner = self.inner.lock();
+        inner.bytes = Bucket::new(bytes_per_second.unwrap_or(inner.bytes.rate));
+        inner.packets = Bucket::new(packets_per_second.unwrap_or(inner.packets.rate));
+        inner.excess = excess.unwrap_or(inner.excess);
+        inner.last = Instant::now();
+    }
+
+    /// Accounts for a packet
+    ///
+    /// # Arguments
+    ///
+    /// - `size`: The size of the packet
+    ///
+    /// # Returns
+    ///
+    /// The decision for the packet, the caller must never block on a deferred packet.
+    /// Packets are deferred while earlier packets are held back (to retain their order),
+    /// every deferred packet must be accounted for by a call to `released`.
+    pub fn admit(&self, size: usize) -> Admission {
+        let mut inner = self.inner.lock();
+
+        // fast path: unlimited
+        if inner.bytes.rate == 0 && inner.packets.rate == 0 && inner.deferred == 0 {
+            return Admission::Pass;
+        }
+
+        // refill the buckets
+        let now = Instant::now();
+        let elapsed = now.duration_since(inner.last);
+        inner.last = now;
+        inner.bytes.refill(elapsed);
+        inner.packets.refill(elapsed);
+
+        // check if the packet must be dropped
+        let delay = inner.bytes.wait(size).max(inner.packets.wait(1));
+        if delay > Duration::from_secs(0)
+            && (inner.excess == Excess::Drop || delay > MAX_SHAPING_DELAY)
+        {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return Admission::Drop;
+        }
+
+        inner.bytes.consume(size);
+        inner.packets.consume(1);
+
+        // check if the packet must be held back
+        if delay > Duration::from_secs(0) || inner.deferred > 0 {
+            inner.deferred += 1;
+            self.delayed.fetch_add(1, Ordering::Relaxed);
+            Admission::Defer(now + delay)
+        } else {
+            Admission::Pass
+        }
+    }
+
+    /// Accounts for the release of a deferred packet
+    pub fn released(&self) {
+        let mut inner = self.inner.lock();
+        debug_assert!(inner.deferred > 0, "no deferred packets");
+        inner.deferred = inner.deferred.saturating_sub(1);
+    }
+
+    pub fn state(&self) -> LimiterState {
+        let inner = self.inner.lock();
+        LimiterState {
+            bytes_per_second: inner.bytes.rate,
+            packets_per_second: inner.packets.rate,
+            excess: inner.excess,
+            available_bytes: inner.bytes.available(),
+            available_packets: inner.packets.available(),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            delayed: self.delayed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limiter_drop() {
+        let limiter = Limiter::new();
+        assert_eq!(
+            limiter.admit(1 << 20),
+            Admission::Pass,
+            "unlimited by default"
+        );
+
+        // one second worth of packets is admitted, then the bucket is empty
+        limiter.configure(None, Some(10), None);
+        for _ in 0..10 {
+            assert_eq!(limiter.admit(100), Admission::Pass);
+        }
+        assert_eq!(limiter.admit(100), Admission::Drop);
+
+        let state = limiter.state();
+        assert_eq!(state.packets_per_second, 10);
+        assert_eq!(state.dropped, 1);
+        assert_eq!(state.delayed, 0);
+    }
+
+    #[test]
+    fn test_limiter_delay() {
+        let limiter = Limiter::new();
+
+        // the next packet is available after 10ms
+        limiter.configure(None, Some(100), Some(Excess::Delay));
+        for _ in 0..100 {
+            assert_eq!(limiter.admit(100), Admission::Pass);
+        }
+        let start = Instant::now();
+        match limiter.admit(100) {
+            Admission::Defer(release) => {
+                assert!(release > start + Duration::from_millis(5));
+                assert!(start.elapsed() < Duration::from_millis(5), "admit blocked");
+            }
+            decision => panic!("packet not deferred: {:?}", decision),
+        }
+
+        // packets following a deferred packet are deferred (retaining the order)
+        limiter.configure(Some(0), Some(0), None);
+        assert!(matches!(limiter.admit(100), Admission::Defer(_)));
+        limiter.released();
+        limiter.released();
+        assert_eq!(limiter.admit(100), Admission::Pass);
+
+        // a debt exceeding the maximum delay causes drops
+        limiter.configure(Some(1000), None, None);
+        assert_eq!(limiter.admit(1000 * 1000), Admission::Pass);
+        assert_eq!(limiter.admit(1), Admission::Drop);
+
+        let state = limiter.state();
+        assert_eq!(state.delayed, 2);
+        assert_eq!(state.dropped, 1);
+        assert_eq!(state.excess, Excess::Delay);
+    }
+}
@@ -1,10 +1,15 @@
 mod anti_replay;
 mod constants;
 mod device;
+mod filter;
+mod icmp;
 mod ip;
+mod limiter;
 mod messages;
 mod peer;
 mod route;
+mod shaper;
+mod tos;
 mod types;
 
 mod queue;
@@ -32,6 +37,8 @@ pub const fn message_data_len(payload: usize) -> usize {
 }
 
 pub use device::DeviceHandle as Device;
+pub use filter::{Direction, FilterRule};
+pub use limiter::{Excess, LimiterState};
 pub use messages::TYPE_TRANSPORT;
-pub use peer::PeerHandle;
-pub use types::Callbacks;
+pub use peer::{PeerHandle, Roaming};
+pub use types::{Callbacks, Layer};
@@ -5,29 +5,33 @@ use super::anti_replay::AntiReplay;
 use super::device::DecryptionState;
 use super::device::Device;
 use super::device::EncryptionState;
+use super::filter::{Direction, Filter, FilterRule};
+use super::limiter::{Admission, Excess, Limiter, LimiterState};
 
 use super::constants::*;
-use super::types::{Callbacks, RouterError};
-use super::SIZE_MESSAGE_PREFIX;
+use super::types::{Callbacks, Layer, RouterError};
+use super::{message_data_len, SIZE_MESSAGE_PREFIX};
 
 use super::queue::Queue;
 use super::receive::ReceiveJob;
 use super::send::SendJob;
+use super::tos;
 use super::worker::JobUnion;
 
 use core::mem;
 use core::ops::Deref;
-use core::sync::atomic::AtomicBool;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 
+use alloc::collections::VecDeque;
 use alloc::sync::Arc;
 
 // TODO: consider no_std alternatives
 use std::fmt;
 use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
 
-use arraydeque::{ArrayDeque, Wrapping};
 use log;
-use spin::Mutex;
+use spin::{Mutex, RwLock};
 
 pub struct KeyWheel {
     next: Option<Arc<KeyPair>>,     // next key state (unconfirmed)
@@ -36,15 +40,28 @@ pub struct KeyWheel {
     retired: Vec<u32>,              // retired ids
 }
 
+/// Governs how authenticated inbound packets affect the endpoint of a peer
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Roaming {
+    Enabled, // the endpoint follows the source of authenticated packets (default)
+    Locked,  // the endpoint is fixed, packets from other sources are accepted
+    Strict,  // the endpoint is fixed, packets from other sources are rejected
+}
+
 pub struct PeerInner<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> {
     pub device: Device<E, C, T, B>,
     pub opaque: C::Opaque,
     pub outbound: Queue<SendJob<E, C, T, B>>,
     pub inbound: Queue<ReceiveJob<E, C, T, B>>,
-    pub staged_packets: Mutex<ArrayDeque<[Vec<u8>; MAX_QUEUED_PACKETS], Wrapping>>,
+    pub staged_packets: Mutex<VecDeque<Vec<u8>>>, // at most MAX_QUEUED_PACKETS (oldest dropped)
     pub keys: Mutex<KeyWheel>,
     pub enc_key: Mutex<Option<EncryptionState>>,
     pub endpoint: Mutex<Option<E>>,
+    pub roaming: Mutex<Roaming>,
+    pub roaming_attempts: AtomicU64, // packets from other sources while the endpoint is locked
+    pub filter: RwLock<Filter>,
+    pub tx_limiter: Limiter,
+    pub rx_limiter: Limiter,
 }
 
 impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> Deref for PeerInner<E, C, T, B> {
@@ -117,11 +134,14 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> Deref
     }
 }
 
+// the peer is identified by the opaque value (e.g. by the public key)
 impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> fmt::Display
     for PeerHandle<E, C, T, B>
+where
+    C::Opaque: fmt::Display,
 {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "PeerHandle(format: TODO)")
+        self.opaque().fmt(f)
     }
 }
 
@@ -180,7 +200,7 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> Drop for Peer
         *peer.enc_key.lock() = None;
         *peer.endpoint.lock() = None;
 
-        log::debug!("peer dropped & removed from device");
+        log::debug!("event=router_peer_removed");
     }
 }
 
@@ -199,13 +219,18 @@ pub fn new_peer<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>>(
                 outbound: Queue::new(),
                 enc_key: spin::Mutex::new(None),
                 endpoint: spin::Mutex::new(None),
+                roaming: spin::Mutex::new(Roaming::Enabled),
+                roaming_attempts: AtomicU64::new(0),
+                filter: RwLock::new(Filter::new(vec![])),
+                tx_limiter: Limiter::new(),
+                rx_limiter: Limiter::new(),
                 keys: spin::Mutex::new(KeyWheel {
                     next: None,
                     current: None,
                     previous: None,
                     retired: vec![],
                 }),
-                staged_packets: spin::Mutex::new(ArrayDeque::new()),
+                staged_packets: spin::Mutex::new(VecDeque::new()),
             }),
         }
     };
@@ -214,6 +239,20 @@ pub fn new_peer<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>>(
 }
 
 impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> PeerInner<E, C, T, B> {
+    /// Returns the largest IP packet which can be sent to the peer
+    /// without fragmenting the transport message,
+    /// if the path MTU towards the endpoint of the peer is known.
+    pub fn path_mtu(&self) -> Option<usize> {
+        let endpoint = self.endpoint.lock();
+        let endpoint = endpoint.as_ref()?;
+        let mtu = self.device.outbound.read().1.as_ref()?.path_mtu(endpoint)?;
+        let header = match endpoint.into_address() {
+            SocketAddr::V4(_) => SIZE_IP4_HEADER + SIZE_UDP_HEADER,
+            SocketAddr::V6(_) => SIZE_IP6_HEADER + SIZE_UDP_HEADER,
+        };
+        Some(mtu.saturating_sub(header + message_data_len(0)))
+    }
+
     /// Send a raw message to the peer (used for handshake messages)
     ///
     /// # Arguments
@@ -224,6 +263,11 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> PeerInner<E,
     ///
     /// Unit if packet was sent, or an error indicating why sending failed
     pub fn send_raw(&self, msg: &[u8]) -> Result<(), RouterError> {
+        self.send_raw_tos(msg, 0)
+    }
+
+    /// Send a message with the given TOS / traffic class in the outer packet
+    pub(super) fn send_raw_tos(&self, msg: &[u8], tos: u8) -> Result<(), RouterError> {
         // send to endpoint (if known)
         match self.endpoint.lock().as_mut() {
             Some(endpoint) => {
@@ -232,8 +276,11 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> PeerInner<E,
                     outbound
                         .1
                         .as_ref()
-                        .ok_or(RouterError::SendError)
-                        .and_then(|w| w.write(msg, endpoint).map_err(|_| RouterError::SendError))
+                        .ok_or_else(|| RouterError::SendError("no bind".to_owned()))
+                        .and_then(|w| {
+                            w.write_tos(msg, endpoint, tos)
+                                .map_err(|e| RouterError::SendError(e.to_string()))
+                        })
                 } else {
                     Ok(())
                 }
@@ -241,6 +288,61 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> PeerInner<E,
             None => Err(RouterError::NoEndpoint),
         }
     }
+
+    /// Update the endpoint to the source of an authenticated packet,
+    /// subject to the roaming policy of the peer.
+    ///
+    /// # Arguments
+    ///
+    /// - `src`, the source of the authenticated packet
+    ///
+    /// # Returns
+    ///
+    /// A bool indicating whether the packet should be accepted
+    pub fn roam(&self, src: E) -> bool {
+        match self.check_source(&src) {
+            Some(true) => {
+                *self.endpoint.lock() = Some(src);
+                true
+            }
+            Some(false) => true,
+            None => false,
+        }
+    }
+
+    /// Check the source of an authenticated packet against the roaming policy of the peer.
+    /// Sources other than a locked endpoint are counted as roaming attempts.
+    ///
+    /// # Arguments
+    ///
+    /// - `src`, the source of the authenticated packet
+    ///
+    /// # Returns
+    ///
+    /// None if the packet should be rejected,
+    /// otherwise a bool indicating whether the endpoint may be updated to the source.
+    pub fn check_source(&self, src: &E) -> Option<bool> {
+        let roaming = *self.roaming.lock();
+        if roaming == Roaming::Enabled {
+            return Some(true);
+        }
+        match self.endpoint.lock().as_ref() {
+            Some(current) if current.into_address() != src.into_address() => {
+                self.roaming_attempts.fetch_add(1, Ordering::Relaxed);
+                log::debug!(
+                    "endpoint={} roaming={:?} event=source_ignored",
+                    src.into_address(),
+                    roaming
+                );
+                if roaming == Roaming::Locked {
+                    Some(false)
+                } else {
+                    None
+                }
+            }
+            _ => Some(true),
+        }
+    }
 }
 
 impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> Peer<E, C, T, B> {
@@ -252,30 +354,86 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> Peer<E, C, T,
     /// - `stage`: Should the message be staged if no key is available
     ///
     pub(super) fn send(&self, msg: Vec<u8>, stage: bool) {
+        // apply the rate limit of the peer to the plaintext (keepalives are never limited)
+        if msg.len() > SIZE_MESSAGE_PREFIX {
+            match self.tx_limiter.admit(msg.len() - SIZE_MESSAGE_PREFIX) {
+                Admission::Pass => (),
+                Admission::Defer(release) => {
+                    log::debug!("reason=rate_limit event=packet_deferred");
+                    self.device.shaper.defer(self.clone(), msg, release);
+                    return;
+                }
+                Admission::Drop => {
+                    log::debug!("reason=rate_limit event=packet_dropped");
+                    return;
+                }
+            }
+        }
+        self.transmit(msg, stage)
+    }
+
+    /// Encrypt and send a message to the peer, bypassing the rate limit
+    /// (used for messages released by the shaper)
+    ///
+    /// Arguments:
+    ///
+    /// - `msg` : A padded vector holding the message (allows in-place construction of the transport header)
+    /// - `stage`: Should the message be staged if no key is available
+    ///
+    pub(super) fn transmit(&self, msg: Vec<u8>, stage: bool) {
         // check if key available
         let (job, need_key) = {
             let mut enc_key = self.enc_key.lock();
             match enc_key.as_mut() {
                 None => {
-                    log::debug!("no key encryption key available");
+                    log::debug!("event=no_encryption_key");
                     if stage {
-                        self.staged_packets.lock().push_back(msg);
+                        self.stage(msg);
                     };
                     (None, true)
                 }
                 Some(mut state) => {
                     // avoid integer overflow in nonce
                     if state.nonce >= REJECT_AFTER_MESSAGES - 1 {
-                        log::debug!("encryption key expired");
+                        log::debug!(
+                            "key={} event=encryption_key_expired",
+                            state.keypair.local_id()
+                        );
                         *enc_key = None;
                         if stage {
-                            self.staged_packets.lock().push_back(msg);
+                            self.stage(msg);
                         }
                         (None, true)
                     } else {
-                        log::debug!("encryption state available, nonce = {}", state.nonce);
-                        let job =
-                            SendJob::new(msg, state.nonce, state.keypair.clone(), self.clone());
+                        log::debug!(
+                            "key={} nonce={} event=encryption_scheduled",
+                            state.keypair.local_id(),
+                            state.nonce
+                        );
+                        // capture the plaintext packet (if not a keepalive)
+                        if msg.len() > SIZE_MESSAGE_PREFIX && C::capturing(&self.opaque) {
+                            let packet = &msg[SIZE_MESSAGE_PREFIX..];
+                            C::capture(
+                                &self.opaque,
+                                Layer::Inner,
+                                Direction::Out,
+                                packet,
+                                &state.keypair,
+                            );
+                        }
+                        // the DSCP and ECN bits of the outer packet
+                        let tos = tos::encapsulate(
+                            &msg[SIZE_MESSAGE_PREFIX..],
+                            self.device.dscp.load(Ordering::Relaxed),
+                            self.device.ecn.load(Ordering::Relaxed),
+                        );
+                        let job = SendJob::new(
+                            msg,
+                            state.nonce,
+                            tos,
+                            state.keypair.clone(),
+                            self.clone(),
+                        );
                         if self.outbound.push(job.clone()) {
                             state.nonce += 1;
                             (Some(job), false)
@@ -288,27 +446,36 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> Peer<E, C, T,
         };
 
         if need_key {
-            log::debug!("request new key");
+            log::debug!("event=key_requested");
             debug_assert!(job.is_none());
             C::need_key(&self.opaque);
         };
 
         if let Some(job) = job {
-            log::debug!("schedule outbound job");
+            log::debug!("event=outbound_job_scheduled");
             self.device.work.send(JobUnion::Outbound(job))
         }
     }
 
+    // Stage a packet until a key is available (dropping the oldest if full)
+    fn stage(&self, msg: Vec<u8>) {
+        let mut staged = self.staged_packets.lock();
+        if staged.len() >= MAX_QUEUED_PACKETS {
+            staged.pop_front();
+        }
+        staged.push_back(msg);
+    }
+
     // Transmit all staged packets
     fn send_staged(&self) -> bool {
-        log::trace!("peer.send_staged");
+        log::trace!("event=send_staged");
         let mut sent = false;
         let mut staged = self.staged_packets.lock();
         loop {
             match staged.pop_front() {
                 Some(msg) => {
                     sent = true;
-                    self.send(msg, false);
+                    self.transmit(msg, false);
                 }
                 None => break sent,
             }
@@ -316,7 +483,7 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> Peer<E, C, T,
     }
 
     pub(super) fn confirm_key(&self, keypair: &Arc<KeyPair>) {
-        log::trace!("peer.confirm_key");
+        log::trace!("key={} event=confirm_key", keypair.local_id());
         {
             // take lock and check keypair = keys.next
             let mut keys = self.keys.lock();
@@ -363,7 +530,7 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> PeerHandle<E,
     /// This API still permits support for the "sticky socket" behavior,
     /// as sockets should be "unsticked" when manually updating the endpoint
     pub fn set_endpoint(&self, endpoint: E) {
-        log::trace!("peer.set_endpoint");
+        log::trace!("endpoint={} event=set_endpoint", endpoint.into_address());
         *self.peer.endpoint.lock() = Some(endpoint);
     }
 
@@ -371,19 +538,93 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> PeerHandle<E,
         &self.opaque
     }
 
+    /// Set the roaming policy of the peer
+    pub fn set_roaming(&self, roaming: Roaming) {
+        log::trace!("roaming={:?} event=set_roaming", roaming);
+        *self.peer.roaming.lock() = roaming;
+    }
+
+    pub fn get_roaming(&self) -> Roaming {
+        *self.peer.roaming.lock()
+    }
+
+    /// Returns the number of authenticated packets received from
+    /// a source other than the locked endpoint.
+    pub fn get_roaming_attempts(&self) -> u64 {
+        self.peer.roaming_attempts.load(Ordering::Relaxed)
+    }
+
+    /// Replaces the filter rules applied to the inner packets exchanged with the peer
+    /// (resetting the hit counters)
+    pub fn set_filter(&self, rules: Vec<FilterRule>) {
+        log::trace!("rules={} event=set_filter", rules.len());
+        *self.peer.filter.write() = Filter::new(rules);
+    }
+
+    /// Returns the filter rules of the peer and the number of packets matching each rule
+    pub fn list_filter(&self) -> Vec<(FilterRule, u64)> {
+        self.peer.filter.read().list()
+    }
+
+    /// Updates the rate limits of the peer (in bytes and packets per second, 0 for unlimited)
+    ///
+    /// # Arguments
+    ///
+    /// - `tx`: The limits on transmission to the peer, None to retain the current limits
+    /// - `rx`: The limits on reception from the peer, None to retain the current limits
+    /// - `excess`: The action for transmitted packets exceeding the limits, None to retain the current
+    ///   (received packets exceeding the limits are always dropped)
+    pub fn set_limits(
+        &self,
+        tx: (Option<u64>, Option<u64>),
+        rx: (Option<u64>, Option<u64>),
+        excess: Option<Excess>,
+    ) {
+        log::trace!("tx={:?} rx={:?} event=set_limits", tx, rx);
+        self.peer.tx_limiter.configure(tx.0, tx.1, excess);
+        self.peer
+            .rx_limiter
+            .configure(rx.0, rx.1, Some(Excess::Drop));
+    }
+
+    /// Returns the state of the (tx, rx) rate limiters of the peer
+    pub fn get_limits(&self) -> (LimiterState, LimiterState) {
+        (self.peer.tx_limiter.state(), self.peer.rx_limiter.state())
+    }
+
     /// Returns the current endpoint of the peer (for configuration)
     ///
     /// # Note
     ///
     /// Does not convey potential "sticky socket" information
     pub fn get_endpoint(&self) -> Option<SocketAddr> {
-        log::trace!("peer.get_endpoint");
+        log::trace!("event=get_endpoint");
         self.peer.endpoint.lock().as_ref().map(|e| e.into_address())
     }
 
+    /// Returns the age of the keypair currently used for encryption (for configuration)
+    pub fn current_keypair_age(&self) -> Option<Duration> {
+        self.peer
+            .keys
+            .lock()
+            .current
+            .as_ref()
+            .map(|k| k.birth.elapsed())
+    }
+
+    /// Returns the receiver ids of the keypairs of the peer (next, current and previous)
+    #[cfg(test)]
+    pub fn keypair_ids(&self) -> Vec<u32> {
+        let keys = self.peer.keys.lock();
+        [&keys.next, &keys.current, &keys.previous]
+            .iter()
+            .filter_map(|k| k.as_ref().map(|k| k.local_id()))
+            .collect()
+    }
+
     /// Zero all key-material related to the peer
     pub fn zero_keys(&self) {
-        log::trace!("peer.zero_keys");
+        log::trace!("event=zero_keys");
 
         let mut release: Vec<u32> = Vec::with_capacity(3);
         let mut keys = self.peer.keys.lock();
@@ -430,9 +671,14 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> PeerHandle<E,
     /// since the only way to add additional keys to the peer is by using this method
     /// and a peer can have at most 3 keys allocated in the router at any time.
     pub fn add_keypair(&self, new: KeyPair) -> Vec<u32> {
-        log::trace!("Router, add_keypair: {:?}", new);
+        log::trace!(
+            "key={} initiator={} event=add_keypair",
+            new.local_id(),
+            new.initiator
+        );
 
         let initiator = new.initiator;
+        let id = new.local_id();
         let release = {
             let new = Arc::new(new);
             let mut keys = self.peer.keys.lock();
@@ -454,7 +700,7 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> PeerHandle<E,
 
             // update incoming packet id map
             {
-                log::trace!("peer.add_keypair: updating inbound id map");
+                log::trace!("key={} event=inbound_id_mapped", id);
                 let mut recv = self.peer.device.recv.write();
 
                 // purge recv map of previous id
@@ -476,14 +722,14 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> PeerHandle<E,
         // schedule confirmation
         if initiator {
             debug_assert!(self.peer.enc_key.lock().is_some());
-            log::trace!("peer.add_keypair: is initiator, must confirm the key");
+            log::trace!("key={} event=confirmation_required", id);
             // attempt to confirm using staged packets
             if !self.peer.send_staged() {
                 // fall back to keepalive packet
                 self.send_keepalive();
-                log::debug!("peer.add_keypair: keepalive for confirmation",);
+                log::debug!("key={} event=confirmation_keepalive", id);
             }
-            log::trace!("peer.add_keypair: key attempted confirmed");
+            log::trace!("key={} event=confirmation_sent", id);
         }
 
         debug_assert!(
@@ -494,7 +740,7 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> PeerHandle<E,
     }
 
     pub fn send_keepalive(&self) {
-        log::trace!("peer.send_keepalive");
+        log::trace!("event=send_keepalive");
         self.peer.send(vec![0u8; SIZE_MESSAGE_PREFIX], false)
     }
 
@@ -542,4 +788,17 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> PeerHandle<E,
     pub fn purge_staged_packets(&self) {
         self.peer.staged_packets.lock().clear();
     }
+
+    /// Evict the state of an idle peer:
+    /// zeroes all key-material, drops staged packets and releases the memory held by the queues.
+    ///
+    /// The configuration (endpoint, allowed IPs, filter and limits) is retained
+    /// and the state is rebuilt on demand.
+    pub fn evict(&self) {
+        log::trace!("event=evict");
+        self.zero_keys();
+        *self.peer.staged_packets.lock() = VecDeque::new();
+        self.peer.inbound.shrink();
+        self.peer.outbound.shrink();
+    }
 }
@@ -1,4 +1,4 @@
-use arraydeque::ArrayDeque;
+use alloc::collections::VecDeque;
 use spin::Mutex;
 
 use core::mem;
@@ -20,7 +20,7 @@ pub trait ParallelJob: Sized + SequentialJob {
 
 pub struct Queue<J: SequentialJob> {
     contenders: AtomicUsize,
-    queue: Mutex<ArrayDeque<[J; INORDER_QUEUE_SIZE]>>,
+    queue: Mutex<VecDeque<J>>, // at most INORDER_QUEUE_SIZE jobs (allocated on demand)
 
     #[cfg(debug)]
     _flag: Mutex<()>,
@@ -30,7 +30,7 @@ impl<J: SequentialJob> Queue<J> {
     pub fn new() -> Queue<J> {
         Queue {
             contenders: AtomicUsize::new(0),
-            queue: Mutex::new(ArrayDeque::new()),
+            queue: Mutex::new(VecDeque::new()),
 
             #[cfg(debug)]
             _flag: Mutex::new(()),
@@ -38,7 +38,21 @@ impl<J: SequentialJob> Queue<J> {
     }
 
     pub fn push(&self, job: J) -> bool {
-        self.queue.lock().push_back(job).is_ok()
+        let mut queue = self.queue.lock();
+        if queue.len() < INORDER_QUEUE_SIZE {
+            queue.push_back(job);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Release the memory held by the queue (if empty)
+    pub fn shrink(&self) {
+        let mut queue = self.queue.lock();
+        if queue.is_empty() {
+            queue.shrink_to_fit();
+        }
     }
 
     pub fn consume(&self) {
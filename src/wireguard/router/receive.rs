@@ -1,9 +1,12 @@
 use super::device::DecryptionState;
+use super::filter::Direction;
 use super::ip::inner_length;
+use super::limiter::Admission;
 use super::messages::TransportHeader;
 use super::queue::{ParallelJob, Queue, SequentialJob};
-use super::types::Callbacks;
-use super::{REJECT_AFTER_MESSAGES, SIZE_TAG};
+use super::tos;
+use super::types::{Callbacks, Layer};
+use super::{REJECT_AFTER_MESSAGES, SIZE_MESSAGE_PREFIX, SIZE_TAG};
 
 use super::super::{tun, udp, Endpoint};
 
@@ -16,6 +19,7 @@ use zerocopy::{AsBytes, LayoutVerified};
 struct Inner<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> {
     ready: AtomicBool,                       // job status
     buffer: Mutex<(Option<E>, Vec<u8>)>,     // endpoint & ciphertext buffer
+    capture: Option<Vec<u8>>,                // copy of the transport message (if captured)
     state: Arc<DecryptionState<E, C, T, B>>, // decryption state (keys and replay protector)
 }
 
@@ -34,12 +38,14 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> Clone
 impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> ReceiveJob<E, C, T, B> {
     pub fn new(
         buffer: Vec<u8>,
+        capture: Option<Vec<u8>>,
         state: Arc<DecryptionState<E, C, T, B>>,
         endpoint: E,
     ) -> ReceiveJob<E, C, T, B> {
         ReceiveJob(Arc::new(Inner {
             ready: AtomicBool::new(false),
             buffer: Mutex::new((Some(endpoint), buffer)),
+            capture,
             state,
         }))
     }
@@ -69,7 +75,7 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> ParallelJob
             false,
             "doing parallel work on completed job"
         );
-        log::trace!("processing parallel receive job");
+        log::trace!("key={} event=decrypt", self.0.state.keypair.local_id());
 
         // decrypt
         {
@@ -137,49 +143,126 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> SequentialJob
             true,
             "doing sequential work on an incomplete job"
         );
-        log::trace!("processing sequential receive job");
-
         let job = &self.0;
+        log::trace!("key={} event=receive", job.state.keypair.local_id());
         let peer = &job.state.peer;
         let mut msg = job.buffer.lock();
         let endpoint = msg.0.take();
+        let size = msg.1.len();
 
         // cast transport header
-        let (header, packet): (LayoutVerified<&[u8], TransportHeader>, &[u8]) =
-            match LayoutVerified::new_from_prefix(&msg.1[..]) {
+        let (header, packet): (LayoutVerified<&mut [u8], TransportHeader>, &mut [u8]) =
+            match LayoutVerified::new_from_prefix(&mut msg.1[..]) {
                 Some(v) => v,
                 None => {
                     // also covers authentication failure (will fail to parse header)
+                    log::debug!(
+                        "key={} event=authentication_failed",
+                        job.state.keypair.local_id()
+                    );
                     return;
                 }
             };
 
         // check for replay
         if !job.state.protector.lock().update(header.f_counter.get()) {
-            log::debug!("inbound worker: replay detected");
+            log::debug!(
+                "key={} counter={} event=replay_detected",
+                job.state.keypair.local_id(),
+                header.f_counter.get()
+            );
             return;
         }
 
+        // capture the (authenticated) transport message
+        if let Some(capture) = job.capture.as_ref() {
+            C::capture(
+                &peer.opaque,
+                Layer::Outer(endpoint.as_ref().map(|e| e.into_address())),
+                Direction::In,
+                &capture[..],
+                &job.state.keypair,
+            );
+        }
+
+        // update endpoint (subject to the roaming policy)
+        let outer = endpoint.as_ref().map(|e| e.tos()).unwrap_or(0);
+        if let Some(endpoint) = endpoint {
+            let address = endpoint.into_address();
+            if !peer.roam(endpoint) {
+                log::debug!(
+                    "key={} endpoint={} event=source_rejected",
+                    job.state.keypair.local_id(),
+                    address
+                );
+                return;
+            }
+        }
+
         // check for confirms key
         if !job.state.confirmed.swap(true, Ordering::SeqCst) {
-            log::debug!("inbound worker: message confirms key");
+            log::debug!("key={} event=key_confirmed", job.state.keypair.local_id());
             peer.confirm_key(&job.state.keypair);
         }
 
-        // update endpoint
-        *peer.endpoint.lock() = endpoint;
-
-        // check if should be written to TUN
+        // check if should be written to TUN (or relayed)
         // (keep-alive and malformed packets will have no inner length)
         if let Some(inner) = inner_length(packet) {
             if inner + SIZE_TAG <= packet.len() {
-                let _ = peer.device.inbound.write(&packet[..inner]).map_err(|e| {
-                    log::debug!("failed to write inbound packet to TUN: {:?}", e);
-                });
+                // propagate the ECN bits of the outer packet
+                let ip = &mut packet[..inner];
+                let accept =
+                    !peer.device.ecn.load(Ordering::Relaxed) || tos::decapsulate(outer, ip);
+                let ip = &ip[..];
+                if C::capturing(&peer.opaque) {
+                    C::capture(
+                        &peer.opaque,
+                        Layer::Inner,
+                        Direction::In,
+                        ip,
+                        &job.state.keypair,
+                    );
+                }
+
+                // check if should be relayed to another peer
+                let relay = if peer.device.relay.load(Ordering::Relaxed) {
+                    peer.device.table.get_route(ip).filter(|dst| dst != peer)
+                } else {
+                    None
+                };
+
+                let id = job.state.keypair.local_id();
+                if !accept {
+                    log::debug!(
+                        "key={} reason=congestion_experienced event=packet_dropped",
+                        id
+                    );
+                } else if peer.rx_limiter.admit(size) != Admission::Pass {
+                    log::debug!("key={} reason=rate_limit event=packet_dropped", id);
+                } else if !peer.filter.read().check(Direction::In, ip) {
+                    log::debug!("key={} reason=filter event=packet_dropped", id);
+                } else if let Some(dst) = relay {
+                    if !C::relay(&peer.opaque, &dst.opaque) {
+                        log::debug!("key={} reason=relay_denied event=packet_dropped", id);
+                    } else if !dst.filter.read().check(Direction::Out, ip) {
+                        log::debug!("key={} reason=relay_filter event=packet_dropped", id);
+                    } else {
+                        // retain the padding of the sender
+                        let padded = packet.len() - SIZE_TAG;
+                        let mut msg = Vec::with_capacity(SIZE_MESSAGE_PREFIX + padded + SIZE_TAG);
+                        msg.resize(SIZE_MESSAGE_PREFIX, 0);
+                        msg.extend_from_slice(&packet[..padded]);
+                        dst.send(msg, true);
+                    }
+                } else {
+                    let _ = peer.device.inbound.write(ip).map_err(|e| {
+                        log::debug!("key={} error={:?} event=tun_write_failed", id, e);
+                    });
+                }
             }
         }
 
         // trigger callback
-        C::recv(&peer.opaque, msg.1.len(), true, &job.state.keypair);
+        C::recv(&peer.opaque, size, true, &job.state.keypair);
     }
 }
@@ -80,7 +80,7 @@ impl<T: Eq + Clone> RoutingTable<T> {
                     LayoutVerified::new_from_prefix(packet)?;
 
                 log::trace!(
-                    "router, get route for IPv4 destination: {:?}",
+                    "destination={} event=route_lookup",
                     Ipv4Addr::from(header.f_destination)
                 );
 
@@ -96,7 +96,7 @@ impl<T: Eq + Clone> RoutingTable<T> {
                     LayoutVerified::new_from_prefix(packet)?;
 
                 log::trace!(
-                    "router, get route for IPv6 destination: {:?}",
+                    "destination={} event=route_lookup",
                     Ipv6Addr::from(header.f_destination)
                 );
 
@@ -107,7 +107,7 @@ impl<T: Eq + Clone> RoutingTable<T> {
                     .and_then(|(_, _, p)| Some(p.clone()))
             }
             v => {
-                log::trace!("router, invalid IP version {}", v);
+                log::trace!("version={} event=invalid_ip_version", v);
                 None
             }
         }
@@ -1,7 +1,8 @@
+use super::filter::Direction;
 use super::messages::{TransportHeader, TYPE_TRANSPORT};
 use super::peer::Peer;
 use super::queue::{ParallelJob, Queue, SequentialJob};
-use super::types::Callbacks;
+use super::types::{Callbacks, Layer};
 use super::KeyPair;
 use super::{REJECT_AFTER_MESSAGES, SIZE_TAG};
 
@@ -18,6 +19,7 @@ struct Inner<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> {
     ready: AtomicBool,
     buffer: Mutex<Vec<u8>>,
     counter: u64,
+    tos: u8, // TOS / traffic class of the outer packet
     keypair: Arc<KeyPair>,
     peer: Peer<E, C, T, B>,
 }
@@ -36,12 +38,14 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> SendJob<E, C,
     pub fn new(
         buffer: Vec<u8>,
         counter: u64,
+        tos: u8,
         keypair: Arc<KeyPair>,
         peer: Peer<E, C, T, B>,
     ) -> SendJob<E, C, T, B> {
         SendJob(Arc::new(Inner {
             buffer: Mutex::new(buffer),
             counter,
+            tos,
             keypair,
             peer,
             ready: AtomicBool::new(false),
@@ -62,7 +66,7 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> ParallelJob
             false,
             "doing parallel work on completed job"
         );
-        log::trace!("processing parallel send job");
+        log::trace!("key={} event=encrypt", self.0.keypair.local_id());
 
         // encrypt body
         {
@@ -123,12 +127,33 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> SequentialJob
             "doing sequential work 
             on an incomplete job"
         );
-        log::trace!("processing sequential send job");
-
         // send to peer
         let job = &self.0;
+        log::trace!("key={} event=transmit", job.keypair.local_id());
         let msg = job.buffer.lock();
-        let xmit = job.peer.send_raw(&msg[..]).is_ok();
+        let xmit = match job.peer.send_raw_tos(&msg[..], job.tos) {
+            Ok(()) => true,
+            Err(e) => {
+                log::debug!(
+                    "key={} error={} event=transmit_failed",
+                    job.keypair.local_id(),
+                    e
+                );
+                false
+            }
+        };
+
+        // capture the transport message
+        if C::capturing(&job.peer.opaque) {
+            let endpoint = job.peer.endpoint.lock().as_ref().map(|e| e.into_address());
+            C::capture(
+                &job.peer.opaque,
+                Layer::Outer(endpoint),
+                Direction::Out,
+                &msg[..],
+                &job.keypair,
+            );
+        }
 
         // trigger callback (for timers)
         C::send(&job.peer.opaque, msg.len(), xmit, &job.keypair, job.counter);
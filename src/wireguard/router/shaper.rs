@@ -0,0 +1,136 @@
+use super::peer::Peer;
+use super::types::Callbacks;
+
+use super::super::{tun, udp, Endpoint};
+
+use core::cmp::Ordering;
+
+// TODO: consider no_std alternatives
+use std::collections::BinaryHeap;
+use std::sync::{Condvar, Mutex};
+use std::time::Instant;
+
+/* A transport message held back by the rate limit of a peer.
+ *
+ * Deferred messages are ordered by release time,
+ * ties are broken by the order in which the messages were deferred.
+ */
+struct Deferred<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> {
+    release: Instant,
+    seq: u64,
+    peer: Peer<E, C, T, B>,
+    msg: Vec<u8>,
+}
+
+impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> PartialEq
+    for Deferred<E, C, T, B>
+{
+    fn eq(&self, other: &Self) -> bool {
+        self.seq == other.seq
+    }
+}
+
+impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> Eq for Deferred<E, C, T, B> {}
+
+impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> PartialOrd
+    for Deferred<E, C, T, B>
+{
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> Ord for Deferred<E, C, T, B> {
+    // reversed: the binary heap is a max-heap
+    fn cmp(&self, other: &Self) -> Ordering {
+        (other.release, other.seq).cmp(&(self.release, self.seq))
+    }
+}
+
+struct ShaperState<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> {
+    closed: bool,
+    seq: u64,
+    deferred: BinaryHeap<Deferred<E, C, T, B>>,
+}
+
+/// Holds back the messages exceeding the rate limits of the peers,
+/// releasing them from a dedicated thread (the router workers never block on a rate limit).
+pub struct Shaper<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> {
+    state: Mutex<ShaperState<E, C, T, B>>,
+    cond: Condvar,
+}
+
+impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> Shaper<E, C, T, B> {
+    pub fn new() -> Shaper<E, C, T, B> {
+        Shaper {
+            state: Mutex::new(ShaperState {
+                closed: false,
+                seq: 0,
+                deferred: BinaryHeap::new(),
+            }),
+            cond: Condvar::new(),
+        }
+    }
+
+    /// Holds back a message until the release time
+    ///
+    /// # Arguments
+    ///
+    /// - `peer`: The peer to which the message is sent
+    /// - `msg`: The padded message (as passed to `Peer::send`)
+    /// - `release`: The time at which the message is released
+    pub fn defer(&self, peer: Peer<E, C, T, B>, msg: Vec<u8>, release: Instant) {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            peer.tx_limiter.released();
+            return;
+        }
+        let seq = state.seq;
+        state.seq += 1;
+        state.deferred.push(Deferred {
+            release,
+            seq,
+            peer,
+            msg,
+        });
+        self.cond.notify_one();
+    }
+
+    /// Drops all deferred messages and stops the shaper thread
+    pub fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        state.deferred.clear();
+        self.cond.notify_one();
+    }
+
+    /// Releases deferred messages until the shaper is closed
+    pub fn run(&self) {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.closed {
+                return;
+            }
+            let now = Instant::now();
+            match state.deferred.peek().map(|d| d.release) {
+                Some(release) if release <= now => {
+                    let d = state.deferred.pop().unwrap();
+                    drop(state);
+
+                    // transmit before accounting for the release,
+                    // so that later messages cannot overtake the released message
+                    log::trace!("event=deferred_message_released");
+                    d.peer.transmit(d.msg, true);
+                    d.peer.tx_limiter.released();
+                    state = self.state.lock().unwrap();
+                }
+                Some(release) => {
+                    state = self.cond.wait_timeout(state, release - now).unwrap().0;
+                }
+                None => {
+                    state = self.cond.wait(state).unwrap();
+                }
+            }
+        }
+    }
+}
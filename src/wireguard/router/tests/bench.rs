@@ -58,6 +58,9 @@ impl Callbacks for BencherCallbacks {
     }
     fn need_key(_t: &Self::Opaque) {}
     fn key_confirmed(_t: &Self::Opaque) {}
+    fn relay(_src: &Self::Opaque, _dst: &Self::Opaque) -> bool {
+        false
+    }
 }
 
 #[cfg(feature = "profiler")]
@@ -389,6 +392,9 @@ fn bench_router_inbound(b: &mut Bencher) {
         }
         fn need_key(_t: &Self::Opaque) {}
         fn key_confirmed(_t: &Self::Opaque) {}
+        fn relay(_src: &Self::Opaque, _dst: &Self::Opaque) -> bool {
+            false
+        }
     }
 
     // create device
@@ -2,6 +2,7 @@ mod bench;
 mod tests;
 
 use super::message_data_len;
+use super::types::RouterError;
 use super::SIZE_MESSAGE_PREFIX;
 use super::{Callbacks, Device};
 use super::{Key, KeyPair};
@@ -1,14 +1,18 @@
 use crate::platform::udp::Reader;
+use crate::platform::Endpoint;
 
-use std::net::IpAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::ops::Deref;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::time::Duration;
+use std::time::Instant;
 
 use rand::Rng;
 
+use super::super::{Excess, Roaming};
 use super::*;
 
 #[cfg(feature = "unstable")]
@@ -55,6 +59,7 @@ struct Inner {
     recv: EventTracker<(usize, bool)>,
     need_key: EventTracker<()>,
     key_confirmed: EventTracker<()>,
+    relay: AtomicBool,
 }
 
 #[derive(Clone)]
@@ -80,6 +85,7 @@ impl Opaque {
                 recv: EventTracker::new(),
                 need_key: EventTracker::new(),
                 key_confirmed: EventTracker::new(),
+                relay: AtomicBool::new(true),
             }),
         }
     }
@@ -116,6 +122,10 @@ impl Callbacks for TestCallbacks {
     fn key_confirmed(t: &Self::Opaque) {
         t.key_confirmed.log(());
     }
+
+    fn relay(src: &Self::Opaque, _dst: &Self::Opaque) -> bool {
+        src.relay.load(Ordering::Acquire)
+    }
 }
 
 #[test]
@@ -480,3 +490,325 @@ fn test_bidirectional() {
         }
     }
 }
+
+#[test]
+fn test_relay() {
+    init();
+
+    let ((bind_reader1, _bind_writer1), (_bind_reader2, bind_writer2)) = dummy::PairBind::pair();
+
+    // create hub (relaying between its peers) and spoke
+    let (_fake, _, tun_writer1, _) = dummy::TunTest::create(false);
+    let (_fake, _, tun_writer2, _) = dummy::TunTest::create(false);
+
+    let hub: Device<_, TestCallbacks, _, _> = Device::new(1, tun_writer1);
+    hub.set_outbound_writer(dummy::VoidBind::new());
+    hub.set_relay(true);
+
+    let spoke: Device<_, TestCallbacks, _, _> = Device::new(1, tun_writer2);
+    spoke.set_outbound_writer(bind_writer2);
+
+    // spoke A (connected to the spoke device) and spoke B on the hub
+    let opaque_a = Opaque::new();
+    let opaque_b = Opaque::new();
+    let opaque_hub = Opaque::new();
+
+    let peer_a = hub.new_peer(opaque_a.clone());
+    peer_a.add_allowed_ip("10.0.0.2".parse().unwrap(), 32);
+    peer_a.add_keypair(dummy_keypair(false));
+
+    let peer_b = hub.new_peer(opaque_b.clone());
+    peer_b.add_allowed_ip("10.0.0.3".parse().unwrap(), 32);
+    peer_b.set_endpoint(dummy::UnitEndpoint::new());
+    peer_b.add_keypair(KeyPair {
+        birth: Instant::now(),
+        initiator: true,
+        send: Key {
+            key: [0x42u8; 32],
+            id: 0x42,
+        },
+        recv: Key {
+            key: [0x43u8; 32],
+            id: 0x43,
+        },
+    });
+
+    assert_eq!(
+        opaque_b.send.wait(TIMEOUT),
+        Some((SIZE_KEEPALIVE, true)),
+        "expected confirmation of the key of spoke B"
+    );
+
+    let peer_hub = spoke.new_peer(opaque_hub.clone());
+    peer_hub.add_allowed_ip("0.0.0.0".parse().unwrap(), 0);
+    peer_hub.set_endpoint(dummy::UnitEndpoint::new());
+    peer_hub.add_keypair(dummy_keypair(true));
+
+    assert_eq!(
+        opaque_hub.send.wait(TIMEOUT),
+        Some((SIZE_KEEPALIVE, true)),
+        "expected confirmation of the key of the hub"
+    );
+
+    // deliver the confirmation to the hub
+    let mut buf = vec![0u8; SIZE_MSG * 2];
+    let (len, from) = bind_reader1.read(&mut buf).unwrap();
+    buf.truncate(len);
+    hub.recv(from, buf).unwrap();
+
+    assert_eq!(opaque_a.recv.wait(TIMEOUT), Some((SIZE_KEEPALIVE, true)));
+    assert_eq!(opaque_a.key_confirmed.wait(TIMEOUT), Some(()));
+
+    no_events!(opaque_a);
+    no_events!(opaque_b);
+    no_events!(opaque_hub);
+
+    // send A -> B through the hub, with and without permission
+    for (id, allowed) in [true, false].iter().enumerate() {
+        opaque_a.relay.store(*allowed, Ordering::Release);
+
+        let msg = make_packet(
+            SIZE_MSG,
+            "10.0.0.2".parse().unwrap(), // src
+            "10.0.0.3".parse().unwrap(), // dst
+            id as u64,
+        );
+        spoke.send(pad(&msg)).unwrap();
+        assert_eq!(
+            opaque_hub.send.wait(TIMEOUT),
+            Some((msg.len() + SIZE_KEEPALIVE, true))
+        );
+
+        let mut buf = vec![0u8; SIZE_MSG * 2];
+        let (len, from) = bind_reader1.read(&mut buf).unwrap();
+        buf.truncate(len);
+        hub.recv(from, buf).unwrap();
+
+        assert_eq!(
+            opaque_a.recv.wait(TIMEOUT),
+            Some((msg.len() + SIZE_KEEPALIVE, true)),
+            "decryption and routing should succeed"
+        );
+
+        if *allowed {
+            assert_eq!(
+                opaque_b.send.wait(TIMEOUT),
+                Some((msg.len() + SIZE_KEEPALIVE, true)),
+                "packet should be relayed to spoke B"
+            );
+        }
+
+        no_events!(opaque_a);
+        no_events!(opaque_b);
+        no_events!(opaque_hub);
+    }
+}
+
+#[test]
+fn test_roaming() {
+    init();
+
+    let ((bind_reader1, _bind_writer1), (_bind_reader2, bind_writer2)) = dummy::PairBind::pair();
+
+    // the receiver distinguishes the sources of the transport messages
+    let (_fake, _, tun_writer1, _) = dummy::TunTest::create(false);
+    let (_fake, _, tun_writer2, _) = dummy::TunTest::create(false);
+
+    let receiver: Device<dummy::AddrEndpoint, TestCallbacks, _, dummy::PcapWriter> =
+        Device::new(1, tun_writer1);
+    let sender: Device<_, TestCallbacks, _, _> = Device::new(1, tun_writer2);
+    sender.set_outbound_writer(bind_writer2);
+
+    let opaque1 = Opaque::new();
+    let opaque2 = Opaque::new();
+
+    let peer1 = receiver.new_peer(opaque1.clone());
+    peer1.add_allowed_ip("192.168.2.0".parse().unwrap(), 24);
+    peer1.add_keypair(dummy_keypair(false));
+
+    let peer2 = sender.new_peer(opaque2.clone());
+    peer2.add_allowed_ip("192.168.1.0".parse().unwrap(), 24);
+    peer2.set_endpoint(dummy::UnitEndpoint::new());
+    peer2.add_keypair(dummy_keypair(true));
+
+    let locked: SocketAddr = "192.0.2.1:51820".parse().unwrap();
+    let foreign: SocketAddr = "198.51.100.1:51820".parse().unwrap();
+    peer1.set_endpoint(dummy::AddrEndpoint::from_address(locked));
+
+    // deliver the next transport message to the receiver from the source
+    let deliver = |id: Option<u64>, src: SocketAddr| {
+        let size = match id {
+            Some(id) => {
+                let msg = make_packet(
+                    SIZE_MSG,
+                    "192.168.2.20".parse().unwrap(), // src
+                    "192.168.1.20".parse().unwrap(), // dst
+                    id,
+                );
+                sender.send(pad(&msg)).unwrap();
+                msg.len() + SIZE_KEEPALIVE
+            }
+            None => SIZE_KEEPALIVE,
+        };
+        assert_eq!(opaque2.send.wait(TIMEOUT), Some((size, true)));
+
+        let mut buf = vec![0u8; SIZE_MSG * 2];
+        let (len, _) = bind_reader1.read(&mut buf).unwrap();
+        buf.truncate(len);
+        receiver
+            .recv(dummy::AddrEndpoint::from_address(src), buf)
+            .unwrap();
+        size
+    };
+
+    // locked: the key confirmation from a foreign source is accepted,
+    // but the endpoint is retained
+    peer1.set_roaming(Roaming::Locked);
+    let size = deliver(None, foreign);
+    assert_eq!(opaque1.recv.wait(TIMEOUT), Some((size, true)));
+    assert_eq!(opaque1.key_confirmed.wait(TIMEOUT), Some(()));
+    assert_eq!(peer1.get_endpoint(), Some(locked));
+    assert_eq!(peer1.get_roaming_attempts(), 1);
+
+    // strict: a transport message from a foreign source is dropped
+    peer1.set_roaming(Roaming::Strict);
+    deliver(Some(0), foreign);
+    assert_eq!(opaque1.recv.wait(TIMEOUT), None);
+    assert_eq!(peer1.get_endpoint(), Some(locked));
+    assert_eq!(peer1.get_roaming_attempts(), 2);
+
+    // strict: a transport message from the locked endpoint is accepted (and not counted)
+    let size = deliver(Some(1), locked);
+    assert_eq!(opaque1.recv.wait(TIMEOUT), Some((size, true)));
+    assert_eq!(peer1.get_roaming_attempts(), 2);
+
+    // enabled: the endpoint roams to the source
+    peer1.set_roaming(Roaming::Enabled);
+    let size = deliver(Some(2), foreign);
+    assert_eq!(opaque1.recv.wait(TIMEOUT), Some((size, true)));
+    assert_eq!(peer1.get_endpoint(), Some(foreign));
+    assert_eq!(peer1.get_roaming_attempts(), 2);
+
+    no_events!(opaque1);
+    no_events!(opaque2);
+}
+
+#[test]
+fn test_path_mtu() {
+    init();
+
+    // the path MTU leaves room for 1280 - 20 (IPv4) - 8 (UDP) - 32 (transport message) bytes
+    let (fake, _reader, tun_writer, _mtu) = dummy::TunTest::create(true);
+    let router: Device<_, TestCallbacks, _, _> = Device::new(1, tun_writer);
+    router.set_outbound_writer(dummy::PathMtuBind::new(1280));
+    let mtu = 1280 - 20 - 8 - SIZE_KEEPALIVE;
+
+    let opaque = Opaque::new();
+    let peer = router.new_peer(opaque.clone());
+    peer.add_allowed_ip("192.168.1.0".parse().unwrap(), 24);
+    peer.add_keypair(dummy_keypair(true));
+    assert_eq!(opaque.send.wait(TIMEOUT), Some((SIZE_KEEPALIVE, false)));
+    assert_eq!(peer.path_mtu(), None, "no endpoint");
+    peer.set_endpoint(dummy::UnitEndpoint::new());
+    assert_eq!(peer.path_mtu(), Some(mtu));
+
+    let src = "192.168.2.1".parse().unwrap();
+    let dst = "192.168.1.20".parse().unwrap();
+
+    // the padding is truncated to the path MTU
+    let msg = make_packet(mtu - 20 - 4, src, dst, 0);
+    let mut padded = pad(&msg);
+    padded.resize(padded.len() + 15, 0);
+    assert!(router.send(padded).is_ok());
+    assert_eq!(
+        opaque.send.wait(TIMEOUT),
+        Some((SIZE_KEEPALIVE + mtu, true))
+    );
+
+    // larger packets without the DF bit are sent (the outer packet is fragmented)
+    let mut msg = make_packet(mtu - 20 + 1, src, dst, 0);
+    assert!(router.send(pad(&msg)).is_ok());
+    assert_eq!(
+        opaque.send.wait(TIMEOUT),
+        Some((SIZE_KEEPALIVE + mtu + 1, true))
+    );
+
+    // larger packets with the DF bit are dropped and an ICMP error is written to the TUN device
+    msg[6] |= 0x40;
+    match router.send(pad(&msg)) {
+        Err(RouterError::PacketTooBig(n)) => assert_eq!(n, mtu),
+        res => panic!("expected the packet to be dropped, got {:?}", res),
+    }
+    let icmp = fake.read();
+    assert_eq!(&icmp[12..16], &[192, 168, 1, 20]);
+    assert_eq!(&icmp[16..20], &[192, 168, 2, 1]);
+    assert_eq!(&icmp[20..22], &[3, 4]);
+    assert_eq!(u16::from_be_bytes([icmp[26], icmp[27]]) as usize, mtu);
+    no_events!(opaque);
+
+    // IPv6 packets of at most 1280 bytes are never dropped
+    peer.add_allowed_ip("fd00::".parse().unwrap(), 64);
+    let src = "fd01::1".parse().unwrap();
+    let dst = "fd00::1".parse().unwrap();
+    let msg = make_packet(1280 - 40, src, dst, 0);
+    assert!(router.send(pad(&msg)).is_ok());
+    assert_eq!(
+        opaque.send.wait(TIMEOUT),
+        Some((SIZE_KEEPALIVE + 1280, true))
+    );
+
+    // larger IPv6 packets are answered by an ICMPv6 error (advertising at least 1280 bytes)
+    let msg = make_packet(1280 - 40 + 1, src, dst, 0);
+    match router.send(pad(&msg)) {
+        Err(RouterError::PacketTooBig(n)) => assert_eq!(n, mtu),
+        res => panic!("expected the packet to be dropped, got {:?}", res),
+    }
+    let icmp = fake.read();
+    assert_eq!(icmp[40], 2);
+    assert_eq!(
+        u32::from_be_bytes([icmp[44], icmp[45], icmp[46], icmp[47]]),
+        1280
+    );
+    no_events!(opaque);
+}
+
+#[test]
+fn test_shaping() {
+    init();
+
+    let (_fake, _reader, tun_writer, _mtu) = dummy::TunTest::create(false);
+    let router: Device<_, TestCallbacks, _, _> = Device::new(1, tun_writer);
+    router.set_outbound_writer(dummy::VoidBind::new());
+
+    let opaque = Opaque::new();
+    let peer = router.new_peer(opaque.clone());
+    peer.add_allowed_ip("192.168.1.0".parse().unwrap(), 24);
+    peer.add_keypair(dummy_keypair(true));
+    assert_eq!(opaque.send.wait(TIMEOUT), Some((SIZE_KEEPALIVE, false)));
+
+    // one second worth of packets passes, then a packet every 20ms:
+    // two packets are deferred (20ms and 40ms) and the third exceeds the maximum delay
+    peer.set_limits((None, Some(50)), (None, None), Some(Excess::Delay));
+    let msg = make_packet(
+        SIZE_MSG,
+        "192.168.2.1".parse().unwrap(),
+        "192.168.1.20".parse().unwrap(),
+        0,
+    );
+    for _ in 0..53 {
+        assert!(router.send(pad(&msg)).is_ok());
+    }
+
+    // the deferred packets are released by the shaper
+    for _ in 0..52 {
+        assert_eq!(
+            opaque.send.wait(TIMEOUT),
+            Some((SIZE_KEEPALIVE + msg.len(), false))
+        );
+    }
+    no_events!(opaque);
+
+    let (tx, _) = peer.get_limits();
+    assert_eq!(tx.delayed, 2);
+    assert_eq!(tx.dropped, 1);
+}
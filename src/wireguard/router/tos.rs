@@ -0,0 +1,174 @@
+use super::icmp::checksum;
+use super::ip::{VERSION_IP4, VERSION_IP6};
+
+/* The TOS / traffic class (DS field) of the outer packets:
+ * the DSCP bits are optionally copied from the inner packet,
+ * while the ECN bits are propagated as by the normal mode of RFC 6040.
+ */
+
+const ECN_MASK: u8 = 0b11;
+const ECN_NOT_ECT: u8 = 0b00;
+const ECN_ECT_1: u8 = 0b01;
+const ECN_ECT_0: u8 = 0b10;
+const ECN_CE: u8 = 0b11;
+
+const SIZE_IP4_HEADER: usize = 20;
+const SIZE_IP6_HEADER: usize = 40;
+
+// the DS field (DSCP and ECN bits) of an IP packet
+fn dsfield(packet: &[u8]) -> Option<u8> {
+    match packet.first()? >> 4 {
+        VERSION_IP4 if packet.len() >= SIZE_IP4_HEADER => Some(packet[1]),
+        VERSION_IP6 if packet.len() >= SIZE_IP6_HEADER => Some((packet[0] << 4) | (packet[1] >> 4)),
+        _ => None,
+    }
+}
+
+// replace the DS field of an IP packet (with a valid DS field),
+// updating the header checksum of IPv4
+fn set_dsfield(packet: &mut [u8], value: u8) {
+    if packet[0] >> 4 == VERSION_IP4 {
+        let ihl = (usize::from(packet[0] & 0xf) * 4).clamp(SIZE_IP4_HEADER, packet.len());
+        packet[1] = value;
+        packet[10..12].copy_from_slice(&[0, 0]);
+        let sum = checksum(&[&packet[..ihl]]);
+        packet[10..12].copy_from_slice(&sum.to_be_bytes());
+    } else {
+        packet[0] = (packet[0] & 0xf0) | (value >> 4);
+        packet[1] = (packet[1] & 0x0f) | (value << 4);
+    }
+}
+
+/// Computes the TOS / traffic class of the outer packet
+///
+/// # Arguments
+///
+/// - `packet`: The inner IP packet (empty for keepalives)
+/// - `dscp`: Copy the DSCP bits of the inner packet
+/// - `ecn`: Propagate the ECN bits of the inner packet
+///
+/// # Returns
+///
+/// The DS field of the outer packet (0 to use the default of the socket)
+pub fn encapsulate(packet: &[u8], dscp: bool, ecn: bool) -> u8 {
+    let inner = match dsfield(packet) {
+        Some(inner) => inner,
+        None => return 0,
+    };
+    let mut outer = 0;
+    if dscp {
+        outer |= inner & !ECN_MASK;
+    }
+    if ecn {
+        // the ECN bits are copied unchanged (including congestion experienced)
+        outer |= inner & ECN_MASK;
+    }
+    outer
+}
+
+/// Propagates the ECN bits of the outer packet to the inner packet
+///
+/// # Arguments
+///
+/// - `outer`: The TOS / traffic class of the outer packet
+/// - `packet`: The inner IP packet (updated in place)
+///
+/// # Returns
+///
+/// A bool indicating whether the packet should be accepted
+/// (congestion experienced by a packet which is not ECN-capable must be signaled by a drop)
+pub fn decapsulate(outer: u8, packet: &mut [u8]) -> bool {
+    let inner = match dsfield(packet) {
+        Some(inner) => inner,
+        None => return true,
+    };
+    let ecn = match (outer & ECN_MASK, inner & ECN_MASK) {
+        (ECN_CE, ECN_NOT_ECT) => return false,
+        (ECN_CE, _) => ECN_CE,
+        (ECN_ECT_1, ECN_ECT_0) => ECN_ECT_1,
+        (_, bits) => bits,
+    };
+    if ecn != inner & ECN_MASK {
+        set_dsfield(packet, (inner & !ECN_MASK) | ecn);
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pnet::packet::ipv4::{self, MutableIpv4Packet};
+    use pnet::packet::ipv6::MutableIpv6Packet;
+
+    fn packet4(dsfield: u8) -> Vec<u8> {
+        let mut msg = vec![0u8; 28];
+        let mut packet = MutableIpv4Packet::new(&mut msg[..]).unwrap();
+        packet.set_version(4);
+        packet.set_header_length(5);
+        packet.set_total_length(28);
+        packet.set_dscp(dsfield >> 2);
+        packet.set_ecn(dsfield & ECN_MASK);
+        packet.set_source("10.0.0.1".parse().unwrap());
+        packet.set_destination("10.0.0.2".parse().unwrap());
+        let sum = ipv4::checksum(&packet.to_immutable());
+        packet.set_checksum(sum);
+        msg
+    }
+
+    fn packet6(dsfield: u8) -> Vec<u8> {
+        let mut msg = vec![0u8; 48];
+        let mut packet = MutableIpv6Packet::new(&mut msg[..]).unwrap();
+        packet.set_version(6);
+        packet.set_traffic_class(dsfield);
+        packet.set_flow_label(0xfffff);
+        packet.set_payload_length(8);
+        msg
+    }
+
+    #[test]
+    fn test_encapsulate() {
+        let af41 = 34 << 2;
+        for packet in [packet4(af41 | ECN_ECT_1), packet6(af41 | ECN_ECT_1)].iter() {
+            assert_eq!(encapsulate(packet, true, true), af41 | ECN_ECT_1);
+            assert_eq!(encapsulate(packet, true, false), af41);
+            assert_eq!(encapsulate(packet, false, true), ECN_ECT_1);
+            assert_eq!(encapsulate(packet, false, false), 0);
+        }
+        assert_eq!(encapsulate(&packet4(ECN_CE), false, true), ECN_CE);
+        assert_eq!(encapsulate(&packet6(ECN_CE), false, true), ECN_CE);
+        assert_eq!(encapsulate(&packet4(ECN_NOT_ECT), false, true), ECN_NOT_ECT);
+        assert_eq!(encapsulate(&[], true, true), 0);
+    }
+
+    #[test]
+    fn test_decapsulate() {
+        let af41 = 34 << 2;
+        let cases = [
+            (ECN_NOT_ECT, ECN_ECT_0, Some(ECN_ECT_0)),
+            (ECN_ECT_1, ECN_ECT_0, Some(ECN_ECT_1)),
+            (ECN_ECT_0, ECN_ECT_1, Some(ECN_ECT_1)),
+            (ECN_CE, ECN_ECT_0, Some(ECN_CE)),
+            (ECN_CE, ECN_ECT_1, Some(ECN_CE)),
+            (ECN_CE, ECN_NOT_ECT, None),
+            (ECN_ECT_0, ECN_CE, Some(ECN_CE)),
+        ];
+        for &(outer, inner, expected) in cases.iter() {
+            let mut packet = packet4(af41 | inner);
+            assert_eq!(decapsulate(outer, &mut packet), expected.is_some());
+            if let Some(ecn) = expected {
+                assert_eq!(packet[1], af41 | ecn);
+                assert_eq!(checksum(&[&packet[..SIZE_IP4_HEADER]]), 0);
+            }
+
+            let mut packet = packet6(af41 | inner);
+            assert_eq!(decapsulate(outer, &mut packet), expected.is_some());
+            if let Some(ecn) = expected {
+                let packet = MutableIpv6Packet::new(&mut packet[..]).unwrap();
+                assert_eq!(packet.get_traffic_class(), af41 | ecn);
+                assert_eq!(packet.get_flow_label(), 0xfffff);
+                assert_eq!(packet.get_version(), 6);
+            }
+        }
+    }
+}
@@ -1,8 +1,11 @@
+use super::filter::Direction;
 use super::KeyPair;
 
 use alloc::sync::Arc;
 use core::fmt;
 
+use std::net::SocketAddr;
+
 // TODO: no_std alternatives
 use std::error::Error;
 
@@ -32,6 +35,32 @@ pub trait Callbacks: Send + Sync + 'static {
     fn recv(opaque: &Self::Opaque, size: usize, sent: bool, keypair: &Arc<KeyPair>);
     fn need_key(opaque: &Self::Opaque);
     fn key_confirmed(opaque: &Self::Opaque);
+
+    // may packets from `src` be relayed to `dst` (when relaying is enabled)
+    fn relay(src: &Self::Opaque, dst: &Self::Opaque) -> bool;
+
+    // are the packets exchanged with the peer captured?
+    // (checked before the arguments of capture are prepared)
+    fn capturing(_opaque: &Self::Opaque) -> bool {
+        false
+    }
+
+    // a packet exchanged with the peer under the keypair (for packet capture)
+    fn capture(
+        _opaque: &Self::Opaque,
+        _layer: Layer,
+        _direction: Direction,
+        _packet: &[u8],
+        _keypair: &Arc<KeyPair>,
+    ) {
+    }
+}
+
+/// The layer of a packet passed to `Callbacks::capture`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Layer {
+    Inner,                     // plaintext IP packet
+    Outer(Option<SocketAddr>), // transport message (and the endpoint of the peer)
 }
 
 #[derive(Debug)]
@@ -40,7 +69,9 @@ pub enum RouterError {
     MalformedTransportMessage,
     UnknownReceiverId,
     NoEndpoint,
-    SendError,
+    SendError(String), // the error of the bind
+    Filtered,
+    PacketTooBig(usize), // exceeds the MTU of the path to the peer
 }
 
 impl fmt::Display for RouterError {
@@ -52,7 +83,11 @@ impl fmt::Display for RouterError {
                 write!(f, "No decryption state associated with receiver id")
             }
             RouterError::NoEndpoint => write!(f, "No endpoint for peer"),
-            RouterError::SendError => write!(f, "Failed to send packet on bind"),
+            RouterError::SendError(e) => write!(f, "Failed to send packet on bind: {}", e),
+            RouterError::Filtered => write!(f, "Packet dropped by peer filter"),
+            RouterError::PacketTooBig(mtu) => {
+                write!(f, "Packet exceeds the path MTU to the peer ({})", mtu)
+            }
         }
     }
 }
@@ -17,10 +17,10 @@ pub fn worker<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>>(
     receiver: Receiver<JobUnion<E, C, T, B>>,
 ) {
     loop {
-        log::trace!("pool worker awaiting job");
+        log::trace!("event=worker_awaiting_job");
         match receiver.recv() {
             Err(e) => {
-                log::debug!("worker stopped with {}", e);
+                log::debug!("error={} event=worker_stopped", e);
                 break;
             }
             Ok(JobUnion::Inbound(job)) => {
@@ -1,10 +1,29 @@
+use super::capture::KeyLogFile;
+use super::constants::{ENDPOINT_FAILOVER_ATTEMPTS, PROVIDER_LOOKUPS_BURSTABLE};
+use super::diagnostics::HandshakeOutcome;
 use super::dummy;
+use super::dummy::pcap;
+use super::events::Event;
+use super::handshake::{self, MAX_HANDSHAKE_MSG_SIZE, TYPE_INITIATION, TYPE_RESPONSE};
+use super::provider::{MemoryPeerProvider, ProvidedPeer};
+use super::udp::{Reader, Writer};
 use super::wireguard::WireGuard;
+use super::workers::HandshakeJob;
+use super::Endpoint;
 
 use std::convert::TryInto;
-use std::net::IpAddr;
-
+use std::env;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::process;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use byteorder::{ByteOrder, LittleEndian};
 use hex;
+use rand::rngs::OsRng;
 use rand_chacha::ChaCha8Rng;
 use rand_core::{RngCore, SeedableRng};
 use x25519_dalek::{PublicKey, StaticSecret};
@@ -59,6 +78,71 @@ fn init() {
     let _ = env_logger::builder().is_test(true).try_init();
 }
 
+type TestWireGuard = WireGuard<dummy::TunTest, dummy::PairBind>;
+
+/* Create two instances of WireGuard connected by a pair of dummy binds,
+ * where the initiator (1) is configured with the responder (2) as a peer
+ * (routing 192.168.2.0/24 to the responder).
+ *
+ * If `mutual` the responder is also configured with the initiator
+ * (routing 192.168.1.0/24 to the initiator).
+ *
+ * Returns the instances, their fake TUN devices and their public keys.
+ */
+fn pair(
+    mutual: bool,
+) -> (
+    TestWireGuard,
+    TestWireGuard,
+    dummy::TunFakeIO,
+    dummy::TunFakeIO,
+    PublicKey,
+    PublicKey,
+) {
+    // up before adding the reader: the TUN worker sizes its first buffer by the MTU
+    let (fake1, tun_reader1, tun_writer1, _) = dummy::TunTest::create(true);
+    let wg1: TestWireGuard = WireGuard::new(tun_writer1);
+    wg1.up(1500);
+    wg1.add_tun_reader(tun_reader1);
+
+    let (fake2, tun_reader2, tun_writer2, _) = dummy::TunTest::create(true);
+    let wg2: TestWireGuard = WireGuard::new(tun_writer2);
+    wg2.up(1500);
+    wg2.add_tun_reader(tun_reader2);
+
+    let ((bind_reader1, bind_writer1), (bind_reader2, bind_writer2)) = dummy::PairBind::pair();
+    wg1.set_writer(bind_writer1);
+    wg2.set_writer(bind_writer2);
+    wg1.add_udp_reader(bind_reader1);
+    wg2.add_udp_reader(bind_reader2);
+
+    let sk1 = StaticSecret::from([0x11; 32]);
+    let sk2 = StaticSecret::from([0x22; 32]);
+    let pk1 = PublicKey::from(&sk1);
+    let pk2 = PublicKey::from(&sk2);
+
+    wg1.add_peer(pk2);
+    if mutual {
+        wg2.add_peer(pk1);
+    }
+    wg1.set_key(Some(sk1));
+    wg2.set_key(Some(sk2));
+
+    {
+        let peers1 = wg1.peers.read();
+        let peer2 = peers1.get(&pk2).unwrap();
+        peer2.add_allowed_ip("192.168.2.0".parse().unwrap(), 24);
+        peer2.set_endpoint(dummy::UnitEndpoint::new());
+    }
+    if mutual {
+        let peers2 = wg2.peers.read();
+        let peer1 = peers2.get(&pk1).unwrap();
+        peer1.add_allowed_ip("192.168.1.0".parse().unwrap(), 24);
+    }
+
+    (wg1, wg2, fake1, fake2, pk1, pk2)
+}
+
 /* Create and configure
  * two matching pure (no side-effects) instances of WireGuard.
  *
@@ -198,3 +282,538 @@ fn test_pure_wireguard() {
         }
     }
 }
+
+/* Test that the candidate endpoints of a peer are rotated in order,
+ * wrapping around to the first after the last candidate.
+ */
+#[test]
+fn test_endpoint_candidates() {
+    init();
+
+    let (wg1, _wg2, _fake1, _fake2, _pk1, pk2) = pair(false);
+    let peers1 = wg1.peers.read();
+    let peer2 = peers1.get(&pk2).unwrap().opaque();
+
+    // a single candidate has no alternative to fail over to
+    let first: SocketAddr = "192.0.2.1:51820".parse().unwrap();
+    assert_eq!(peer2.set_endpoint_candidates(vec![first]), Some(first));
+    assert_eq!(peer2.next_endpoint_candidate(), None);
+
+    let candidates: Vec<SocketAddr> = vec![
+        first,
+        "192.0.2.2:51820".parse().unwrap(),
+        "[2001:db8::1]:51820".parse().unwrap(),
+    ];
+    assert_eq!(
+        peer2.set_endpoint_candidates(candidates.clone()),
+        Some(first)
+    );
+    assert_eq!(peer2.next_endpoint_candidate(), Some(candidates[1]));
+    assert_eq!(peer2.next_endpoint_candidate(), Some(candidates[2]));
+    assert_eq!(peer2.next_endpoint_candidate(), Some(candidates[0]));
+    assert_eq!(peer2.next_endpoint_candidate(), Some(candidates[1]));
+
+    // replacing the candidates restarts from the first
+    assert_eq!(
+        peer2.set_endpoint_candidates(candidates.clone()),
+        Some(first)
+    );
+    assert_eq!(peer2.next_endpoint_candidate(), Some(candidates[1]));
+    assert_eq!(peer2.get_endpoint_candidates(), candidates);
+}
+
+/* Test that retransmitted handshakes fail over to the next candidate endpoint
+ * after every ENDPOINT_FAILOVER_ATTEMPTS attempts.
+ */
+#[test]
+fn test_endpoint_failover() {
+    init();
+
+    let (wg1, _wg2, _fake1, _fake2, _pk1, pk2) = pair(false);
+    let peers1 = wg1.peers.read();
+    let peer2 = peers1.get(&pk2).unwrap().opaque();
+
+    let candidates: Vec<SocketAddr> = vec![
+        "192.0.2.1:51820".parse().unwrap(),
+        "192.0.2.2:51820".parse().unwrap(),
+    ];
+    peer2.set_endpoint_candidates(candidates.clone());
+    for attempts in 1..=2 * ENDPOINT_FAILOVER_ATTEMPTS {
+        let expected = if attempts == ENDPOINT_FAILOVER_ATTEMPTS {
+            Some(candidates[1])
+        } else if attempts == 2 * ENDPOINT_FAILOVER_ATTEMPTS {
+            Some(candidates[0])
+        } else {
+            None
+        };
+        assert_eq!(peer2.endpoint_failover(attempts), expected);
+    }
+
+    // a single candidate has no alternative to fail over to
+    peer2.set_endpoint_candidates(vec![candidates[0]]);
+    assert_eq!(peer2.endpoint_failover(ENDPOINT_FAILOVER_ATTEMPTS), None);
+}
+
+/* Create two instances of WireGuard,
+ * where only the initiator is configured with the peer
+ * and the responder learns the initiator from a peer provider.
+ *
+ * Test:
+ *
+ * - The responder adds the peer on demand
+ * - Handshaking completes successfully (using the provided psk)
+ * - The provided allowed IPs are configured
+ */
+#[test]
+fn test_peer_provider() {
+    init();
+
+    // only the initiator knows the responder
+    let (wg1, wg2, fake1, _fake2, pk1, pk2) = pair(false);
+    let psk = [0x33; 32];
+    wg1.set_psk(pk2, psk);
+
+    // the responder provisions the initiator on demand
+    let provider = Arc::new(MemoryPeerProvider::new());
+    provider.insert(
+        pk1,
+        ProvidedPeer {
+            psk: Some(psk),
+            allowed_ips: vec![("192.168.1.0".parse().unwrap(), 24)],
+            persistent_keepalive_interval: None,
+        },
+    );
+    wg2.set_peer_provider(Some(provider));
+
+    let events1 = wg1.events.subscribe();
+    let events2 = wg2.events.subscribe();
+
+    let packet = make_packet(
+        100,
+        "192.168.1.20".parse().unwrap(),
+        "192.168.2.10".parse().unwrap(),
+        0,
+    );
+    fake1.write(packet);
+
+    match events2.recv_timeout(Duration::from_secs(10)) {
+        Ok(Event::PeerAdded { public_key }) => assert_eq!(public_key.as_bytes(), pk1.as_bytes()),
+        event => panic!("expected peer to be provisioned, got {:?}", event),
+    }
+
+    loop {
+        match events1.recv_timeout(Duration::from_secs(10)) {
+            Ok(Event::HandshakeCompleted { .. }) => break,
+            Ok(_) => (),
+            Err(e) => panic!("handshake did not complete: {:?}", e),
+        }
+    }
+
+    let peers2 = wg2.peers.read();
+    let peer1 = peers2.get(&pk1).unwrap();
+    assert_eq!(
+        peer1.list_allowed_ips(),
+        vec![("192.168.1.0".parse().unwrap(), 24)]
+    );
+}
+
+/* Test that provisioned peers are provisional:
+ *
+ * - Lookups of the peer provider are rate limited by source address
+ * - The peer is removed if the initiation is not accepted when processed again
+ */
+#[test]
+fn test_peer_provider_provisional() {
+    init();
+
+    let (_wg1, wg2, _fake1, _fake2, pk1, _pk2) = pair(false);
+    let provider = Arc::new(MemoryPeerProvider::new());
+    provider.insert(pk1, ProvidedPeer::default());
+    wg2.set_peer_provider(Some(provider));
+
+    // exhaust the lookups of the source with unknown keys
+    let src: SocketAddr = "192.0.2.1:51820".parse().unwrap();
+    for i in 0..PROVIDER_LOOKUPS_BURSTABLE {
+        assert!(!wg2.provision_peer(PublicKey::from([i as u8; 32]), &src.ip()));
+    }
+    assert!(!wg2.provision_peer(pk1, &src.ip()));
+
+    // another source is still served
+    let src: SocketAddr = "192.0.2.2:51820".parse().unwrap();
+    let events2 = wg2.events.subscribe();
+    assert!(wg2.provision_peer(pk1, &src.ip()));
+    assert!(wg2.peers.read().get(&pk1).is_some());
+
+    // an invalid initiation revokes the peer
+    let mut msg = vec![0u8; 148];
+    LittleEndian::write_u32(&mut msg[..], TYPE_INITIATION);
+    wg2.pending.fetch_add(1, Ordering::SeqCst);
+    wg2.queue.send(HandshakeJob::Message(
+        msg,
+        Endpoint::from_address(src),
+        Some(pk1),
+    ));
+    loop {
+        match events2.recv_timeout(Duration::from_secs(10)) {
+            Ok(Event::PeerRemoved { public_key }) => {
+                assert_eq!(public_key.as_bytes(), pk1.as_bytes());
+                break;
+            }
+            Ok(_) => (),
+            Err(e) => panic!("expected peer to be revoked: {:?}", e),
+        }
+    }
+    assert!(wg2.peers.read().get(&pk1).is_none());
+}
+
+/* Test that the state of an idle peer is evicted
+ * and rebuilt when the next packet is sent to the peer.
+ */
+#[test]
+fn test_idle_eviction() {
+    init();
+
+    let (wg1, _wg2, fake1, _fake2, _pk1, pk2) = pair(true);
+
+    let events1 = wg1.events.subscribe();
+    let handshake = || loop {
+        match events1.recv_timeout(Duration::from_secs(10)) {
+            Ok(Event::HandshakeCompleted { .. }) => break,
+            Ok(_) => (),
+            Err(e) => panic!("handshake did not complete: {:?}", e),
+        }
+    };
+
+    let packet = make_packet(
+        100,
+        "192.168.1.20".parse().unwrap(),
+        "192.168.2.10".parse().unwrap(),
+        0,
+    );
+    fake1.write(packet.clone());
+    handshake();
+
+    // the event precedes the installation of the keypair,
+    // also let the responder accept another initiation
+    while wg1
+        .peers
+        .read()
+        .get(&pk2)
+        .unwrap()
+        .current_keypair_age()
+        .is_none()
+    {
+        thread::sleep(Duration::from_millis(10));
+    }
+    thread::sleep(Duration::from_millis(100));
+
+    // the first scan observes the traffic, the second evicts the peer
+    assert_eq!(wg1.evict_idle_peers(Duration::from_secs(0)), 0);
+    assert_eq!(wg1.evict_idle_peers(Duration::from_secs(0)), 1);
+    assert_eq!(wg1.evict_idle_peers(Duration::from_secs(0)), 0);
+    loop {
+        match events1.recv_timeout(Duration::from_secs(10)) {
+            Ok(Event::PeerEvicted { public_key }) => {
+                assert_eq!(public_key.as_bytes(), pk2.as_bytes());
+                break;
+            }
+            Ok(_) => (),
+            Err(e) => panic!("peer was not evicted: {:?}", e),
+        }
+    }
+    {
+        let peers1 = wg1.peers.read();
+        let peer2 = peers1.get(&pk2).unwrap();
+        assert!(peer2.opaque().evicted.load(Ordering::SeqCst));
+        assert!(peer2.current_keypair_age().is_none());
+    }
+
+    // the next outbound packet rebuilds the state
+    fake1.write(packet);
+    handshake();
+    let peers1 = wg1.peers.read();
+    let peer2 = peers1.get(&pk2).unwrap();
+    assert!(!peer2.opaque().evicted.load(Ordering::SeqCst));
+}
+
+/* Test that a peer revived during its eviction (e.g. by a concurrent handshake)
+ * is not left evicted with its timers stopped.
+ */
+#[test]
+fn test_revive_during_eviction() {
+    init();
+
+    let (wg1, _wg2, _fake1, _fake2, _pk1, pk2) = pair(true);
+    let peers1 = wg1.peers.read();
+    let peer2 = peers1.get(&pk2).unwrap();
+    let inner = peer2.opaque();
+    assert!(inner.timers().enabled());
+
+    // the peer is revived after being marked evicted, but before its timers are stopped
+    assert!(inner.begin_eviction());
+    assert!(!inner.begin_eviction(), "already evicted");
+    inner.revive();
+    inner.stop_timers();
+    peer2.evict();
+    assert!(!inner.end_eviction(), "revived during the eviction");
+
+    // the state is rebuilt
+    assert!(!inner.evicted.load(Ordering::SeqCst));
+    assert!(inner.timers().enabled());
+
+    // an uninterrupted eviction completes
+    assert!(inner.begin_eviction());
+    inner.stop_timers();
+    peer2.evict();
+    assert!(inner.end_eviction());
+    assert!(inner.evicted.load(Ordering::SeqCst));
+    assert!(!inner.timers().enabled());
+}
+
+/* Test that handshake failures are attributed to the peer:
+ *
+ * - The peers are configured with mismatching preshared keys
+ * - The responder accepts the initiation
+ * - The initiator fails to decrypt the response,
+ *   which is recorded in the history of the peer and emitted as an event
+ */
+#[test]
+fn test_handshake_failure() {
+    init();
+
+    let (wg1, wg2, fake1, _fake2, pk1, pk2) = pair(true);
+    wg1.set_psk(pk2, [0x33; 32]);
+
+    let events1 = wg1.events.subscribe();
+    fake1.write(make_packet(
+        100,
+        "192.168.1.20".parse().unwrap(),
+        "192.168.2.10".parse().unwrap(),
+        0,
+    ));
+    loop {
+        match events1.recv_timeout(Duration::from_secs(10)) {
+            Ok(Event::HandshakeFailed(public_key, record)) => {
+                assert_eq!(public_key.as_bytes(), pk2.as_bytes());
+                assert_eq!(record.outcome, HandshakeOutcome::DecryptionFailure);
+                break;
+            }
+            Ok(Event::HandshakeCompleted { .. }) => panic!("handshake completed"),
+            Ok(_) => (),
+            Err(e) => panic!("handshake did not fail: {:?}", e),
+        }
+    }
+
+    // the failure is attributed to the peer, not the device
+    let peers1 = wg1.peers.read();
+    let records = peers1.get(&pk2).unwrap().opaque().handshakes.records();
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].outcome, HandshakeOutcome::DecryptionFailure);
+    assert!(wg1.handshake_failures.records().is_empty());
+
+    // the responder accepted the initiation
+    let peers2 = wg2.peers.read();
+    let records = peers2.get(&pk1).unwrap().opaque().handshakes.records();
+    assert_eq!(records[0].outcome, HandshakeOutcome::Accepted);
+}
+
+/* Test that the responder adds the keypair before sending the response:
+ * the initiator may confirm the keypair with a transport message as soon as the response arrives,
+ * which is dropped if received by the responder before the keypair is added.
+ *
+ * The initiator is emulated by a handshake device reading the response from the bind.
+ */
+#[test]
+fn test_keypair_before_response() {
+    init();
+
+    let (_fake2, tun_reader2, tun_writer2, _) = dummy::TunTest::create(true);
+    let wg2: TestWireGuard = WireGuard::new(tun_writer2);
+    wg2.up(1500);
+    wg2.add_tun_reader(tun_reader2);
+
+    let ((bind_reader1, bind_writer1), (bind_reader2, bind_writer2)) = dummy::PairBind::pair();
+    wg2.set_writer(bind_writer2);
+    wg2.add_udp_reader(bind_reader2);
+
+    let sk1 = StaticSecret::from([0x11; 32]);
+    let sk2 = StaticSecret::from([0x22; 32]);
+    let pk1 = PublicKey::from(&sk1);
+    let pk2 = PublicKey::from(&sk2);
+    wg2.add_peer(pk1);
+    wg2.set_key(Some(sk2));
+
+    let mut dev1 = handshake::Device::new();
+    dev1.set_sk(Some(sk1));
+    dev1.add(pk2, 0usize).unwrap();
+
+    for _ in 0..3 {
+        let msg = dev1.begin(&mut OsRng, &pk2).unwrap();
+        bind_writer1
+            .write(&msg[..], &mut dummy::UnitEndpoint::new())
+            .unwrap();
+
+        // the keypair is added by the time the response is received
+        let mut msg = vec![0u8; MAX_HANDSHAKE_MSG_SIZE];
+        let (len, _) = bind_reader1.read(&mut msg[..]).unwrap();
+        assert_eq!(LittleEndian::read_u32(&msg[..]), TYPE_RESPONSE);
+        let id = LittleEndian::read_u32(&msg[4..]);
+        assert!(wg2
+            .peers
+            .read()
+            .get(&pk1)
+            .unwrap()
+            .keypair_ids()
+            .contains(&id));
+
+        let (_, _, kp) = dev1.process(&mut OsRng, &msg[..len], None).unwrap();
+        dev1.release(kp.unwrap().local_id());
+
+        // avoid initiation flood detection
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+#[test]
+fn test_capture() {
+    init();
+
+    let (wg1, _wg2, fake1, _fake2, _pk1, pk2) = pair(true);
+
+    // capture the packets of the peer and log the secrets of the handshake
+    let capture = env::temp_dir().join(format!("wg-test-capture-{}.pcapng", process::id()));
+    let keylog = env::temp_dir().join(format!("wg-test-keylog-{}", process::id()));
+    let _ = fs::remove_file(&capture);
+    let _ = fs::remove_file(&keylog);
+    wg1.set_capture(Some(&capture)).unwrap();
+    wg1.set_keylog(Some(Arc::new(KeyLogFile::create(&keylog).unwrap())));
+    wg1.peers
+        .read()
+        .get(&pk2)
+        .unwrap()
+        .opaque()
+        .capture
+        .store(true, Ordering::SeqCst);
+
+    let events1 = wg1.events.subscribe();
+    fake1.write(make_packet(
+        100,
+        "192.168.1.20".parse().unwrap(),
+        "192.168.2.10".parse().unwrap(),
+        0,
+    ));
+    loop {
+        match events1.recv_timeout(Duration::from_secs(10)) {
+            Ok(Event::HandshakeCompleted { .. }) => break,
+            Ok(_) => (),
+            Err(e) => panic!("handshake did not complete: {:?}", e),
+        }
+    }
+
+    // wait for the staged packet to be sent
+    while wg1
+        .peers
+        .read()
+        .get(&pk2)
+        .unwrap()
+        .opaque()
+        .tx_packets
+        .load(Ordering::SeqCst)
+        == 0
+    {
+        thread::sleep(Duration::from_millis(10));
+    }
+    wg1.set_capture(None).unwrap();
+    wg1.set_keylog(None);
+
+    // count the enhanced packet blocks of every interface
+    let data = fs::read(&capture).unwrap();
+    let mut packets = [0, 0];
+    let mut offset = 0;
+    while offset < data.len() {
+        let len = LittleEndian::read_u32(&data[offset + 4..]) as usize;
+        if LittleEndian::read_u32(&data[offset..]) == 6 {
+            packets[LittleEndian::read_u32(&data[offset + 8..]) as usize] += 1;
+        }
+        offset += len;
+    }
+    assert_eq!(packets[0], 1, "plaintext packet");
+    assert_eq!(packets[1], 3, "initiation, response and transport message");
+
+    // the initiation is logged
+    let log = fs::read_to_string(&keylog).unwrap();
+    assert_eq!(log.matches("LOCAL_EPHEMERAL_PRIVATE_KEY = ").count(), 1);
+
+    fs::remove_file(&capture).unwrap();
+    fs::remove_file(&keylog).unwrap();
+}
+
+#[test]
+fn test_pcap_replay() {
+    init();
+
+    let (_wg1, wg2, fake1, fake2, pk1, _pk2) = pair(true);
+
+    // capture the traffic of the responder
+    let path = |name: &str| env::temp_dir().join(format!("wg-test-{}-{}", name, process::id()));
+    let _ = fs::remove_file(&path("replay-input"));
+    wg2.set_capture(Some(&path("replay-input"))).unwrap();
+    wg2.set_capture_all(true);
+    for id in 0..2 {
+        let packet = make_packet(
+            100,
+            "192.168.1.20".parse().unwrap(),
+            "192.168.2.10".parse().unwrap(),
+            id,
+        );
+        fake1.write(packet.clone());
+        assert_eq!(fake2.read(), packet);
+    }
+    wg2.set_capture(None).unwrap();
+
+    // replay the capture through a new responder (recording the output)
+    let (tun_reader3, tun_writer3, _) =
+        dummy::PcapTun::open(&path("replay-input"), &path("replay-tun"), false).unwrap();
+    let (bind_reader3, bind_writer3) =
+        dummy::PcapBind::open(&path("replay-input"), &path("replay-bind"), false).unwrap();
+    let wg3: WireGuard<dummy::PcapTun, dummy::PcapBind> = WireGuard::new(tun_writer3);
+    wg3.up(1500);
+    wg3.add_peer(pk1);
+    wg3.set_key(Some(StaticSecret::from([0x22; 32])));
+    wg3.set_writer(bind_writer3);
+    wg3.add_tun_reader(tun_reader3);
+    wg3.add_udp_reader(bind_reader3);
+
+    // the initiation is answered (sent to the source of the initiation)
+    let response = |record: &pcap::Record| match record.udp() {
+        Some((_, dst, payload)) => {
+            payload.len() >= 4
+                && LittleEndian::read_u32(payload) == 2
+                && dst.ip() == IpAddr::from([127, 0, 0, 1])
+        }
+        None => false,
+    };
+    let mut answered = false;
+    for _ in 0..500 {
+        answered = pcap::read(&path("replay-bind"))
+            .unwrap()
+            .iter()
+            .any(&response);
+        if answered {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(answered, "handshake response");
+
+    // the transport messages (under the previous keys) are dropped
+    assert!(pcap::read(&path("replay-bind"))
+        .unwrap()
+        .iter()
+        .all(response));
+    assert!(pcap::read(&path("replay-tun")).unwrap().is_empty());
+
+    for name in &["replay-input", "replay-tun", "replay-bind"] {
+        fs::remove_file(path(name)).unwrap();
+    }
+}
@@ -1,3 +1,4 @@
+use std::net::SocketAddr;
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, Instant, SystemTime};
@@ -8,12 +9,14 @@ use hjul::Timer;
 use x25519_dalek::PublicKey;
 
 use super::constants::*;
+use super::events::Event;
+use super::keys::Base64Key;
 use super::peer::PeerInner;
-use super::router::{message_data_len, Callbacks};
+use super::router::{message_data_len, Callbacks, Direction, Layer};
 use super::tun::Tun;
 use super::types::KeyPair;
 use super::udp::UDP;
-use super::WireGuard;
+use super::{Endpoint, WireGuard};
 
 pub struct Timers {
     // only updated during configuration
@@ -36,6 +39,11 @@ impl Timers {
     fn need_another_keepalive(&self) -> bool {
         self.need_another_keepalive.swap(false, Ordering::SeqCst)
     }
+
+    #[cfg(test)]
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
 }
 
 impl<T: Tun, B: UDP> PeerInner<T, B> {
@@ -43,6 +51,10 @@ impl<T: Tun, B: UDP> PeerInner<T, B> {
         self.timers().keepalive_interval
     }
 
+    pub fn get_handshake_attempts(&self) -> usize {
+        self.timers().handshake_attempts.load(Ordering::Relaxed)
+    }
+
     pub fn stop_timers(&self) {
         // take a write lock preventing simultaneous timer events or "start_timers" call
         let mut timers = self.timers_mut();
@@ -110,7 +122,7 @@ impl<T: Tun, B: UDP> PeerInner<T, B> {
      * - handshake
      */
     pub fn timers_any_authenticated_packet_sent(&self) {
-        log::trace!("timers_any_authenticated_packet_sent");
+        log::trace!("{} event=timers_any_authenticated_packet_sent", self);
         let timers = self.timers();
         if timers.enabled {
             timers.send_keepalive.stop()
@@ -123,7 +135,7 @@ impl<T: Tun, B: UDP> PeerInner<T, B> {
      * - handshake
      */
     pub fn timers_any_authenticated_packet_received(&self) {
-        log::trace!("timers_any_authenticated_packet_received");
+        log::trace!("{} event=timers_any_authenticated_packet_received", self);
         let timers = self.timers();
         if timers.enabled {
             timers.new_handshake.stop();
@@ -132,7 +144,7 @@ impl<T: Tun, B: UDP> PeerInner<T, B> {
 
     /* Should be called after a handshake initiation message is sent. */
     pub fn timers_handshake_initiated(&self) {
-        log::trace!("timers_handshake_initiated");
+        log::trace!("{} event=timers_handshake_initiated", self);
         let timers = self.timers();
         if timers.enabled {
             timers.send_keepalive.stop();
@@ -144,7 +156,7 @@ impl<T: Tun, B: UDP> PeerInner<T, B> {
      * or when getting key confirmation via the first data message.
      */
     pub fn timers_handshake_complete(&self) {
-        log::trace!("timers_handshake_complete");
+        log::trace!("{} event=timers_handshake_complete", self);
         let timers = self.timers();
         if timers.enabled {
             timers.retransmit_handshake.stop();
@@ -153,6 +165,9 @@ impl<T: Tun, B: UDP> PeerInner<T, B> {
                 .sent_lastminute_handshake
                 .store(false, Ordering::SeqCst);
             *self.walltime_last_handshake.lock() = Some(SystemTime::now());
+            self.wg.events.emit(Event::HandshakeCompleted {
+                public_key: self.pk,
+            });
         }
     }
 
@@ -160,7 +175,7 @@ impl<T: Tun, B: UDP> PeerInner<T, B> {
      * handshake response or after receiving a handshake response.
      */
     pub fn timers_session_derived(&self) {
-        log::trace!("timers_session_derived");
+        log::trace!("{} event=timers_session_derived", self);
         let timers = self.timers();
         if timers.enabled {
             timers.zero_key_material.reset(REJECT_AFTER_TIME * 3);
@@ -171,7 +186,7 @@ impl<T: Tun, B: UDP> PeerInner<T, B> {
      * keepalive, data, or handshake is sent, or after one is received.
      */
     pub fn timers_any_authenticated_packet_traversal(&self) {
-        log::trace!("timers_any_authenticated_packet_traversal");
+        log::trace!("{} event=timers_any_authenticated_packet_traversal", self);
         let timers = self.timers();
         if timers.enabled && timers.keepalive_interval > 0 {
             // push persistent_keepalive into the future
@@ -181,8 +196,20 @@ impl<T: Tun, B: UDP> PeerInner<T, B> {
         }
     }
 
+    /* Should be called when a handshake initiation is retransmitted
+     *
+     * Returns the next candidate endpoint after every ENDPOINT_FAILOVER_ATTEMPTS attempts,
+     * if the peer has alternative candidates to fail over to.
+     */
+    pub fn endpoint_failover(&self, attempts: usize) -> Option<SocketAddr> {
+        if attempts % ENDPOINT_FAILOVER_ATTEMPTS != 0 {
+            return None;
+        }
+        self.next_endpoint_candidate()
+    }
+
     fn timers_set_retransmit_handshake(&self) {
-        log::trace!("timers_set_retransmit_handshake");
+        log::trace!("{} event=timers_set_retransmit_handshake", self);
         let timers = self.timers();
         if timers.enabled {
             timers.retransmit_handshake.reset(REKEY_TIMEOUT);
@@ -206,6 +233,11 @@ impl<T: Tun, B: UDP> PeerInner<T, B> {
     }
 
     pub fn set_persistent_keepalive_interval(&self, secs: u64) {
+        // peers with persistent keepalive are never idle
+        if secs > 0 {
+            self.revive();
+        }
+
         let mut timers = self.timers_mut();
 
         // update the stored keepalive_interval
@@ -278,8 +310,8 @@ impl Timers {
                     let attempts = timers.handshake_attempts.fetch_add(1, Ordering::SeqCst);
                     if attempts > MAX_TIMER_HANDSHAKES {
                         debug!(
-                            "Handshake for peer {} did not complete after {} attempts, giving up",
-                            peer,
+                            "{} attempts={} event=handshake_abandoned",
+                            peer.opaque(),
                             attempts + 1
                         );
                         timers.send_keepalive.stop();
@@ -287,13 +319,23 @@ impl Timers {
                         peer.purge_staged_packets();
                     } else {
                         debug!(
-                            "Handshake for {} did not complete after {} seconds, retrying (try {})",
-                            peer,
-                            REKEY_TIMEOUT.as_secs(),
-                            attempts
+                            "{} attempts={} event=handshake_retransmitted",
+                            peer.opaque(),
+                            attempts + 1
                         );
                         timers.retransmit_handshake.reset(REKEY_TIMEOUT);
                         peer.clear_src();
+
+                        // fail over to the next candidate endpoint
+                        if let Some(addr) = peer.endpoint_failover(attempts + 1) {
+                            debug!(
+                                "{} endpoint={} attempts={} event=endpoint_failover",
+                                peer.opaque(),
+                                addr,
+                                attempts + 1
+                            );
+                            peer.set_endpoint(B::Endpoint::from_address(addr));
+                        }
                         peer.packet_send_queued_handshake_initiation(true);
                     }
                 })
@@ -323,8 +365,8 @@ impl Timers {
 
                     // clear source and retry
                     log::debug!(
-                        "Retrying handshake with {} because we stopped hearing back after {} seconds",
-                        peer,
+                        "{} timeout={} event=handshake_restarted",
+                        peer.opaque(),
                         (KEEPALIVE_TIMEOUT + REKEY_TIMEOUT).as_secs()
                     );
                     peer.clear_src();
@@ -337,7 +379,7 @@ impl Timers {
                 runner.timer(move || {
                     // fetch peer by public key
                     fetch_peer!(wg, pk, peer);
-                    log::trace!("{} : timer fired (zero_key_material)", peer);
+                    log::trace!("{} event=timer_zero_key_material", peer);
 
                     // null all key-material
                     peer.zero_keys();
@@ -350,13 +392,13 @@ impl Timers {
                     // fetch peer by public key
                     fetch_peer!(wg, pk, peer);
                     fetch_timers!(peer, timers);
-                    log::trace!("{} : timer fired (send_persistent_keepalive)", peer);
+                    log::trace!("{} event=timer_send_persistent_keepalive", peer);
 
                     // send and schedule persistent keepalive
                     if timers.keepalive_interval > 0 {
                         timers.send_keepalive.stop();
                         peer.send_keepalive();
-                        log::trace!("{} : keepalive queued", peer);
+                        log::trace!("{} event=keepalive_queued", peer);
                         timers
                             .send_persistent_keepalive
                             .start(Duration::from_secs(timers.keepalive_interval));
@@ -375,13 +417,14 @@ impl<T: Tun, B: UDP> Callbacks for PeerInner<T, B> {
      */
     #[inline(always)]
     fn send(peer: &Self::Opaque, size: usize, sent: bool, keypair: &Arc<KeyPair>, counter: u64) {
-        log::trace!("{} : EVENT(send)", peer);
+        log::trace!("{} key={} event=send", peer, keypair.local_id());
 
         // update timers and stats
 
         peer.timers_any_authenticated_packet_traversal();
         peer.timers_any_authenticated_packet_sent();
         peer.tx_bytes.fetch_add(size as u64, Ordering::Relaxed);
+        peer.tx_packets.fetch_add(1, Ordering::Relaxed);
         if size > message_data_len(0) && sent {
             peer.timers_data_sent();
         }
@@ -407,13 +450,14 @@ impl<T: Tun, B: UDP> Callbacks for PeerInner<T, B> {
      */
     #[inline(always)]
     fn recv(peer: &Self::Opaque, size: usize, sent: bool, keypair: &Arc<KeyPair>) {
-        log::trace!("{} : EVENT(recv)", peer);
+        log::trace!("{} key={} event=recv", peer, keypair.local_id());
 
         // update timers and stats
 
         peer.timers_any_authenticated_packet_traversal();
         peer.timers_any_authenticated_packet_received();
         peer.rx_bytes.fetch_add(size as u64, Ordering::Relaxed);
+        peer.rx_packets.fetch_add(1, Ordering::Relaxed);
         if size > 0 && sent {
             peer.timers_data_received();
         }
@@ -443,13 +487,46 @@ impl<T: Tun, B: UDP> Callbacks for PeerInner<T, B> {
      */
     #[inline(always)]
     fn need_key(peer: &Self::Opaque) {
-        log::trace!("{} : EVENT(need_key)", peer);
+        log::trace!("{} event=need_key", peer);
+        peer.revive();
         peer.packet_send_queued_handshake_initiation(false);
     }
 
     #[inline(always)]
     fn key_confirmed(peer: &Self::Opaque) {
-        log::trace!("{} : EVENT(key_confirmed)", peer);
+        log::trace!("{} event=key_confirmed", peer);
         peer.timers_handshake_complete();
     }
+
+    /* Called when a packet from the peer is destined for the allowed IPs of another peer
+     * and the device relays between peers.
+     */
+    #[inline(always)]
+    fn relay(src: &Self::Opaque, dst: &Self::Opaque) -> bool {
+        log::trace!("{} dst_peer={} event=relay", src, Base64Key::from(&dst.pk));
+        src.relay_acl.lock().allows(&dst.pk)
+    }
+
+    /* Called for every packet exchanged with the peer:
+     * plaintext IP packets and transport messages (annotated with the receiver index).
+     */
+    #[inline(always)]
+    fn capturing(peer: &Self::Opaque) -> bool {
+        peer.wg.capturing(peer)
+    }
+
+    fn capture(
+        peer: &Self::Opaque,
+        layer: Layer,
+        direction: Direction,
+        packet: &[u8],
+        keypair: &Arc<KeyPair>,
+    ) {
+        let id = match direction {
+            Direction::In => keypair.recv.id,
+            _ => keypair.send.id,
+        };
+        peer.wg
+            .capture(Some(peer), layer, direction, packet, Some(id));
+    }
 }
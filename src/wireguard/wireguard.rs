@@ -1,7 +1,12 @@
+use super::capture::{Capture, KeyLog};
 use super::constants::*;
+use super::diagnostics::{HandshakeHistory, HandshakeOutcome};
+use super::events::{Event, Events};
 use super::handshake;
-use super::peer::PeerInner;
-use super::router;
+use super::load::{Load, LoadPolicy, LoadStats};
+use super::peer::{PeerInner, RelayAcl};
+use super::provider::{PeerProvider, PskProvider};
+use super::router::{self, Direction, Layer};
 use super::timers::Timers;
 
 use super::queue::ParallelQueue;
@@ -13,13 +18,16 @@ use super::udp::UDP;
 use super::workers::{handshake_worker, tun_worker, udp_worker};
 
 use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
 use std::ops::Deref;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::sync::Condvar;
 use std::sync::Mutex as StdMutex;
 use std::thread;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use hjul::Runner;
 use rand::rngs::OsRng;
@@ -53,9 +61,28 @@ pub struct WireguardInner<T: Tun, B: UDP> {
     pub router: router::Device<B::Endpoint, PeerInner<T, B>, T::Writer, B::Writer>,
 
     // handshake related state
-    pub last_under_load: Mutex<Instant>,
+    pub load: Load,
     pub pending: AtomicUsize, // number of pending handshake packets in queue
     pub queue: ParallelQueue<HandshakeJob<B::Endpoint>>,
+
+    // subscribers to device events
+    pub events: Events,
+
+    // source of peers unknown to the device
+    pub provider: RwLock<Option<Arc<dyn PeerProvider>>>,
+    pub provider_limiter: handshake::RateLimiter,
+
+    // idle peer eviction: timeout (None if disabled) and is the scanning thread running?
+    pub idle_timeout: Mutex<Option<Duration>>,
+    pub evictor_running: AtomicBool,
+
+    // packet capture (None if disabled), is a capture open? and are all peers captured?
+    pub capture: RwLock<Option<Arc<Capture>>>,
+    pub capturing: AtomicBool,
+    pub capture_all: AtomicBool,
+
+    // recent handshake failures not attributed to any peer
+    pub handshake_failures: HandshakeHistory,
 }
 
 pub struct WireGuard<T: Tun, B: UDP> {
@@ -66,7 +93,7 @@ pub struct WaitCounter(StdMutex<usize>, Condvar);
 
 impl<T: Tun, B: UDP> fmt::Display for WireGuard<T, B> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "wireguard({:x})", self.id)
+        write!(f, "device={:x}", self.id)
     }
 }
 
@@ -143,6 +170,7 @@ impl<T: Tun, B: UDP> WireGuard<T, B> {
         }
 
         *enabled = false;
+        self.events.emit(Event::DeviceDown);
     }
 
     /// Brings the WireGuard device up.
@@ -169,14 +197,21 @@ impl<T: Tun, B: UDP> WireGuard<T, B> {
         }
 
         *enabled = true;
+        self.events.emit(Event::DeviceUp { mtu });
     }
 
     pub fn clear_peers(&self) {
-        self.peers.write().clear();
+        let mut peers = self.peers.write();
+        for (public_key, _) in peers.iter() {
+            self.events.emit(Event::PeerRemoved { public_key });
+        }
+        peers.clear();
     }
 
     pub fn remove_peer(&self, pk: &PublicKey) {
-        let _ = self.peers.write().remove(pk);
+        if self.peers.write().remove(pk).is_ok() {
+            self.events.emit(Event::PeerRemoved { public_key: *pk });
+        }
     }
 
     pub fn set_key(&self, sk: Option<StaticSecret>) {
@@ -185,11 +220,65 @@ impl<T: Tun, B: UDP> WireGuard<T, B> {
         self.router.clear_sending_keys();
     }
 
+    /// Replace the private key with a (possibly external) key backend
+    ///
+    /// # Arguments
+    ///
+    /// - `key`: The new private key (or None, if the private key should be cleared)
+    pub fn set_static_key(&self, key: Option<Box<dyn handshake::StaticKey>>) {
+        let mut peers = self.peers.write();
+        peers.set_key(key);
+        self.router.clear_sending_keys();
+    }
+
+    /// Rotate the private key without dropping established sessions:
+    /// current sessions are used until their natural rekey,
+    /// new handshakes use the new key and initiations to the previous public key
+    /// are accepted until the end of the grace window
+    /// (the previous key is then ignored, and erased by the next change of the private key).
+    ///
+    /// # Arguments
+    ///
+    /// - `sk`: The new private key
+    /// - `grace`: Duration for which initiations to the previous public key are accepted
+    pub fn rotate_key(&self, sk: StaticSecret, grace: Duration) {
+        self.peers.write().rotate_sk(sk, grace);
+    }
+
+    /// Rotate the private key to a (possibly external) key backend (see "rotate_key")
+    ///
+    /// # Arguments
+    ///
+    /// - `key`: The new private key
+    /// - `grace`: Duration for which initiations to the previous public key are accepted
+    pub fn rotate_static_key(&self, key: Box<dyn handshake::StaticKey>, grace: Duration) {
+        self.peers.write().rotate_key(key, grace);
+    }
+
+    /// Returns the previous public key, if still accepted after a key rotation
+    pub fn get_previous_pk(&self) -> Option<PublicKey> {
+        self.peers.read().get_previous_pk()
+    }
+
+    /// Returns (a copy of) the private key, if the key backend permits exporting it
     pub fn get_sk(&self) -> Option<StaticSecret> {
-        self.peers
-            .read()
-            .get_sk()
-            .map(|sk| StaticSecret::from(sk.to_bytes()))
+        self.peers.read().get_sk()
+    }
+
+    pub fn get_pk(&self) -> Option<PublicKey> {
+        self.peers.read().get_pk()
+    }
+
+    pub fn set_load_policy(&self, policy: LoadPolicy) {
+        self.load.set_policy(policy)
+    }
+
+    pub fn get_load_policy(&self) -> LoadPolicy {
+        self.load.get_policy()
+    }
+
+    pub fn get_load_stats(&self) -> LoadStats {
+        self.load.stats(&self.events)
     }
 
     pub fn set_psk(&self, pk: PublicKey, psk: [u8; 32]) -> bool {
@@ -214,7 +303,6 @@ impl<T: Tun, B: UDP> WireGuard<T, B> {
         // create new router peer
         let peer: router::PeerHandle<B::Endpoint, PeerInner<T, B>, T::Writer, B::Writer> =
             self.router.new_peer(PeerInner {
-                id: OsRng.gen(),
                 pk,
                 wg: self.clone(),
                 walltime_last_handshake: Mutex::new(None),
@@ -222,11 +310,287 @@ impl<T: Tun, B: UDP> WireGuard<T, B> {
                 handshake_queued: AtomicBool::new(false),
                 rx_bytes: AtomicU64::new(0),
                 tx_bytes: AtomicU64::new(0),
+                rx_packets: AtomicU64::new(0),
+                tx_packets: AtomicU64::new(0),
+                endpoints: Mutex::new((vec![], 0)),
+                relay_acl: Mutex::new(RelayAcl::Peers(vec![])),
+                evicted: AtomicBool::new(false),
+                capture: AtomicBool::new(false),
+                handshakes: HandshakeHistory::new(),
+                activity: Mutex::new((0, Instant::now())),
                 timers: RwLock::new(timers),
             });
 
         // finally, add the peer to the handshake device
-        peers.add(pk, peer).is_ok()
+        let added = peers.add(pk, peer).is_ok();
+        if added {
+            self.events.emit(Event::PeerAdded { public_key: pk });
+        }
+        added
+    }
+
+    pub fn set_peer_provider(&self, provider: Option<Arc<dyn PeerProvider>>) {
+        *self.provider.write() = provider;
+    }
+
+    pub fn set_psk_provider(&self, provider: Option<Arc<dyn PskProvider>>) {
+        self.peers.write().set_psk_provider(provider);
+    }
+
+    /// Returns the epoch of the psk used by the latest handshake with the peer
+    /// (None for the static psk) and the duration since the psk was first used (or set)
+    pub fn get_psk_age(&self, pk: &PublicKey) -> Option<(Option<u64>, Duration)> {
+        self.peers.read().get_psk_age(pk).ok()
+    }
+
+    /// Starts or stops the capture of packets
+    ///
+    /// # Arguments
+    ///
+    /// - `path`: The pcapng file (created, must not exist), None to stop capturing
+    pub fn set_capture(&self, path: Option<&Path>) -> io::Result<()> {
+        let capture = match path {
+            Some(path) => Some(Arc::new(Capture::create(path)?)),
+            None => None,
+        };
+        let mut current = self.capture.write();
+        self.capturing.store(capture.is_some(), Ordering::Relaxed);
+        *current = capture;
+        Ok(())
+    }
+
+    pub fn get_capture_path(&self) -> Option<PathBuf> {
+        self.capture.read().as_ref().map(|c| c.path().to_owned())
+    }
+
+    /// Selects whether the packets of every peer are captured,
+    /// otherwise only the packets of peers with capture enabled (and unauthenticated messages are not).
+    pub fn set_capture_all(&self, all: bool) {
+        self.capture_all.store(all, Ordering::Relaxed);
+    }
+
+    pub fn get_capture_all(&self) -> bool {
+        self.capture_all.load(Ordering::Relaxed)
+    }
+
+    /// Returns whether the packets of the peer are captured
+    /// (without locking: checked on the data path before preparing a packet for capture)
+    pub fn capturing(&self, peer: &PeerInner<T, B>) -> bool {
+        self.capturing.load(Ordering::Relaxed)
+            && (self.capture_all.load(Ordering::Relaxed) || peer.capture.load(Ordering::Relaxed))
+    }
+
+    /// Appends a packet to the capture (if capturing the peer)
+    ///
+    /// # Arguments
+    ///
+    /// - `peer`: The peer exchanging the packet, None if unknown
+    /// - `layer`: Plaintext IP packet or WireGuard message
+    /// - `direction`: Received from or sent to the peer
+    /// - `packet`: The packet
+    /// - `id`: The receiver index of a transport message
+    pub fn capture(
+        &self,
+        peer: Option<&PeerInner<T, B>>,
+        layer: Layer,
+        direction: Direction,
+        packet: &[u8],
+        id: Option<u32>,
+    ) {
+        let capture = match self.capture.read().as_ref() {
+            Some(capture) => capture.clone(),
+            None => return,
+        };
+        let selected = self.capture_all.load(Ordering::Relaxed)
+            || peer
+                .map(|peer| peer.capture.load(Ordering::Relaxed))
+                .unwrap_or(false);
+        if selected {
+            capture.write(layer, direction, peer.map(|peer| &peer.pk), id, packet);
+        }
+    }
+
+    /// Records the outcome of processing a handshake message,
+    /// failures are logged and those attributed to a peer are emitted as events
+    /// (unattributed messages are unauthenticated and may be sent by anyone)
+    ///
+    /// # Arguments
+    ///
+    /// - `peer`: The peer the message was attributed to, None if unknown
+    /// - `endpoint`: The source of the message
+    /// - `outcome`: The outcome of processing the message
+    pub fn handshake_outcome(
+        &self,
+        peer: Option<&PeerInner<T, B>>,
+        endpoint: SocketAddr,
+        outcome: HandshakeOutcome,
+    ) {
+        match peer {
+            Some(peer) => {
+                let record = peer.handshakes.push(endpoint, outcome);
+                if outcome.is_failure() {
+                    log::debug!(
+                        "{} endpoint={} error={} event=handshake_failed",
+                        peer,
+                        endpoint,
+                        outcome
+                    );
+                    self.events.emit(Event::HandshakeFailed(peer.pk, record));
+                }
+            }
+            None if outcome.is_failure() => {
+                self.handshake_failures.push(endpoint, outcome);
+                log::debug!(
+                    "endpoint={} error={} event=handshake_failed",
+                    endpoint,
+                    outcome
+                );
+            }
+            None => (),
+        }
+    }
+
+    /// Sets the receiver of the secrets of every handshake
+    /// (not logged while the private key cannot be exported)
+    pub fn set_keylog(&self, keylog: Option<Arc<dyn KeyLog>>) {
+        self.peers.write().set_keylog(keylog);
+    }
+
+    /// Consults the peer provider (if any) about an unknown public key,
+    /// provisionally adding the peer to the device if the provider supplies a configuration.
+    ///
+    /// The public key has not yet been authenticated:
+    /// the caller must process the initiation again and remove the peer
+    /// unless the initiation is accepted.
+    ///
+    /// # Arguments
+    ///
+    /// - `pk`: The unknown public key
+    /// - `src`: The source address of the initiation (lookups are rate limited by source)
+    ///
+    /// # Returns
+    ///
+    /// A bool indicating if the peer was added
+    pub fn provision_peer(&self, pk: PublicKey, src: &IpAddr) -> bool {
+        let provider = match self.provider.read().clone() {
+            Some(provider) => provider,
+            None => return false,
+        };
+
+        if !self.provider_limiter.allow(src) {
+            log::debug!("endpoint={} event=peer_provider_rate_limited", src);
+            return false;
+        }
+
+        let config = match provider.provide(&pk) {
+            Some(config) => config,
+            None => return false,
+        };
+
+        if !self.add_peer(pk) {
+            return false;
+        }
+
+        if let Some(psk) = config.psk {
+            self.set_psk(pk, psk);
+        }
+
+        if let Some(peer) = self.peers.read().get(&pk) {
+            for (ip, masklen) in config.allowed_ips {
+                peer.add_allowed_ip(ip, masklen);
+            }
+            if let Some(secs) = config.persistent_keepalive_interval {
+                peer.opaque().set_persistent_keepalive_interval(secs);
+            }
+        }
+        true
+    }
+
+    /// Enable or disable eviction of idle peers
+    ///
+    /// # Arguments
+    ///
+    /// - `timeout`: Peers without traffic for this duration have their runtime state
+    ///   (keys, handshake state, timers and queues) evicted, None to disable eviction.
+    pub fn set_idle_timeout(&self, timeout: Option<Duration>) {
+        let mut idle_timeout = self.idle_timeout.lock();
+        *idle_timeout = timeout;
+
+        // start scanning thread (exits when disabled or the device is dropped)
+        if timeout.is_some() && !self.evictor_running.swap(true, Ordering::SeqCst) {
+            let wg = Arc::downgrade(&self.inner);
+            thread::spawn(move || loop {
+                thread::sleep(IDLE_EVICTION_INTERVAL);
+                let wg = match wg.upgrade() {
+                    Some(inner) => WireGuard { inner },
+                    None => return,
+                };
+                let timeout = {
+                    let timeout = wg.idle_timeout.lock();
+                    match *timeout {
+                        Some(timeout) => timeout,
+                        None => {
+                            wg.evictor_running.store(false, Ordering::SeqCst);
+                            return;
+                        }
+                    }
+                };
+                wg.evict_idle_peers(timeout);
+            });
+        }
+    }
+
+    pub fn get_idle_timeout(&self) -> Option<Duration> {
+        *self.idle_timeout.lock()
+    }
+
+    /// Evict the runtime state of peers without traffic for longer than the timeout,
+    /// their configuration is retained and the state is rebuilt on demand.
+    ///
+    /// Note that traffic is detected by comparing counters between calls.
+    ///
+    /// # Returns
+    ///
+    /// The number of evicted peers
+    pub fn evict_idle_peers(&self, timeout: Duration) -> usize {
+        let peers = self.peers.read();
+        let mut evicted = 0;
+        for (pk, peer) in peers.iter() {
+            let inner = peer.opaque();
+            if inner.evicted.load(Ordering::SeqCst) || inner.get_keepalive_interval() > 0 {
+                continue;
+            }
+
+            // check for traffic since the last scan
+            {
+                let traffic = inner.traffic();
+                let mut activity = inner.activity.lock();
+                if activity.0 != traffic {
+                    *activity = (traffic, Instant::now());
+                    continue;
+                }
+                if activity.1.elapsed() < timeout {
+                    continue;
+                }
+            }
+
+            // mark evicted first: a concurrent handshake revives the peer
+            if !inner.begin_eviction() {
+                continue;
+            }
+            inner.stop_timers();
+            peer.evict();
+            peers.evict(&pk);
+            if !inner.end_eviction() {
+                log::debug!("{} event=peer_eviction_aborted", inner);
+                continue;
+            }
+
+            log::debug!("{} event=peer_evicted", inner);
+            self.events.emit(Event::PeerEvicted { public_key: pk });
+            evicted += 1;
+        }
+        evicted
     }
 
     /// Begin consuming messages from the reader.
@@ -273,6 +637,14 @@ impl<T: Tun, B: UDP> WireGuard<T, B> {
         let router: router::Device<B::Endpoint, PeerInner<T, B>, T::Writer, B::Writer> =
             router::Device::new(num_cpus::get(), writer);
 
+        // create rate limiter of peer provider lookups
+        let provider_limiter = handshake::RateLimiter::new();
+        provider_limiter.configure(handshake::RateLimiterConfig {
+            packets_per_second: PROVIDER_LOOKUPS_PER_SECOND,
+            packets_burstable: PROVIDER_LOOKUPS_BURSTABLE,
+            ..Default::default()
+        });
+
         // create arc to state
         let wg = WireGuard {
             inner: Arc::new(WireguardInner {
@@ -280,12 +652,21 @@ impl<T: Tun, B: UDP> WireGuard<T, B> {
                 tun_readers: WaitCounter::new(),
                 id: OsRng.gen(),
                 mtu: AtomicUsize::new(0),
-                last_under_load: Mutex::new(Instant::now() - TIME_HORIZON),
+                load: Load::new(),
                 router,
                 pending: AtomicUsize::new(0),
                 peers: RwLock::new(handshake::Device::new()),
                 runner: Mutex::new(Runner::new(TIMERS_TICK, TIMERS_SLOTS, TIMERS_CAPACITY)),
                 queue: tx,
+                events: Events::new(),
+                provider: RwLock::new(None),
+                provider_limiter,
+                idle_timeout: Mutex::new(None),
+                evictor_running: AtomicBool::new(false),
+                capture: RwLock::new(None),
+                capturing: AtomicBool::new(false),
+                handshake_failures: HandshakeHistory::new(),
+                capture_all: AtomicBool::new(false),
             }),
         };
 
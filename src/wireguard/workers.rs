@@ -1,5 +1,5 @@
+use std::cell::Cell;
 use std::sync::atomic::Ordering;
-use std::time::Instant;
 
 use byteorder::{ByteOrder, LittleEndian};
 use crossbeam_channel::Receiver;
@@ -17,18 +17,20 @@ use super::udp::Reader as UDPReader;
 use super::udp::UDP;
 
 // constants
-use super::constants::{
-    DURATION_UNDER_LOAD, MAX_QUEUED_INCOMING_HANDSHAKES, MESSAGE_PADDING_MULTIPLE,
-    THRESHOLD_UNDER_LOAD,
-};
+use super::constants::MESSAGE_PADDING_MULTIPLE;
+use super::diagnostics::HandshakeOutcome;
+use super::handshake::HandshakeError;
 use super::handshake::MAX_HANDSHAKE_MSG_SIZE;
 use super::handshake::{TYPE_COOKIE_REPLY, TYPE_INITIATION, TYPE_RESPONSE};
+use super::peer::PeerInner;
+use super::router::{Direction, Layer, PeerHandle};
 use super::router::{CAPACITY_MESSAGE_POSTFIX, SIZE_MESSAGE_PREFIX, TYPE_TRANSPORT};
 
+use super::types::KeyPair;
 use super::wireguard::WireGuard;
 
 pub enum HandshakeJob<E> {
-    Message(Vec<u8>, E),
+    Message(Vec<u8>, E, Option<PublicKey>), // the peer provisioned for the message (if retried)
     New(PublicKey),
 }
 
@@ -65,11 +67,11 @@ pub fn tun_worker<T: Tun, B: UDP>(wg: &WireGuard<T, B>, reader: T::Reader) {
         let payload = match reader.read(&mut msg[..], SIZE_MESSAGE_PREFIX) {
             Ok(payload) => payload,
             Err(e) => {
-                debug!("TUN worker, failed to read from tun device: {}", e);
+                debug!("{} error={} event=tun_read_failed", wg, e);
                 break;
             }
         };
-        debug!("TUN worker, IP packet of {} bytes (MTU = {})", payload, mtu);
+        debug!("{} size={} mtu={} event=tun_read", wg, payload, mtu);
 
         // check if device is down
         if mtu == 0 {
@@ -78,11 +80,7 @@ pub fn tun_worker<T: Tun, B: UDP>(wg: &WireGuard<T, B>, reader: T::Reader) {
 
         // truncate padding
         let padded = padding(payload, mtu);
-        log::trace!(
-            "TUN worker, payload length = {}, padded length = {}",
-            payload,
-            padded
-        );
+        log::trace!("{} size={} padded={} event=padded", wg, payload, padded);
         msg.truncate(SIZE_MESSAGE_PREFIX + padded);
         debug_assert!(padded <= mtu);
         debug_assert_eq!(
@@ -95,8 +93,9 @@ pub fn tun_worker<T: Tun, B: UDP>(wg: &WireGuard<T, B>, reader: T::Reader) {
         );
 
         // crypt-key route
-        let e = wg.router.send(msg);
-        debug!("TUN worker, router returned {:?}", e);
+        let _ = wg.router.send(msg).map_err(|e| {
+            debug!("{} error={} event=route_failed", wg, e);
+        });
     }
 }
 
@@ -110,7 +109,7 @@ pub fn udp_worker<T: Tun, B: UDP>(wg: &WireGuard<T, B>, reader: B::Reader) {
         // read UDP packet into vector
         let (size, src) = match reader.read(&mut msg) {
             Err(e) => {
-                debug!("Bind reader closed with {}", e);
+                debug!("{} error={} event=udp_reader_closed", wg, e);
                 return;
             }
             Ok(v) => v,
@@ -128,16 +127,26 @@ pub fn udp_worker<T: Tun, B: UDP>(wg: &WireGuard<T, B>, reader: B::Reader) {
         }
         match LittleEndian::read_u32(&msg[..]) {
             TYPE_COOKIE_REPLY | TYPE_INITIATION | TYPE_RESPONSE => {
-                debug!("{} : reader, received handshake message", wg);
-                wg.pending.fetch_add(1, Ordering::SeqCst);
-                wg.queue.send(HandshakeJob::Message(msg, src));
+                debug!(
+                    "{} endpoint={} event=handshake_received",
+                    wg,
+                    src.into_address()
+                );
+                if !wg.load.admit(wg.pending.fetch_add(1, Ordering::SeqCst)) {
+                    debug!(
+                        "{} endpoint={} event=handshake_dropped",
+                        wg,
+                        src.into_address()
+                    );
+                    wg.pending.fetch_sub(1, Ordering::SeqCst);
+                    continue;
+                }
+                wg.queue.send(HandshakeJob::Message(msg, src, None));
             }
             TYPE_TRANSPORT => {
-                debug!("{} : reader, received transport message", wg);
-
                 // transport message
                 let _ = wg.router.recv(src, msg).map_err(|e| {
-                    debug!("Failed to handle incoming transport message: {}", e);
+                    debug!("{} error={} event=transport_rejected", wg, e);
                 });
             }
             _ => (),
@@ -149,38 +158,40 @@ pub fn handshake_worker<T: Tun, B: UDP>(
     wg: &WireGuard<T, B>,
     rx: Receiver<HandshakeJob<B::Endpoint>>,
 ) {
-    debug!("{} : handshake worker, started", wg);
+    debug!("{} event=handshake_worker_started", wg);
 
     // process elements from the handshake queue
     for job in rx {
         // check if under load
-        let mut under_load = false;
         let job: HandshakeJob<B::Endpoint> = job;
         let pending = wg.pending.fetch_sub(1, Ordering::SeqCst);
-        debug_assert!(pending < MAX_QUEUED_INCOMING_HANDSHAKES + (1 << 16));
-
-        // immediate go under load if too many handshakes pending
-        if pending > THRESHOLD_UNDER_LOAD {
-            log::trace!("{} : handshake worker, under load (above threshold)", wg);
-            *wg.last_under_load.lock() = Instant::now();
-            under_load = true;
-        }
-
-        // remain under load for DURATION_UNDER_LOAD
-        if !under_load {
-            let elapsed = wg.last_under_load.lock().elapsed();
-            if DURATION_UNDER_LOAD >= elapsed {
-                log::trace!("{} : handshake worker, under load (recent)", wg);
-                under_load = true;
-            }
+        let under_load = wg.load.check(pending, &wg.events);
+        if under_load {
+            log::trace!("{} event=handshake_under_load", wg);
         }
 
         // de-multiplex staged handshake jobs and handshake messages
         match job {
-            HandshakeJob::Message(msg, mut src) => {
+            HandshakeJob::Message(msg, mut src, provisioned) => {
                 // process message
+                let mut unknown = None;
+                let mut accepted = false;
                 let device = wg.peers.read();
-                match device.process(
+
+                // check the source against the roaming policy of the peer
+                // (before the handshake state is updated)
+                let roam = Cell::new(false);
+                let admit =
+                    |peer: &PeerHandle<B::Endpoint, PeerInner<T, B>, T::Writer, B::Writer>| {
+                        match peer.check_source(&src) {
+                            Some(allowed) => {
+                                roam.set(allowed);
+                                true
+                            }
+                            None => false,
+                        }
+                    };
+                let result = device.process_attributed(
                     &mut OsRng,
                     &msg[..],
                     if under_load {
@@ -188,24 +199,96 @@ pub fn handshake_worker<T: Tun, B: UDP>(
                     } else {
                         None
                     },
-                ) {
-                    Ok((peer, resp, keypair)) => {
+                    &admit,
+                );
+
+                // capture the message (attributed to the peer if authenticated)
+                let addr = src.into_address();
+                let endpoint = Layer::Outer(Some(addr));
+                let opaque = match result {
+                    Ok(((Some(peer), _, _), _)) => Some(peer.opaque()),
+                    _ => None,
+                };
+                wg.capture(opaque, endpoint, Direction::In, &msg[..], None);
+
+                // record the outcome (a cookie reply received is attributed, but not authenticated)
+                match result {
+                    Ok(((Some(peer), _, _), _)) => {
+                        wg.handshake_outcome(Some(peer.opaque()), addr, HandshakeOutcome::Accepted)
+                    }
+                    Ok(((None, None, None), Some(peer))) => wg.handshake_outcome(
+                        Some(peer.opaque()),
+                        addr,
+                        HandshakeOutcome::CookieReceived,
+                    ),
+                    Ok(_) => (),
+                    // recorded unless provisioned by the peer provider (below)
+                    Err((HandshakeError::UnknownPublicKey(_), _)) => (),
+                    Err((ref e, peer)) => {
+                        wg.handshake_outcome(peer.map(|peer| peer.opaque()), addr, e.into())
+                    }
+                }
+
+                match result {
+                    Ok(((peer, resp, keypair), _)) => {
+                        let add_keypair = |kp: KeyPair| {
+                            if let Some(peer) = peer {
+                                debug!(
+                                    "{} key={} event=keypair_created",
+                                    peer.opaque(),
+                                    kp.local_id()
+                                );
+
+                                // this means that a handshake response was processed or is about to be sent
+                                peer.opaque().timers_session_derived();
+
+                                // free any unused ids
+                                for id in peer.add_keypair(kp) {
+                                    device.release(id);
+                                }
+                            }
+                        };
+
+                        // add the (unconfirmed) keypair of the responder before sending the response:
+                        // the initiator confirms the keypair with a transport message as soon as the response arrives,
+                        // which is dropped by the router if received before the keypair is added.
+                        // Should sending the response fail, the keypair is never confirmed (nor used to send):
+                        // it is replaced (and its id released) by the keypair of the retransmitted initiation.
+                        // The keypair of the initiator is added last:
+                        // adding it sends staged packets (or a keepalive) to the endpoint updated below.
+                        let keypair = match (peer, keypair) {
+                            (Some(peer), Some(kp)) if !kp.initiator => {
+                                peer.opaque().revive();
+                                add_keypair(kp);
+                                None
+                            }
+                            (_, keypair) => keypair,
+                        };
+
                         // send response (might be cookie reply or handshake response)
                         let mut resp_len: u64 = 0;
                         if let Some(msg) = resp {
                             resp_len = msg.len() as u64;
+                            if LittleEndian::read_u32(&msg[..]) == TYPE_COOKIE_REPLY {
+                                wg.load.cookie_reply();
+                            }
                             // TODO: consider a more elegant solution for accessing the bind
                             let _ = wg.router.send_raw(&msg[..], &mut src).map_err(|e| {
                                 debug!(
-                                    "{} : handshake worker, failed to send response, error = {}",
-                                    wg, e
+                                    "endpoint={} event=handshake_send_failed error={:?}",
+                                    addr,
+                                    e.to_string()
                                 );
                             });
+                            wg.capture(opaque, endpoint, Direction::Out, &msg[..], None);
                         }
 
                         // update peer state
                         if let Some(peer) = peer {
+                            accepted = true;
+
                             // authenticated handshake packet received
+                            peer.opaque().revive();
 
                             // add to rx_bytes and tx_bytes
                             let req_len = msg.len() as u64;
@@ -215,49 +298,83 @@ pub fn handshake_worker<T: Tun, B: UDP>(
                                 .fetch_add(resp_len, Ordering::Relaxed);
 
                             // update endpoint
-                            peer.set_endpoint(src);
+                            if roam.get() {
+                                peer.set_endpoint(src);
+                            }
 
                             if resp_len > 0 {
                                 // update timers after sending handshake response
-                                debug!("{} : handshake worker, handshake response sent", wg);
+                                debug!(
+                                    "{} endpoint={} event=handshake_response_sent",
+                                    peer.opaque(),
+                                    addr
+                                );
                                 peer.opaque().sent_handshake_response();
                             } else {
                                 // update timers after receiving handshake response
                                 debug!(
-                                    "{} : handshake worker, handshake response was received",
-                                    wg
+                                    "{} endpoint={} event=handshake_response_received",
+                                    peer.opaque(),
+                                    addr
                                 );
                                 peer.opaque().timers_handshake_complete();
                             }
 
                             // add any new keypair to peer
-                            keypair.map(|kp| {
-                                debug!("{} : handshake worker, new keypair for {}", wg, peer);
-
-                                // this means that a handshake response was processed or sent
-                                peer.opaque().timers_session_derived();
-
-                                // free any unused ids
-                                for id in peer.add_keypair(kp) {
-                                    device.release(id);
-                                }
-                            });
+                            if let Some(kp) = keypair {
+                                add_keypair(kp);
+                            }
                         }
                     }
-                    Err(e) => debug!("{} : handshake worker, error = {:?}", wg, e),
+                    Err((HandshakeError::UnknownPublicKey(pk), _)) => unknown = Some((pk, src)),
+                    Err(_) => (),
+                }
+
+                // consult the peer provider, then process the initiation again
+                // (the provisioned peer is removed unless the initiation is then accepted)
+                drop(device);
+                if let Some(pk) = provisioned {
+                    if !accepted {
+                        debug!("endpoint={} event=peer_provision_revoked", addr);
+                        wg.remove_peer(&pk);
+                    }
+                } else if let Some((pk, src)) = unknown {
+                    if wg.provision_peer(pk, &addr.ip()) {
+                        debug!("endpoint={} event=peer_provisioned", addr);
+                        wg.pending.fetch_add(1, Ordering::SeqCst);
+                        wg.queue.send(HandshakeJob::Message(msg, src, Some(pk)));
+                    } else {
+                        wg.handshake_outcome(None, addr, HandshakeOutcome::UnknownPeer);
+                    }
                 }
             }
             HandshakeJob::New(pk) => {
                 if let Some(peer) = wg.peers.read().get(&pk) {
-                    debug!(
-                        "{} : handshake worker, new handshake requested for {}",
-                        wg, peer
-                    );
+                    debug!("{} event=handshake_requested", peer.opaque());
                     let device = wg.peers.read();
                     let _ = device.begin(&mut OsRng, &pk).map(|msg| {
                         let _ = peer.send_raw(&msg[..]).map_err(|e| {
-                            debug!("{} : handshake worker, failed to send handshake initiation, error = {}", wg, e)
+                            debug!(
+                                "{} event=handshake_send_failed error={:?}",
+                                peer.opaque(),
+                                e.to_string()
+                            )
                         });
+                        let endpoint = Layer::Outer(peer.get_endpoint());
+                        wg.capture(
+                            Some(peer.opaque()),
+                            endpoint,
+                            Direction::Out,
+                            &msg[..],
+                            None,
+                        );
+                        debug!(
+                            "{} endpoint={} event=handshake_initiation_sent",
+                            peer.opaque(),
+                            peer.get_endpoint()
+                                .map(|addr| addr.to_string())
+                                .unwrap_or_default()
+                        );
                         peer.opaque().sent_handshake_initiation();
                     });
                     peer.opaque()